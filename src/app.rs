@@ -1,75 +1,324 @@
 use std::fs;
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{mpsc, Arc};
 use std::time::Duration;
 
 use eframe::egui::{Modifiers, Vec2};
 use eframe::{self, egui, CreationContext};
 use egui_extras::{Size, StripBuilder};
+use midly::live::LiveEvent;
 
-use crate::common::Time;
-use crate::engine::{Engine, EngineCommand, StatusEvent};
-use crate::project::Project;
+use crate::common::{format_time, parse_time, Time, VersionId};
+use crate::config::Config;
+use crate::engine::{Engine, EngineCommand, StatusEvent, StatusEventReceiver};
+use crate::meter::{MeterMap, TimeSignature};
+use crate::pitch::pitch_name;
+use crate::project::{Project, ProjectMeta, SessionState};
+use crate::range::{Range, RangeLike};
 use crate::stave::Stave;
+use crate::track::{ExportOptions, Level, MAX_LEVEL, MIDI_CC_SUSTAIN_ID};
+use crate::track_edit::{clear_time_signature, set_time_signature};
+use crate::Pix;
+
+const PAN_CENTER: Level = 64;
+/// Playback transpose limit either way, semitones.
+const MAX_TRANSPOSE: i8 = 24;
+/// Playhead should move at least this far between status repaints.
+const PIXELS_PER_STATUS_UPDATE: Pix = 2.0;
+const MIN_STATUS_UPDATE_INTERVAL: Time = 10_000;
+const MAX_STATUS_UPDATE_INTERVAL: Time = 500_000;
+
+/// Repaint interval during playback for the given stave zoom (pixels per microsecond).
+fn status_update_interval(time_scale: f32) -> Time {
+    let interval = (PIXELS_PER_STATUS_UPDATE / time_scale) as Time;
+    interval.clamp(MIN_STATUS_UPDATE_INTERVAL, MAX_STATUS_UPDATE_INTERVAL)
+}
+
+/// Current history version, shows when redo is available (the version is undone).
+fn version_label(version: VersionId, max_version: VersionId) -> String {
+    if version < max_version {
+        format!("{} of {}, ⤴ redo available", version, max_version)
+    } else {
+        version.to_string()
+    }
+}
+
+/// Stave scroll for the mouse wheel delta, negative `speed` reverses the direction.
+/// Vertical wheel scrolls forward on wheel down, if enabled and there is no horizontal delta.
+fn wheel_scroll(delta: Vec2, speed: f32, vertical_wheel_scroll: bool) -> Pix {
+    let dx = if delta.x == 0.0 && vertical_wheel_scroll {
+        -delta.y
+    } else {
+        delta.x
+    };
+    dx * speed
+}
+
+/// Forwards engine status to the UI thread.
+fn status_receiver(
+    message_sender: mpsc::Sender<Message>,
+    ctx: egui::Context,
+    status_update_interval: Arc<AtomicI64>,
+) -> Box<StatusEventReceiver> {
+    Box::new(move |ev| {
+        match ev {
+            StatusEvent::Time(t) => {
+                // Will try next time if this fails.
+                if message_sender.send(Message::UpdateTime(t)).is_ok() {
+                    ctx.request_repaint_after(Duration::from_micros(
+                        status_update_interval.load(Ordering::Relaxed) as u64,
+                    ));
+                }
+            }
+            StatusEvent::Transport { paused } => {
+                if message_sender
+                    .send(Message::UpdateTransport { paused })
+                    .is_ok()
+                {
+                    ctx.request_repaint();
+                }
+            }
+            StatusEvent::Recorded(at, event) => {
+                let _ = message_sender.send(Message::Recorded(at, event));
+            }
+        }
+    })
+}
 
 enum Message {
     UpdateTime(Time),
+    /// Engine's pause state changed.
+    UpdateTransport {
+        paused: bool,
+    },
+    /// Background export is complete.
+    Exported(Result<PathBuf, String>),
+    /// Event played on the input keyboard while recording.
+    Recorded(Time, LiveEvent<'static>),
 }
 
 pub struct EmApp {
     title: String,
     home_path: PathBuf,
+    meta: ProjectMeta,
+    /// Last stored session state.
+    session: SessionState,
     stave: Stave,
     engine_command_send: mpsc::Sender<Box<EngineCommand>>,
     message_receiver: mpsc::Receiver<Message>,
     follow_playback: bool,
+    looping: bool,
+    /// Loop region that was last sent to the engine.
+    loop_region: Option<Range<Time>>,
+    /// Track edit count the engine was last synchronized with.
+    engine_edit_count: u64,
+    middle_c_octave: i32,
+    /// Wheel scroll multiplier, negative when reversed.
+    scroll_speed: f32,
+    vertical_wheel_scroll: bool,
+    /// Microseconds, shared with the engine status callback.
+    status_update_interval: Arc<AtomicI64>,
+    message_sender: mpsc::Sender<Message>,
+    /// As last reported by the engine, which may pause by itself.
+    engine_paused: bool,
+    /// Outcome of the last export.
+    export_status: String,
+    /// History compaction is due once the window is shown, see [Config::compact_history].
+    compact_pending: bool,
+    /// Progress or outcome of the history compaction.
+    history_status: String,
+    /// Overrides the resolution of the project.
+    export_ticks_per_beat: Option<u16>,
+    /// Playback pitch offset, semitones.
+    transpose: i8,
+    export_transposed: bool,
+    /// Clear track command is waiting for confirmation.
+    confirm_clear: bool,
+    /// Text of the "go to time" dialog, if it is open.
+    go_to_input: Option<String>,
+    go_to_error: String,
+    /// Text of the "time signature" dialog, if it is open.
+    time_signature_input: Option<String>,
+    time_signature_error: String,
+    /// Path in the "run script" dialog, if it is open.
+    script_input: Option<String>,
+    script_error: String,
+    /// Last successfully run script.
+    script_path: String,
+    /// Path in the "merge MIDI file" dialog, if it is open.
+    merge_input: Option<String>,
+    /// Imported times multiplier, text.
+    merge_time_scale: String,
+    merge_error: String,
+    /// The merge dialog has just been opened, its path field should take focus.
+    merge_opened: bool,
+    /// Input keyboard events are recorded into the track while playing.
+    recording: bool,
+    /// Recorded notes are moved to the quantize grid.
+    record_quantize: bool,
+    /// Events of the current take, added to the track when playback stops.
+    recorded: Vec<(Time, LiveEvent<'static>)>,
+    /// Time selection when recording was armed, the takes replace only this range.
+    punch: Option<Range<Time>>,
 }
 
 impl EmApp {
     pub fn new(
         ctx: &CreationContext,
+        config: &Config,
         engine_command_send: mpsc::Sender<Box<EngineCommand>>,
         project: Project,
     ) -> EmApp {
         let (message_sender, message_receiver) = mpsc::channel();
 
-        let app = EmApp {
+        let mut app = EmApp {
             title: project.title,
             home_path: project.home_path,
-            stave: Stave::new(project.history),
+            meta: project.meta,
+            session: project.session.clone(),
+            stave: Stave::new(config, project.history),
             engine_command_send,
             message_receiver,
             follow_playback: false,
+            looping: false,
+            loop_region: None,
+            engine_edit_count: 0,
+            middle_c_octave: config.middle_c_octave,
+            scroll_speed: if config.reverse_scroll {
+                -config.scroll_speed
+            } else {
+                config.scroll_speed
+            },
+            vertical_wheel_scroll: config.vertical_wheel_scroll,
+            status_update_interval: Arc::new(AtomicI64::new(MIN_STATUS_UPDATE_INTERVAL)),
+            message_sender: message_sender.clone(),
+            engine_paused: false,
+            export_status: String::new(),
+            compact_pending: config.compact_history,
+            history_status: String::new(),
+            export_ticks_per_beat: config.export_ticks_per_beat,
+            transpose: 0,
+            export_transposed: config.export_transposed,
+            confirm_clear: false,
+            go_to_input: None,
+            go_to_error: String::new(),
+            time_signature_input: None,
+            time_signature_error: String::new(),
+            script_input: None,
+            script_error: String::new(),
+            script_path: String::new(),
+            merge_input: None,
+            merge_time_scale: "1".to_string(),
+            merge_error: String::new(),
+            merge_opened: false,
+            recording: false,
+            record_quantize: config.record_quantize,
+            recorded: vec![],
+            punch: None,
         };
 
-        let engine_receiver_ctx = ctx.egui_ctx.clone();
-        let engine_status_receiver = Box::new(move |ev| {
-            match ev {
-                StatusEvent::Time(t) => {
-                    match message_sender.send(Message::UpdateTime(t)) {
-                        Ok(_) => {
-                            engine_receiver_ctx.request_repaint_after(Duration::from_micros(20_000))
-                        }
-                        _ => (), // Will try next time.
-                    }
-                }
-            }
-        });
+        app.stave.restore_session(&project.session);
+
+        let engine_status_receiver = status_receiver(
+            message_sender,
+            ctx.egui_ctx.clone(),
+            app.status_update_interval.clone(),
+        );
         app.engine_command_send
             .send(Box::new(|engine| {
                 engine.set_status_receiver(Some(engine_status_receiver));
             }))
             .unwrap();
+        app.stave.ties = app.meta.ties.clone();
         app
     }
 
+    /// Compact the history on the frame after the one that shows that it is started,
+    /// a long history would otherwise keep the window from appearing.
+    fn compact_history(&mut self, ctx: &egui::Context) {
+        if !self.compact_pending {
+            return;
+        }
+        if self.history_status.is_empty() {
+            self.history_status = "compacting history...".to_string();
+            ctx.request_repaint();
+        } else {
+            self.stave.history.borrow_mut().compact();
+            self.compact_pending = false;
+            self.history_status = "history compacted".to_string();
+        }
+    }
+
+    /// Arm or disarm recording, the time selection (if any) becomes the punch range.
+    fn toggle_recording(&mut self, ctx: &egui::Context) {
+        self.recording = !self.recording;
+        let recording = self.recording;
+        self.engine_command_send
+            .send(Box::new(move |engine| engine.set_recording(recording)))
+            .unwrap();
+        if recording {
+            self.punch = self
+                .stave
+                .time_selection
+                .map(|sel| (sel.0.min(sel.1), sel.0.max(sel.1)))
+                .filter(|r| !r.is_empty());
+        } else {
+            // The last take still belongs to the punch range.
+            self.store_take(ctx, self.stave.cursor_position);
+            self.punch = None;
+        }
+    }
+
+    /// Add the recorded events to the track as one edit, held notes end at `end` or later.
+    fn store_take(&mut self, ctx: &egui::Context, end: Time) {
+        if self.recorded.is_empty() {
+            return;
+        }
+        let events = std::mem::take(&mut self.recorded);
+        let end = events
+            .iter()
+            .map(|(at, _)| *at)
+            .max()
+            .unwrap_or_default()
+            .max(end);
+        let punch = self.punch;
+        self.stave
+            .record(ctx, &events, end, punch.as_ref(), self.record_quantize);
+    }
+
+    /// With punch in, each pass through the range is a separate take.
+    fn punch_out(&mut self, ctx: &egui::Context, at: Time) {
+        let (Some(punch), Some((last, _))) = (self.punch, self.recorded.last()) else {
+            return;
+        };
+        if at < *last {
+            // Looped back to the start.
+            self.store_take(ctx, punch.1);
+        } else if punch.1 <= at {
+            self.store_take(ctx, at);
+        }
+    }
+
+    /// Pause or resume, relative to the engine's actual state.
     fn toggle_pause(&mut self) {
+        let paused = !self.engine_paused;
         self.engine_command_send
-            .send(Box::new(|engine| engine.toggle_pause()))
+            .send(Box::new(move |engine| engine.set_paused(paused)))
             .unwrap();
     }
 
-    fn export(&mut self) {
+    /// Play the selected notes once.
+    fn audition_selection(&mut self) {
+        if let Some(range) = self.stave.selection_range() {
+            self.engine_command_send
+                .send(Box::new(move |engine| engine.play_range(range)))
+                .unwrap();
+        }
+    }
+
+    /// Make a new timestamped file path in the project's export directory.
+    fn export_path(&self, extension: &str) -> PathBuf {
         let mut path = self.home_path.clone();
         path.push("export");
         if !path.is_dir() {
@@ -78,11 +327,357 @@ impl EmApp {
         }
         path.push(
             chrono::Local::now()
-                .format("%Y-%m-%d_%H-%M-%S.mid")
-                .to_string(),
+                .format("%Y-%m-%d_%H-%M-%S.")
+                .to_string()
+                + extension,
         );
+        path
+    }
+
+    /// Notify the UI when background export completes.
+    fn export_done(&self, ctx: &egui::Context) -> impl FnOnce(Result<PathBuf, String>) {
+        let sender = self.message_sender.clone();
+        let ctx = ctx.clone();
+        move |result| {
+            if sender.send(Message::Exported(result)).is_ok() {
+                ctx.request_repaint();
+            }
+        }
+    }
+
+    fn export_options(&self) -> ExportOptions {
+        let mut options = self.meta.export_options();
+        if self.export_ticks_per_beat.is_some() {
+            options.ticks_per_beat = self.export_ticks_per_beat;
+        }
+        if self.export_transposed {
+            options.transpose = self.transpose;
+        }
+        options
+    }
+
+    fn export(&mut self, ctx: &egui::Context) {
+        let path = self.export_path("mid");
         log::info!("Saving to {}", path.to_string_lossy());
-        self.stave.save_to(&PathBuf::from(path));
+        self.export_status = "exporting...".to_string();
+        self.stave
+            .save_to(&self.export_options(), path, self.export_done(ctx));
+    }
+
+    fn has_selection(&self) -> bool {
+        self.stave.time_selection.is_some() || self.stave.note_selection.count() > 0
+    }
+
+    /// Export the time selection, or the selected notes if there is no time selection.
+    fn export_selection(&mut self, ctx: &egui::Context) {
+        if !self.has_selection() {
+            return;
+        }
+        let path = self.export_path("mid");
+        log::info!("Saving selection to {}", path.to_string_lossy());
+        self.export_status = "exporting...".to_string();
+        if let Some(sel) = self.stave.time_selection {
+            let range = (sel.0.min(sel.1), sel.0.max(sel.1));
+            self.stave
+                .save_region_to(&self.export_options(), &range, path, self.export_done(ctx));
+        } else {
+            self.stave
+                .save_selected_to(&self.export_options(), path, self.export_done(ctx));
+        }
+    }
+
+    fn export_bundle(&mut self) {
+        let path = self.export_path(Project::BUNDLE_NAME_EXT);
+        log::info!("Bundling project to {}", path.to_string_lossy());
+        Project::export_bundle(&self.home_path, &path);
+    }
+
+    fn show_clear_confirmation(&mut self, ctx: &egui::Context) {
+        if !self.confirm_clear {
+            return;
+        }
+        egui::Window::new("Clear track")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("Delete all events of the track? This can be undone.");
+                ui.horizontal(|ui| {
+                    if ui.button("Clear").clicked() {
+                        self.stave.clear_track(ctx);
+                        self.confirm_clear = false;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.confirm_clear = false;
+                    }
+                });
+            });
+    }
+
+    fn show_go_to_dialog(&mut self, ctx: &egui::Context) {
+        let Some(input) = &mut self.go_to_input else {
+            return;
+        };
+        let mut close = false;
+        let mut go_to = None;
+        egui::Window::new("Go to")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("Time as mm'ss.mmm or bar:beat");
+                let response = ui.text_edit_singleline(input);
+                response.request_focus();
+                if !self.go_to_error.is_empty() {
+                    ui.colored_label(ui.visuals().error_fg_color, &self.go_to_error);
+                }
+                let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                ui.horizontal(|ui| {
+                    if ui.button("Go").clicked() || enter {
+                        let meter = self.stave.history.borrow().with_track(MeterMap::of_track);
+                        match parse_time(input, &meter) {
+                            Ok(at) => go_to = Some(at),
+                            Err(message) => self.go_to_error = message,
+                        }
+                    }
+                    if ui.button("Cancel").clicked()
+                        || ui.input(|i| i.key_pressed(egui::Key::Escape))
+                    {
+                        close = true;
+                    }
+                });
+            });
+        if let Some(at) = go_to {
+            self.stave.cursor_position = at;
+            self.stave.scroll_to(at, 0.1);
+            self.engine_seek(at);
+            close = true;
+        }
+        if close {
+            self.go_to_input = None;
+            self.go_to_error.clear();
+        }
+    }
+
+    /// Set or remove the time signature change at the cursor.
+    fn show_time_signature_dialog(&mut self, ctx: &egui::Context) {
+        let Some(input) = &mut self.time_signature_input else {
+            return;
+        };
+        let at = self.stave.cursor_position;
+        let mut close = false;
+        egui::Window::new("Time signature")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(format!("Meter from {}, e.g. 3/4", format_time(at)));
+                let response = ui.text_edit_singleline(input);
+                response.request_focus();
+                if !self.time_signature_error.is_empty() {
+                    ui.colored_label(ui.visuals().error_fg_color, &self.time_signature_error);
+                }
+                let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                ui.horizontal(|ui| {
+                    if ui.button("Set").clicked() || enter {
+                        match input.parse::<TimeSignature>() {
+                            Ok(signature) => {
+                                let mut history = self.stave.history.borrow_mut();
+                                let id_seq = history.id_seq.clone();
+                                history.update_track(|track| {
+                                    set_time_signature(track, &id_seq, &at, signature)
+                                });
+                                close = true;
+                            }
+                            Err(message) => self.time_signature_error = message,
+                        }
+                    }
+                    if ui.button("Remove").clicked() {
+                        self.stave
+                            .history
+                            .borrow_mut()
+                            .update_track(|track| clear_time_signature(track, &at));
+                        close = true;
+                    }
+                    if ui.button("Cancel").clicked()
+                        || ui.input(|i| i.key_pressed(egui::Key::Escape))
+                    {
+                        close = true;
+                    }
+                });
+            });
+        if close {
+            self.time_signature_input = None;
+            self.time_signature_error.clear();
+        }
+    }
+
+    fn show_script_dialog(&mut self, ctx: &egui::Context) {
+        let Some(input) = &mut self.script_input else {
+            return;
+        };
+        let mut close = false;
+        egui::Window::new("Run script")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("Rhai script file");
+                let response = ui.text_edit_singleline(input);
+                response.request_focus();
+                if !self.script_error.is_empty() {
+                    ui.colored_label(ui.visuals().error_fg_color, &self.script_error);
+                }
+                let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                ui.horizontal(|ui| {
+                    if ui.button("Run").clicked() || enter {
+                        let result = fs::read_to_string(input.trim())
+                            .map_err(|e| format!("Cannot read {}: {}", input.trim(), e))
+                            .and_then(|source| self.stave.run_script(ctx, &source));
+                        match result {
+                            Ok(()) => {
+                                self.script_path = input.trim().to_string();
+                                close = true;
+                            }
+                            Err(message) => self.script_error = message,
+                        }
+                    }
+                    if ui.button("Cancel").clicked()
+                        || ui.input(|i| i.key_pressed(egui::Key::Escape))
+                    {
+                        close = true;
+                    }
+                });
+            });
+        if close {
+            self.script_input = None;
+            self.script_error.clear();
+        }
+    }
+
+    /// Write the session state if it has changed. View changes alone (e.g. scrolling)
+    /// are only written with `including_view`, to avoid writing on every frame.
+    fn store_session(&mut self, including_view: bool) {
+        let session = self.stave.session_state();
+        let changed = if including_view {
+            session != self.session
+        } else {
+            SessionState {
+                view: self.session.view,
+                ..session.clone()
+            } != self.session
+        };
+        if changed {
+            session.store(&self.home_path);
+            self.session = session;
+        }
+    }
+
+    fn show_merge_dialog(&mut self, ctx: &egui::Context) {
+        let Some(input) = &mut self.merge_input else {
+            return;
+        };
+        let mut close = false;
+        egui::Window::new("Merge MIDI file")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("MIDI file, inserted at the cursor");
+                let response = ui.text_edit_singleline(input);
+                if self.merge_opened {
+                    // Focusing on every frame would keep the time scale field from being edited.
+                    response.request_focus();
+                    self.merge_opened = false;
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Time scale")
+                        .on_hover_text("Multiplies imported times, 2 makes it twice slower.");
+                    ui.text_edit_singleline(&mut self.merge_time_scale);
+                });
+                if !self.merge_error.is_empty() {
+                    ui.colored_label(ui.visuals().error_fg_color, &self.merge_error);
+                }
+                let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                ui.horizontal(|ui| {
+                    if ui.button("Merge").clicked() || enter {
+                        let result = match self.merge_time_scale.trim().parse::<f64>() {
+                            Ok(time_scale) if time_scale > 0.0 => {
+                                self.stave
+                                    .merge_file(ctx, &PathBuf::from(input.trim()), time_scale)
+                            }
+                            _ => Err(format!(
+                                "Time scale should be a positive number, got \"{}\"",
+                                self.merge_time_scale.trim()
+                            )),
+                        };
+                        match result {
+                            Ok(()) => close = true,
+                            Err(message) => self.merge_error = message,
+                        }
+                    }
+                    if ui.button("Cancel").clicked()
+                        || ui.input(|i| i.key_pressed(egui::Key::Escape))
+                    {
+                        close = true;
+                    }
+                });
+            });
+        if close {
+            self.merge_input = None;
+            self.merge_error.clear();
+        }
+    }
+
+    /// Select which controller's values are shown and edited on the damper lane.
+    fn controller_picker(&mut self, ui: &mut egui::Ui) {
+        let mut controller_ids = self
+            .stave
+            .history
+            .borrow()
+            .with_track(|track| track.controller_ids());
+        // Sustain is always available, to be able to add pedal to a track that has none yet.
+        controller_ids.insert(MIDI_CC_SUSTAIN_ID);
+        egui::ComboBox::from_id_salt("controller")
+            .selected_text(format!("CC {}", self.stave.active_controller))
+            .show_ui(ui, |ui| {
+                for cc_id in controller_ids {
+                    ui.selectable_value(
+                        &mut self.stave.active_controller,
+                        cc_id,
+                        format!("CC {}", cc_id),
+                    );
+                }
+            });
+    }
+
+    /// Keep engine's loop in sync with the time selection.
+    fn update_loop_region(&mut self) {
+        let region = if self.looping {
+            self.stave
+                .time_selection
+                .map(|sel| (sel.0.min(sel.1), sel.0.max(sel.1)))
+                .filter(|r| !r.is_empty())
+        } else {
+            None
+        };
+        if region != self.loop_region {
+            self.loop_region = region;
+            self.engine_command_send
+                .send(Box::new(move |engine| engine.set_loop_region(region)))
+                .unwrap();
+        }
+    }
+
+    /// Tell the engine to catch up after the track is edited.
+    fn update_engine_track(&mut self) {
+        let edit_count = self.stave.history.borrow().edit_count();
+        if edit_count != self.engine_edit_count {
+            self.engine_edit_count = edit_count;
+            self.engine_command_send
+                .send(Box::new(|engine| engine.resync()))
+                .unwrap();
+        }
     }
 
     fn engine_seek(&self, to: Time) {
@@ -93,34 +688,114 @@ impl EmApp {
 }
 
 impl eframe::App for EmApp {
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.store_session(true);
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        if let Some(message) = self.message_receiver.try_iter().last() {
+        let mut time_update = None;
+        let mut take_done = false;
+        while let Ok(message) = self.message_receiver.try_recv() {
             match message {
-                Message::UpdateTime(t) => {
-                    self.stave.cursor_position = t;
-                    if self.follow_playback {
-                        let at = self.stave.cursor_position;
-                        self.stave.scroll_to(at, 0.1);
-                    }
+                // Only the latest time matters.
+                Message::UpdateTime(t) => time_update = Some(t),
+                Message::UpdateTransport { paused } => {
+                    self.engine_paused = paused;
+                    take_done |= paused;
+                }
+                Message::Recorded(at, event) => {
+                    self.punch_out(ctx, at);
+                    self.recorded.push((at, event));
+                }
+                Message::Exported(Ok(path)) => {
+                    log::info!("Exported {}", path.to_string_lossy());
+                    self.export_status = "exported".to_string();
+                }
+                Message::Exported(Err(message)) => {
+                    log::error!("Export failed: {}", message);
+                    self.export_status = format!("export failed: {}", message);
                 }
             }
         }
+        if let Some(t) = time_update {
+            self.stave.cursor_position = t;
+            if self.follow_playback {
+                let at = self.stave.cursor_position;
+                self.stave.scroll_to(at, 0.1);
+            }
+        }
+        if take_done {
+            self.store_take(ctx, self.stave.cursor_position);
+        } else if let Some(t) = time_update {
+            self.punch_out(ctx, t);
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
-            if ui.input_mut(|i| {
+            if ctx.wants_keyboard_input() {
+                // Keys go to a text field.
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(
+                    Modifiers::SHIFT,
+                    egui::Key::Space,
+                ))
+            }) {
+                self.audition_selection();
+            } else if ui.input_mut(|i| {
                 i.consume_shortcut(&egui::KeyboardShortcut::new(
                     Modifiers::NONE,
                     egui::Key::Space,
                 ))
             }) {
                 self.toggle_pause();
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(
+                    Modifiers::CTRL | Modifiers::SHIFT,
+                    egui::Key::S,
+                ))
+            }) {
+                self.export_selection(ctx);
             } else if ui.input_mut(|i| {
                 i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::S))
             }) {
-                self.export();
+                self.export(ctx);
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(
+                    Modifiers::NONE,
+                    egui::Key::Escape,
+                ))
+            }) {
+                self.engine_command_send
+                    .send(Box::new(Engine::reset))
+                    .unwrap();
             } else if ui.input_mut(|i| {
                 i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::F))
             }) {
                 self.follow_playback = !self.follow_playback;
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::G))
+            }) {
+                self.go_to_input = Some(format_time(self.stave.cursor_position));
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::T))
+            }) {
+                let at = self.stave.cursor_position;
+                let meter = self.stave.history.borrow().with_track(MeterMap::of_track);
+                self.time_signature_input = Some(meter.time_signature_at(at).to_string());
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(
+                    Modifiers::CTRL | Modifiers::SHIFT,
+                    egui::Key::R,
+                ))
+            }) {
+                self.toggle_recording(ui.ctx());
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::R))
+            }) {
+                self.script_input = Some(self.script_path.clone());
+            } else if ui.input_mut(|i| {
+                i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::I))
+            }) {
+                self.merge_input = Some(String::new());
+                self.merge_opened = true;
             } else if ui.input_mut(|i| {
                 i.consume_shortcut(&egui::KeyboardShortcut::new(
                     Modifiers::NONE,
@@ -139,7 +814,11 @@ impl eframe::App for EmApp {
 
             {
                 let h = self.stave.history.borrow();
-                ui.heading(format!("🌲 {} [{}]", self.title, h.version()));
+                ui.heading(format!(
+                    "🌲 {} [{}]",
+                    self.title,
+                    version_label(h.version(), h.max_version)
+                ));
             }
             StripBuilder::new(ui)
                 .size(Size::remainder())
@@ -148,15 +827,35 @@ impl eframe::App for EmApp {
                 .vertical(|mut strip| {
                     strip.cell(|ui| {
                         let response = self.stave.show(ui);
+                        self.update_engine_track();
+                        self.store_session(false);
+                        if self.stave.ties != self.meta.ties {
+                            self.meta.ties = self.stave.ties.clone();
+                            self.meta.store(&self.home_path);
+                        }
 
                         if let Some(hover_pos) = response.ui_response.hover_pos() {
                             let dz = ui.input(|i| i.zoom_delta());
                             if dz != 1.0 {
                                 self.stave.zoom(dz, hover_pos.x);
                             }
-                            let scroll_delta = ui.input(|i| i.smooth_scroll_delta);
-                            if scroll_delta != Vec2::ZERO {
-                                self.stave.scroll_by(scroll_delta.x);
+                            let (delta, modifiers) =
+                                ui.input(|i| (i.smooth_scroll_delta, i.modifiers));
+                            if modifiers.alt {
+                                // Shift turns the wheel movement horizontal.
+                                let dy = delta.x + delta.y;
+                                if dy != 0.0 {
+                                    self.stave.pitch_wheel(dy, hover_pos.y, !modifiers.shift);
+                                }
+                            } else {
+                                let dx = wheel_scroll(
+                                    delta,
+                                    self.scroll_speed,
+                                    self.vertical_wheel_scroll,
+                                );
+                                if dx != 0.0 {
+                                    self.stave.scroll_by(dx);
+                                }
                             }
                         }
                         if let Some(pos) = response.new_cursor_position {
@@ -180,28 +879,92 @@ impl eframe::App for EmApp {
                                 self.stave.scroll_by(scroll_step);
                             }
                             ui.checkbox(&mut self.follow_playback, "Follow playback");
+                            ui.checkbox(&mut self.stave.show_bar_ruler, "Bars");
+                            ui.checkbox(&mut self.looping, "🔁 Loop selection");
+                            let record_label = if self.recording {
+                                egui::RichText::new("⏺ Rec").color(egui::Color32::RED)
+                            } else {
+                                egui::RichText::new("⏺ Rec")
+                            };
+                            if ui
+                                .selectable_label(self.recording, record_label)
+                                .on_hover_text(
+                                    "Record the input keyboard while playing (Ctrl+Shift+R), \
+                                    only in the time selection if there is one",
+                                )
+                                .clicked()
+                            {
+                                self.toggle_recording(ui.ctx());
+                            }
+                            ui.checkbox(&mut self.record_quantize, "Quantize input")
+                                .on_hover_text("Recorded notes start on the quantize grid");
+                            if ui
+                                .add(
+                                    egui::DragValue::new(&mut self.transpose)
+                                        .range(-MAX_TRANSPOSE..=MAX_TRANSPOSE)
+                                        .prefix("transpose "),
+                                )
+                                .on_hover_text("Playback pitch offset, semitones")
+                                .changed()
+                            {
+                                let semitones = self.transpose;
+                                self.engine_command_send
+                                    .send(Box::new(move |engine| engine.set_transpose(semitones)))
+                                    .unwrap();
+                            }
                             if ui.button(" ⏮ ").clicked() {
                                 self.engine_seek(0);
                             }
-                            if ui.button("🔇").clicked() {
+                            if ui
+                                .button("🔇")
+                                .on_hover_text("Stop playback and silence all notes (Escape)")
+                                .clicked()
+                            {
                                 self.engine_command_send
                                     .send(Box::new(Engine::reset))
                                     .unwrap();
                             }
                             if ui.button("🚩Export").clicked() {
-                                self.export();
+                                self.export(ctx);
                             }
+                            if self.has_selection()
+                                && ui
+                                    .button("🚩Export selection")
+                                    .on_hover_text("Ctrl+Shift+S")
+                                    .clicked()
+                            {
+                                self.export_selection(ctx);
+                            }
+                            if ui.button("📦 Bundle").clicked() {
+                                self.export_bundle();
+                            }
+                            let mut pan = self.meta.pan.unwrap_or(PAN_CENTER);
+                            if ui
+                                .add(
+                                    egui::DragValue::new(&mut pan)
+                                        .range(0..=MAX_LEVEL)
+                                        .prefix("pan "),
+                                )
+                                .changed()
+                            {
+                                self.meta.pan = if pan == PAN_CENTER { None } else { Some(pan) };
+                                self.meta.store(&self.home_path);
+                            }
+                            self.controller_picker(ui);
                             if ui.button("⤵ Undo").clicked() {
                                 self.stave.history.borrow_mut().undo(&mut vec![]);
                             }
                             if ui.button("⤴ Redo").clicked() {
                                 self.stave.history.borrow_mut().redo(&mut vec![]);
                             }
+                            if ui.button("🗑 Clear").clicked() {
+                                self.confirm_clear = true;
+                            }
                         });
                         ui.horizontal(|ui| {
                             // Status line
                             ui.label(format!(
-                                "track_len={}  n_sel={}  t_sel={}  at={}s ",
+                                "track_len={}  n_sel={}  t_sel={}  at={}  key={}  {}  {}",
                                 self.stave.history.borrow().with_track(|t| t.events.len()),
                                 self.stave.note_selection.count(),
                                 self.stave.time_selection.as_ref().map_or(
@@ -216,11 +979,100 @@ impl eframe::App for EmApp {
                                         )
                                     },
                                 ),
-                                Duration::from_micros(self.stave.cursor_position as u64).as_secs()
+                                format_time(self.stave.cursor_position),
+                                self.stave
+                                    .pitch_hovered
+                                    .map_or("-".to_string(), |p| pitch_name(
+                                        p,
+                                        self.middle_c_octave
+                                    )),
+                                self.export_status,
+                                self.history_status
                             ));
                         });
                     })
                 });
         });
+        self.show_clear_confirmation(ctx);
+        self.show_go_to_dialog(ctx);
+        self.show_time_signature_dialog(ctx);
+        self.show_script_dialog(ctx);
+        self.show_merge_dialog(ctx);
+        self.compact_history(ctx);
+        self.update_loop_region();
+        if !self.stave.is_degenerate() {
+            self.status_update_interval.store(
+                status_update_interval(self.stave.time_scale()),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_update_interval_by_zoom() {
+        // 1 pixel per millisecond.
+        assert_eq!(status_update_interval(0.001), 10_000);
+        // 1 pixel per 10 milliseconds.
+        assert_eq!(status_update_interval(0.000_1), 20_000);
+        // Zoomed out, 1 pixel per second.
+        assert_eq!(status_update_interval(0.000_001), 500_000);
+        // Zoomed in.
+        assert_eq!(status_update_interval(1.0), 10_000);
+    }
+
+    #[test]
+    fn wheel_scroll_mapping() {
+        let horizontal = Vec2::new(10.0, 0.0);
+        let vertical = Vec2::new(0.0, -10.0);
+        assert_eq!(wheel_scroll(horizontal, 1.0, false), 10.0);
+        assert_eq!(wheel_scroll(horizontal, -2.0, true), -20.0);
+        assert_eq!(wheel_scroll(vertical, 1.0, false), 0.0);
+        // Wheel down scrolls forward.
+        assert_eq!(wheel_scroll(vertical, 1.5, true), 15.0);
+        assert_eq!(wheel_scroll(vertical, -1.0, true), -10.0);
+        // Horizontal delta takes precedence.
+        assert_eq!(wheel_scroll(Vec2::new(3.0, 5.0), 1.0, true), 3.0);
+    }
+
+    #[test]
+    fn transport_feedback() {
+        let (sender, receiver) = mpsc::channel();
+        let status_update_interval = Arc::new(AtomicI64::new(MIN_STATUS_UPDATE_INTERVAL));
+        let (command_sender, command_receiver) = mpsc::channel();
+        let mut engine = Engine::with_output(Box::new(|_| {}), command_sender, command_receiver);
+        engine.set_status_receiver(Some(status_receiver(
+            sender,
+            egui::Context::default(),
+            status_update_interval,
+        )));
+        // The app keeps the last reported state as `engine_paused`.
+        let engine_paused = || {
+            receiver
+                .try_iter()
+                .filter_map(|message| match message {
+                    Message::UpdateTransport { paused } => Some(paused),
+                    _ => None,
+                })
+                .last()
+        };
+        assert_eq!(engine_paused(), Some(false));
+        engine.set_paused(true);
+        assert_eq!(engine_paused(), Some(true));
+        engine.set_paused(false);
+        assert_eq!(engine_paused(), Some(false));
+        // Engine reset pauses playback by itself, so Space should resume it after that.
+        engine.reset();
+        assert_eq!(engine_paused(), Some(true));
+    }
+
+    #[test]
+    fn redo_indication() {
+        assert_eq!(version_label(5, 5), "5");
+        assert_eq!(version_label(3, 5), "3 of 5, ⤴ redo available");
     }
 }
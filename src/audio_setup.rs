@@ -1,10 +1,14 @@
+use std::fs;
+use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 use std::sync::{mpsc, Arc, Mutex};
 
 use midir::{MidiInput, MidiInputConnection, MidiOutputConnection};
 use midly::live::LiveEvent;
+use serde::{Deserialize, Serialize};
 
 use crate::engine::{Engine, EngineCommand};
+use crate::{common, util};
 
 pub fn setup_audio_engine(
     midi_output: MidiOutputConnection,
@@ -14,30 +18,126 @@ pub fn setup_audio_engine(
     (engine.start(), command_sender)
 }
 
-// TODO (refactoring) Convert this into event source? Note: on pause engine stops all sources,
-//      may want this to be active when not playing the track (e.g. to make edits audible).
-pub fn midi_keyboard_input(
-    name_prefix: &str,
+/// MIDI devices used in the last session, these are preferred on the next start.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DeviceState {
+    midi_input_port_names: Vec<String>,
+}
+
+impl DeviceState {
+    fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|d| d.join(common::APP_NAME).join("midi-devices"))
+    }
+
+    fn load() -> Self {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => DeviceState::default(),
+        }
+    }
+
+    /// The state is not essential, an unreadable file is ignored.
+    fn load_from(path: &PathBuf) -> Self {
+        if !path.is_file() {
+            return DeviceState::default();
+        }
+        util::try_load(path).unwrap_or_else(|message| {
+            log::warn!("MIDI devices state is not restored, {}", message);
+            DeviceState::default()
+        })
+    }
+
+    fn store(&self) {
+        if let Some(path) = Self::path() {
+            if let Some(dir) = path.parent() {
+                if let Err(e) = fs::create_dir_all(dir) {
+                    log::warn!("Cannot save MIDI devices state: {}", e);
+                    return;
+                }
+            }
+            util::store(self, &path);
+        }
+    }
+}
+
+/// Choose input ports to connect to. Remembered ports are preferred if any of them
+/// are still available, otherwise the first port matching each configured name prefix is used.
+fn select_input_ports(
+    available: &[String],
+    remembered: &[String],
+    configured_prefixes: &[String],
+) -> Vec<String> {
+    let mut selected = vec![];
+    for name in remembered {
+        if available.contains(name) {
+            selected.push(name.to_owned());
+        } else {
+            log::warn!("Remembered MIDI input '{}' is not available.", name);
+        }
+    }
+    if selected.is_empty() {
+        for prefix in configured_prefixes {
+            if let Some(name) = available.iter().find(|name| name.starts_with(prefix)) {
+                if !selected.contains(name) {
+                    selected.push(name.to_owned());
+                }
+            }
+        }
+    }
+    selected
+}
+
+/// Connect to all the selected keyboards. The returned connections should be kept open.
+pub fn midi_keyboard_inputs(
+    configured_prefixes: &[String],
     engine: &mut Arc<Mutex<Engine>>,
-) -> Option<MidiInputConnection<()>> {
+) -> Vec<MidiInputConnection<()>> {
     let input = MidiInput::new("emmate").unwrap();
-    let mut port_idx = None;
     log::debug!("Available MIDI input ports:");
-    let ports = input.ports();
-    for (i, port) in ports.iter().enumerate() {
+    let mut available = vec![];
+    for port in input.ports() {
         let name = input.port_name(&port).unwrap();
         log::debug!("\t{}", name);
-        if name.starts_with(name_prefix) {
-            port_idx = Some(i);
-            log::info!("Selected MIDI input: '{}'", name);
-            break;
-        }
+        available.push(name);
     }
-    if port_idx == None {
+    let mut state = DeviceState::load();
+    let selected = select_input_ports(
+        &available,
+        &state.midi_input_port_names,
+        configured_prefixes,
+    );
+    if selected.is_empty() {
         log::warn!("WARN No midi input selected.");
-        return None;
     }
-    let port = ports.get(port_idx.unwrap()).unwrap();
+    let mut connections = vec![];
+    for name in &selected {
+        if let Some(connection) = midi_keyboard_input(name, engine) {
+            connections.push(connection);
+        }
+    }
+    if !selected.is_empty() {
+        state.midi_input_port_names = selected;
+        state.store();
+    }
+    connections
+}
+
+// TODO (refactoring) Convert this into event source? Note: on pause engine stops all sources,
+//      may want this to be active when not playing the track (e.g. to make edits audible).
+fn midi_keyboard_input(
+    port_name: &str,
+    engine: &mut Arc<Mutex<Engine>>,
+) -> Option<MidiInputConnection<()>> {
+    let input = MidiInput::new("emmate").unwrap();
+    let ports = input.ports();
+    let Some(port) = ports
+        .iter()
+        .find(|port| input.port_name(port).is_ok_and(|name| name == port_name))
+    else {
+        log::warn!("MIDI input '{}' is not found.", port_name);
+        return None;
+    };
+    log::info!("Selected MIDI input: '{}'", port_name);
     let engine = engine.clone();
     // TODO Probably we should have an input source for this case. It may need
     //      special handling while the engine is paused.
@@ -56,10 +156,49 @@ pub fn midi_keyboard_input(
                     }
                     // TODO (bug) Effect of sustain events does not last for some reason.
                     //      Triggering noise is there but subsequent notes do not feel the effect.
-                    engine.lock().unwrap().process(le);
+                    engine.lock().unwrap().input(le);
                 },
                 (),
             )
             .expect("MIDI input port"),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn prefer_remembered_ports() {
+        let available = names(&["Midi Through:0", "MPK mini 3:0", "XPIANOGT:0"]);
+        let configured = names(&["XPIANOGT", "MPK mini"]);
+        assert_eq!(
+            select_input_ports(&available, &names(&["MPK mini 3:0"]), &configured),
+            names(&["MPK mini 3:0"])
+        );
+        // Remembered device has disappeared.
+        assert_eq!(
+            select_input_ports(&available, &names(&["Digital Piano:0"]), &configured),
+            names(&["XPIANOGT:0", "MPK mini 3:0"])
+        );
+        assert_eq!(
+            select_input_ports(&available, &[], &configured),
+            names(&["XPIANOGT:0", "MPK mini 3:0"])
+        );
+        assert!(select_input_ports(&[], &[], &configured).is_empty());
+    }
+
+    #[test]
+    fn corrupt_device_state() {
+        let dir = util::fresh_test_dir("test_corrupt_device_state");
+        let path = dir.join("midi-devices");
+        fs::write(&path, b"trunc").unwrap();
+        assert!(DeviceState::load_from(&path)
+            .midi_input_port_names
+            .is_empty());
+    }
+}
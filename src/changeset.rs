@@ -84,29 +84,52 @@ impl Changeset {
 
         if let Some(op) = new_action {
             self.changes.insert(id, op);
+        } else {
+            // The actions cancel each other.
+            self.changes.remove(&id);
         }
     }
 
+    /// Compose an action with the previous one on the same event, `None` if they cancel out.
+    /// The result has the state before `prev_action` and after `action`. Inconsistent
+    /// combinations (e.g. a double insert) are logged and resolved in favour of the latest state.
     fn merge_actions(
         id: EventId,
         action: &EventAction,
         prev_action: &EventAction,
     ) -> Option<EventAction> {
-        // May not even need all the cases, no use-case for merging changesets ATM...
-        // Just covering everything, for now, to see how it goes...
         use EventAction::*;
+        let update = |a: &TrackEvent, b: &TrackEvent| {
+            if a == b {
+                None
+            } else {
+                Some(Update(a.clone(), b.clone()))
+            }
+        };
         match (&prev_action, &action) {
-            (Insert(_), Insert(_)) => panic!("double insert, ev.id={}", id),
+            (Insert(_), Insert(b)) => {
+                log::warn!("Double insert, ev.id={}", id);
+                Some(Insert(b.clone()))
+            }
             (Insert(_), Update(_, b)) => Some(Insert(b.clone())),
             (Insert(_), Delete(_)) => None,
 
-            (Update(_, _), Insert(_)) => panic!("inserting existing event, ev.id={}", id),
-            (Update(a, _), Update(_, c)) => Some(Update(a.clone(), c.clone())),
+            (Update(a, _), Insert(b)) => {
+                log::warn!("Inserting existing event, ev.id={}", id);
+                update(a, b)
+            }
+            (Update(a, _), Update(_, c)) => update(a, c),
             (Update(a, _), Delete(_)) => Some(Delete(a.clone())),
 
-            (Delete(a), Insert(b)) => Some(Update(a.clone(), b.clone())),
-            (Delete(_), Update(_, _)) => panic!("update of a deleted event, ev.id={}", id),
-            (Delete(_), Delete(_)) => panic!("double delete, ev.id={}", id),
+            (Delete(a), Insert(b)) => update(a, b),
+            (Delete(a), Update(_, c)) => {
+                log::warn!("Update of a deleted event, ev.id={}", id);
+                update(a, c)
+            }
+            (Delete(a), Delete(_)) => {
+                log::warn!("Double delete, ev.id={}", id);
+                Some(Delete(a.clone()))
+            }
         }
     }
 
@@ -116,11 +139,20 @@ impl Changeset {
         }
     }
 
+    /// Apply `actions` after the ones already in this changeset. Every event has at most one
+    /// action in a changeset, so the result does not depend on the iteration order.
     pub fn merge(&mut self, actions: &Changeset) {
         for a in actions.changes.values().cloned() {
             self.add(a);
         }
     }
+
+    /// Actions list ordered by event id, so the result is reproducible.
+    pub fn to_actions(&self) -> EventActionsList {
+        let mut ids: Vec<&EventId> = self.changes.keys().collect();
+        ids.sort();
+        ids.iter().map(|id| self.changes[id].clone()).collect()
+    }
 }
 
 /// Serializable changeset, diff. Storing these to keep whole edit history persistent, help with
@@ -150,3 +182,109 @@ impl Snapshot {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::note_event;
+
+    fn note(pitch: u8) -> TrackEvent {
+        note_event(7, 100, pitch, 50)
+    }
+
+    #[test]
+    fn inverse_transposes_cancel() {
+        let mut up = Changeset::empty();
+        up.add(EventAction::Update(note(60), note(61)));
+        let mut down = Changeset::empty();
+        down.add(EventAction::Update(note(61), note(60)));
+
+        let mut net = Changeset::empty();
+        net.merge(&up);
+        assert_eq!(
+            net.to_actions(),
+            vec![EventAction::Update(note(60), note(61))]
+        );
+        net.merge(&down);
+        assert!(net.changes.is_empty());
+    }
+
+    fn merged(prev: EventAction, next: EventAction) -> Option<EventAction> {
+        let mut changeset = Changeset::empty();
+        changeset.add(prev);
+        changeset.add(next);
+        changeset.changes.get(&7).cloned()
+    }
+
+    #[test]
+    fn merge_after_insert() {
+        use EventAction::*;
+        assert_eq!(
+            merged(Insert(note(60)), Insert(note(61))),
+            Some(Insert(note(61)))
+        );
+        assert_eq!(
+            merged(Insert(note(60)), Update(note(60), note(61))),
+            Some(Insert(note(61)))
+        );
+        assert_eq!(merged(Insert(note(60)), Delete(note(60))), None);
+    }
+
+    #[test]
+    fn merge_after_update() {
+        use EventAction::*;
+        assert_eq!(
+            merged(Update(note(60), note(61)), Insert(note(62))),
+            Some(Update(note(60), note(62)))
+        );
+        assert_eq!(merged(Update(note(60), note(61)), Insert(note(60))), None);
+        assert_eq!(
+            merged(Update(note(60), note(61)), Update(note(61), note(62))),
+            Some(Update(note(60), note(62)))
+        );
+        assert_eq!(
+            merged(Update(note(60), note(61)), Delete(note(61))),
+            Some(Delete(note(60)))
+        );
+    }
+
+    #[test]
+    fn merge_after_delete() {
+        use EventAction::*;
+        assert_eq!(
+            merged(Delete(note(60)), Insert(note(62))),
+            Some(Update(note(60), note(62)))
+        );
+        assert_eq!(merged(Delete(note(60)), Insert(note(60))), None);
+        assert_eq!(
+            merged(Delete(note(60)), Update(note(60), note(61))),
+            Some(Update(note(60), note(61)))
+        );
+        assert_eq!(
+            merged(Delete(note(60)), Delete(note(60))),
+            Some(Delete(note(60)))
+        );
+    }
+
+    #[test]
+    fn merge_is_per_event() {
+        let other = |pitch| TrackEvent {
+            id: 8,
+            ..note(pitch)
+        };
+        let mut first = Changeset::empty();
+        first.add(EventAction::Update(note(60), note(61)));
+        first.add(EventAction::Insert(other(70)));
+        let mut second = Changeset::empty();
+        second.add(EventAction::Update(other(70), other(71)));
+        second.add(EventAction::Delete(note(61)));
+        first.merge(&second);
+        assert_eq!(
+            first.to_actions(),
+            vec![
+                EventAction::Delete(note(60)),
+                EventAction::Insert(other(71))
+            ]
+        );
+    }
+}
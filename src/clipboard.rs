@@ -1,25 +1,174 @@
 // A clippoard for exchanging track fragments between emmate instances.
 
-use crate::common;
+use std::fs;
 use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common;
+use crate::config::Config;
+use crate::track::TrackEvent;
+use crate::util;
 
 const CLIPBOARD_DIR: &str = "clipboard";
+const FRAGMENT_NAME_EXT: &str = "fragment";
+
+/// Fragment file name parts: creation time in microseconds since the epoch, then the id
+/// of the process that stored it, so concurrently running instances do not collide.
+type FragmentId = (u64, u32);
 
 pub struct Clipboard {
     base_path: PathBuf,
+    /// Number of latest fragments to keep, 0 keeps all of them.
+    retention: usize,
 }
 
 impl Clipboard {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         Clipboard {
-            base_path: dirs::data_dir()
-                .expect("clipboard directory path is not found")
-                .join(common::APP_NAME)
-                .join(CLIPBOARD_DIR),
+            base_path: config.clipboard_dir.clone().unwrap_or_else(|| {
+                dirs::data_dir()
+                    .expect("clipboard directory path is not found")
+                    .join(common::APP_NAME)
+                    .join(CLIPBOARD_DIR)
+            }),
+            retention: config.clipboard_retention,
+        }
+    }
+
+    /// Store a new fragment, older ones beyond the retention limit are deleted.
+    /// The fragment is written to a temporary file first, so other instances
+    /// never see it half-written.
+    pub fn put(&self, events: &Vec<TrackEvent>) {
+        fs::create_dir_all(&self.base_path).expect("create clipboard directory");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time after the epoch")
+            .as_micros() as u64;
+        // Keep the order even if the clock goes back.
+        let time = self
+            .fragment_ids()
+            .last()
+            .map_or(now, |(last, _)| now.max(last + 1));
+        let path = self.fragment_path((time, process::id()));
+        let temp_path = path.with_extension(FRAGMENT_NAME_EXT.to_string() + ".tmp");
+        util::store(events, &temp_path);
+        fs::rename(&temp_path, &path)
+            .unwrap_or_else(|e| panic!("rename {} into place: {}", temp_path.display(), e));
+        self.prune();
+    }
+
+    /// The most recently stored fragment, None if there is none or it cannot be read.
+    pub fn get_latest(&self) -> Option<Vec<TrackEvent>> {
+        let path = self.fragment_path(*self.fragment_ids().last()?);
+        util::try_load(&path)
+            .map_err(|message| log::warn!("Cannot read clipboard fragment: {}", message))
+            .ok()
+    }
+
+    fn prune(&self) {
+        if self.retention == 0 {
+            return;
+        }
+        let ids = self.fragment_ids();
+        for &id in &ids[..ids.len().saturating_sub(self.retention)] {
+            let path = self.fragment_path(id);
+            if let Err(e) = fs::remove_file(&path) {
+                log::warn!("Cannot delete {}: {}", path.to_string_lossy(), e);
+            }
         }
     }
 
-    pub fn get_latest(&self) -> String {
-        todo!()
+    /// Ids of the stored fragments, oldest first.
+    fn fragment_ids(&self) -> Vec<FragmentId> {
+        let Ok(entries) = fs::read_dir(&self.base_path) else {
+            return vec![];
+        };
+        let mut ids: Vec<FragmentId> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != FRAGMENT_NAME_EXT {
+                    return None;
+                }
+                let (time, pid) = path.file_stem()?.to_str()?.split_once('-')?;
+                Some((time.parse().ok()?, pid.parse().ok()?))
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn fragment_path(&self, (time, pid): FragmentId) -> PathBuf {
+        self.base_path
+            .join(format!("{}-{}.{}", time, pid, FRAGMENT_NAME_EXT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::{note_event, Track};
+    use crate::track_edit::{apply_diffs, paste_events, selected_events};
+    use crate::util::IdSeq;
+    use std::collections::HashSet;
+
+    #[test]
+    fn prune_old_fragments() {
+        let dir = util::fresh_test_dir("test_clipboard_prune");
+        let clipboard = Clipboard::new(&Config {
+            clipboard_dir: Some(dir.clone()),
+            clipboard_retention: 3,
+            ..Config::default()
+        });
+        assert_eq!(clipboard.get_latest(), None);
+        let mut ids = vec![];
+        for at in 0..5 {
+            let fragment = vec![note_event(1, at, 60, 100)];
+            clipboard.put(&fragment);
+            assert_eq!(clipboard.get_latest(), Some(fragment));
+            ids.push(*clipboard.fragment_ids().last().unwrap());
+        }
+        assert_eq!(clipboard.fragment_ids(), ids[2..]);
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn unreadable_fragment() {
+        let dir = util::fresh_test_dir("test_clipboard_unreadable");
+        let clipboard = Clipboard::new(&Config {
+            clipboard_dir: Some(dir.clone()),
+            ..Config::default()
+        });
+        clipboard.put(&vec![note_event(1, 0, 60, 100)]);
+        let path = clipboard.fragment_path(*clipboard.fragment_ids().last().unwrap());
+        fs::write(&path, b"trunc").unwrap();
+        assert_eq!(clipboard.get_latest(), None);
+    }
+
+    #[test]
+    fn copy_paste_between_instances() {
+        let dir = util::fresh_test_dir("test_clipboard_copy_paste");
+        let config = Config {
+            clipboard_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let note = |id, at, pitch| note_event(id, at, pitch, 100);
+        let source = Track {
+            events: vec![note(1, 1000, 60), note(2, 1200, 62), note(3, 1500, 64)],
+        };
+        Clipboard::new(&config).put(&selected_events(&source, &HashSet::from([2, 3])));
+
+        let mut target = Track {
+            events: vec![note(1, 0, 50)],
+        };
+        let fragment = Clipboard::new(&config).get_latest().unwrap();
+        let (_, diff) = paste_events(&target, &IdSeq::new(10), &fragment, 5000, false).unwrap();
+        apply_diffs(&mut target, &diff, &mut vec![]);
+        assert_eq!(
+            target.events,
+            vec![note(1, 0, 50), note(10, 5000, 62), note(11, 5300, 64)]
+        );
     }
 }
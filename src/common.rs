@@ -1,3 +1,5 @@
+use crate::meter::MeterMap;
+
 /// Track time in microseconds.
 pub type Time = i64;
 
@@ -5,3 +7,113 @@ pub type Time = i64;
 pub type VersionId = i64;
 
 pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Format time as mm'ss.mmm
+pub fn format_time(at: Time) -> String {
+    let sign = if at < 0 { "-" } else { "" };
+    let millis = at.abs() / 1000;
+    format!(
+        "{}{:02}'{:02}.{:03}",
+        sign,
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Parse a time given either as mm'ss.mmm (minutes and fraction are optional)
+/// or as bar:beat (counting from 1, bars follow the `meter`).
+pub fn parse_time(text: &str, meter: &MeterMap) -> Result<Time, String> {
+    let text = text.trim();
+    let number = |s: &str| -> Result<Time, String> {
+        if s.is_empty() || !s.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("'{}' is not a number", s));
+        }
+        s.parse::<Time>().map_err(|e| format!("'{}': {}", s, e))
+    };
+    if let Some((bar, beat)) = text.split_once(':') {
+        return meter.bar_beat_time(number(bar)?, number(beat)?);
+    }
+    let (minutes, seconds) = match text.split_once('\'') {
+        Some((minutes, seconds)) => (number(minutes)?, seconds),
+        None => (0, text),
+    };
+    let (seconds, fraction) = match seconds.split_once('.') {
+        Some((seconds, fraction)) => (number(seconds)?, fraction),
+        None => (number(seconds)?, "0"),
+    };
+    if seconds >= 60 && minutes > 0 {
+        return Err(format!("Too many seconds: {}", seconds));
+    }
+    if fraction.len() > 6 {
+        return Err("Sub-microsecond precision is not supported".to_string());
+    }
+    let micros = number(fraction)? * 10_i64.pow(6 - fraction.len() as u32);
+    Ok((minutes * 60 + seconds) * 1_000_000 + micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meter::TimeSignature;
+    use crate::metronome::DEFAULT_BEAT_DURATION;
+
+    fn parse_common_time(text: &str) -> Result<Time, String> {
+        parse_time(text, &MeterMap::new([]))
+    }
+
+    #[test]
+    fn parse_valid_times() {
+        assert_eq!(parse_common_time("01'23.456"), Ok(83_456_000));
+        assert_eq!(parse_common_time("1'02"), Ok(62_000_000));
+        assert_eq!(parse_common_time(" 75.5 "), Ok(75_500_000));
+        assert_eq!(parse_common_time("0.000001"), Ok(1));
+        assert_eq!(parse_common_time("1:1"), Ok(0));
+        assert_eq!(parse_common_time("2:3"), Ok(6 * DEFAULT_BEAT_DURATION));
+        assert_eq!(parse_common_time(&format_time(83_456_000)), Ok(83_456_000));
+    }
+
+    #[test]
+    fn parse_invalid_times() {
+        for text in [
+            "",
+            "abc",
+            "1'",
+            "'12",
+            "1'75",
+            "1.2.3",
+            "-5",
+            "1'2x",
+            "0:1",
+            "1:0",
+            "1:5",
+            "1:",
+            "1.1234567",
+        ] {
+            assert!(
+                parse_common_time(text).is_err(),
+                "'{}' should be rejected",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn parse_bars_after_meter_change() {
+        let waltz = TimeSignature {
+            beats: 3,
+            beat_unit: 4,
+        };
+        let meter = MeterMap::new([(2 * 4 * DEFAULT_BEAT_DURATION, waltz)]);
+        assert_eq!(parse_time("2:4", &meter), Ok(7 * DEFAULT_BEAT_DURATION));
+        assert_eq!(parse_time("4:2", &meter), Ok(12 * DEFAULT_BEAT_DURATION));
+        assert!(parse_time("4:4", &meter).is_err());
+    }
+
+    #[test]
+    fn time_formatting() {
+        assert_eq!(format_time(0), "00'00.000");
+        assert_eq!(format_time(83_456_789), "01'23.456");
+        assert_eq!(format_time(-1_500_000), "-00'01.500");
+    }
+}
@@ -1,22 +1,292 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use serde::Deserialize;
 
+use crate::common;
+use crate::common::Time;
+use crate::pitch::DEFAULT_MIDDLE_C_OCTAVE;
+use crate::stave::{DeleteKeyMode, GridOrigin, MiddleButtonMode};
+use crate::track::Level;
+use crate::util::StorageFormat;
+
 pub const DEFAULT_CONFIG_TOML: &str = include_str!("default-config.toml");
+const USER_CONFIG_FILE: &str = "config.toml";
+
+/// sRGB color with (not premultiplied) alpha.
+pub type ColorRgba = [u8; 4];
 
-#[derive(Deserialize)]
+/// Missing values are taken from [Config::default], it should be kept in sync
+/// with default-config.toml.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct Config {
     // Add configurable values as pub fields here.
+    /// Name prefixes of MIDI keyboards to connect to when none of last used ones are available.
+    pub midi_input_port_names: Vec<String>,
+    pub middle_button: MiddleButtonMode,
+    /// Delete key behavior when both time and notes are selected.
+    pub delete_key: DeleteKeyMode,
+    /// Collapse runs of history versions on startup to save space.
+    pub compact_history: bool,
+    /// Encoding of history and project files that are written.
+    pub storage_format: StorageFormat,
+    /// Play metronome clicks while looping.
+    pub loop_click: bool,
+    /// Play metronome clicks all the time if the track has no notes on startup.
+    pub empty_track_click: bool,
+    /// Outline notes that sound at the cursor position.
+    pub highlight_sounding_notes: bool,
+    /// Show the row of bar and beat numbers above the notes on startup.
+    pub bar_ruler: bool,
+    /// Draw bar lines across the notes thicker.
+    pub heavy_bar_lines: bool,
+    /// Longest allowed note duration for the "cap note durations" command, microseconds.
+    pub max_note_duration: Time,
+    /// Send MIDI output this much ahead of time (can be negative), microseconds.
+    pub output_latency: Time,
+    /// Maximum number of simultaneously sounding notes in playback, 0 means no limit.
+    pub max_polyphony: usize,
+    /// Fade out duration on pause, microseconds, 0 stops notes right away.
+    pub pause_fade: Time,
+    /// Mouse wheel scroll speed multiplier.
+    pub scroll_speed: f32,
+    /// Scroll the stave in the opposite direction of the wheel.
+    pub reverse_scroll: bool,
+    /// Vertical wheel scrolls the stave when there is no horizontal scroll (mice without a tilt wheel).
+    pub vertical_wheel_scroll: bool,
+    /// Octave number of middle C (MIDI note 60) in note names.
+    pub middle_c_octave: i32,
+    /// Grid step of the quantize command, microseconds.
+    pub quantize_grid: Time,
+    /// Delay of every other quantize grid position, percent of the grid step.
+    pub quantize_swing: u8,
+    /// Where the quantize grid starts.
+    pub quantize_origin: GridOrigin,
+    /// Smart snap moves only the notes that are this close to the quantize grid, microseconds.
+    pub snap_tolerance: Time,
+    /// Recorded notes start on the quantize grid, it can also be toggled in the toolbar.
+    pub record_quantize: bool,
+    /// Delay between successive notes of a strummed chord, microseconds.
+    pub strum_step: Time,
+    /// Notes are shortened to this duration (microseconds) when their sustain is moved to the pedal.
+    pub pedaled_note_duration: Time,
+    /// Largest random offset of a humanized note's start, microseconds.
+    pub humanize_time: Time,
+    /// Largest random change of a humanized note's velocity.
+    pub humanize_velocity: Level,
+    /// Duration of notes entered from keyboard at the cursor, microseconds.
+    pub new_note_duration: Time,
+    /// Key (egui key name, e.g. "P") that toggles the lane controller (sustain pedal) at the cursor.
+    pub damper_toggle_key: String,
+    /// Time stretch ratio of the tempo adjustment command (Shift+brackets).
+    pub tempo_step: f32,
+    /// Tempo adjustment ratio with Ctrl added.
+    pub tempo_coarse_step: f32,
+    /// Margin of the zoom to fit view (Alt+A) on either side, fraction of the track length.
+    pub zoom_to_fit_margin: f32,
+    /// Time resolution of exported files, overrides the one of the imported file.
+    pub export_ticks_per_beat: Option<u16>,
+    /// Apply the playback transpose to exported notes.
+    pub export_transposed: bool,
+    /// Where track fragments are exchanged, defaults to a folder in the user's data directory.
+    pub clipboard_dir: Option<PathBuf>,
+    /// Number of latest clipboard fragments to keep, 0 keeps all.
+    pub clipboard_retention: usize,
+    /// Scale velocities of pasted notes to the average loudness of the notes they are pasted over.
+    pub paste_match_velocity: bool,
+    /// How long changed events stay outlined after an undo/redo animation, seconds. 0 disables.
+    pub undo_highlight_duration: f32,
+    /// Lightest point of the note velocity color ramp, 0 (lightest) .. 1 (black).
+    pub note_color_floor: f32,
+    /// Notes are drawn at least this wide (pixels) so they can be clicked when zoomed out.
+    pub min_note_width: f32,
+    /// Stave key lines.
+    pub grid_black_key_color: ColorRgba,
+    pub grid_white_key_color: ColorRgba,
+    pub time_selection_color: ColorRgba,
+    /// Shading of the area before the track start.
+    pub nothing_zone_color: ColorRgba,
+    /// Bar lines and time signature labels.
+    pub bar_line_color: ColorRgba,
+    /// Stave command shortcuts that replace the default ones, action name -> shortcuts,
+    /// see [crate::keymap].
+    pub key_bindings: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            midi_input_port_names: vec![
+                "Digital Piano".to_string(),
+                "XPIANOGT".to_string(),
+                "MPK mini 3".to_string(),
+            ],
+            middle_button: MiddleButtonMode::default(),
+            delete_key: DeleteKeyMode::default(),
+            compact_history: false,
+            storage_format: StorageFormat::default(),
+            loop_click: false,
+            empty_track_click: false,
+            highlight_sounding_notes: false,
+            bar_ruler: true,
+            heavy_bar_lines: false,
+            max_note_duration: 10_000_000,
+            output_latency: 0,
+            max_polyphony: 0,
+            pause_fade: 0,
+            scroll_speed: 1.0,
+            reverse_scroll: false,
+            vertical_wheel_scroll: false,
+            middle_c_octave: DEFAULT_MIDDLE_C_OCTAVE,
+            quantize_grid: 125_000,
+            quantize_swing: 0,
+            quantize_origin: GridOrigin::default(),
+            snap_tolerance: 20_000,
+            record_quantize: false,
+            strum_step: 30_000,
+            pedaled_note_duration: 100_000,
+            humanize_time: 10_000,
+            humanize_velocity: 8,
+            new_note_duration: 250_000,
+            damper_toggle_key: "P".to_string(),
+            tempo_step: 1.01,
+            tempo_coarse_step: 1.05,
+            zoom_to_fit_margin: 0.05,
+            export_ticks_per_beat: None,
+            export_transposed: false,
+            clipboard_dir: None,
+            clipboard_retention: 20,
+            paste_match_velocity: false,
+            undo_highlight_duration: 1.0,
+            note_color_floor: 0.4,
+            min_note_width: 2.0,
+            grid_black_key_color: [63, 63, 63, 255],
+            grid_white_key_color: [196, 196, 196, 255],
+            time_selection_color: [64, 80, 100, 60],
+            nothing_zone_color: [0, 0, 0, 15],
+            bar_line_color: [120, 120, 160, 90],
+            key_bindings: BTreeMap::new(),
+        }
+    }
 }
 
 impl Config {
+    /// Explicitly given config file, or the user's one, or the embedded default.
     pub fn load(config_path: Option<&PathBuf>) -> Config {
-        let toml_str = match config_path {
-            None => DEFAULT_CONFIG_TOML.into(),
-            Some(path) => std::fs::read_to_string(path)
-                .expect(format!("Cannot load config file {:?}", path).as_str()),
+        match config_path {
+            Some(path) => {
+                let toml_str = std::fs::read_to_string(path)
+                    .unwrap_or_else(|e| panic!("Cannot load config file {:?}: {}", path, e));
+                let config: Config = toml::from_str(&toml_str)
+                    .unwrap_or_else(|e| panic!("Cannot parse config toml {:?}: {}", path, e));
+                config.validated()
+            }
+            None => Self::load_user(Self::user_config_path().as_ref()),
+        }
+    }
+
+    /// E.g. ~/.config/emmate/config.toml
+    fn user_config_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join(common::APP_NAME).join(USER_CONFIG_FILE))
+    }
+
+    /// The user's config file if there is a valid one, the embedded default otherwise.
+    fn load_user(path: Option<&PathBuf>) -> Config {
+        let embedded = || toml::from_str(DEFAULT_CONFIG_TOML).expect("embedded config");
+        let Some(path) = path.filter(|p| p.exists()) else {
+            return embedded();
         };
-        toml::from_str(&toml_str)
-            .expect(format!("Cannot parse config toml {:?}", toml_str).as_str())
+        log::info!("Loading config {}", path.to_string_lossy());
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|toml_str| toml::from_str(&toml_str).map_err(|e| e.to_string()));
+        parsed.map(Config::validated).unwrap_or_else(|message| {
+            log::warn!("Ignoring config {}: {}", path.to_string_lossy(), message);
+            embedded()
+        })
+    }
+
+    /// Out of range values are replaced with the defaults.
+    fn validated(mut self) -> Config {
+        let default = Config::default();
+        let check = |valid: bool, name: &str| {
+            if !valid {
+                log::warn!("Config value {} is out of range, using the default.", name);
+            }
+            valid
+        };
+        if !check(
+            (0.0..1.0).contains(&self.note_color_floor),
+            "note_color_floor (0..1)",
+        ) {
+            self.note_color_floor = default.note_color_floor;
+        }
+        if !check(self.quantize_grid > 0, "quantize_grid (above 0)") {
+            self.quantize_grid = default.quantize_grid;
+        }
+        if !check(self.quantize_swing < 100, "quantize_swing (below 100)") {
+            self.quantize_swing = default.quantize_swing;
+        }
+        if !check(self.tempo_step > 1.0, "tempo_step (above 1)") {
+            self.tempo_step = default.tempo_step;
+        }
+        if !check(self.tempo_coarse_step > 1.0, "tempo_coarse_step (above 1)") {
+            self.tempo_coarse_step = default.tempo_coarse_step;
+        }
+        if !check(
+            self.zoom_to_fit_margin >= 0.0,
+            "zoom_to_fit_margin (not negative)",
+        ) {
+            self.zoom_to_fit_margin = default.zoom_to_fit_margin;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn default_config_is_consistent() {
+        assert_eq!(Config::load_user(None), Config::default());
+        // Missing values are defaulted.
+        let partial: Config = toml::from_str("loop_click = true").unwrap();
+        assert!(partial.loop_click);
+        assert_eq!(
+            partial.max_note_duration,
+            Config::default().max_note_duration
+        );
+    }
+
+    #[test]
+    fn config_precedence() {
+        let dir = util::fresh_test_dir("test_config_precedence");
+        let flag_path = dir.join("flag.toml");
+        std::fs::write(&flag_path, "max_polyphony = 8").unwrap();
+        let user_path = dir.join("user.toml");
+        std::fs::write(&user_path, "max_polyphony = 16").unwrap();
+
+        assert_eq!(Config::load(Some(&flag_path)).max_polyphony, 8);
+        assert_eq!(Config::load_user(Some(&user_path)).max_polyphony, 16);
+        // Missing or invalid user config falls back to the default.
+        assert_eq!(
+            Config::load_user(Some(&dir.join("missing.toml"))),
+            Config::default()
+        );
+        std::fs::write(&user_path, "max_polyphony = \"many\"").unwrap();
+        assert_eq!(Config::load_user(Some(&user_path)), Config::default());
+        // Out of range values are defaulted, the valid ones are kept.
+        std::fs::write(
+            &user_path,
+            "max_polyphony = 16\nquantize_swing = 100\ntempo_step = 0.5",
+        )
+        .unwrap();
+        let config = Config::load_user(Some(&user_path));
+        assert_eq!(config.max_polyphony, 16);
+        assert_eq!(config.quantize_swing, Config::default().quantize_swing);
+        assert_eq!(config.tempo_step, Config::default().tempo_step);
     }
 }
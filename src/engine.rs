@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{BTreeSet, BinaryHeap, VecDeque};
+use std::sync::atomic::AtomicI8;
+use std::sync::{atomic, mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -8,17 +9,33 @@ use midir::MidiOutputConnection;
 use midly::live::LiveEvent;
 use midly::num::u7;
 use midly::MidiMessage;
-use midly::MidiMessage::NoteOff;
+use midly::MidiMessage::{NoteOff, NoteOn};
 
 use crate::common::Time;
-use crate::track::{ChannelId, MIDI_CC_SUSTAIN_ID};
+use crate::midi::{controller_set, note_off};
+use crate::range::Range;
+use crate::track::{ChannelId, ControllerId, Level, Pitch, MIDI_CC_SUSTAIN_ID, MIDI_CC_VOLUME_ID};
 
 pub const MIDI_CHANNEL: ChannelId = 1;
+const MIDI_CC_ALL_SOUND_OFF: ControllerId = 120;
+const MIDI_CC_ALL_NOTES_OFF: ControllerId = 123;
+/// Number of MIDI channels.
+const MIDI_CHANNEL_COUNT: ChannelId = 16;
+/// Channel volume that General MIDI synths start with.
+const MIDI_DEFAULT_VOLUME: Level = 100;
+/// Number of volume changes of a pause fade.
+const FADE_STEPS: Time = 8;
 
 /** Event that is produced by engine. */
 #[derive(Clone, Debug)]
 pub enum StatusEvent {
     Time(Time),
+    /// Playback is paused or resumed, including when the engine pauses itself (e.g. on reset).
+    Transport {
+        paused: bool,
+    },
+    /// Event from the input keyboard while recording, at the track time.
+    Recorded(Time, LiveEvent<'static>),
 }
 
 /// A sound event to be rendered by the engine at given time.
@@ -50,17 +67,134 @@ pub trait EventSource {
     fn is_running(&self) -> bool;
     /** Reset current source's time to this moment. */
     fn seek(&mut self, at: &Time);
+    /** Catch up with changes of the underlying data, keeping the current time. */
+    fn resync(&mut self) {}
     /** The next event to be played at the instant. On subsequent
     calls instants must not decrease unless a reset call sets back the time. */
     fn next(&mut self, at: &Time) -> Vec<EngineEvent>;
+    /** Controller value that is in effect just before the instant,
+    None if the source does not control it. */
+    fn controller_value_at(&self, _controller_id: ControllerId, _at: &Time) -> Option<Level> {
+        None
+    }
+    /** Output channels that the source sends controller changes to. */
+    fn controller_channels(&self) -> Vec<ChannelId> {
+        vec![]
+    }
+}
+
+/// Output channel of a stored note channel. Channels are relative to the engine's
+/// base channel: 0 plays on [MIDI_CHANNEL], as notes always did, and distinct
+/// channels stay distinct. Export writes the stored channels as is.
+pub fn output_channel(channel: ChannelId) -> ChannelId {
+    (MIDI_CHANNEL + channel) % MIDI_CHANNEL_COUNT
 }
 
 type EventSourceHandle = dyn EventSource + Send;
 
+/// Time range that is played repeatedly, if set. Shared with sources that depend on it.
+pub type LoopRegion = Arc<Mutex<Option<Range<Time>>>>;
+
+/// Semitones added to the played notes' pitches. Shared with sources that depend on it.
+pub type Transpose = Arc<AtomicI8>;
+
 pub type EngineCommand = dyn FnOnce(&mut Engine) + Send;
 
+/// Destination of the encoded outgoing MIDI messages.
+pub type MidiSink = dyn FnMut(&[u8]) + Send;
+
+/// Notes that are currently sounding, oldest first.
+#[derive(Debug, Default)]
+struct Voices {
+    /// Maximum number of simultaneously sounding notes, unlimited if None.
+    limit: Option<usize>,
+    sounding: VecDeque<(ChannelId, Pitch)>,
+}
+
+impl Voices {
+    /// Account for an outgoing event, returns note-offs for the stolen (oldest) notes if
+    /// the event would exceed the polyphony limit.
+    fn update(&mut self, event: &LiveEvent) -> Vec<LiveEvent<'static>> {
+        let LiveEvent::Midi { channel, message } = event else {
+            return vec![];
+        };
+        let channel = channel.as_int();
+        match message {
+            NoteOn { key, vel } if *vel > 0 => {
+                let voice = (channel, key.as_int());
+                self.release(voice);
+                let mut stolen = vec![];
+                if let Some(limit) = self.limit {
+                    while self.sounding.len() >= limit.max(1) {
+                        let (channel, pitch) = self.sounding.pop_front().unwrap();
+                        stolen.push(note_off(channel, pitch, 0));
+                    }
+                }
+                self.sounding.push_back(voice);
+                stolen
+            }
+            NoteOn { key, .. } | NoteOff { key, .. } => {
+                self.release((channel, key.as_int()));
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn release(&mut self, voice: (ChannelId, Pitch)) {
+        self.sounding.retain(|v| *v != voice);
+    }
+}
+
+/// Events that stop all sounds on the channels: note-offs for every key, then the sustain
+/// pedal release and "all notes off" for synths that keep notes otherwise.
+fn silence(channels: &BTreeSet<ChannelId>) -> Vec<LiveEvent<'static>> {
+    let mut events = vec![];
+    for &channel in channels {
+        events.extend((0..=u7::max_value().as_int()).map(|key| note_off(channel, key, 64)));
+        events.push(controller_set(channel, MIDI_CC_SUSTAIN_ID, 0));
+        events.push(controller_set(channel, MIDI_CC_ALL_NOTES_OFF, 0));
+    }
+    events
+}
+
+/// Events that stop any sound on every channel, including notes that are not known
+/// to be sounding and the synth's release tails.
+fn panic_events() -> Vec<LiveEvent<'static>> {
+    let mut events = vec![];
+    for channel in 0..MIDI_CHANNEL_COUNT {
+        events.extend((0..=u7::max_value().as_int()).map(|key| note_off(channel, key, 64)));
+        events.push(controller_set(channel, MIDI_CC_SUSTAIN_ID, 0));
+        events.push(controller_set(channel, MIDI_CC_ALL_SOUND_OFF, 0));
+        events.push(controller_set(channel, MIDI_CC_ALL_NOTES_OFF, 0));
+    }
+    events
+}
+
+/// Volume ramp from `volume` down to 0 over `duration` on the channels, followed by [silence].
+/// Times are relative to the fade start.
+fn fade_out(
+    volume: Level,
+    duration: Time,
+    channels: &BTreeSet<ChannelId>,
+) -> Vec<(Time, LiveEvent<'static>)> {
+    let mut events: Vec<(Time, LiveEvent<'static>)> = (1..=FADE_STEPS)
+        .flat_map(|i| {
+            let value = (volume as Time * (FADE_STEPS - i) / FADE_STEPS) as Level;
+            channels.iter().map(move |&channel| {
+                (
+                    duration * i / FADE_STEPS,
+                    controller_set(channel, MIDI_CC_VOLUME_ID, value),
+                )
+            })
+        })
+        .collect();
+    events.extend(silence(channels).into_iter().map(|event| (duration, event)));
+    events
+}
+
 pub struct Engine {
-    midi_output: MidiOutputConnection,
+    midi_output: Box<MidiSink>,
     sources: Vec<Box<EventSourceHandle>>,
     running_at: Time,
     reset_at: Instant,
@@ -68,8 +202,28 @@ pub struct Engine {
     status_receiver: Option<Box<StatusEventReceiver>>,
     command_receiver: mpsc::Receiver<Box<EngineCommand>>,
     command_sender: mpsc::Sender<Box<EngineCommand>>,
-    current_sustain: Option<LiveEvent<'static>>,
+    /// Last sustain pedal value that is sent by the sources.
+    current_sustain: Option<Level>,
+    /// Output channels that notes were sent on.
+    played_channels: BTreeSet<ChannelId>,
+    /// Last channel volume that is sent by the sources.
+    current_volume: Level,
+    /// Fade out duration on pause, microseconds. Notes are silenced right away if 0.
+    pause_fade: Time,
+    /// Fade in progress: start and the remaining events, relative to the start.
+    fade: Option<(Instant, VecDeque<(Time, LiveEvent<'static>)>)>,
+    /// Volume is turned down by a fade, and should be restored on resume.
+    faded: bool,
     queue: BinaryHeap<EngineEvent>,
+    loop_region: LoopRegion,
+    transpose: Transpose,
+    /// Output (e.g. synthesizer) delay to compensate, microseconds.
+    latency: Time,
+    voices: Voices,
+    /// End of a one-shot playback, pauses when reached.
+    stop_at: Option<Time>,
+    /// Input events are reported to the status receiver while playing.
+    recording: bool,
 }
 
 impl Engine {
@@ -77,6 +231,19 @@ impl Engine {
         midi_output: MidiOutputConnection,
         command_sender: mpsc::Sender<Box<EngineCommand>>,
         command_receiver: mpsc::Receiver<Box<EngineCommand>>,
+    ) -> Engine {
+        let mut midi_output = midi_output;
+        Self::with_output(
+            Box::new(move |message| midi_output.send(message).expect("send output MIDI event")),
+            command_sender,
+            command_receiver,
+        )
+    }
+
+    pub fn with_output(
+        midi_output: Box<MidiSink>,
+        command_sender: mpsc::Sender<Box<EngineCommand>>,
+        command_receiver: mpsc::Receiver<Box<EngineCommand>>,
     ) -> Engine {
         Engine {
             midi_output,
@@ -86,9 +253,20 @@ impl Engine {
             paused: false,
             status_receiver: None,
             current_sustain: None,
+            played_channels: BTreeSet::new(),
+            current_volume: MIDI_DEFAULT_VOLUME,
+            pause_fade: 0,
+            fade: None,
+            faded: false,
             command_receiver,
             command_sender,
             queue: BinaryHeap::new(),
+            loop_region: Arc::new(Mutex::new(None)),
+            transpose: Transpose::default(),
+            latency: 0,
+            voices: Voices::default(),
+            stop_at: None,
+            recording: false,
         }
     }
 
@@ -103,52 +281,75 @@ impl Engine {
                 if let Err(_) = lock {
                     continue; // Will try next time.
                 }
-                let mut locked = lock.unwrap();
-                let pending_commands: Vec<Box<EngineCommand>> =
-                    locked.command_receiver.try_iter().collect();
-                for command in pending_commands {
-                    command(&mut locked);
-                }
-                if locked.paused {
-                    continue;
-                };
-                locked.sources.retain(|s| s.is_running());
-                Self::update_track_time(&mut locked);
-                let transport_time = locked.running_at;
-                for ev in locked
-                    .sources
-                    .iter_mut()
-                    .map(|s| s.next(&transport_time))
-                    .flatten()
-                    .collect::<Vec<EngineEvent>>()
-                {
-                    locked.queue.push(ev);
+                lock.unwrap().tick();
+            }
+        });
+        engine
+    }
+
+    /// Run pending commands and send the events that are due by now.
+    fn tick(&mut self) {
+        let pending_commands: Vec<Box<EngineCommand>> = self.command_receiver.try_iter().collect();
+        for command in pending_commands {
+            command(self);
+        }
+        self.continue_fade();
+        if self.paused {
+            return;
+        };
+        self.sources.retain(|s| s.is_running());
+        self.update_track_time();
+        if self.stop_at_end() {
+            return;
+        }
+        self.wrap_loop();
+        // Sending events ahead of time to compensate for the output latency.
+        let output_time = self.running_at + self.latency;
+        for ev in self
+            .sources
+            .iter_mut()
+            .flat_map(|s| s.next(&output_time))
+            .collect::<Vec<EngineEvent>>()
+        {
+            self.queue.push(ev);
+        }
+        let batch = Self::take_due(&mut self.queue, output_time);
+        for ev in batch {
+            // Keeping actual value to resume playback with sustain enabled if necessary.
+            // Otherwise, it will only be active after next explicit change.
+            if let LiveEvent::Midi {
+                message: MidiMessage::Controller { controller, value },
+                ..
+            } = ev
+            {
+                if controller == MIDI_CC_SUSTAIN_ID {
+                    self.current_sustain = Some(value.as_int());
                 }
-                let mut batch = vec![];
-                while let Some(ev) = locked.queue.peek() {
-                    if ev.at > transport_time {
-                        break;
-                    }
-                    batch.push(locked.queue.pop().unwrap().event);
+            }
+            if let LiveEvent::Midi {
+                message: MidiMessage::Controller { controller, value },
+                ..
+            } = ev
+            {
+                if controller == MIDI_CC_VOLUME_ID {
+                    self.current_volume = value.as_int();
                 }
-                for ev in batch {
-                    // Keeping actual value to resume playback with sustain enabled if necessary.
-                    // Otherwise, it will only be active after next explicit change.
-                    if let LiveEvent::Midi {
-                        message: MidiMessage::Controller { controller, .. },
-                        ..
-                    } = ev
-                    {
-                        if controller == MIDI_CC_SUSTAIN_ID {
-                            locked.current_sustain = Some(ev.to_static());
-                        }
-                    }
+            }
 
-                    locked.process(ev);
-                }
+            self.process(ev);
+        }
+    }
+
+    /// Remove events that should be sent by the given time from the queue.
+    fn take_due(queue: &mut BinaryHeap<EngineEvent>, at: Time) -> Vec<LiveEvent<'static>> {
+        let mut batch = vec![];
+        while let Some(ev) = queue.peek() {
+            if ev.at > at {
+                break;
             }
-        });
-        engine
+            batch.push(queue.pop().unwrap().event);
+        }
+        batch
     }
 
     fn update_track_time(&mut self) {
@@ -162,50 +363,222 @@ impl Engine {
         for s in self.sources.iter_mut() {
             s.seek(&at);
         }
+        // The pedal state before the seek is unrelated to the new position.
+        self.current_sustain = Self::sustain_at(&self.sources, &at);
+        if !self.paused {
+            self.restore_sustain(at);
+        }
         self.running_at = at;
         self.update_realtime();
         self.update_track_time();
     }
 
-    pub fn toggle_pause(&mut self) {
-        self.paused = !self.paused;
+    /// Let the sources re-read their data after it is edited.
+    pub fn resync(&mut self) {
+        for s in self.sources.iter_mut() {
+            s.resync();
+        }
+    }
+
+    /// Sustain pedal state the sources have at the instant.
+    fn sustain_at(sources: &[Box<EventSourceHandle>], at: &Time) -> Option<Level> {
+        sources
+            .iter()
+            .find_map(|s| s.controller_value_at(MIDI_CC_SUSTAIN_ID, at))
+    }
+
+    /// Schedule the current pedal state on every channel in use.
+    fn restore_sustain(&mut self, at: Time) {
+        let Some(value) = self.current_sustain else {
+            return;
+        };
+        for channel in self.channels() {
+            self.queue.push(EngineEvent {
+                at,
+                event: controller_set(channel, MIDI_CC_SUSTAIN_ID, value),
+            });
+        }
+    }
+
+    /// Output channels in use: the base one, the ones the sources send controllers to
+    /// and the ones that notes were sent on.
+    fn channels(&self) -> BTreeSet<ChannelId> {
+        let mut channels = BTreeSet::from([MIDI_CHANNEL]);
+        for source in &self.sources {
+            channels.extend(source.controller_channels());
+        }
+        channels.extend(&self.played_channels);
+        channels
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            self.report_transport();
+            return;
+        }
+        self.paused = paused;
+        if paused {
+            self.stop_at = None;
+        }
+        self.report_transport();
         if !self.paused {
             self.update_realtime();
         }
         self.command_sender
             .send(Box::new(|engine| {
                 if engine.paused {
-                    // Mute ongoing notes before clearing.
-                    engine.queue.clear();
-                    for key in 0..u7::max_value().into() {
-                        engine.process(LiveEvent::Midi {
-                            channel: MIDI_CHANNEL.into(),
-                            message: NoteOff {
-                                key: key.into(),
-                                vel: 64.into(),
-                            },
-                        });
+                    if engine.pause_fade > 0 {
+                        engine.start_fade();
+                    } else {
+                        engine.mute();
+                    }
+                } else {
+                    engine.fade = None;
+                    if engine.faded {
+                        engine.faded = false;
+                        let volume = engine.current_volume;
+                        for channel in engine.channels() {
+                            engine.process(controller_set(channel, MIDI_CC_VOLUME_ID, volume));
+                        }
                     }
-                    engine.process(LiveEvent::Midi {
-                        channel: MIDI_CHANNEL.into(),
-                        message: MidiMessage::Controller {
-                            controller: MIDI_CC_SUSTAIN_ID.into(),
-                            value: 0.into(),
-                        },
-                    });
-                } else if let Some(sustain) = engine.current_sustain {
-                    engine.queue.push(EngineEvent {
-                        at: engine.running_at,
-                        event: sustain,
-                    });
+                    let at = engine.running_at;
+                    engine.restore_sustain(at);
                 }
             }))
             .unwrap();
     }
 
-    /// Stop all sounds.
+    /// Jump back to the loop start when the loop end is reached.
+    fn wrap_loop(&mut self) {
+        let Some(region) = *self.loop_region.lock().unwrap() else {
+            return;
+        };
+        if self.running_at < region.1 {
+            return;
+        }
+        self.release_pending();
+        self.seek(region.0);
+    }
+
+    /// Play the time range once, then pause.
+    pub fn play_range(&mut self, range: Range<Time>) {
+        self.release_pending();
+        self.seek(range.0);
+        self.set_paused(false);
+        self.stop_at = Some(range.1);
+    }
+
+    /// Pause if the end of the one-shot playback is reached.
+    fn stop_at_end(&mut self) -> bool {
+        match self.stop_at {
+            Some(end) if self.running_at >= end => {
+                self.set_paused(true);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Release notes that are still sounding, the rest of the queue belongs to the old position.
+    fn release_pending(&mut self) {
+        let pending: Vec<EngineEvent> = self.queue.drain().collect();
+        for ev in pending {
+            if let LiveEvent::Midi {
+                message: NoteOff { .. },
+                ..
+            } = ev.event
+            {
+                self.process(ev.event);
+            }
+        }
+    }
+
+    /// Turn the volume down over this time (microseconds) before silencing notes on pause,
+    /// 0 silences them right away.
+    pub fn set_pause_fade(&mut self, duration: Time) {
+        self.pause_fade = duration;
+    }
+
+    fn start_fade(&mut self) {
+        self.queue.clear();
+        let events = fade_out(self.current_volume, self.pause_fade, &self.channels());
+        self.fade = Some((Instant::now(), events.into()));
+        self.faded = true;
+    }
+
+    /// Send the fade events that are due.
+    fn continue_fade(&mut self) {
+        let Some((start, mut events)) = self.fade.take() else {
+            return;
+        };
+        let elapsed = start.elapsed().as_micros() as Time;
+        while let Some((at, event)) = events.front() {
+            if *at > elapsed {
+                self.fade = Some((start, events));
+                return;
+            }
+            let event = *event;
+            events.pop_front();
+            self.process(event);
+        }
+    }
+
+    pub fn set_latency(&mut self, latency: Time) {
+        self.latency = latency;
+    }
+
+    /// Limit the number of simultaneously sounding notes, the oldest ones are released
+    /// when it is exceeded. No limit if None.
+    pub fn set_max_polyphony(&mut self, limit: Option<usize>) {
+        self.voices.limit = limit;
+    }
+
+    pub fn loop_region(&self) -> LoopRegion {
+        self.loop_region.clone()
+    }
+
+    pub fn set_loop_region(&mut self, region: Option<Range<Time>>) {
+        *self.loop_region.lock().unwrap() = region;
+    }
+
+    pub fn transpose(&self) -> Transpose {
+        self.transpose.clone()
+    }
+
+    /// Shift pitches of the notes that are played after this by the number of semitones.
+    pub fn set_transpose(&mut self, semitones: i8) {
+        self.transpose.store(semitones, atomic::Ordering::Relaxed);
+    }
+
+    /// Pause and stop all sounds right away.
     pub fn reset(&mut self) {
-        self.paused = true;
+        self.set_paused(true);
+        self.panic();
+    }
+
+    /// Drop scheduled events and stop all sounds on all channels, the transport state is kept.
+    pub fn panic(&mut self) {
+        self.queue.clear();
+        self.fade = None;
+        for event in panic_events() {
+            self.process(event);
+        }
+    }
+
+    /// Drop scheduled events and silence ongoing notes.
+    fn mute(&mut self) {
+        self.queue.clear();
+        self.fade = None;
+        for event in silence(&self.channels()) {
+            self.process(event);
+        }
+    }
+
+    fn report_transport(&mut self) {
+        let paused = self.paused;
+        if let Some(recv) = self.status_receiver.as_mut() {
+            recv(StatusEvent::Transport { paused });
+        }
     }
 
     pub fn update_realtime(&mut self) {
@@ -218,14 +591,298 @@ impl Engine {
 
     /// Process the event immediately.
     pub fn process(&mut self, event: LiveEvent) {
+        if let LiveEvent::Midi {
+            channel,
+            message: NoteOn { .. },
+        } = event
+        {
+            self.played_channels.insert(channel.as_int());
+        }
+        for stolen in self.voices.update(&event) {
+            self.send(stolen);
+        }
+        self.send(event);
+    }
+
+    /// Play the event from the input keyboard, record it if armed.
+    pub fn input(&mut self, event: LiveEvent<'static>) {
+        if self.recording && !self.paused {
+            if let LiveEvent::Midi { .. } = event {
+                let at = Instant::now().duration_since(self.reset_at).as_micros() as Time;
+                if let Some(recv) = self.status_receiver.as_mut() {
+                    recv(StatusEvent::Recorded(at, event));
+                }
+            }
+        }
+        self.process(event);
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    fn send(&mut self, event: LiveEvent) {
         let mut midi_buf = vec![];
         event.write(&mut midi_buf).unwrap();
-        self.midi_output
-            .send(&midi_buf)
-            .expect("send output MIDI event");
+        (self.midi_output)(&midi_buf);
     }
 
     pub fn set_status_receiver(&mut self, receiver: Option<Box<StatusEventReceiver>>) {
         self.status_receiver = receiver;
+        self.report_transport();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{note_off, note_on};
+    use crate::track::{
+        note_event, ControllerSetValue, Track, TrackEvent, TrackEventType, MAX_LEVEL,
+    };
+    use crate::track_source::TrackSource;
+    use sync_cow::SyncCow;
+
+    type SentEvents = Arc<Mutex<Vec<LiveEvent<'static>>>>;
+
+    /// Engine that collects the outgoing events instead of sending them to a MIDI port.
+    fn test_engine() -> (Engine, SentEvents) {
+        let sent: SentEvents = Arc::default();
+        let output = sent.clone();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let engine = Engine::with_output(
+            Box::new(move |message| {
+                let event = LiveEvent::parse(message).unwrap().to_static();
+                output.lock().unwrap().push(event);
+            }),
+            command_sender,
+            command_receiver,
+        );
+        (engine, sent)
+    }
+
+    #[test]
+    fn latency_compensation() {
+        let mut queue = BinaryHeap::new();
+        for at in [1000, 2000, 3000] {
+            queue.push(EngineEvent {
+                at,
+                event: note_on(MIDI_CHANNEL, 60, 64),
+            });
+        }
+        // Events are dispatched when transport time + latency reaches them.
+        let latency = 500;
+        assert!(Engine::take_due(&mut queue, 499 + latency).is_empty());
+        assert_eq!(Engine::take_due(&mut queue, 500 + latency).len(), 1);
+        assert_eq!(Engine::take_due(&mut queue, 1600 + latency).len(), 1);
+        // Negative latency delays the output.
+        let latency = -500;
+        assert!(Engine::take_due(&mut queue, 3000 + latency).is_empty());
+        assert_eq!(Engine::take_due(&mut queue, 3500 + latency).len(), 1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn engine_sends_ahead_by_latency() {
+        let (mut engine, sent) = test_engine();
+        let mut track = Track::default();
+        track.events.push(note_event(1, 1_000_000, 60, 1_000_000));
+        engine.add(Box::new(TrackSource::new(
+            Arc::new(SyncCow::new(track)),
+            Transpose::default(),
+        )));
+        let note_sent = || {
+            sent.lock()
+                .unwrap()
+                .contains(&note_on(MIDI_CHANNEL, 60, 64))
+        };
+        engine.seek(600_000);
+        engine.tick();
+        assert!(!note_sent());
+        // The note is 400ms ahead, within the latency.
+        engine.set_latency(500_000);
+        engine.tick();
+        assert!(note_sent());
+    }
+
+    #[test]
+    fn sustain_after_seek() {
+        let mut track = Track::default();
+        for (id, at, value) in [(1, 1000, MAX_LEVEL), (2, 5000, 0)] {
+            track.events.push(TrackEvent {
+                id,
+                at,
+                event: TrackEventType::Controller(ControllerSetValue {
+                    controller_id: MIDI_CC_SUSTAIN_ID,
+                    value,
+                }),
+            });
+        }
+        let sources: Vec<Box<EventSourceHandle>> = vec![Box::new(TrackSource::new(
+            Arc::new(SyncCow::new(track)),
+            Transpose::default(),
+        ))];
+        let pedal = Some;
+        // Seeking into the held pedal region resumes with the pedal down.
+        assert_eq!(Engine::sustain_at(&sources, &3000), pedal(MAX_LEVEL));
+        assert_eq!(Engine::sustain_at(&sources, &6000), pedal(0));
+        assert_eq!(Engine::sustain_at(&sources, &0), pedal(0));
+        // The source plays the change at the seek target itself.
+        assert_eq!(Engine::sustain_at(&sources, &1000), pedal(0));
+        assert_eq!(Engine::sustain_at(&[], &3000), None);
+    }
+
+    #[test]
+    fn polyphony_limit_releases_oldest() {
+        let mut voices = Voices {
+            limit: Some(2),
+            ..Voices::default()
+        };
+        assert!(voices.update(&note_on(MIDI_CHANNEL, 60, 64)).is_empty());
+        assert!(voices.update(&note_on(MIDI_CHANNEL, 62, 64)).is_empty());
+        assert_eq!(
+            voices.update(&note_on(MIDI_CHANNEL, 64, 64)),
+            vec![note_off(MIDI_CHANNEL, 60, 0)]
+        );
+        // Released notes free their voices.
+        assert!(voices.update(&note_off(MIDI_CHANNEL, 62, 0)).is_empty());
+        assert!(voices.update(&note_on(MIDI_CHANNEL, 65, 64)).is_empty());
+        // Retriggering a sounding note does not take another voice.
+        assert!(voices.update(&note_on(MIDI_CHANNEL, 65, 64)).is_empty());
+        // No limit by default.
+        let mut voices = Voices::default();
+        for pitch in 0..100 {
+            assert!(voices.update(&note_on(MIDI_CHANNEL, pitch, 64)).is_empty());
+        }
+    }
+
+    #[test]
+    fn fade_before_silence() {
+        let channels = BTreeSet::from([MIDI_CHANNEL, 5]);
+        let events = fade_out(100, 80_000, &channels);
+        let volumes: Vec<(Time, LiveEvent)> = events
+            .iter()
+            .take_while(|(_, ev)| {
+                matches!(ev, LiveEvent::Midi { message: MidiMessage::Controller { controller, .. }, .. }
+                    if *controller == MIDI_CC_VOLUME_ID)
+            })
+            .cloned()
+            .collect();
+        assert_eq!(volumes.len() as Time, FADE_STEPS * channels.len() as Time);
+        for &channel in &channels {
+            let ramp: Vec<(Time, u8)> = volumes
+                .iter()
+                .filter_map(|(at, ev)| match ev {
+                    LiveEvent::Midi {
+                        channel: c,
+                        message: MidiMessage::Controller { value, .. },
+                    } if c.as_int() == channel => Some((*at, value.as_int())),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(ramp.len() as Time, FADE_STEPS);
+            assert!(ramp.windows(2).all(|w| w[0].1 > w[1].1));
+            assert_eq!(ramp.last().map(|(_, value)| *value), Some(0));
+            assert!(ramp.windows(2).all(|w| w[0].0 < w[1].0));
+        }
+        // Notes are released after the volume is down.
+        let rest: Vec<LiveEvent> = events[volumes.len()..]
+            .iter()
+            .map(|(at, ev)| {
+                assert_eq!(*at, 80_000);
+                *ev
+            })
+            .collect();
+        assert_eq!(rest, silence(&channels));
+    }
+
+    #[test]
+    fn resume_restores_volume_on_played_channels() {
+        let (mut engine, sent) = test_engine();
+        engine.set_pause_fade(1);
+        engine.process(note_on(output_channel(4), 60, 64));
+        engine.set_paused(true);
+        engine.tick();
+        thread::sleep(Duration::from_millis(1));
+        engine.tick();
+        sent.lock().unwrap().clear();
+        engine.set_paused(false);
+        engine.tick();
+        let sent = sent.lock().unwrap();
+        for channel in [MIDI_CHANNEL, output_channel(4)] {
+            assert!(sent.contains(&controller_set(
+                channel,
+                MIDI_CC_VOLUME_ID,
+                MIDI_DEFAULT_VOLUME
+            )));
+        }
+    }
+
+    #[test]
+    fn panic_covers_all_channels() {
+        let mut voices = Voices::default();
+        for channel in [0, MIDI_CHANNEL, 9, 15] {
+            voices.update(&note_on(channel, 60, 64));
+        }
+        let events = panic_events();
+        for event in &events {
+            voices.update(event);
+        }
+        assert!(voices.sounding.is_empty());
+        for channel in 0..MIDI_CHANNEL_COUNT {
+            for cc in [
+                MIDI_CC_SUSTAIN_ID,
+                MIDI_CC_ALL_SOUND_OFF,
+                MIDI_CC_ALL_NOTES_OFF,
+            ] {
+                assert!(events.contains(&controller_set(channel, cc, 0)));
+            }
+            assert!(events.contains(&note_off(channel, 127, 64)));
+        }
+    }
+
+    #[test]
+    fn reset_pauses_and_silences() {
+        let (mut engine, sent) = test_engine();
+        engine.process(note_on(MIDI_CHANNEL, 60, 64));
+        engine.reset();
+        engine.tick();
+        assert!(engine.paused);
+        assert!(engine.voices.sounding.is_empty());
+        let sent = sent.lock().unwrap();
+        assert!(sent.contains(&note_off(MIDI_CHANNEL, 60, 64)));
+        assert!(sent.contains(&controller_set(MIDI_CHANNEL, MIDI_CC_ALL_SOUND_OFF, 0)));
+    }
+
+    #[test]
+    fn pause_silences_every_played_channel() {
+        let (mut engine, sent) = test_engine();
+        engine.process(note_on(output_channel(4), 60, 64));
+        engine.set_paused(true);
+        engine.tick();
+        assert!(sent
+            .lock()
+            .unwrap()
+            .contains(&note_off(output_channel(4), 60, 64)));
+    }
+
+    #[test]
+    fn silence_releases_everything() {
+        let mut voices = Voices::default();
+        for pitch in [0, 60, 127] {
+            voices.update(&note_on(MIDI_CHANNEL, pitch, 64));
+            voices.update(&note_on(5, pitch, 64));
+        }
+        let events = silence(&BTreeSet::from([MIDI_CHANNEL, 5]));
+        for event in &events {
+            voices.update(event);
+        }
+        assert!(voices.sounding.is_empty());
+        assert!(events.contains(&controller_set(MIDI_CHANNEL, MIDI_CC_SUSTAIN_ID, 0)));
+        assert!(events.contains(&controller_set(5, MIDI_CC_SUSTAIN_ID, 0)));
+        assert_eq!(
+            events.last(),
+            Some(&controller_set(5, MIDI_CC_ALL_NOTES_OFF, 0))
+        );
     }
 }
@@ -0,0 +1,396 @@
+// Keyboard shortcuts of the stave commands. The defaults can be replaced per action
+// in the [key_bindings] table of the config, e.g. `strum_up = ["Alt+U"]`,
+// an empty list leaves the action without a shortcut.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use eframe::egui::{Event, InputState, Key, KeyboardShortcut, Modifiers};
+
+use crate::track::ChannelId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StaveAction {
+    ClearSelection,
+    TempoStretch,
+    TempoStretchCoarse,
+    TempoShrink,
+    TempoShrinkCoarse,
+    Delete,
+    InsertGapBar,
+    InsertGapBeat,
+    TapeInsert,
+    TapeDuplicate,
+    ShiftTailLater,
+    ShiftTailEarlier,
+    ToggleSnap,
+    SelectionEndEarlier,
+    SelectionEndLater,
+    SelectionStartEarlier,
+    SelectionStartLater,
+    NotesLater,
+    NotesEarlier,
+    NotesShorter,
+    NotesLonger,
+    TransposeUp,
+    TransposeDown,
+    AccentUp,
+    AccentDown,
+    SnapNotes,
+    Quantize,
+    AlignChord,
+    AlignChordToCursor,
+    Spread,
+    CapDuration,
+    SetChannel(ChannelId),
+    ZoomToFit,
+    Undo,
+    Redo,
+    Mirror,
+    StrumUp,
+    StrumDown,
+    Legato,
+    Pedal,
+    Unpedal,
+    Shuffle,
+    Humanize,
+    SelectSamePitch,
+    SelectSameVelocity,
+    SelectSameDuration,
+    ToggleLock,
+    Tie,
+    Untie,
+    AddNote,
+    ToggleDamper,
+    Copy,
+    Paste,
+    SetBookmark,
+    ClearBookmark,
+    PreviousBookmark,
+    NextBookmark,
+    PreviousNoteOfPitch,
+    NextNoteOfPitch,
+    PreviousEvent,
+    NextEvent,
+    TrackStart,
+    TrackEnd,
+}
+
+/// Actions, their names in the config, and default shortcuts.
+/// The toggle_damper default comes from the damper_toggle_key setting.
+#[rustfmt::skip]
+const DEFAULT_BINDINGS: &[(StaveAction, &str, &[&str])] = &[
+    (StaveAction::ClearSelection, "clear_selection", &["Q"]),
+    (StaveAction::TempoStretch, "tempo_stretch", &["Shift+]"]),
+    (StaveAction::TempoStretchCoarse, "tempo_stretch_coarse", &["Ctrl+Shift+]"]),
+    (StaveAction::TempoShrink, "tempo_shrink", &["Shift+["]),
+    (StaveAction::TempoShrinkCoarse, "tempo_shrink_coarse", &["Ctrl+Shift+["]),
+    (StaveAction::Delete, "delete", &["Delete"]),
+    (StaveAction::InsertGapBar, "insert_gap_bar", &["Shift+Insert"]),
+    (StaveAction::InsertGapBeat, "insert_gap_beat", &["Ctrl+Shift+Insert"]),
+    (StaveAction::TapeInsert, "tape_insert", &["Insert"]),
+    (StaveAction::TapeDuplicate, "tape_duplicate", &["Ctrl+D"]),
+    (StaveAction::ShiftTailLater, "shift_tail_later", &["Ctrl+Shift+Right"]),
+    (StaveAction::ShiftTailEarlier, "shift_tail_earlier", &["Ctrl+Shift+Left"]),
+    (StaveAction::ToggleSnap, "toggle_snap", &["G"]),
+    (StaveAction::SelectionEndEarlier, "selection_end_earlier", &["Shift+Left"]),
+    (StaveAction::SelectionEndLater, "selection_end_later", &["Shift+Right"]),
+    (StaveAction::SelectionStartEarlier, "selection_start_earlier", &["Ctrl+Alt+Left"]),
+    (StaveAction::SelectionStartLater, "selection_start_later", &["Ctrl+Alt+Right"]),
+    (StaveAction::NotesLater, "notes_later", &["Alt+Shift+Right", "Shift+L"]),
+    (StaveAction::NotesEarlier, "notes_earlier", &["Alt+Shift+Left", "Shift+H"]),
+    (StaveAction::NotesShorter, "notes_shorter", &["H"]),
+    (StaveAction::NotesLonger, "notes_longer", &["L"]),
+    (StaveAction::TransposeUp, "transpose_up", &["U"]),
+    (StaveAction::TransposeDown, "transpose_down", &["J"]),
+    (StaveAction::AccentUp, "accent_up", &["I"]),
+    (StaveAction::AccentDown, "accent_down", &["K"]),
+    (StaveAction::SnapNotes, "snap_notes", &["Alt+Shift+Q"]),
+    (StaveAction::Quantize, "quantize", &["Alt+Q"]),
+    (StaveAction::AlignChord, "align_chord", &["Alt+C"]),
+    (StaveAction::AlignChordToCursor, "align_chord_to_cursor", &["Alt+Shift+C"]),
+    (StaveAction::Spread, "spread", &["Alt+S"]),
+    (StaveAction::CapDuration, "cap_duration", &["Alt+L"]),
+    (StaveAction::SetChannel(0), "channel_1", &["Alt+1"]),
+    (StaveAction::SetChannel(1), "channel_2", &["Alt+2"]),
+    (StaveAction::SetChannel(2), "channel_3", &["Alt+3"]),
+    (StaveAction::SetChannel(3), "channel_4", &["Alt+4"]),
+    (StaveAction::SetChannel(4), "channel_5", &["Alt+5"]),
+    (StaveAction::SetChannel(5), "channel_6", &["Alt+6"]),
+    (StaveAction::SetChannel(6), "channel_7", &["Alt+7"]),
+    (StaveAction::SetChannel(7), "channel_8", &["Alt+8"]),
+    (StaveAction::SetChannel(8), "channel_9", &["Alt+9"]),
+    (StaveAction::ZoomToFit, "zoom_to_fit", &["Alt+A"]),
+    (StaveAction::Undo, "undo", &["Ctrl+Z"]),
+    (StaveAction::Redo, "redo", &["Ctrl+Y", "Ctrl+Shift+Z"]),
+    (StaveAction::Mirror, "mirror", &["Alt+M"]),
+    (StaveAction::StrumUp, "strum_up", &["Alt+B"]),
+    (StaveAction::StrumDown, "strum_down", &["Alt+Shift+B"]),
+    (StaveAction::Legato, "legato", &["Alt+G"]),
+    (StaveAction::Pedal, "pedal", &["Alt+E"]),
+    (StaveAction::Unpedal, "unpedal", &["Alt+Shift+E"]),
+    (StaveAction::Shuffle, "shuffle", &["Alt+R"]),
+    (StaveAction::Humanize, "humanize", &["Alt+H"]),
+    (StaveAction::SelectSamePitch, "select_same_pitch", &["Alt+P"]),
+    (StaveAction::SelectSameVelocity, "select_same_velocity", &["Alt+V"]),
+    (StaveAction::SelectSameDuration, "select_same_duration", &["Alt+D"]),
+    (StaveAction::ToggleLock, "toggle_lock", &["Ctrl+L"]),
+    (StaveAction::Tie, "tie", &["Alt+T"]),
+    (StaveAction::Untie, "untie", &["Alt+Shift+T"]),
+    (StaveAction::AddNote, "add_note", &["E"]),
+    (StaveAction::ToggleDamper, "toggle_damper", &[]),
+    (StaveAction::Copy, "copy", &["Ctrl+C"]),
+    (StaveAction::Paste, "paste", &["Ctrl+V"]),
+    (StaveAction::SetBookmark, "set_bookmark", &["M"]),
+    (StaveAction::ClearBookmark, "clear_bookmark", &["N"]),
+    (StaveAction::PreviousBookmark, "previous_bookmark", &["Ctrl+Left"]),
+    (StaveAction::NextBookmark, "next_bookmark", &["Ctrl+Right"]),
+    (StaveAction::PreviousNoteOfPitch, "previous_note_of_pitch", &["Ctrl+Alt+Shift+Left"]),
+    (StaveAction::NextNoteOfPitch, "next_note_of_pitch", &["Ctrl+Alt+Shift+Right"]),
+    (StaveAction::PreviousEvent, "previous_event", &["Alt+Left"]),
+    (StaveAction::NextEvent, "next_event", &["Alt+Right"]),
+    (StaveAction::TrackStart, "track_start", &["Ctrl+Home"]),
+    (StaveAction::TrackEnd, "track_end", &["Ctrl+End"]),
+];
+
+/// Parse a shortcut like "Ctrl+Shift+Z", key names are as in [Key::from_name].
+pub fn parse_shortcut(text: &str) -> Result<KeyboardShortcut, String> {
+    let (modifier_names, key_name) = match text.strip_suffix("++") {
+        Some(prefix) => (prefix, "+"),
+        None => text.rsplit_once('+').unwrap_or(("", text)),
+    };
+    let mut modifiers = Modifiers::NONE;
+    for name in modifier_names.split('+').filter(|name| !name.is_empty()) {
+        modifiers = modifiers
+            | match name {
+                "Ctrl" => Modifiers::CTRL,
+                "Alt" => Modifiers::ALT,
+                "Shift" => Modifiers::SHIFT,
+                "Cmd" => Modifiers::COMMAND,
+                _ => return Err(format!("Unknown modifier {:?} in {:?}", name, text)),
+            };
+    }
+    let key =
+        Key::from_name(key_name).ok_or(format!("Unknown key {:?} in {:?}", key_name, text))?;
+    Ok(KeyboardShortcut::new(modifiers, key))
+}
+
+#[derive(Debug)]
+pub struct Keymap {
+    bindings: Vec<(KeyboardShortcut, StaveAction)>,
+}
+
+impl Keymap {
+    /// Default bindings with the configured ones replacing them, problems are logged.
+    pub fn new(overrides: &BTreeMap<String, Vec<String>>, damper_toggle_key: &str) -> Keymap {
+        let (keymap, problems) = Self::build(overrides, damper_toggle_key);
+        for problem in problems {
+            log::warn!("Key bindings: {}", problem);
+        }
+        keymap
+    }
+
+    fn build(
+        overrides: &BTreeMap<String, Vec<String>>,
+        damper_toggle_key: &str,
+    ) -> (Keymap, Vec<String>) {
+        let mut problems = vec![];
+        for name in overrides.keys() {
+            if !DEFAULT_BINDINGS.iter().any(|(_, n, _)| n == name) {
+                problems.push(format!("unknown action {:?}", name));
+            }
+        }
+        let damper_default = [damper_toggle_key.to_string()];
+        let mut actions: Vec<(StaveAction, &str, &[String])> = vec![];
+        // Configured bindings take precedence over the defaults they conflict with.
+        for (action, name, _) in DEFAULT_BINDINGS {
+            if let Some(shortcuts) = overrides.get(*name) {
+                actions.push((*action, name, shortcuts));
+            }
+        }
+        let defaults: Vec<(StaveAction, &str, Vec<String>)> = DEFAULT_BINDINGS
+            .iter()
+            .filter(|(_, name, _)| !overrides.contains_key(*name))
+            .map(|(action, name, shortcuts)| {
+                let shortcuts = if *action == StaveAction::ToggleDamper {
+                    damper_default.to_vec()
+                } else {
+                    shortcuts.iter().map(|s| s.to_string()).collect()
+                };
+                (*action, *name, shortcuts)
+            })
+            .collect();
+        for (action, name, shortcuts) in &defaults {
+            actions.push((*action, name, shortcuts));
+        }
+
+        let mut bindings = vec![];
+        let mut names = HashMap::new();
+        for (action, name, shortcuts) in actions {
+            for text in shortcuts {
+                match parse_shortcut(text) {
+                    Ok(shortcut) => {
+                        if let Some(other) = names.get(&shortcut) {
+                            problems.push(format!(
+                                "{} of {} is already bound to {}",
+                                text, name, other
+                            ));
+                        } else {
+                            bindings.push((shortcut, action));
+                            names.insert(shortcut, name);
+                        }
+                    }
+                    Err(message) => problems.push(message),
+                }
+            }
+        }
+        (Keymap { bindings }, problems)
+    }
+
+    /// Consume the pressed shortcuts and return their actions. Modifiers should match exactly,
+    /// so e.g. Alt+Shift+L triggers neither Alt+L nor Shift+L.
+    pub fn consume(&self, input: &mut InputState) -> HashSet<StaveAction> {
+        let mut actions = HashSet::new();
+        input.events.retain(|event| {
+            let Event::Key {
+                key,
+                modifiers,
+                pressed: true,
+                ..
+            } = event
+            else {
+                return true;
+            };
+            let bound = self.bindings.iter().find(|(shortcut, _)| {
+                shortcut.logical_key == *key && modifiers.matches_exact(shortcut.modifiers)
+            });
+            match bound {
+                Some((_, action)) => {
+                    actions.insert(*action);
+                    false
+                }
+                None => true,
+            }
+        });
+        actions
+    }
+
+    #[cfg(test)]
+    fn action(&self, shortcut: &KeyboardShortcut) -> Option<StaveAction> {
+        self.bindings
+            .iter()
+            .find(|(s, _)| s == shortcut)
+            .map(|(_, action)| *action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortcuts() {
+        assert_eq!(
+            parse_shortcut("Ctrl+Shift+Z"),
+            Ok(KeyboardShortcut::new(
+                Modifiers::CTRL | Modifiers::SHIFT,
+                Key::Z
+            ))
+        );
+        assert_eq!(
+            parse_shortcut("Alt++"),
+            Ok(KeyboardShortcut::new(Modifiers::ALT, Key::Plus))
+        );
+        assert_eq!(
+            parse_shortcut("]"),
+            Ok(KeyboardShortcut::new(Modifiers::NONE, Key::CloseBracket))
+        );
+        assert!(parse_shortcut("Hyper+A").is_err());
+        assert!(parse_shortcut("Ctrl+Nope").is_err());
+    }
+
+    #[test]
+    fn bindings() {
+        let (keymap, problems) = Keymap::build(&BTreeMap::new(), "P");
+        assert_eq!(problems, Vec::<String>::new());
+        assert_eq!(
+            keymap.action(&KeyboardShortcut::new(Modifiers::NONE, Key::P)),
+            Some(StaveAction::ToggleDamper)
+        );
+        assert_eq!(
+            keymap.bindings.len(),
+            DEFAULT_BINDINGS
+                .iter()
+                .map(|(_, _, shortcuts)| shortcuts.len())
+                .sum::<usize>()
+                + 1
+        );
+
+        // Every arrow modifier combination has its own action.
+        let action = |text| keymap.action(&parse_shortcut(text).unwrap());
+        for (text, expected) in [
+            ("Ctrl+Alt+Shift+Left", StaveAction::PreviousNoteOfPitch),
+            ("Ctrl+Alt+Shift+Right", StaveAction::NextNoteOfPitch),
+            ("Ctrl+Alt+Left", StaveAction::SelectionStartEarlier),
+            ("Ctrl+Shift+Left", StaveAction::ShiftTailEarlier),
+            ("Alt+Shift+Left", StaveAction::NotesEarlier),
+            ("Ctrl+Left", StaveAction::PreviousBookmark),
+            ("Alt+Left", StaveAction::PreviousEvent),
+        ] {
+            assert_eq!(action(text), Some(expected), "{}", text);
+        }
+
+        let overrides = BTreeMap::from([
+            ("strum_up".to_string(), vec!["Alt+U".to_string()]),
+            ("mirror".to_string(), vec![]),
+            ("legato".to_string(), vec!["Alt+U".to_string()]),
+            ("transpose_up".to_string(), vec!["Alt+W".to_string()]),
+            ("no_such_action".to_string(), vec!["F1".to_string()]),
+        ]);
+        let (keymap, problems) = Keymap::build(&overrides, "P");
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("no_such_action"));
+        assert!(problems[1].contains("legato"));
+        let action = |text| keymap.action(&parse_shortcut(text).unwrap());
+        assert_eq!(action("Alt+U"), Some(StaveAction::StrumUp));
+        assert_eq!(action("Alt+B"), None);
+        assert_eq!(action("Alt+M"), None);
+        assert_eq!(action("U"), None);
+        assert_eq!(action("Alt+W"), Some(StaveAction::TransposeUp));
+    }
+
+    #[test]
+    fn modifiers_match_exactly() {
+        let overrides = BTreeMap::from([
+            ("strum_up".to_string(), vec!["Alt+X".to_string()]),
+            ("strum_down".to_string(), vec!["Shift+X".to_string()]),
+        ]);
+        let (keymap, problems) = Keymap::build(&overrides, "P");
+        assert_eq!(problems, Vec::<String>::new());
+        let press = |text| {
+            let shortcut = parse_shortcut(text).unwrap();
+            let mut input = InputState::default();
+            input.modifiers = shortcut.modifiers;
+            input.events.push(Event::Key {
+                key: shortcut.logical_key,
+                physical_key: None,
+                pressed: true,
+                repeat: false,
+                modifiers: shortcut.modifiers,
+            });
+            keymap.consume(&mut input)
+        };
+        assert_eq!(press("Q"), HashSet::from([StaveAction::ClearSelection]));
+        assert_eq!(press("Alt+Q"), HashSet::from([StaveAction::Quantize]));
+        assert_eq!(
+            press("Alt+Shift+Q"),
+            HashSet::from([StaveAction::SnapNotes])
+        );
+        assert_eq!(press("L"), HashSet::from([StaveAction::NotesLonger]));
+        assert_eq!(press("Alt+L"), HashSet::from([StaveAction::CapDuration]));
+        assert_eq!(press("Shift+L"), HashSet::from([StaveAction::NotesLater]));
+        assert_eq!(press("Alt+Shift+L"), HashSet::new());
+        assert_eq!(press("Alt+X"), HashSet::from([StaveAction::StrumUp]));
+        assert_eq!(press("Shift+X"), HashSet::from([StaveAction::StrumDown]));
+        assert_eq!(press("Alt+Shift+X"), HashSet::new());
+    }
+}
@@ -11,8 +11,10 @@ use std::sync::mpsc::Sender;
 use crate::app::EmApp;
 use crate::config::Config;
 use crate::engine::EngineCommand;
+use crate::metronome::Metronome;
 use crate::midi::SmfSource;
 use crate::project::Project;
+use crate::track::TrackEventType;
 use crate::track_source::TrackSource;
 
 mod app;
@@ -22,10 +24,16 @@ mod clipboard;
 mod common;
 mod config;
 mod engine;
+mod keymap;
+mod meter;
+mod metronome;
 mod midi;
+mod pitch;
 mod project;
 mod range;
+mod script;
 mod stave;
+mod tie;
 mod track;
 mod track_edit;
 mod track_history;
@@ -62,18 +70,29 @@ pub fn main() {
         return;
     }
 
-    // No configurable values at the moment, keeping it here to keep config loader compilable.
-    let _config = Config::load(arg_matches.get_one::<std::path::PathBuf>("config-file"));
-
-    let midi_file_path = arg_matches
-        .get_one::<std::path::PathBuf>("midi-file")
-        .unwrap_or_else(|| {
-            log::error!("Missing argument 'midi-file'");
-            std::process::exit(1);
-        });
-    log::info!("MIDI file name {:?}", midi_file_path);
-    let project = Project::open_file(midi_file_path);
-
+    let config = Config::load(arg_matches.get_one::<std::path::PathBuf>("config-file"));
+    util::set_storage_format(config.storage_format);
+
+    let project =
+        if let Some(bundle_path) = arg_matches.get_one::<std::path::PathBuf>("bundle-file") {
+            log::info!("Bundle file name {:?}", bundle_path);
+            Project::import_bundle(bundle_path).unwrap_or_else(|message| {
+                show_error("Cannot import bundle", &message);
+                std::process::exit(1);
+            })
+        } else {
+            let midi_file_path = arg_matches
+                .get_one::<std::path::PathBuf>("midi-file")
+                .unwrap_or_else(|| {
+                    log::error!("Missing argument 'midi-file'");
+                    std::process::exit(1);
+                });
+            log::info!("MIDI file name {:?}", midi_file_path);
+            Project::open_file(midi_file_path).unwrap_or_else(|message| {
+                show_error("Cannot open MIDI file", &message);
+                std::process::exit(1);
+            })
+        };
     let midi_output = MidiOutput::new(common::APP_NAME)
         .expect("MIDI sequencer client")
         .create_virtual(common::APP_NAME)
@@ -83,19 +102,42 @@ pub fn main() {
     let (mut engine, engine_command_sender) = audio_setup::setup_audio_engine(midi_output);
 
     {
-        let track_midi_source = TrackSource::new(project.history.borrow().track.clone());
+        let track_midi_source = TrackSource::new(
+            project.history.borrow().track.clone(),
+            engine.lock().unwrap().transpose(),
+        );
+        let latency = config.output_latency;
+        let max_polyphony = Some(config.max_polyphony).filter(|&n| n > 0);
+        let pause_fade = config.pause_fade;
+        engine_command_sender
+            .send(Box::new(move |engine| {
+                engine.set_latency(latency);
+                engine.set_max_polyphony(max_polyphony);
+                engine.set_pause_fade(pause_fade);
+                engine.add(Box::new(track_midi_source))
+            }))
+            .unwrap();
+    }
+
+    let track_is_empty = project.history.borrow().with_track(|track| {
+        !track
+            .events
+            .iter()
+            .any(|ev| matches!(ev.event, TrackEventType::Note(_)))
+    });
+    if let Some(click) = Metronome::configured(
+        &config,
+        engine.lock().unwrap().loop_region(),
+        track_is_empty,
+    ) {
         engine_command_sender
-            .send(Box::new(|engine| engine.add(Box::new(track_midi_source))))
+            .send(Box::new(|engine| engine.add(Box::new(click))))
             .unwrap();
     }
 
-    let mut midi_inputs = vec![]; // Keeps inputs open
-    midi_inputs.push(audio_setup::midi_keyboard_input(
-        "Digital Piano",
-        &mut engine,
-    ));
-    midi_inputs.push(audio_setup::midi_keyboard_input("XPIANOGT", &mut engine));
-    midi_inputs.push(audio_setup::midi_keyboard_input("MPK mini 3", &mut engine));
+    // Keeps inputs open
+    let _midi_inputs =
+        audio_setup::midi_keyboard_inputs(&config.midi_input_port_names, &mut engine);
 
     // GUI
     let window_builder = Box::new(|wb: egui::ViewportBuilder| {
@@ -113,16 +155,45 @@ pub fn main() {
         native_options,
         Box::new(|ctx| {
             ctx.egui_ctx.set_visuals(egui::Visuals::light());
-            Ok(Box::new(EmApp::new(ctx, engine_command_sender, project)))
+            Ok(Box::new(EmApp::new(
+                ctx,
+                &config,
+                engine_command_sender,
+                project,
+            )))
         }),
     )
     .expect("Emmate UI")
 }
 
+/// Report an error that prevents the application from starting.
+fn show_error(title: &str, message: &str) {
+    log::error!("{}: {}", title, message);
+    let title = title.to_string();
+    let message = message.to_string();
+    let native_options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([480.0, 160.0]),
+        ..Default::default()
+    };
+    let shown = eframe::run_simple_native(common::APP_NAME, native_options, move |ctx, _frame| {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading(&title);
+            ui.label(&message);
+            ui.label("Please check the file or choose another one.");
+            if ui.button("Close").clicked() {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+        });
+    });
+    if let Err(e) = shown {
+        log::error!("Cannot show the error window: {}", e);
+    }
+}
+
 // Play MIDI from an SMD file.
 fn play_midi_file(midi_file_path: &PathBuf, engine_command_sender: &Sender<Box<EngineCommand>>) {
     let smf_data = std::fs::read(midi_file_path).unwrap();
-    let smf_midi_source = SmfSource::new(smf_data);
+    let smf_midi_source = SmfSource::new(smf_data).unwrap();
     engine_command_sender
         .send(Box::new(|engine| engine.add(Box::new(smf_midi_source))))
         .unwrap();
@@ -140,6 +211,12 @@ fn build_cli() -> Command {
                 .value_parser(clap::value_parser!(std::path::PathBuf))
                 .value_hint(clap::ValueHint::FilePath),
         )
+        .arg(
+            clap::arg!(--"bundle-file" <FILE>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .value_hint(clap::ValueHint::FilePath)
+                .help("Unpack a project bundle into a new project and open it."),
+        )
         .arg(
             clap::arg!(--"shell-completion-script" <SHELL_NAME>)
                 .value_parser(clap::value_parser!(ccomplete::Shell)),
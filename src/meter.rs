@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::Time;
+use crate::metronome::DEFAULT_BEAT_DURATION;
+use crate::range::Range;
+use crate::track::{Track, TrackEventType};
+
+/// Time signature, e.g. 6/8 is `TimeSignature { beats: 6, beat_unit: 8 }`.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub beats: u8,
+    /// Note value of a beat, a power of 2.
+    pub beat_unit: u8,
+}
+
+impl TimeSignature {
+    pub const COMMON: TimeSignature = TimeSignature {
+        beats: 4,
+        beat_unit: 4,
+    };
+
+    /// Tempo is not supported yet, quarter note is always [DEFAULT_BEAT_DURATION] long.
+    pub fn beat_duration(&self) -> Time {
+        DEFAULT_BEAT_DURATION * 4 / self.beat_unit as Time
+    }
+
+    pub fn bar_duration(&self) -> Time {
+        self.beat_duration() * self.beats as Time
+    }
+}
+
+impl std::str::FromStr for TimeSignature {
+    type Err = String;
+
+    /// Parse a time signature given as beats/beat_unit, e.g. "6/8".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (beats, beat_unit) = text
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("'{}' should be beats/unit, e.g. 3/4", text))?;
+        let number = |s: &str| {
+            s.trim()
+                .parse::<u8>()
+                .map_err(|e| format!("'{}': {}", s.trim(), e))
+        };
+        let (beats, beat_unit) = (number(beats)?, number(beat_unit)?);
+        if beats == 0 {
+            return Err("A bar should have at least one beat".to_string());
+        }
+        if !beat_unit.is_power_of_two() || beat_unit > 64 {
+            return Err(format!("Beat unit {} is not a note value", beat_unit));
+        }
+        Ok(TimeSignature { beats, beat_unit })
+    }
+}
+
+impl std::fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.beats, self.beat_unit)
+    }
+}
+
+/// Time signature changes of a track. A change always starts a new bar.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeterMap {
+    /// Ordered by time, the first one is at 0.
+    changes: Vec<(Time, TimeSignature)>,
+}
+
+impl MeterMap {
+    pub fn new(changes: impl IntoIterator<Item = (Time, TimeSignature)>) -> Self {
+        let mut changes: Vec<(Time, TimeSignature)> = changes
+            .into_iter()
+            .filter(|(_, sig)| sig.beats > 0 && sig.beat_unit.is_power_of_two())
+            .collect();
+        changes.sort_by_key(|(at, _)| *at);
+        if changes.first().is_none_or(|(at, _)| *at > 0) {
+            changes.insert(0, (0, TimeSignature::COMMON));
+        }
+        MeterMap { changes }
+    }
+
+    pub fn of_track(track: &Track) -> Self {
+        Self::new(track.events.iter().filter_map(|ev| match &ev.event {
+            TrackEventType::TimeSignature(sig) => Some((ev.at, *sig)),
+            _ => None,
+        }))
+    }
+
+    /// Time signature in effect at the given time.
+    pub fn time_signature_at(&self, at: Time) -> TimeSignature {
+        self.changes
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= at)
+            .map_or(TimeSignature::COMMON, |(_, sig)| *sig)
+    }
+
+    /// Time signature sections as (start, end, signature), the last one is open-ended.
+    fn sections(&self) -> impl Iterator<Item = (Time, Option<Time>, TimeSignature)> + '_ {
+        self.changes.iter().enumerate().map(|(i, (at, sig))| {
+            let end = self.changes.get(i + 1).map(|(next, _)| *next);
+            (*at, end, *sig)
+        })
+    }
+
+    /// Starting times of the bars within the range.
+    pub fn bar_lines(&self, range: &Range<Time>) -> Vec<Time> {
+        let mut result = vec![];
+        for (start, end, sig) in self.sections() {
+            let end = end.map_or(range.1, |end| end.min(range.1));
+            let bar = sig.bar_duration();
+            let first = if range.0 > start {
+                (range.0 - start + bar - 1) / bar
+            } else {
+                0
+            };
+            let mut at = start + first * bar;
+            while at < end {
+                result.push(at);
+                at += bar;
+            }
+        }
+        result
+    }
+
+    /// Beats within the range as (time, bar, beat), bars and beats are counted from 1.
+    pub fn beats(&self, range: &Range<Time>) -> Vec<(Time, Time, Time)> {
+        let mut result = vec![];
+        let mut first_bar = 1;
+        for (start, end, sig) in self.sections() {
+            let section_end = end.map_or(range.1, |end| end.min(range.1));
+            let beat = sig.beat_duration();
+            let beats = sig.beats as Time;
+            let mut i = if range.0 > start {
+                (range.0 - start + beat - 1) / beat
+            } else {
+                0
+            };
+            while start + i * beat < section_end {
+                result.push((start + i * beat, first_bar + i / beats, i % beats + 1));
+                i += 1;
+            }
+            if let Some(end) = end {
+                let bar = sig.bar_duration();
+                first_bar += (end - start + bar - 1) / bar;
+            }
+        }
+        result
+    }
+
+    /// Time of the beat, bars and beats are counted from 1.
+    pub fn bar_beat_time(&self, bar: Time, beat: Time) -> Result<Time, String> {
+        if bar < 1 {
+            return Err("Bars start from 1".to_string());
+        }
+        let mut bar = bar - 1;
+        for (start, end, sig) in self.sections() {
+            let bar_duration = sig.bar_duration();
+            let section_bars = end.map(|end| (end - start + bar_duration - 1) / bar_duration);
+            if section_bars.is_none_or(|n| bar < n) {
+                if !(1..=sig.beats as Time).contains(&beat) {
+                    return Err(format!(
+                        "Beats of a {} bar should be within 1..{}",
+                        sig, sig.beats
+                    ));
+                }
+                return Ok(start + bar * bar_duration + (beat - 1) * sig.beat_duration());
+            }
+            bar -= section_bars.unwrap();
+        }
+        unreachable!("the last section is not bounded")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WALTZ: TimeSignature = TimeSignature {
+        beats: 3,
+        beat_unit: 4,
+    };
+
+    #[test]
+    fn bar_lines_after_meter_change() {
+        let common = MeterMap::new([]);
+        assert_eq!(
+            common.bar_lines(&(0, 5_000_000)),
+            vec![0, 2_000_000, 4_000_000]
+        );
+        // 4/4 -> 3/4 at the start of the 3rd bar.
+        let meter = MeterMap::new([(4_000_000, WALTZ)]);
+        assert_eq!(
+            meter.bar_lines(&(0, 9_000_000)),
+            vec![0, 2_000_000, 4_000_000, 5_500_000, 7_000_000, 8_500_000]
+        );
+        assert_eq!(
+            meter.bar_lines(&(2_000_001, 7_000_001)),
+            vec![4_000_000, 5_500_000, 7_000_000]
+        );
+        // A change in the middle of a bar starts a new bar.
+        let meter = MeterMap::new([(0, WALTZ), (2_000_000, TimeSignature::COMMON)]);
+        assert_eq!(
+            meter.bar_lines(&(0, 6_000_000)),
+            vec![0, 1_500_000, 2_000_000, 4_000_000]
+        );
+    }
+
+    #[test]
+    fn parse_time_signature() {
+        assert_eq!("3/4".parse::<TimeSignature>(), Ok(WALTZ));
+        assert_eq!(
+            " 4 / 4 ".parse::<TimeSignature>(),
+            Ok(TimeSignature::COMMON)
+        );
+        for text in ["", "3", "0/4", "3/0", "3/5", "3/128", "a/4"] {
+            assert!(text.parse::<TimeSignature>().is_err(), "'{}'", text);
+        }
+    }
+
+    #[test]
+    fn bar_beat_positions() {
+        let meter = MeterMap::new([(4_000_000, WALTZ)]);
+        assert_eq!(meter.bar_beat_time(1, 1), Ok(0));
+        assert_eq!(meter.bar_beat_time(2, 4), Ok(3_500_000));
+        assert_eq!(meter.bar_beat_time(3, 1), Ok(4_000_000));
+        assert_eq!(meter.bar_beat_time(4, 3), Ok(6_500_000));
+        assert!(meter.bar_beat_time(3, 4).is_err());
+        assert!(meter.bar_beat_time(0, 1).is_err());
+        let eighths = MeterMap::new([(
+            0,
+            TimeSignature {
+                beats: 6,
+                beat_unit: 8,
+            },
+        )]);
+        assert_eq!(eighths.bar_beat_time(2, 6), Ok(1_500_000 + 1_250_000));
+    }
+
+    #[test]
+    fn beat_numbers() {
+        let meter = MeterMap::new([(4_000_000, WALTZ)]);
+        assert_eq!(
+            meter.beats(&(3_000_000, 5_000_001)),
+            vec![
+                (3_000_000, 2, 3),
+                (3_500_000, 2, 4),
+                (4_000_000, 3, 1),
+                (4_500_000, 3, 2),
+                (5_000_000, 3, 3)
+            ]
+        );
+        // A change in the middle of a bar, the cut bar still counts.
+        let meter = MeterMap::new([(0, WALTZ), (2_000_000, TimeSignature::COMMON)]);
+        assert_eq!(
+            meter.beats(&(1_000_000, 2_600_000)),
+            vec![
+                (1_000_000, 1, 3),
+                (1_500_000, 2, 1),
+                (2_000_000, 3, 1),
+                (2_500_000, 3, 2)
+            ]
+        );
+        for (at, bar, beat) in meter.beats(&(0, 10_000_000)) {
+            assert_eq!(meter.bar_beat_time(bar, beat), Ok(at));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::common::Time;
+use crate::config::Config;
+use crate::engine::{EngineEvent, EventSource, LoopRegion};
+use crate::midi::{note_off, note_on};
+use crate::range::RangeLike;
+use crate::track::{ChannelId, Level, Pitch};
+
+/// General MIDI percussion channel (10th, counting from 1).
+const CLICK_CHANNEL: ChannelId = 9;
+/// Hi wood block.
+const CLICK_PITCH: Pitch = 76;
+const CLICK_VELOCITY: Level = 100;
+const CLICK_DURATION: Time = 20_000;
+
+/// 120 beats per minute, same as the SMF default.
+pub const DEFAULT_BEAT_DURATION: Time = 500_000;
+
+/// Produces a click on every beat.
+pub struct Metronome {
+    beat_duration: Time,
+    next_beat: Time,
+    /// When set, clicks are only produced inside the active loop.
+    loop_region: Option<LoopRegion>,
+}
+
+impl Metronome {
+    /// Clicks on every beat, regardless of the loop.
+    pub fn new(beat_duration: Time) -> Self {
+        assert!(beat_duration > 0);
+        Metronome {
+            beat_duration,
+            next_beat: 0,
+            loop_region: None,
+        }
+    }
+
+    /// Metronome to play with the track as configured, if any.
+    pub fn configured(
+        config: &Config,
+        loop_region: LoopRegion,
+        track_is_empty: bool,
+    ) -> Option<Self> {
+        if config.empty_track_click && track_is_empty {
+            // Tempo reference for recording, this also covers the loop.
+            Some(Metronome::new(DEFAULT_BEAT_DURATION))
+        } else if config.loop_click {
+            Some(Metronome::in_loop(DEFAULT_BEAT_DURATION, loop_region))
+        } else {
+            None
+        }
+    }
+
+    pub fn in_loop(beat_duration: Time, loop_region: LoopRegion) -> Self {
+        assert!(beat_duration > 0);
+        Metronome {
+            beat_duration,
+            next_beat: 0,
+            loop_region: Some(loop_region),
+        }
+    }
+
+    fn is_audible_at(&self, at: Time) -> bool {
+        match &self.loop_region {
+            None => true,
+            Some(region) => region.lock().unwrap().is_some_and(|r| r.contains(&at)),
+        }
+    }
+}
+
+impl EventSource for Metronome {
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    fn seek(&mut self, at: &Time) {
+        self.next_beat =
+            (*at as f64 / self.beat_duration as f64).ceil() as Time * self.beat_duration;
+    }
+
+    fn next(&mut self, at: &Time) -> Vec<EngineEvent> {
+        let mut events = vec![];
+        while self.next_beat <= *at {
+            if self.is_audible_at(self.next_beat) {
+                events.push(EngineEvent {
+                    at: self.next_beat,
+                    event: note_on(CLICK_CHANNEL, CLICK_PITCH, CLICK_VELOCITY),
+                });
+                events.push(EngineEvent {
+                    at: self.next_beat + CLICK_DURATION,
+                    event: note_off(CLICK_CHANNEL, CLICK_PITCH, 0),
+                });
+            }
+            self.next_beat += self.beat_duration;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::live::LiveEvent;
+    use midly::MidiMessage;
+    use std::sync::{Arc, Mutex};
+
+    fn click_times(events: &[EngineEvent]) -> Vec<Time> {
+        events
+            .iter()
+            .filter(|ev| {
+                matches!(
+                    ev.event,
+                    LiveEvent::Midi {
+                        message: MidiMessage::NoteOn { .. },
+                        ..
+                    }
+                )
+            })
+            .map(|ev| ev.at)
+            .collect()
+    }
+
+    #[test]
+    fn clicks_only_in_loop() {
+        let region: LoopRegion = Arc::new(Mutex::new(None));
+        let mut metronome = Metronome::in_loop(100, region.clone());
+        metronome.seek(&0);
+        assert!(metronome.next(&1000).is_empty());
+
+        *region.lock().unwrap() = Some((250, 550));
+        metronome.seek(&0);
+        assert_eq!(click_times(&metronome.next(&1000)), vec![300, 400, 500]);
+
+        // Starting in the middle.
+        metronome.seek(&420);
+        assert_eq!(click_times(&metronome.next(&1000)), vec![500]);
+    }
+
+    #[test]
+    fn click_on_empty_track() {
+        let region: LoopRegion = Arc::new(Mutex::new(None));
+        let config = Config {
+            empty_track_click: true,
+            ..Config::default()
+        };
+        let mut metronome = Metronome::configured(&config, region.clone(), true).unwrap();
+        metronome.seek(&0);
+        assert_eq!(
+            click_times(&metronome.next(&1_000_000)),
+            vec![0, 500_000, 1_000_000]
+        );
+        assert!(Metronome::configured(&config, region.clone(), false).is_none());
+        assert!(Metronome::configured(&Config::default(), region.clone(), true).is_none());
+        let config = Config {
+            loop_click: true,
+            ..config
+        };
+        let metronome = Metronome::configured(&config, region, false).unwrap();
+        assert!(metronome.loop_region.is_some());
+    }
+}
@@ -1,10 +1,10 @@
-use std::time::Duration;
-
 use midly::io::WriteResult;
 use midly::live::LiveEvent;
-use midly::num::u15;
+use midly::num::{u15, u28};
 use midly::MidiMessage::Controller;
-use midly::{Format, Header, MidiMessage, Smf, Timing, Track, TrackEvent};
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind,
+};
 
 use crate::common::Time;
 use crate::engine::{EngineEvent, EventSource};
@@ -12,47 +12,127 @@ use crate::track::{ChannelId, ControllerId, Level, Pitch};
 
 pub struct SmfSource {
     events: Vec<TrackEvent<'static>>,
-    tick: Duration,
+    tempo: TempoMap,
     current_idx: usize,
+    /// Ticks since the start to the current event.
+    current_tick: u64,
     running_at: Time,
 }
 
-pub fn load_smf(smf_data: &Vec<u8>) -> (Vec<TrackEvent<'static>>, u32) {
-    let smf = Smf::parse(smf_data).unwrap();
+/// Tick to time conversion that follows tempo changes (Set Tempo meta events).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    /// (start tick, start time, microseconds per tick) of the constant tempo segments, ascending.
+    segments: Vec<(u64, f64, f64)>,
+}
+
+impl TempoMap {
+    pub fn constant(usec_per_tick: f64) -> TempoMap {
+        TempoMap {
+            segments: vec![(0, 0.0, usec_per_tick)],
+        }
+    }
+
+    /// Tempo changes of the (merged) event stream, 120 BPM until the first one.
+    pub fn of_events(ticks_per_beat: u16, events: &[TrackEvent]) -> TempoMap {
+        let usec_per_tick = |usec_per_beat: u32| usec_per_beat as f64 / ticks_per_beat as f64;
+        let mut tempo_map = Self::constant(usec_per_tick(DEFAULT_USEC_PER_BEAT));
+        let mut tick = 0u64;
+        for event in events {
+            tick += event.delta.as_int() as u64;
+            if let TrackEventKind::Meta(MetaMessage::Tempo(usec_per_beat)) = event.kind {
+                let start = tempo_map.time_at(tick);
+                let last = tempo_map.segments.last_mut().unwrap();
+                let segment = (tick, start, usec_per_tick(usec_per_beat.as_int()));
+                if last.0 == tick {
+                    *last = segment;
+                } else {
+                    tempo_map.segments.push(segment);
+                }
+            }
+        }
+        tempo_map
+    }
+
+    /// Microseconds since the start.
+    fn time_at(&self, tick: u64) -> f64 {
+        let i = self.segments.partition_point(|s| s.0 <= tick);
+        let (start_tick, start, usec_per_tick) = self.segments[i.saturating_sub(1)];
+        start + (tick - start_tick) as f64 * usec_per_tick
+    }
+
+    pub fn time(&self, tick: u64) -> Time {
+        self.time_at(tick).round() as Time
+    }
+}
+
+pub fn load_smf(smf_data: &[u8]) -> Result<(Vec<TrackEvent<'static>>, TempoMap), String> {
+    let smf = Smf::parse(smf_data).map_err(|e| format!("Cannot parse MIDI SMF data: {}", e))?;
     log::debug!("SMF header {:#?}", &smf.header);
     log::debug!(
         "SMF file has {} tracks, format is {:?}.",
         smf.tracks.len(),
         smf.header.format
     );
-    assert_eq!(
-        &smf.header.format,
-        &Format::SingleTrack,
-        "MIDI SMF format {:#?} is not supported.",
-        &smf.header.format
-    );
-    assert!(
-        smf.tracks.len() > 0,
-        "No tracks in SMF file. At least one is required."
-    );
+    // Parallel tracks are merged into one, sequential ones are independent pieces.
+    if smf.header.format == Format::Sequential {
+        return Err(format!(
+            "MIDI SMF format {:?} is not supported.",
+            &smf.header.format
+        ));
+    }
+    if smf.tracks.is_empty() {
+        return Err("No tracks in SMF file. At least one is required.".to_string());
+    }
+    match smf.header.timing {
+        Timing::Metrical(d) if d.as_int() > 0 => (),
+        timing => return Err(format!("Timing format {:?} is not supported.", timing)),
+    }
     // println!("Starting events of the 1st track are {:#?}", &track[..10]);
-    let usec_per_tick = usec_per_tick(&smf.header.timing);
-    let mut events = vec![];
-    for me in &smf.tracks[0] {
-        let event = me.to_static();
-        events.push(event);
+    let events = merge_tracks(&smf.tracks);
+    let tempo_map = TempoMap::of_events(ticks_per_beat(&smf.header.timing) as u16, &events);
+    Ok((events, tempo_map))
+}
+
+/// Combine tracks into a single stream ordered by absolute time. Events at the same
+/// time keep the order of their tracks.
+pub fn merge_tracks(tracks: &[Track]) -> Vec<TrackEvent<'static>> {
+    let mut timed = vec![];
+    for track in tracks {
+        let mut at: u32 = 0;
+        for event in track {
+            at += event.delta.as_int();
+            timed.push((at, event.to_static()));
+        }
+    }
+    timed.sort_by_key(|(at, _)| *at);
+    let mut prev_at = 0;
+    timed
+        .into_iter()
+        .map(|(at, mut event)| {
+            event.delta = u28::from(at - prev_at);
+            prev_at = at;
+            event
+        })
+        .collect()
+}
+
+/// Resolution of the SMF header, if the data has a supported one.
+pub fn smf_ticks_per_beat(smf_data: &[u8]) -> Option<u16> {
+    match midly::parse(smf_data).ok()?.0.timing {
+        Timing::Metrical(d) if d.as_int() > 0 => Some(d.as_int()),
+        _ => None,
     }
-    (events, usec_per_tick)
 }
 
 pub fn serialize_smf(
     events: Vec<TrackEvent<'static>>,
-    usec_per_tick: u32,
+    ticks_per_beat: u16,
     out: &mut Vec<u8>,
 ) -> WriteResult<Vec<u8>> {
     let mut track = Track::new();
     track.extend_from_slice(events.as_slice());
-    let timing = timing_from_usec_per_tick(usec_per_tick);
+    let timing = Timing::Metrical(u15::from(ticks_per_beat));
     let header = Header::new(Format::SingleTrack, timing);
     let mut smf = Smf::new(header);
     smf.tracks.push(track);
@@ -60,42 +140,34 @@ pub fn serialize_smf(
 }
 
 impl SmfSource {
-    pub fn new(smf_data: Vec<u8>) -> SmfSource {
-        let (events, usec_per_tick) = load_smf(&smf_data);
-        SmfSource {
+    pub fn new(smf_data: Vec<u8>) -> Result<SmfSource, String> {
+        let (events, tempo) = load_smf(&smf_data)?;
+        Ok(SmfSource {
             events,
-            tick: Duration::from_micros(usec_per_tick as u64),
+            tempo,
             current_idx: 0,
+            current_tick: 0,
             running_at: 0,
-        }
+        })
     }
 }
 
 // Default SMF tempo is 120 beats per minute and default signature 4/4
-const DEFAULT_BEATS_PER_SEC: u32 = 120 / 60;
-
-fn usec_per_tick(timing: &Timing) -> u32 {
-    let tick_per_beat = ticks_per_beat(timing);
-    let usec_per_tick = 1_000_000 / (DEFAULT_BEATS_PER_SEC * tick_per_beat);
-    println!(
-        "tick/beat {:#?}, beat/second  {:#?}, usec/tick {:#?}",
-        tick_per_beat, DEFAULT_BEATS_PER_SEC, usec_per_tick
-    );
-    usec_per_tick
-}
+const DEFAULT_USEC_PER_BEAT: u32 = 500_000;
 
 fn ticks_per_beat(timing: &Timing) -> u32 {
-    // Also maybe support Tempo messages. Tempo messages set micros per beat.
     match timing {
         Timing::Metrical(d) => d.as_int() as u32,
         _ => panic!("Timing format {:#?} is not supported.", timing),
     }
 }
 
-fn timing_from_usec_per_tick(usec_per_tick: u32) -> Timing {
-    Timing::Metrical(u15::from(
-        (1_000_000f32 / (usec_per_tick as f32 * DEFAULT_BEATS_PER_SEC as f32)) as u16,
-    ))
+/// Resolution of exported files unless another one is requested, about 26 microseconds per tick.
+pub const DEFAULT_TICKS_PER_BEAT: u16 = 19230;
+
+/// Tick duration of exported files (they have no tempo events), microseconds.
+pub fn export_usec_per_tick(ticks_per_beat: u16) -> f64 {
+    DEFAULT_USEC_PER_BEAT as f64 / ticks_per_beat as f64
 }
 
 impl EventSource for SmfSource {
@@ -104,10 +176,18 @@ impl EventSource for SmfSource {
     }
 
     fn seek(&mut self, at: &Time) {
-        assert!(
-            self.running_at >= *at,
-            "SmfSource back reset is not supported."
-        );
+        // Deltas are relative, so replay them from the beginning up to the earliest event
+        // at or after the `at` moment.
+        self.current_idx = 0;
+        self.current_tick = 0;
+        while let Some(event) = self.events.get(self.current_idx) {
+            let tick = self.current_tick + event.delta.as_int() as u64;
+            if self.tempo.time(tick) >= *at {
+                break;
+            }
+            self.current_tick = tick;
+            self.current_idx += 1;
+        }
         self.running_at = *at;
     }
 
@@ -116,12 +196,13 @@ impl EventSource for SmfSource {
         let mut events = vec![];
         while self.is_running() {
             let event = track[self.current_idx];
-            let running_at =
-                self.running_at + self.tick.as_micros() as Time * event.delta.as_int() as Time;
+            let tick = self.current_tick + event.delta.as_int() as u64;
+            let running_at = self.tempo.time(tick);
             if running_at > *at {
                 return events;
             }
             self.running_at = running_at;
+            self.current_tick = tick;
             self.current_idx += 1;
             if let Some(lev) = event.kind.as_live_event() {
                 events.push(EngineEvent {
@@ -235,14 +316,118 @@ pub fn controller_set(
 mod tests {
     use super::*;
 
+    #[test]
+    fn smf_source_seek() {
+        let data = std::fs::read("test/files/short.mid").unwrap();
+        let mut source = SmfSource::new(data).unwrap();
+        let all = source.next(&Time::MAX).len();
+        assert!(all > 0);
+        assert!(!source.is_running());
+        source.seek(&0);
+        assert!(source.is_running());
+        assert_eq!(source.next(&Time::MAX).len(), all);
+        // Somewhere in the middle of the track.
+        let (events, tempo) = load_smf(&std::fs::read("test/files/short.mid").unwrap()).unwrap();
+        let ticks: u64 = events[..events.len() / 2]
+            .iter()
+            .map(|ev| ev.delta.as_int() as u64)
+            .sum();
+        let middle = tempo.time(ticks) + 1;
+        for _ in 0..2 {
+            source.seek(&middle);
+            let later = source.next(&Time::MAX).len();
+            assert!(0 < later && later < all);
+            source.seek(&0);
+            assert_eq!(source.next(&Time::MAX).len(), all);
+        }
+    }
+
     #[test]
     fn timing_conversion() {
-        let timing = Timing::Metrical(u15::from(1000u16));
-        assert_eq!(usec_per_tick(&timing), 500);
-        let timing = Timing::Metrical(u15::from(19200u16));
-        assert_eq!(usec_per_tick(&timing), 26);
+        assert_eq!(TempoMap::of_events(1000, &[]).time(3), 1500);
+        assert_eq!(TempoMap::of_events(19200, &[]).time(1000), 26042);
+        assert_eq!(export_usec_per_tick(1000), 500.0);
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let tempo = |delta: u32, usec_per_beat: u32| TrackEvent {
+            delta: u28::from(delta),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(usec_per_beat.into())),
+        };
+        // 60 BPM from the start, 120 BPM after 2 beats, 240 BPM after 2 more.
+        let events = [
+            tempo(0, 1_000_000),
+            tempo(200, 500_000),
+            tempo(200, 250_000),
+        ];
+        let tempo_map = TempoMap::of_events(100, &events);
+        assert_eq!(tempo_map.time(0), 0);
+        assert_eq!(tempo_map.time(100), 1_000_000);
+        assert_eq!(tempo_map.time(200), 2_000_000);
+        assert_eq!(tempo_map.time(300), 2_500_000);
+        assert_eq!(tempo_map.time(400), 3_000_000);
+        assert_eq!(tempo_map.time(500), 3_250_000);
+    }
+
+    #[test]
+    fn load_truncated() {
+        let data = std::fs::read("test/files/short.mid").unwrap();
+        assert!(load_smf(&data).is_ok());
+        // Truncated track data is tolerated by the parser, but not incomplete headers.
+        for length in [0, 4, 10, 20] {
+            assert!(load_smf(&data[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn load_parallel_tracks() {
+        let event = |delta: u32, message| TrackEvent {
+            delta: u28::from(delta),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message,
+            },
+        };
+        let note = |delta, key: u8, on| {
+            let (key, vel) = (key.into(), 64.into());
+            event(
+                delta,
+                if on {
+                    MidiMessage::NoteOn { key, vel }
+                } else {
+                    MidiMessage::NoteOff { key, vel }
+                },
+            )
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::from(1000)),
+        ));
+        smf.tracks
+            .push(vec![note(0, 60, true), note(100, 60, false)]);
+        smf.tracks
+            .push(vec![note(50, 67, true), note(100, 67, false)]);
+        let mut data = vec![];
+        smf.write(&mut data).unwrap();
+
+        let (events, tempo_map) = load_smf(&data).unwrap();
+        assert_eq!(tempo_map, TempoMap::constant(500.0));
+        let keys: Vec<(u32, u8)> = events
+            .iter()
+            .map(|ev| match ev.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. },
+                    ..
+                } => (ev.delta.as_int(), key.as_int()),
+                _ => panic!("unexpected {:?}", ev),
+            })
+            .collect();
+        assert_eq!(keys, vec![(0, 60), (50, 67), (50, 60), (50, 67)]);
 
-        let timing = Timing::Metrical(u15::from(1234u16));
-        assert_eq!(timing_from_usec_per_tick(usec_per_tick(&timing)), timing);
+        smf.header.format = Format::Sequential;
+        data.clear();
+        smf.write(&mut data).unwrap();
+        assert!(load_smf(&data).is_err());
     }
 }
@@ -0,0 +1,98 @@
+use crate::track::Pitch;
+
+/// Octave number of middle C (tone 60). Emmate's convention is C3 (tones start at C-2),
+/// other applications may call it C4 or C5.
+pub const DEFAULT_MIDDLE_C_OCTAVE: i32 = 3;
+pub const MIDDLE_C: Pitch = 60;
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+const MAX_PITCH: i32 = 127;
+
+pub fn is_black_key(pitch: Pitch) -> bool {
+    [1, 3, 6, 8, 10].contains(&(pitch % 12))
+}
+
+fn lowest_octave(middle_c_octave: i32) -> i32 {
+    middle_c_octave - MIDDLE_C as i32 / 12
+}
+
+/// Note name with octave number, e.g. 60 -> "C3", 61 -> "C#3" with the default octave numbering.
+pub fn pitch_name(pitch: Pitch, middle_c_octave: i32) -> String {
+    format!(
+        "{}{}",
+        NOTE_NAMES[(pitch % 12) as usize],
+        (pitch / 12) as i32 + lowest_octave(middle_c_octave)
+    )
+}
+
+/// Reverse of [pitch_name], flats (e.g. "Db3") are also accepted.
+#[allow(dead_code)] // For settings and scripts that name pitches, none of them do yet.
+pub fn parse_pitch(name: &str, middle_c_octave: i32) -> Option<Pitch> {
+    let mut chars = name.chars();
+    let letter = chars.next()?.to_ascii_uppercase();
+    let mut tone = NOTE_NAMES.iter().position(|n| *n == letter.to_string())? as i32;
+    let rest = chars.as_str();
+    let octave = if let Some(octave) = rest.strip_prefix('#') {
+        tone += 1;
+        octave
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        tone -= 1;
+        octave
+    } else {
+        rest
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let pitch = (octave - lowest_octave(middle_c_octave)) * 12 + tone;
+    if (0..=MAX_PITCH).contains(&pitch) {
+        Some(pitch as Pitch)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_pitches() {
+        assert_eq!(pitch_name(0, DEFAULT_MIDDLE_C_OCTAVE), "C-2");
+        assert_eq!(pitch_name(21, DEFAULT_MIDDLE_C_OCTAVE), "A-1");
+        assert_eq!(pitch_name(60, DEFAULT_MIDDLE_C_OCTAVE), "C3");
+        assert_eq!(pitch_name(61, DEFAULT_MIDDLE_C_OCTAVE), "C#3");
+        assert_eq!(pitch_name(69, DEFAULT_MIDDLE_C_OCTAVE), "A3");
+        assert_eq!(pitch_name(127, DEFAULT_MIDDLE_C_OCTAVE), "G8");
+        assert!(is_black_key(61));
+        assert!(!is_black_key(60));
+        assert!(!is_black_key(64));
+        assert!(is_black_key(70));
+    }
+
+    #[test]
+    fn name_round_trip() {
+        let octave = DEFAULT_MIDDLE_C_OCTAVE;
+        for pitch in 0..=127 {
+            assert_eq!(parse_pitch(&pitch_name(pitch, octave), octave), Some(pitch));
+        }
+        assert_eq!(parse_pitch("Db3", DEFAULT_MIDDLE_C_OCTAVE), Some(61));
+        assert_eq!(parse_pitch("c3", DEFAULT_MIDDLE_C_OCTAVE), Some(60));
+        assert_eq!(parse_pitch("Cb-2", DEFAULT_MIDDLE_C_OCTAVE), None);
+        assert_eq!(parse_pitch("G#8", DEFAULT_MIDDLE_C_OCTAVE), None);
+        assert_eq!(parse_pitch("H3", DEFAULT_MIDDLE_C_OCTAVE), None);
+        assert_eq!(parse_pitch("C", DEFAULT_MIDDLE_C_OCTAVE), None);
+    }
+
+    #[test]
+    fn octave_numbering() {
+        assert_eq!(pitch_name(60, 3), "C3");
+        assert_eq!(pitch_name(60, 4), "C4");
+        assert_eq!(pitch_name(60, 5), "C5");
+        assert_eq!(pitch_name(0, 4), "C-1");
+        assert_eq!(parse_pitch("C4", 4), Some(60));
+        assert_eq!(parse_pitch("C4", 3), Some(72));
+        for pitch in 0..=127 {
+            assert_eq!(parse_pitch(&pitch_name(pitch, 5), 5), Some(pitch));
+        }
+    }
+}
@@ -1,5 +1,12 @@
+use crate::common::Time;
+use crate::midi;
+use crate::tie::Ties;
+use crate::track::{ControllerId, EventId, ExportOptions, Level};
 use crate::track_history::TrackHistory;
+use crate::util;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{absolute, Path, PathBuf};
 
@@ -7,14 +14,162 @@ pub struct Project {
     pub title: String,
     pub history: RefCell<TrackHistory>,
     pub home_path: PathBuf,
+    pub meta: ProjectMeta,
+    pub session: SessionState,
+}
+
+/// Project-wide settings that are not part of the edit history.
+/// New fields should have defaults so older projects can still be opened.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ProjectMeta {
+    /// Initial pan (MIDI CC 10) of the track, `None` is center.
+    #[serde(default)]
+    pub pan: Option<Level>,
+    /// MIDI file the project was created from, for reference only: the history is
+    /// self-contained, so the file may be moved or deleted afterwards.
+    #[serde(default)]
+    pub source_file: Option<PathBuf>,
+    /// Time resolution of the source file, exported files keep it.
+    #[serde(default)]
+    pub ticks_per_beat: Option<u16>,
+    /// Tied notes, drawn on the stave only.
+    #[serde(default)]
+    pub ties: Ties,
+}
+
+impl ProjectMeta {
+    const FILE_NAME: &'static str = "project.meta";
+
+    pub fn load(home_path: &Path) -> Self {
+        let path = home_path.join(Self::FILE_NAME);
+        if path.is_file() {
+            util::load(&path)
+        } else {
+            log::debug!("No project metadata found, using defaults.");
+            ProjectMeta::default()
+        }
+    }
+
+    pub fn store(&self, home_path: &Path) {
+        util::store(self, &home_path.join(Self::FILE_NAME));
+    }
+
+    pub fn export_options(&self) -> ExportOptions {
+        ExportOptions {
+            pan: self.pan,
+            ticks_per_beat: self.ticks_per_beat,
+            transpose: 0,
+        }
+    }
+}
+
+/// UI state of the last session (selection, view, etc.), restored when the project is opened.
+/// It is not essential, so a missing or unreadable file is replaced with defaults.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    #[serde(default)]
+    pub selection: HashSet<EventId>,
+    /// Visible time range, (left, right).
+    #[serde(default)]
+    pub view: Option<(Time, Time)>,
+    #[serde(default)]
+    pub cursor_position: Time,
+    /// Controller shown on the damper lane.
+    #[serde(default)]
+    pub active_controller: Option<ControllerId>,
+    /// Grid step of snapping, `None` if it is off.
+    #[serde(default)]
+    pub snap: Option<Time>,
+}
+
+impl SessionState {
+    const FILE_NAME: &'static str = "session.state";
+
+    pub fn load(home_path: &Path) -> Self {
+        let path = home_path.join(Self::FILE_NAME);
+        if !path.is_file() {
+            return SessionState::default();
+        }
+        util::try_load(&path).unwrap_or_else(|message| {
+            log::warn!("Session state is not restored, {}", message);
+            SessionState::default()
+        })
+    }
+
+    pub fn store(&self, home_path: &Path) {
+        util::store(self, &home_path.join(Self::FILE_NAME));
+    }
+}
+
+/// Whole project packed into a single file, for sharing.
+#[derive(Serialize, Deserialize)]
+struct ProjectBundle {
+    format_version: u32,
+    /// Paths are relative to the project's home directory.
+    files: Vec<(PathBuf, Vec<u8>)>,
 }
 
 impl Project {
     const DIRECTORY_NAME_SUFFIX: &'static str = "emmate";
     const HISTORY_DIR_NAME: &'static str = "history";
+    pub const BUNDLE_NAME_EXT: &'static str = "emmate-bundle";
+    const BUNDLE_FORMAT_VERSION: u32 = 1;
 
-    pub fn open_file(source_file: &PathBuf) -> Project {
+    pub fn open_file(source_file: &PathBuf) -> Result<Project, String> {
         log::info!("Source file {}", source_file.to_string_lossy());
+        let directory = Self::directory_for(source_file);
+        let snapshots_dir = directory.join(Self::HISTORY_DIR_NAME);
+        if !snapshots_dir.is_dir() {
+            let existed = directory.exists();
+            fs::create_dir_all(&snapshots_dir).expect(
+                format!("create project directory {:?}", directory.to_string_lossy()).as_str(),
+            );
+            if let Err(message) = TrackHistory::with_directory(&snapshots_dir).init(source_file) {
+                // Do not leave a broken project behind, so the file can be opened again once fixed.
+                let created = if existed { &snapshots_dir } else { &directory };
+                fs::remove_dir_all(created)
+                    .unwrap_or_else(|_| panic!("remove {}", created.display()));
+                return Err(message);
+            }
+            let ticks_per_beat = fs::read(source_file)
+                .ok()
+                .and_then(|data| midi::smf_ticks_per_beat(&data));
+            let meta = ProjectMeta {
+                source_file: Some(absolute(source_file).unwrap_or(source_file.to_owned())),
+                ticks_per_beat,
+                ..ProjectMeta::load(&directory)
+            };
+            meta.store(&directory);
+        };
+        Ok(Self::open_directory(&directory))
+    }
+
+    /// Open an existing project.
+    pub fn open_directory(directory: &PathBuf) -> Project {
+        log::info!("Project directory {}", &directory.to_string_lossy());
+        let snapshots_dir = directory.join(Self::HISTORY_DIR_NAME);
+        let mut history = TrackHistory::with_directory(&snapshots_dir);
+        history.open();
+        let meta = ProjectMeta::load(directory);
+        if let Some(source_file) = &meta.source_file {
+            if !source_file.is_file() {
+                log::info!(
+                    "Source file {} is not available, it is not needed to open the project.",
+                    source_file.to_string_lossy()
+                );
+            }
+        }
+        Project {
+            title: Self::path_to_title(directory),
+            home_path: directory.to_owned(),
+            history: RefCell::new(history),
+            meta,
+            session: SessionState::load(directory),
+        }
+    }
+
+    /// Project directory that corresponds to the given source (MIDI or bundle) file.
+    fn directory_for(source_file: &PathBuf) -> PathBuf {
         let mut directory = source_file.to_owned();
         if directory.file_name().is_none() {
             panic!(
@@ -24,25 +179,104 @@ impl Project {
         }
         directory.set_extension("");
         directory.set_extension(Project::DIRECTORY_NAME_SUFFIX);
-        let directory = absolute(directory).expect("project directory path can be normalized");
-        log::info!("Project directory {}", &directory.to_string_lossy());
-
-        let mut snapshots_dir = directory.clone();
-        snapshots_dir.push(Self::HISTORY_DIR_NAME);
+        absolute(directory).expect("project directory path can be normalized")
+    }
 
-        let mut history = TrackHistory::with_directory(&snapshots_dir);
-        if !snapshots_dir.is_dir() {
-            fs::create_dir_all(&snapshots_dir).expect(
-                format!("create project directory {:?}", directory.to_string_lossy()).as_str(),
-            );
-            history = history.init(&source_file)
+    /// Pack the project's history and settings into a single file.
+    pub fn export_bundle(home_path: &PathBuf, bundle_file: &PathBuf) {
+        let mut files = vec![];
+        Self::collect_files(
+            home_path,
+            &PathBuf::from(Self::HISTORY_DIR_NAME),
+            &mut files,
+        );
+        let meta_path = home_path.join(ProjectMeta::FILE_NAME);
+        if meta_path.is_file() {
+            let data =
+                fs::read(&meta_path).unwrap_or_else(|_| panic!("read {}", meta_path.display()));
+            files.push((PathBuf::from(ProjectMeta::FILE_NAME), data));
+        }
+        log::info!(
+            "Bundling {} files into {}",
+            files.len(),
+            bundle_file.to_string_lossy()
+        );
+        let bundle = ProjectBundle {
+            format_version: Self::BUNDLE_FORMAT_VERSION,
+            files,
         };
-        history.open();
-        Project {
-            title: Self::path_to_title(&directory),
-            home_path: directory,
-            history: RefCell::new(history),
+        util::store(&bundle, bundle_file);
+    }
+
+    fn collect_files(home: &PathBuf, relative: &PathBuf, files: &mut Vec<(PathBuf, Vec<u8>)>) {
+        let path = home.join(relative);
+        for entry in fs::read_dir(&path).unwrap_or_else(|_| panic!("list {}", path.display())) {
+            let entry = entry.expect("directory entry");
+            let relative = relative.join(entry.file_name());
+            if entry.path().is_dir() {
+                Self::collect_files(home, &relative, files);
+            } else {
+                let data = fs::read(entry.path())
+                    .unwrap_or_else(|_| panic!("read {}", relative.display()));
+                files.push((relative, data));
+            }
+        }
+    }
+
+    /// Unpack a bundle into a new project directory next to the bundle file and open it.
+    pub fn import_bundle(bundle_file: &PathBuf) -> Result<Project, String> {
+        let directory = Self::directory_for(bundle_file);
+        if directory.exists() {
+            return Err(format!(
+                "Project directory {} already exists.",
+                directory.to_string_lossy()
+            ));
+        }
+        let bundle: ProjectBundle = util::try_load(bundle_file)?;
+        if bundle.format_version != Self::BUNDLE_FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported bundle format version {} (expected {}).",
+                bundle.format_version,
+                Self::BUNDLE_FORMAT_VERSION
+            ));
+        }
+        if let Some((relative, _)) = bundle.files.iter().find(|(relative, _)| {
+            relative.is_absolute()
+                || relative
+                    .components()
+                    .any(|c| c == std::path::Component::ParentDir)
+        }) {
+            return Err(format!(
+                "Bundle entry {} is outside of the project.",
+                relative.display()
+            ));
+        }
+        if let Err(message) = Self::unpack(&directory, &bundle.files) {
+            // Do not leave a broken project behind, so the import can be retried.
+            if directory.exists() {
+                fs::remove_dir_all(&directory)
+                    .unwrap_or_else(|_| panic!("remove {}", directory.display()));
+            }
+            return Err(message);
+        }
+        Ok(Self::open_directory(&directory))
+    }
+
+    /// Write bundle files into the project directory and check that the metadata is readable.
+    fn unpack(directory: &Path, files: &[(PathBuf, Vec<u8>)]) -> Result<(), String> {
+        for (relative, data) in files {
+            let path = directory.join(relative);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("create {}: {}", parent.display(), e))?;
+            }
+            fs::write(&path, data).map_err(|e| format!("write {}: {}", path.display(), e))?;
+        }
+        let meta_path = directory.join(ProjectMeta::FILE_NAME);
+        if meta_path.is_file() {
+            util::try_load::<ProjectMeta>(&meta_path)?;
         }
+        Ok(())
     }
 
     // Clean the project path to make it less cluttered.
@@ -60,3 +294,142 @@ impl Project {
         result.to_string_lossy().to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::export_smf;
+
+    #[test]
+    fn bundle_round_trip() {
+        let work_dir = util::fresh_test_dir("test_bundle_round_trip");
+        let source_file = work_dir.join("short.mid");
+        fs::copy("test/files/short.mid", &source_file).unwrap();
+        let project = Project::open_file(&source_file).unwrap();
+
+        let bundle_file = work_dir.join("copy.".to_string() + Project::BUNDLE_NAME_EXT);
+        Project::export_bundle(&project.home_path, &bundle_file);
+        let copy = Project::import_bundle(&bundle_file).unwrap();
+        assert_ne!(project.home_path, copy.home_path);
+        assert_eq!(
+            project.history.borrow().version(),
+            copy.history.borrow().version()
+        );
+        assert_eq!(
+            project.history.borrow().with_track(|t| t.events.clone()),
+            copy.history.borrow().with_track(|t| t.events.clone())
+        );
+        // Will not overwrite an existing project.
+        assert!(Project::import_bundle(&bundle_file).is_err());
+        // Not a bundle.
+        let broken_file = work_dir.join("broken.".to_string() + Project::BUNDLE_NAME_EXT);
+        fs::write(&broken_file, b"not a bundle").unwrap();
+        assert!(Project::import_bundle(&broken_file).is_err());
+        // Bad entries are rejected before anything is written.
+        let meta_entry = (
+            PathBuf::from(ProjectMeta::FILE_NAME),
+            fs::read(project.home_path.join(ProjectMeta::FILE_NAME)).unwrap(),
+        );
+        let outside_file = work_dir.join("outside.".to_string() + Project::BUNDLE_NAME_EXT);
+        let outside = ProjectBundle {
+            format_version: Project::BUNDLE_FORMAT_VERSION,
+            files: vec![meta_entry, (PathBuf::from("../escaped"), vec![1])],
+        };
+        util::store(&outside, &outside_file);
+        assert!(Project::import_bundle(&outside_file).is_err());
+        assert!(!Project::directory_for(&outside_file).exists());
+        // A corrupt metadata file fails the import instead of opening the project.
+        let corrupt_file = work_dir.join("corrupt.".to_string() + Project::BUNDLE_NAME_EXT);
+        let corrupt = ProjectBundle {
+            format_version: Project::BUNDLE_FORMAT_VERSION,
+            files: vec![(PathBuf::from(ProjectMeta::FILE_NAME), b"garbage".to_vec())],
+        };
+        util::store(&corrupt, &corrupt_file);
+        assert!(Project::import_bundle(&corrupt_file).is_err());
+        assert!(!Project::directory_for(&corrupt_file).exists());
+        // Can be retried once fixed.
+        fs::copy(&bundle_file, &corrupt_file).unwrap();
+        assert!(Project::import_bundle(&corrupt_file).is_ok());
+    }
+
+    #[test]
+    fn open_invalid_file() {
+        let work_dir = util::fresh_test_dir("test_open_invalid_file");
+        let source_file = work_dir.join("truncated.mid");
+        let data = fs::read("test/files/short.mid").unwrap();
+        fs::write(&source_file, &data[..10]).unwrap();
+        assert!(Project::open_file(&source_file).is_err());
+        assert!(!Project::directory_for(&source_file).exists());
+    }
+
+    #[test]
+    fn open_without_source_file() {
+        let work_dir = util::fresh_test_dir("test_open_without_source_file");
+        let source_file = work_dir.join("short.mid");
+        fs::copy("test/files/short.mid", &source_file).unwrap();
+        let project = Project::open_file(&source_file).unwrap();
+        assert_eq!(
+            project.meta.source_file,
+            Some(absolute(&source_file).unwrap())
+        );
+        let events = project.history.borrow().with_track(|t| t.events.clone());
+        assert!(!events.is_empty());
+
+        fs::remove_file(&source_file).unwrap();
+        let reopened = Project::open_file(&source_file).unwrap();
+        assert_eq!(reopened.meta.source_file, project.meta.source_file);
+        assert_eq!(
+            reopened.history.borrow().with_track(|t| t.events.clone()),
+            events
+        );
+    }
+
+    #[test]
+    fn session_state_round_trip() {
+        let work_dir = util::fresh_test_dir("test_session_state_round_trip");
+        assert_eq!(SessionState::load(&work_dir), SessionState::default());
+
+        let session = SessionState {
+            selection: HashSet::from([3, 5, 8]),
+            view: Some((1_000, 2_000_000)),
+            cursor_position: 500_000,
+            active_controller: Some(1),
+            snap: Some(250_000),
+        };
+        session.store(&work_dir);
+        assert_eq!(SessionState::load(&work_dir), session);
+
+        fs::write(work_dir.join(SessionState::FILE_NAME), b"garbage").unwrap();
+        assert_eq!(SessionState::load(&work_dir), SessionState::default());
+    }
+
+    #[test]
+    fn export_keeps_source_resolution() {
+        let work_dir = util::fresh_test_dir("test_export_keeps_source_resolution");
+        let (events, _) = midi::load_smf(&fs::read("test/files/short.mid").unwrap()).unwrap();
+        let mut data = vec![];
+        midi::serialize_smf(events, 480, &mut data).unwrap();
+        let source_file = work_dir.join("480.mid");
+        fs::write(&source_file, &data).unwrap();
+
+        let project = Project::open_file(&source_file).unwrap();
+        assert_eq!(project.meta.ticks_per_beat, Some(480));
+        let exported = work_dir.join("exported.mid");
+        let events = project.history.borrow().with_track(|t| t.events.clone());
+        export_smf(&events, &project.meta.export_options(), &exported).unwrap();
+        assert_eq!(
+            midi::smf_ticks_per_beat(&fs::read(&exported).unwrap()),
+            Some(480)
+        );
+        // Can be overridden.
+        let options = ExportOptions {
+            ticks_per_beat: Some(960),
+            ..project.meta.export_options()
+        };
+        export_smf(&events, &options, &exported).unwrap();
+        assert_eq!(
+            midi::smf_ticks_per_beat(&fs::read(&exported).unwrap()),
+            Some(960)
+        );
+    }
+}
@@ -0,0 +1,270 @@
+// User scripts (Rhai) that edit the track.
+//
+// A script gets a copy of the notes and controller events as `events` array of object maps,
+// and may change, remove, or append (without "id") them. The differences from the track
+// become a single undoable command. Locked notes are visible but changing or removing
+// them is an error. Other event kinds (bookmarks, time signatures) are not exposed.
+// Scripts have no file or network access.
+
+use std::collections::{HashMap, HashSet};
+
+use rhai::{Array, Dynamic, Engine, Map, Scope};
+
+use crate::changeset::EventAction;
+use crate::common::Time;
+use crate::track::{
+    ChannelId, ControllerSetValue, EventId, Level, Note, Pitch, Track, TrackEvent, TrackEventType,
+    MAX_LEVEL,
+};
+use crate::track_edit::{AppliedCommand, CommandDiff, EditCommandType};
+use crate::util::IdSeq;
+
+/// Guard against runaway scripts.
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+/// Run the script over the track, `None` if it changes nothing.
+pub fn run_script(
+    track: &Track,
+    id_seq: &IdSeq,
+    selection: &HashSet<EventId>,
+    cursor: Time,
+    source: &str,
+) -> Result<Option<AppliedCommand>, String> {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    let mut scope = Scope::new();
+    let events: Array = track.events.iter().filter_map(to_map).collect();
+    scope.push("events", events);
+    scope.push_constant(
+        "selection",
+        selection
+            .iter()
+            .map(|&id| Dynamic::from(id as i64))
+            .collect::<Array>(),
+    );
+    scope.push_constant("cursor", cursor);
+    engine
+        .run_with_scope(&mut scope, source)
+        .map_err(|e| format!("Script error: {}", e))?;
+    let events: Array = scope
+        .get_value("events")
+        .ok_or("Script should keep the \"events\" array")?;
+
+    let originals: HashMap<EventId, &TrackEvent> = track
+        .events
+        .iter()
+        .filter(|ev| to_map(ev).is_some())
+        .map(|ev| (ev.id, ev))
+        .collect();
+    let mut kept = HashSet::new();
+    let mut patch = vec![];
+    for item in events {
+        let map = item
+            .try_cast::<Map>()
+            .ok_or("Items of \"events\" should be object maps")?;
+        match map.get("id") {
+            Some(id) if !id.is_unit() => {
+                let id = id.as_int().map_err(|t| format!("Event id is {}", t))? as EventId;
+                let original = originals
+                    .get(&id)
+                    .ok_or(format!("No editable event with id {}", id))?;
+                if !kept.insert(id) {
+                    return Err(format!("Event {} is listed twice", id));
+                }
+                let event = from_map(id, &map)?;
+                if &event != *original {
+                    if original.is_locked() {
+                        return Err(format!("Note {} is locked", id));
+                    }
+                    patch.push(EventAction::Update((*original).clone(), event));
+                }
+            }
+            _ => patch.push(EventAction::Insert(from_map(id_seq.next(), &map)?)),
+        }
+    }
+    for ev in track.events.iter() {
+        if originals.contains_key(&ev.id) && !kept.contains(&ev.id) {
+            if ev.is_locked() {
+                return Err(format!("Note {} is locked", ev.id));
+            }
+            patch.push(EventAction::Delete(ev.clone()));
+        }
+    }
+    if patch.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((
+        EditCommandType::Script,
+        vec![CommandDiff::ChangeList { patch }],
+    )))
+}
+
+fn to_map(ev: &TrackEvent) -> Option<Dynamic> {
+    let mut map = Map::new();
+    map.insert("id".into(), (ev.id as i64).into());
+    map.insert("at".into(), ev.at.into());
+    match &ev.event {
+        TrackEventType::Note(note) => {
+            map.insert("kind".into(), "note".into());
+            map.insert("pitch".into(), (note.pitch as i64).into());
+            map.insert("velocity".into(), (note.velocity as i64).into());
+            map.insert("duration".into(), note.duration.into());
+            map.insert("channel".into(), (note.channel as i64).into());
+            map.insert("locked".into(), note.locked.into());
+        }
+        TrackEventType::Controller(cc) => {
+            map.insert("kind".into(), "controller".into());
+            map.insert("controller".into(), (cc.controller_id as i64).into());
+            map.insert("value".into(), (cc.value as i64).into());
+        }
+        _ => return None,
+    }
+    Some(map.into())
+}
+
+fn from_map(id: EventId, map: &Map) -> Result<TrackEvent, String> {
+    let int = |key: &str| -> Result<i64, String> {
+        map.get(key)
+            .ok_or(format!("Event {} has no \"{}\"", id, key))?
+            .as_int()
+            .map_err(|t| format!("\"{}\" of event {} is {}, expected an integer", key, id, t))
+    };
+    let level = |key: &str| -> Result<Level, String> {
+        let value = int(key)?;
+        if (0..=MAX_LEVEL as i64).contains(&value) {
+            Ok(value as Level)
+        } else {
+            Err(format!(
+                "\"{}\" of event {} is out of range: {}",
+                key, id, value
+            ))
+        }
+    };
+    let kind = map
+        .get("kind")
+        .and_then(|k| k.clone().into_string().ok())
+        .unwrap_or("note".to_string());
+    let event = match kind.as_str() {
+        "note" => {
+            let duration = int("duration")?;
+            if duration <= 0 {
+                return Err(format!("Note {} should have a positive duration", id));
+            }
+            TrackEventType::Note(Note {
+                pitch: level("pitch")? as Pitch,
+                velocity: level("velocity")?,
+                duration,
+                channel: match map.get("channel") {
+                    Some(_) => {
+                        let channel = int("channel")?;
+                        if !(0..=15).contains(&channel) {
+                            return Err(format!(
+                                "\"channel\" of event {} is out of range: {}",
+                                id, channel
+                            ));
+                        }
+                        channel as ChannelId
+                    }
+                    None => 0,
+                },
+                locked: match map.get("locked") {
+                    Some(locked) => locked
+                        .as_bool()
+                        .map_err(|t| format!("\"locked\" of event {} is {}", id, t))?,
+                    None => false,
+                },
+            })
+        }
+        "controller" => TrackEventType::Controller(ControllerSetValue {
+            controller_id: level("controller")?,
+            value: level("value")?,
+        }),
+        other => return Err(format!("Unknown event kind \"{}\"", other)),
+    };
+    let at = int("at")?;
+    if at < 0 {
+        return Err(format!("Event {} should not start before 0: {}", id, at));
+    }
+    Ok(TrackEvent { id, at, event })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::note_event;
+    use crate::track_edit::apply_diffs;
+
+    fn note(id: EventId, at: Time, pitch: Pitch) -> TrackEvent {
+        note_event(id, at, pitch, 100)
+    }
+
+    #[test]
+    fn example_script() {
+        let mut track = Track {
+            events: vec![note(1, 0, 60), note(2, 100, 62), note(3, 200, 64)],
+        };
+        let id_seq = IdSeq::new(10);
+        let source = include_str!("../scripts/double-octave.rhai");
+        let applied_command = run_script(&track, &id_seq, &HashSet::from([1, 3]), 0, source)
+            .unwrap()
+            .unwrap();
+        assert_eq!(applied_command.0, EditCommandType::Script);
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        let notes: Vec<(Time, Pitch)> = track
+            .events
+            .iter()
+            .map(|ev| match &ev.event {
+                TrackEventType::Note(n) => (ev.at, n.pitch),
+                _ => panic!("unexpected {:?}", ev),
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![(0, 60), (0, 72), (100, 62), (200, 64), (200, 76)]
+        );
+    }
+
+    #[test]
+    fn script_edits() {
+        let track = Track {
+            events: vec![note(1, 0, 60), note(2, 100, 62)],
+        };
+        let id_seq = IdSeq::new(10);
+        let run = |source| run_script(&track, &id_seq, &HashSet::new(), 0, source);
+        assert!(run("").unwrap().is_none());
+        let (_, diff) = run("events.remove(0); events[0].velocity = 10;")
+            .unwrap()
+            .unwrap();
+        let CommandDiff::ChangeList { patch } = &diff[0] else {
+            panic!("unexpected {:?}", diff);
+        };
+        assert_eq!(patch.len(), 2);
+        assert!(patch.contains(&EventAction::Delete(note(1, 0, 60))));
+        assert!(run("events[0].velocity = 300;").is_err());
+        assert!(run("events[0].channel = 16;").is_err());
+        assert!(run("events[0].channel = -1;").is_err());
+        assert!(run("events[0].id = 99;").is_err());
+        assert!(run("events[0].at = -1;").is_err());
+        assert!(run("loop {}").is_err());
+        assert!(run("syntax error").is_err());
+    }
+
+    #[test]
+    fn locked_notes_are_kept() {
+        let mut locked = note(1, 0, 60);
+        if let TrackEventType::Note(n) = &mut locked.event {
+            n.locked = true;
+        }
+        let track = Track {
+            events: vec![locked, note(2, 100, 62)],
+        };
+        let id_seq = IdSeq::new(10);
+        let run = |source| run_script(&track, &id_seq, &HashSet::new(), 0, source);
+        assert!(run("events[0].pitch = 61;").is_err());
+        assert!(run("events[0].locked = false;").is_err());
+        assert!(run("events.remove(0);").is_err());
+        assert!(run("let x = events[0].pitch; events[1].pitch = x;")
+            .unwrap()
+            .is_some());
+    }
+}
@@ -1,28 +1,48 @@
 use crate::changeset::{Changeset, EventActionsList};
+use crate::clipboard::Clipboard;
 use crate::common::Time;
+use crate::config::{ColorRgba, Config};
+use crate::keymap::{Keymap, StaveAction};
+use crate::meter::MeterMap;
+use crate::pitch::{is_black_key, MIDDLE_C};
+use crate::project::SessionState;
 use crate::range::{Range, RangeLike, RangeSpan};
+use crate::script::run_script;
+use crate::tie::Ties;
 use crate::track::{
-    export_smf, ControllerSetValue, EventId, Level, MarkerType, Note, Pitch, Track, TrackEvent,
-    TrackEventType, MAX_LEVEL, MIDI_CC_SUSTAIN_ID,
+    export_smf_in_background, import_smf, ControllerId, ControllerSetValue, EventId, ExportOptions,
+    Level, MarkerType, Note, Pitch, Track, TrackEvent, TrackEventType, MAX_LEVEL,
+    MIDI_CC_SUSTAIN_ID,
 };
 use crate::track_edit::{
-    accent_selected_notes, add_new_note, clear_bookmark, clear_time_selection, delete_selected,
-    set_bookmark, set_damper, set_time_selection, shift_selected, shift_tail,
-    stretch_selected_notes, tape_delete, tape_insert, tape_stretch, transpose_selected_notes,
-    AppliedCommand, EditCommandType,
+    accent_selected_notes, add_new_note, align_selected_notes, cap_notes_duration, clear_bookmark,
+    clear_time_selection, clear_track, delete_selected, humanize_selected, insert_gap,
+    legato_selected, merge_events, mirror_selected_notes, paste_events, pedal_selected_notes,
+    quantize_selected_notes, quantized_time, record_events, region_events, scale_selected_velocity,
+    selected_events, selected_notes_range, selected_region_events, set_bookmark, set_damper,
+    set_selected_notes_channel, set_time_selection, shift_selected, shift_tail,
+    shuffle_selected_notes, similar_notes, snap_selected_notes, spread_selected_notes,
+    stretch_selected_notes, strum_selected, tape_delete, tape_delete_with_selected, tape_duplicate,
+    tape_insert, tape_stretch, toggle_damper_at, toggle_lock_selected_notes,
+    transpose_selected_notes, unpedal_selected_notes, AppliedCommand, EditCommandType,
+    NoteAttribute,
 };
 use crate::track_history::{CommandApplication, TrackHistory};
+use crate::util::IdSeq;
 use crate::{range, Pix};
-use chrono::Duration;
 use eframe::egui::{
-    self, Color32, Context, Frame, Margin, Modifiers, Painter, PointerButton, Pos2, Rangef, Rect,
-    Rounding, Sense, Stroke, Ui,
+    self, Align2, Color32, Context, FontId, Frame, Margin, Modifiers, Painter, PointerButton, Pos2,
+    Rangef, Rect, Rounding, Sense, Stroke, Ui,
 };
+use egui::epaint::QuadraticBezierShape;
 use egui::Rgba;
+use midly::live::LiveEvent;
 use ordered_float::OrderedFloat;
+use serde::Deserialize;
 use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
+use std::time::Duration;
 
 // Tone 60 is C3, tones start at C-2 (tone 21).
 const PIANO_LOWEST_KEY: Pitch = 21;
@@ -31,26 +51,94 @@ const PIANO_KEY_COUNT: Pitch = 88;
 const PIANO_DAMPER_LANE: Pitch = PIANO_LOWEST_KEY - 1;
 pub(crate) const PIANO_KEY_LINES: Range<Pitch> =
     (PIANO_LOWEST_KEY, PIANO_LOWEST_KEY + PIANO_KEY_COUNT);
-// Lines including controller values placeholder.
-const STAVE_KEY_LINES: Range<Pitch> = (PIANO_LOWEST_KEY - 1, PIANO_LOWEST_KEY + PIANO_KEY_COUNT);
-
+/// Vertical zoom does not go beyond an octave.
+const MIN_VISIBLE_KEYS: Pitch = 12;
+/// Height of the velocity lane under the keys.
+const VELOCITY_LANE_HEIGHT: Pix = 48.0;
+/// How far from a velocity bar the pointer can grab it.
+const VELOCITY_BAR_REACH: Pix = 5.0;
+
+/// Lines of the visible keys, the controller values lane is always below them.
 fn key_line_ys(view_y_range: &Rangef, pitches: Range<Pitch>) -> (BTreeMap<Pitch, Pix>, Pix) {
     let mut lines = BTreeMap::new();
-    let step = view_y_range.span() / pitches.len() as Pix;
+    let step = view_y_range.span() / (pitches.len() + 1) as Pix;
     let mut y = view_y_range.max - step / 2.0;
-    for p in pitches.range() {
+    for p in std::iter::once(PIANO_DAMPER_LANE).chain(pitches.range()) {
         lines.insert(p, y);
         y -= step;
     }
     (lines, step)
 }
 
+/// Mapping of middle mouse button gestures on the stave.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MiddleButtonMode {
+    /// Click moves the cursor, drag draws a note.
+    #[default]
+    SeekAndDraw,
+    /// Drag draws a note, clicks are ignored to avoid accidental seeks.
+    DrawOnly,
+    /// Click moves the cursor, notes are not drawn.
+    SeekOnly,
+    /// Click on the bar ruler moves the cursor, drag below it draws a note.
+    /// Same as DrawOnly when the ruler is hidden.
+    SeekOnRuler,
+}
+
+impl MiddleButtonMode {
+    fn seeks_on_click(&self, on_ruler: bool) -> bool {
+        match self {
+            MiddleButtonMode::SeekAndDraw | MiddleButtonMode::SeekOnly => true,
+            MiddleButtonMode::DrawOnly => false,
+            MiddleButtonMode::SeekOnRuler => on_ruler,
+        }
+    }
+
+    fn draws_on_drag(&self, on_ruler: bool) -> bool {
+        match self {
+            MiddleButtonMode::SeekAndDraw | MiddleButtonMode::DrawOnly => true,
+            MiddleButtonMode::SeekOnly => false,
+            MiddleButtonMode::SeekOnRuler => !on_ruler,
+        }
+    }
+}
+
+/// What Delete key does when both time and note selections are present.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteKeyMode {
+    /// Delete the time selection and the selected notes in one edit.
+    #[default]
+    TimeAndNotes,
+    /// Delete only the selected notes, the time selection is deleted when no notes are selected.
+    NotesFirst,
+}
+
+/// Where the quantize grid starts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GridOrigin {
+    /// Grid positions are counted from the track start.
+    #[default]
+    TrackStart,
+    /// Grid starts at the time selection start (e.g. after a pickup), track start if there is no selection.
+    SelectionStart,
+}
+
 #[derive(Debug, Clone)]
 pub struct NoteDraw {
     time: Range<Time>,
     pitch: Pitch,
 }
 
+/// Velocity bar that is being dragged.
+#[derive(Debug, Clone)]
+pub struct VelocityDrag {
+    note_id: EventId,
+    velocity: Level,
+}
+
 #[derive(Debug, Default)]
 pub struct NotesSelection {
     selected: HashSet<EventId>,
@@ -116,6 +204,41 @@ impl EditTransition {
     }
 }
 
+/// Outline of the changed events that stays for a while after an undo/redo transition ends.
+#[derive(Debug, PartialEq)]
+pub struct Afterglow {
+    events: HashSet<EventId>,
+    /// Input time (seconds) when it ends.
+    until: f64,
+}
+
+impl Afterglow {
+    /// Next highlight state. Until the transition ends it is animated, then its changes
+    /// glow for `duration` seconds. Edits other than undo/redo are not highlighted.
+    fn step(
+        transition: Option<EditTransition>,
+        afterglow: Option<Afterglow>,
+        now: f64,
+        duration: f64,
+    ) -> (Option<EditTransition>, Option<Afterglow>) {
+        match transition {
+            Some(tr) if tr.value().is_some() => (Some(tr), None),
+            Some(tr)
+                if duration > 0.0
+                    && matches!(tr.command_id, EditCommandType::Undo | EditCommandType::Redo) =>
+            {
+                let afterglow = Afterglow {
+                    events: tr.changeset.changes.keys().copied().collect(),
+                    until: now + duration,
+                };
+                (None, Some(afterglow))
+            }
+            Some(_) => (None, None),
+            None => (None, afterglow.filter(|a| now < a.until)),
+        }
+    }
+}
+
 // #[derive(Debug)]
 pub struct Stave {
     pub history: RefCell<TrackHistory>,
@@ -134,16 +257,93 @@ pub struct Stave {
 
     /// Currently drawn note.
     pub note_draw: Option<NoteDraw>,
+    /// Selection box that is being dragged, from the drag start (min) to the pointer (max).
+    pub select_draw: Option<Rect>,
+    pub velocity_drag: Option<VelocityDrag>,
     pub note_selection: NotesSelection,
     /// Change animation parameters.
     pub transition: Option<EditTransition>,
+    pub afterglow: Option<Afterglow>,
+    /// Controller that is shown and edited on the damper lane.
+    pub active_controller: ControllerId,
+    /// Key lane under the mouse pointer.
+    pub pitch_hovered: Option<Pitch>,
+    /// Lowest visible key.
+    pub pitch_bottom: Pitch,
+    /// Highest visible key.
+    pub pitch_top: Pitch,
+    /// Wheel movement that is not enough yet to change the visible keys, pixels.
+    pitch_wheel_rest: Pix,
+    /// Pitch of the last drawn or clicked note, used for notes entered at the cursor.
+    pub last_pitch: Pitch,
+    pub ties: Ties,
+    /// Show bar and beat numbers above the notes.
+    pub show_bar_ruler: bool,
 
     // Velocity -> note_color lookup map
     note_colors: Vec<Color32>,
+    middle_button: MiddleButtonMode,
+    delete_key: DeleteKeyMode,
+    highlight_sounding_notes: bool,
+    heavy_bar_lines: bool,
+    min_note_width: Pix,
+    max_note_duration: Time,
+    new_note_duration: Time,
+    keymap: Keymap,
+    quantize_grid: Time,
+    /// Drawn notes and keyboard moves land on grid lines of this step, if set.
+    snap: Option<Time>,
+    quantize_swing: f32,
+    quantize_origin: GridOrigin,
+    snap_tolerance: Time,
+    strum_step: Time,
+    pedaled_note_duration: Time,
+    humanize_time: Time,
+    humanize_velocity: Level,
+    tempo_step: f32,
+    tempo_coarse_step: f32,
+    zoom_to_fit_margin: f32,
+    /// Seconds.
+    undo_highlight_duration: f32,
+    paste_match_velocity: bool,
+    clipboard: Clipboard,
+    grid_colors: GridColors,
 }
 
+#[derive(Debug, Clone, Copy)]
+struct GridColors {
+    black_key: Color32,
+    white_key: Color32,
+    time_selection: Color32,
+    nothing_zone: Color32,
+    bar_line: Color32,
+}
+
+impl GridColors {
+    fn new(config: &Config) -> Self {
+        let color = |[r, g, b, a]: ColorRgba| Color32::from_rgba_unmultiplied(r, g, b, a);
+        GridColors {
+            black_key: color(config.grid_black_key_color),
+            white_key: color(config.grid_white_key_color),
+            time_selection: color(config.time_selection_color),
+            nothing_zone: color(config.nothing_zone_color),
+            bar_line: color(config.bar_line_color),
+        }
+    }
+}
+
+/// Bar lines are not drawn when zoomed out beyond this.
+const MIN_BAR_WIDTH: Pix = 4.0;
+const BAR_RULER_HEIGHT: Pix = 16.0;
+/// Beats (or bars) narrower than this are not labeled on the ruler.
+const MIN_RULER_LABEL_WIDTH: Pix = 28.0;
+
 const COLOR_SELECTED: Rgba = Rgba::from_rgb(0.7, 0.1, 0.3);
 const COLOR_HOVERED: Rgba = Rgba::from_rgb(0.2, 0.5, 0.55);
+const COLOR_SOUNDING: Rgba = Rgba::from_rgb(0.9, 0.6, 0.0);
+const COLOR_TIE: Color32 = Color32::from_rgb(40, 40, 120);
+const COLOR_AFTERGLOW: Rgba = Rgba::from_rgb(0.2, 0.6, 0.9);
+const COLOR_LOCKED: Color32 = Color32::from_rgb(30, 110, 40);
 
 struct InnerResponse {
     response: egui::Response,
@@ -151,6 +351,7 @@ struct InnerResponse {
     time_hovered: Option<Time>,
     note_hovered: Option<EventId>,
     modifiers: Modifiers,
+    velocity_lane: Option<egui::Response>,
 }
 
 pub struct StaveResponse {
@@ -159,19 +360,8 @@ pub struct StaveResponse {
 }
 
 impl Stave {
-    pub fn new(history: RefCell<TrackHistory>) -> Stave {
-        let mut note_colors = vec![];
-        assert_eq!(Level::MIN, 0); // Otherwise need to adjust lookups.
-        for velocity in Level::MIN..Level::MAX {
-            note_colors.push(
-                egui::lerp(
-                    Rgba::from_rgb(0.6, 0.7, 0.7)..=Rgba::from_rgb(0.0, 0.0, 0.0),
-                    velocity as f32 / MAX_LEVEL as f32,
-                )
-                .into(),
-            );
-        }
-
+    pub fn new(config: &Config, history: RefCell<TrackHistory>) -> Stave {
+        let note_colors = Self::note_color_ramp(config.note_color_floor);
         Stave {
             history,
             time_left: 0,
@@ -181,28 +371,222 @@ impl Stave {
             time_selection: None,
             index_cache: HashMap::new(),
             note_draw: None,
+            select_draw: None,
+            velocity_drag: None,
             note_selection: NotesSelection::default(),
             transition: None,
+            afterglow: None,
+            active_controller: MIDI_CC_SUSTAIN_ID,
+            pitch_hovered: None,
+            pitch_bottom: PIANO_KEY_LINES.0,
+            pitch_top: PIANO_KEY_LINES.1 - 1,
+            pitch_wheel_rest: 0.0,
+            last_pitch: MIDDLE_C,
+            ties: Ties::default(),
+            show_bar_ruler: config.bar_ruler,
             note_colors,
+            middle_button: config.middle_button,
+            delete_key: config.delete_key,
+            highlight_sounding_notes: config.highlight_sounding_notes,
+            heavy_bar_lines: config.heavy_bar_lines,
+            min_note_width: config.min_note_width,
+            max_note_duration: config.max_note_duration,
+            new_note_duration: config.new_note_duration,
+            keymap: Keymap::new(&config.key_bindings, &config.damper_toggle_key),
+            quantize_grid: config.quantize_grid,
+            snap: None,
+            quantize_swing: config.quantize_swing as f32 / 100.0,
+            quantize_origin: config.quantize_origin,
+            snap_tolerance: config.snap_tolerance,
+            strum_step: config.strum_step,
+            pedaled_note_duration: config.pedaled_note_duration,
+            humanize_time: config.humanize_time,
+            humanize_velocity: config.humanize_velocity,
+            tempo_step: config.tempo_step,
+            tempo_coarse_step: config.tempo_coarse_step,
+            zoom_to_fit_margin: config.zoom_to_fit_margin,
+            undo_highlight_duration: config.undo_highlight_duration,
+            paste_match_velocity: config.paste_match_velocity,
+            clipboard: Clipboard::new(config),
+            grid_colors: GridColors::new(config),
         }
     }
 
-    pub fn save_to(&mut self, file_path: &PathBuf) {
+    /// Velocity -> color lookup, louder notes are darker. The softest notes get the `floor`
+    /// position on the light to black ramp, so they are still visible.
+    fn note_color_ramp(floor: f32) -> Vec<Color32> {
+        assert_eq!(Level::MIN, 0); // Otherwise need to adjust lookups.
+        (Level::MIN..Level::MAX)
+            .map(|velocity| {
+                let loudness = (velocity as f32 / MAX_LEVEL as f32).min(1.0);
+                egui::lerp(
+                    Rgba::from_rgb(0.6, 0.7, 0.7)..=Rgba::from_rgb(0.0, 0.0, 0.0),
+                    floor + (1.0 - floor) * loudness,
+                )
+                .into()
+            })
+            .collect()
+    }
+
+    /// Export the track, the file is written in background.
+    pub fn save_to(
+        &self,
+        options: &ExportOptions,
+        file_path: PathBuf,
+        on_done: impl FnOnce(Result<PathBuf, String>) + Send + 'static,
+    ) {
+        let events = self
+            .history
+            .borrow()
+            .with_track(|track| track.events.clone());
+        export_smf_in_background(events, options.clone(), file_path, on_done);
+    }
+
+    /// Export the time range as a standalone fragment.
+    pub fn save_region_to(
+        &self,
+        options: &ExportOptions,
+        range: &Range<Time>,
+        file_path: PathBuf,
+        on_done: impl FnOnce(Result<PathBuf, String>) + Send + 'static,
+    ) {
+        let events = self
+            .history
+            .borrow()
+            .with_track(|track| region_events(track, range));
+        export_smf_in_background(events, options.clone(), file_path, on_done);
+    }
+
+    /// Export the selected notes as a standalone fragment.
+    pub fn save_selected_to(
+        &self,
+        options: &ExportOptions,
+        file_path: PathBuf,
+        on_done: impl FnOnce(Result<PathBuf, String>) + Send + 'static,
+    ) {
+        let selection = &self.note_selection.selected;
+        let events = self
+            .history
+            .borrow()
+            .with_track(|track| selected_region_events(track, selection));
+        export_smf_in_background(events, options.clone(), file_path, on_done);
+    }
+
+    /// Time span of the selected notes.
+    pub fn selection_range(&self) -> Option<Range<Time>> {
         self.history
             .borrow()
-            .with_track(|track| export_smf(&track.events, file_path));
+            .with_track(|track| selected_notes_range(track, &self.note_selection.selected))
+    }
+
+    /// The view is not laid out yet (first frame) or collapsed, there is nothing to show
+    /// and the time/pixel mapping is not defined.
+    pub fn is_degenerate(&self) -> bool {
+        !self.view_rect.is_positive() || self.time_right <= self.time_left
+    }
+
+    pub fn session_state(&self) -> SessionState {
+        SessionState {
+            selection: self.note_selection.selected.clone(),
+            view: Some((self.time_left, self.time_right)),
+            cursor_position: self.cursor_position,
+            active_controller: Some(self.active_controller),
+            snap: self.snap,
+        }
+    }
+
+    pub fn restore_session(&mut self, session: &SessionState) {
+        self.note_selection.selected = session.selection.clone();
+        if let Some((left, right)) = session.view {
+            if left < right {
+                (self.time_left, self.time_right) = (left, right);
+            }
+        }
+        self.cursor_position = session.cursor_position;
+        if let Some(controller_id) = session.active_controller {
+            self.active_controller = controller_id;
+        }
+        self.snap = session.snap;
+    }
+
+    /// Apply a user script to the track as one edit.
+    pub fn run_script(&mut self, context: &Context, source: &str) -> Result<(), String> {
+        let id_seq = self.history.borrow().id_seq.clone();
+        let mut result = Ok(());
+        self.do_edit_command(context, Self::external_edit_id(), |stave, track| {
+            let (selection, cursor) = (&stave.note_selection.selected, stave.cursor_position);
+            run_script(track, &id_seq, selection, cursor, source).unwrap_or_else(|message| {
+                result = Err(message);
+                None
+            })
+        });
+        result
+    }
+
+    /// Delete all events of the track as one edit.
+    pub fn clear_track(&mut self, context: &Context) {
+        self.do_edit_command(context, Self::external_edit_id(), |_stave, track| {
+            clear_track(track)
+        });
+    }
+
+    /// Insert the MIDI file's events at the cursor as one edit, see [merge_events].
+    pub fn merge_file(
+        &mut self,
+        context: &Context,
+        file_path: &PathBuf,
+        time_scale: f64,
+    ) -> Result<(), String> {
+        let events = import_smf(&IdSeq::new(0), file_path)?;
+        let id_seq = self.history.borrow().id_seq.clone();
+        let at = self.cursor_position;
+        self.do_edit_command(context, Self::external_edit_id(), |_stave, _track| {
+            merge_events(&id_seq, &events, at, time_scale)
+        });
+        Ok(())
+    }
+
+    /// Add the events played live as one edit, see [record_events].
+    /// With `quantize` the notes are moved to the quantize grid.
+    pub fn record(
+        &mut self,
+        context: &Context,
+        events: &[(Time, LiveEvent<'static>)],
+        end: Time,
+        punch: Option<&Range<Time>>,
+        quantize: bool,
+    ) {
+        let id_seq = self.history.borrow().id_seq.clone();
+        let grid = Some((
+            self.quantize_grid,
+            self.quantize_swing,
+            self.quantize_origin(),
+        ))
+        .filter(|_| quantize);
+        self.do_edit_command(context, Self::external_edit_id(), |_stave, track| {
+            record_events(&id_seq, track, events, end, punch, grid)
+        });
     }
 
-    /// Pixel/uSec, can be cached.
+    /// Pixel/uSec, can be cached. Zero if the view is degenerate.
     pub fn time_scale(&self) -> f32 {
+        if self.is_degenerate() {
+            return 0.0;
+        }
         self.view_rect.width() / (self.time_right - self.time_left) as f32
     }
 
     pub fn x_from_time(&self, at: Time) -> Pix {
+        if self.is_degenerate() {
+            return 0.0;
+        }
         self.view_rect.min.x + (at as f32 - self.time_left as f32) * self.time_scale()
     }
 
     pub fn time_from_x(&self, x: Pix) -> Time {
+        if self.is_degenerate() {
+            return self.time_left;
+        }
         self.time_left + ((x - self.view_rect.min.x) / self.time_scale()) as Time
     }
 
@@ -214,9 +598,70 @@ impl Stave {
         self.time_right = at + ((self.time_right - at) as f32 / zoom_factor) as Time;
     }
 
-    pub fn zoom_to_fit(&mut self, time_margin: Time) {
+    /// Visible keys, as a range.
+    fn key_lines(&self) -> Range<Pitch> {
+        (self.pitch_bottom, self.pitch_top + 1)
+    }
+
+    /// Show `count` keys starting from `bottom`, kept within the piano keyboard.
+    fn set_pitch_range(&mut self, bottom: i32, count: i32) {
+        let count = count.clamp(MIN_VISIBLE_KEYS as i32, PIANO_KEY_COUNT as i32);
+        let lowest = PIANO_KEY_LINES.0 as i32;
+        let bottom = bottom.clamp(lowest, PIANO_KEY_LINES.1 as i32 - count);
+        self.pitch_bottom = bottom as Pitch;
+        self.pitch_top = (bottom + count - 1) as Pitch;
+    }
+
+    /// Move the visible keys up (or down if negative) by this many keys.
+    pub fn scroll_pitches(&mut self, keys: i32) {
+        let count = self.key_lines().len() as i32;
+        self.set_pitch_range(self.pitch_bottom as i32 + keys, count);
+    }
+
+    /// Show fewer keys (or more if negative), so the key at `anchor` stays about where it is.
+    pub fn zoom_pitches(&mut self, keys: i32, anchor: Pitch) {
+        let (bottom, count) = (self.pitch_bottom as i32, self.key_lines().len() as i32);
+        let new_count = (count - keys).clamp(MIN_VISIBLE_KEYS as i32, PIANO_KEY_COUNT as i32);
+        let anchor = (anchor as i32).clamp(bottom, bottom + count - 1);
+        // Relative height of the anchor lane's middle, the damper lane is the lowest one.
+        let height = ((anchor - bottom) as f32 + 1.5) / (count + 1) as f32;
+        let below = (height * (new_count + 1) as f32 - 1.5).round() as i32;
+        self.set_pitch_range(anchor - below, new_count);
+    }
+
+    /// Zoom (or scroll) the visible keys by a vertical wheel movement at `y`.
+    pub fn pitch_wheel(&mut self, dy: Pix, y: Pix, zoom: bool) {
+        if self.is_degenerate() {
+            return;
+        }
+        let (key_ys, step) = key_line_ys(&self.keys_y_range(), self.key_lines());
+        self.pitch_wheel_rest += dy;
+        let keys = (self.pitch_wheel_rest / step).trunc();
+        if keys == 0.0 {
+            return;
+        }
+        self.pitch_wheel_rest -= keys * step;
+        if zoom {
+            let anchor = closest_pitch(&key_ys, Pos2::new(0.0, y));
+            self.zoom_pitches(keys as i32, anchor);
+        } else {
+            self.scroll_pitches(keys as i32);
+        }
+    }
+
+    pub fn zoom_to_fit(&mut self) {
+        let span = self.history.borrow().with_track(|tr| tr.max_time());
+        let time_margin = Self::fit_margin(span, self.zoom_to_fit_margin);
         self.time_left = -time_margin;
-        self.time_right = self.history.borrow().with_track(|tr| tr.max_time()) + time_margin;
+        self.time_right = span + time_margin;
+    }
+
+    /// Shortest margin of zoom to fit, so an empty track still has a visible time range.
+    const MIN_FIT_MARGIN: Time = 100_000;
+
+    /// View margin on either side of the content, as a fraction of its time span.
+    fn fit_margin(span: Time, fraction: f32) -> Time {
+        ((span as f64 * fraction as f64) as Time).max(Self::MIN_FIT_MARGIN)
     }
 
     pub fn scroll(&mut self, dt: Time) {
@@ -225,6 +670,9 @@ impl Stave {
     }
 
     pub fn scroll_by(&mut self, dx: Pix) {
+        if self.is_degenerate() {
+            return;
+        }
         self.scroll((dx / self.time_scale()) as Time);
     }
 
@@ -235,6 +683,40 @@ impl Stave {
         );
     }
 
+    /// Scrolling the view by dragging past its edges stops at these bounds.
+    const ZOOM_TIME_LIMIT: Range<Time> = (-10_000_000, 12 * 3600 * 1_000_000);
+    /// Width of the area near the view edges where dragging scrolls the view.
+    const EDGE_SCROLL_ZONE: Pix = 40.0;
+    /// Fastest auto-scroll step, pixels per frame.
+    const EDGE_SCROLL_MAX_STEP: Pix = 25.0;
+
+    /// Auto-scroll step when dragging at `x`: the closer to a view edge the faster.
+    fn edge_scroll_speed(x_range: Rangef, x: Pix) -> Pix {
+        let proximity = |distance: Pix| {
+            ((Self::EDGE_SCROLL_ZONE - distance) / Self::EDGE_SCROLL_ZONE).clamp(0.0, 1.0)
+        };
+        let left = proximity(x - x_range.min);
+        let right = proximity(x_range.max - x);
+        (right - left) * Self::EDGE_SCROLL_MAX_STEP
+    }
+
+    fn auto_scroll(&mut self, ctx: &Context, x: Pix) {
+        let dx = Self::edge_scroll_speed(self.view_rect.x_range(), x);
+        if dx == 0.0 || self.is_degenerate() {
+            return;
+        }
+        // The view may already be outside the limits (e.g. when zoomed out), just do not go further.
+        let dt = ((dx / self.time_scale()) as Time).clamp(
+            (Self::ZOOM_TIME_LIMIT.0 - self.time_left).min(0),
+            (Self::ZOOM_TIME_LIMIT.1 - self.time_right).max(0),
+        );
+        if dt != 0 {
+            self.scroll(dt);
+            // Keep scrolling while the pointer stays at the edge.
+            ctx.request_repaint();
+        }
+    }
+
     const NOTHING_ZONE: Range<Time> = (Time::MIN, 0);
 
     fn view(&mut self, ui: &mut Ui) -> InnerResponse {
@@ -245,7 +727,22 @@ impl Stave {
                 let bounds = ui.available_rect_before_wrap();
                 let egui_response = ui.allocate_response(bounds.size(), Sense::click_and_drag());
                 self.view_rect = bounds;
-                let (key_ys, half_tone_step) = key_line_ys(&bounds.y_range(), STAVE_KEY_LINES);
+                if self.is_degenerate() {
+                    return InnerResponse {
+                        response: egui_response,
+                        pitch_hovered: None,
+                        time_hovered: None,
+                        note_hovered: None,
+                        modifiers: ui.input(|i| i.modifiers),
+                        velocity_lane: None,
+                    };
+                }
+                let velocity_lane = ui.interact(
+                    self.velocity_lane_rect(),
+                    egui_response.id.with("velocity_lane"),
+                    Sense::click_and_drag(),
+                );
+                let (key_ys, half_tone_step) = key_line_ys(&self.keys_y_range(), self.key_lines());
                 let mut pitch_hovered = None;
                 let mut time_hovered = None;
                 let pointer_pos = ui.input(|i| i.pointer.hover_pos());
@@ -255,15 +752,17 @@ impl Stave {
                 }
                 let painter = ui.painter_at(bounds);
 
-                Self::draw_grid(&painter, bounds, &key_ys, &pitch_hovered);
-                let selection_color = Color32::from_rgba_unmultiplied(64, 80, 100, 60);
+                Self::draw_grid(&painter, bounds, &key_ys, &pitch_hovered, &self.grid_colors);
+                let meter = self.history.borrow().with_track(MeterMap::of_track);
+                self.draw_snap_lines(&painter);
+                self.draw_bar_lines(&painter, &meter);
                 if let Some(s) = &self.time_selection {
-                    self.draw_time_selection(&painter, &s, &selection_color);
+                    self.draw_time_selection(&painter, &s, &self.grid_colors.time_selection);
                 }
                 self.draw_time_selection(
                     &painter,
                     &Stave::NOTHING_ZONE,
-                    &Color32::from_black_alpha(15),
+                    &self.grid_colors.nothing_zone,
                 );
                 let mut note_hovered = None;
                 let should_be_visible;
@@ -278,6 +777,11 @@ impl Stave {
                         &painter,
                         &track,
                     );
+                    self.draw_ties(&key_ys, half_tone_step, &painter, &track);
+                    self.draw_velocity_lane(&painter, &track);
+                }
+                if self.show_bar_ruler {
+                    self.draw_bar_ruler(&painter, &meter);
                 }
                 self.draw_cursor(
                     &painter,
@@ -285,14 +789,29 @@ impl Stave {
                     Rgba::from_rgba_unmultiplied(0.0, 0.5, 0.0, 0.7).into(),
                 );
 
+                if let Some(transition) = &self.transition {
+                    Self::draw_command_label(&painter, transition);
+                }
+
                 if let Some(new_note) = &self.note_draw {
-                    self.default_draw_note(
-                        &painter,
-                        64,
-                        (new_note.time.0, new_note.time.1),
-                        *key_ys.get(&new_note.pitch).unwrap(),
-                        half_tone_step,
-                        true,
+                    // The pitch may be scrolled out of view while drawing.
+                    if let Some(y) = key_ys.get(&new_note.pitch) {
+                        self.default_draw_note(
+                            &painter,
+                            64,
+                            (new_note.time.0, new_note.time.1),
+                            *y,
+                            half_tone_step,
+                            true,
+                        );
+                    }
+                }
+
+                if let Some(area) = &self.select_draw {
+                    painter.rect_stroke(
+                        Rect::from_two_pos(area.min, area.max),
+                        Rounding::ZERO,
+                        Stroke::new(1.0, COLOR_SELECTED),
                     );
                 }
 
@@ -308,6 +827,7 @@ impl Stave {
                     time_hovered,
                     note_hovered,
                     modifiers: ui.input(|i| i.modifiers),
+                    velocity_lane: Some(velocity_lane),
                 }
             })
             .inner
@@ -327,6 +847,14 @@ impl Stave {
         let mut selection_hints_left: HashSet<Pitch> = HashSet::new();
         let mut selection_hints_right: HashSet<Pitch> = HashSet::new();
         let mut should_be_visible = None;
+        let sounding: HashSet<EventId> = if self.highlight_sounding_notes {
+            track
+                .notes_active_at(self.cursor_position)
+                .map(|ev| ev.id)
+                .collect()
+        } else {
+            HashSet::new()
+        };
         for i in 0..track.events.len() {
             let event = &track.events[i];
             if let Some(trans) = &self.transition {
@@ -347,6 +875,22 @@ impl Stave {
                         self.draw_track_note(key_ys, half_tone_step, &painter, &event, &note);
                     // Alternatively, can return the known rect from draw_track_note above and check that.
                     if let Some(r) = note_rect {
+                        if sounding.contains(&event.id) {
+                            painter.rect_stroke(
+                                r,
+                                Rounding::ZERO,
+                                Stroke::new(2.0, COLOR_SOUNDING),
+                            );
+                        }
+                        if let Some(afterglow) = &self.afterglow {
+                            if afterglow.events.contains(&event.id) {
+                                painter.rect_stroke(
+                                    r.expand(1.0),
+                                    Rounding::ZERO,
+                                    Stroke::new(2.0, COLOR_AFTERGLOW),
+                                );
+                            }
+                        }
                         if let Some(&pointer_pos) = pointer_pos.as_ref() {
                             if r.contains(pointer_pos) {
                                 *note_hovered = Some(event.id);
@@ -375,6 +919,21 @@ impl Stave {
                 TrackEventType::Marker(_marker_type) => {
                     todo!("new time selection is not drawn yet")
                 }
+                TrackEventType::TimeSignature(sig) => {
+                    let clip = painter.clip_rect();
+                    let y = if self.show_bar_ruler {
+                        clip.min.y + BAR_RULER_HEIGHT
+                    } else {
+                        clip.min.y
+                    };
+                    painter.text(
+                        Pos2::new(self.x_from_time(event.at) + 2.0, y),
+                        Align2::LEFT_TOP,
+                        sig.to_string(),
+                        FontId::proportional(12.0),
+                        self.grid_colors.bar_line,
+                    );
+                }
             }
         }
         if let Some(trans) = &self.transition {
@@ -397,8 +956,8 @@ impl Stave {
                     );
                 }
 
-                let cc_a = Stave::cc_animation_params(action.before());
-                let cc_b = Stave::cc_animation_params(action.after());
+                let cc_a = self.cc_animation_params(action.before());
+                let cc_b = self.cc_animation_params(action.after());
                 if cc_a.is_some() || cc_b.is_some() {
                     self.draw_cc_transition(
                         key_ys,
@@ -435,11 +994,17 @@ impl Stave {
     }
 
     pub fn show(&mut self, ui: &mut Ui) -> StaveResponse {
-        self.transition = self
-            .transition
-            .take()
-            .map(|tr| tr.update(&ui.ctx()))
-            .filter(|tr| tr.value().is_some());
+        let now = ui.input(|i| i.time);
+        (self.transition, self.afterglow) = Afterglow::step(
+            self.transition.take().map(|tr| tr.update(ui.ctx())),
+            self.afterglow.take(),
+            now,
+            self.undo_highlight_duration as f64,
+        );
+        if let Some(afterglow) = &self.afterglow {
+            ui.ctx()
+                .request_repaint_after(Duration::from_secs_f64(afterglow.until - now));
+        }
         if self.transition.is_none() {
             ui.ctx().clear_animations();
         }
@@ -451,10 +1016,14 @@ impl Stave {
                     self.note_selection.clear()
                 }
                 self.note_selection.toggle(&note_id);
+                if let Some(pitch) = stave_response.pitch_hovered {
+                    self.last_pitch = pitch;
+                }
             }
         }
 
         let inner = &stave_response.response;
+        self.pitch_hovered = stave_response.pitch_hovered.filter(|_| inner.hovered());
         self.update_new_note_draw(
             inner,
             &stave_response.modifiers,
@@ -462,6 +1031,15 @@ impl Stave {
             &stave_response.pitch_hovered,
         );
         self.update_time_selection(&inner, &stave_response.time_hovered);
+        self.update_select_draw(inner, stave_response.modifiers.ctrl);
+        if let Some(velocity_lane) = &stave_response.velocity_lane {
+            self.update_velocity_drag(velocity_lane);
+        }
+        if inner.dragged() {
+            if let Some(pos) = inner.interact_pointer_pos() {
+                self.auto_scroll(&inner.ctx, pos.x);
+            }
+        }
         let new_cursor_position = self.handle_commands(&inner);
         if let Some(pos) = new_cursor_position {
             self.cursor_position = pos;
@@ -490,136 +1068,192 @@ impl Stave {
 
     const KEYBOARD_TIME_STEP: Time = 10_000;
 
+    /// Start of the quantize grid, see [GridOrigin].
+    fn quantize_origin(&self) -> Time {
+        match self.quantize_origin {
+            GridOrigin::TrackStart => 0,
+            GridOrigin::SelectionStart => self.time_selection.map_or(0, |sel| sel.0.min(sel.1)),
+        }
+    }
+
+    /// Closest grid line if snapping is on.
+    fn snap_time(&self, t: Time) -> Time {
+        match self.snap {
+            Some(step) => {
+                let origin = self.quantize_origin();
+                origin + quantized_time(t - origin, step, 0.0)
+            }
+            None => t,
+        }
+    }
+
+    /// Move the selected events one step forward (`direction` 1) or back (-1).
+    /// When snapping, the earliest of them lands on a grid line.
+    fn shift_selected_by_step(&self, track: &Track, direction: Time) -> Option<AppliedCommand> {
+        let selection = &self.note_selection.selected;
+        let delta = match self.snap {
+            Some(step) => {
+                let start = track
+                    .events
+                    .iter()
+                    .filter(|ev| selection.contains(&ev.id))
+                    .map(|ev| ev.at)
+                    .min()?;
+                self.snap_time(start + direction * step) - start
+            }
+            None => direction * Self::KEYBOARD_TIME_STEP,
+        };
+        shift_selected(track, selection, &delta)
+    }
+
     /**
      * Applies the command and returns time to move the stave cursor to.
      */
     fn handle_commands(&mut self, response: &egui::Response) -> Option<Time> {
-        // TODO Have to see if duplication here can be reduced. Likely the dispatch needs some
-        //   hash map that for each input state defines a unique command.
-        //   Need to support focus somehow so the commands only active when stave is focused.
+        // TODO Need to support focus somehow so the commands only active when stave is focused.
         //   Currently commands also affect other widgets (e.g. arrows change button focus).
 
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::Q))
-        }) {
+        if response.ctx.wants_keyboard_input() {
+            // A text field is being edited.
+            return None;
+        }
+        // Edits repeated while keys are held down are undone in one step.
+        if response.ctx.input(|i| i.keys_down.is_empty()) {
+            self.history.borrow_mut().finish_group();
+        } else {
+            self.history.borrow_mut().begin_group();
+        }
+        let actions = response.ctx.input_mut(|i| self.keymap.consume(i));
+
+        if actions.contains(&StaveAction::ClearSelection) {
             self.note_selection.clear();
         }
 
         // Tempo adjustment
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::SHIFT,
-                egui::Key::CloseBracket,
-            ))
-        }) {
-            if let Some(time_selection) = &self.time_selection.clone() {
-                self.do_edit_command(&response.ctx, response.id, |_stave, track| {
-                    // FIXME (editing, implementation) shrink time selection accordingly (should it be an event also?)
-                    tape_stretch(track, &(time_selection.0, time_selection.1), 1.01)
-                });
-            }
-        }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::SHIFT,
-                egui::Key::OpenBracket,
-            ))
-        }) {
+        let tempo_command = [
+            (StaveAction::TempoStretchCoarse, true, true),
+            (StaveAction::TempoShrinkCoarse, true, false),
+            (StaveAction::TempoStretch, false, true),
+            (StaveAction::TempoShrink, false, false),
+        ]
+        .into_iter()
+        .find(|(action, _, _)| actions.contains(action));
+        if let Some((_, coarse, stretch)) = tempo_command {
+            let ratio = tempo_ratio(self.tempo_step, self.tempo_coarse_step, coarse, stretch);
             if let Some(time_selection) = &self.time_selection.clone() {
                 self.do_edit_command(&response.ctx, response.id, |_stave, track| {
                     // FIXME (editing, implementation) shrink time selection accordingly (should it be an event also?)
-                    tape_stretch(track, &(time_selection.0, time_selection.1), 0.99)
+                    tape_stretch(track, &(time_selection.0, time_selection.1), ratio)
                 });
             }
         }
         // Tape insert/remove
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::NONE,
-                egui::Key::Delete,
-            ))
-        }) {
+        if actions.contains(&StaveAction::Delete) {
+            let delete_notes = !self.note_selection.selected.is_empty();
+            let time_selection = self
+                .time_selection
+                .filter(|_| !delete_notes || self.delete_key == DeleteKeyMode::TimeAndNotes);
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                match (time_selection, delete_notes) {
+                    (Some(range), true) => {
+                        tape_delete_with_selected(track, &range, &stave.note_selection.selected)
+                    }
+                    (Some(range), false) => tape_delete(track, &range),
+                    (None, true) => delete_selected(track, &stave.note_selection.selected),
+                    (None, false) => None,
+                }
+            });
+        }
+        // Gap at the cursor: a bar, or a beat.
+        let gap_command = [StaveAction::InsertGapBeat, StaveAction::InsertGapBar]
+            .into_iter()
+            .find(|action| actions.contains(action));
+        if let Some(gap) = gap_command {
+            let at = self.cursor_position;
+            self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                let signature = MeterMap::of_track(track).time_signature_at(at);
+                let length = if gap == StaveAction::InsertGapBeat {
+                    signature.beat_duration()
+                } else {
+                    signature.bar_duration()
+                };
+                insert_gap(&at, length)
+            });
+        }
+        if actions.contains(&StaveAction::TapeInsert) {
             if let Some(time_selection) = &self.time_selection.clone() {
-                self.do_edit_command(&response.ctx, response.id, |_stave, track| {
-                    tape_delete(track, &(time_selection.0, time_selection.1))
-                });
-            }
-            if !self.note_selection.selected.is_empty() {
-                self.do_edit_command(&response.ctx, response.id, |stave, track| {
-                    // Deleting both time and event selection in one command for convenience, these can be separate commands.
-                    delete_selected(track, &stave.note_selection.selected)
+                self.do_edit_command(&response.ctx, response.id, |_stave, _track| {
+                    tape_insert(&(time_selection.0, time_selection.1))
                 });
             }
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::NONE,
-                egui::Key::Insert,
-            ))
-        }) {
+
+        if actions.contains(&StaveAction::TapeDuplicate) {
             if let Some(time_selection) = &self.time_selection.clone() {
-                self.do_edit_command(&response.ctx, response.id, |_stave, _track| {
-                    tape_insert(&(time_selection.0, time_selection.1))
+                let range = (
+                    time_selection.0.min(time_selection.1),
+                    time_selection.0.max(time_selection.1),
+                );
+                let id_seq = &self.history.borrow().id_seq.clone();
+                self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                    tape_duplicate(track, id_seq, &range)
                 });
             }
         }
 
         // Tail shift
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::CTRL | Modifiers::SHIFT,
-                egui::Key::ArrowRight,
-            ))
-        }) {
+        if actions.contains(&StaveAction::ShiftTailLater) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 shift_tail(track, &(stave.cursor_position), &Stave::KEYBOARD_TIME_STEP)
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::CTRL | Modifiers::SHIFT,
-                egui::Key::ArrowLeft,
-            ))
-        }) {
+        if actions.contains(&StaveAction::ShiftTailEarlier) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 shift_tail(track, &(stave.cursor_position), &-Stave::KEYBOARD_TIME_STEP)
             });
         }
 
+        if actions.contains(&StaveAction::ToggleSnap) {
+            self.snap = match self.snap {
+                Some(_) => None,
+                None => Some(self.quantize_grid),
+            };
+        }
+
+        // Time selection nudges, Shift moves the end, Ctrl+Alt the start.
+        for (action, direction, move_start) in [
+            (StaveAction::SelectionEndEarlier, -1, false),
+            (StaveAction::SelectionEndLater, 1, false),
+            (StaveAction::SelectionStartEarlier, -1, true),
+            (StaveAction::SelectionStartLater, 1, true),
+        ] {
+            if actions.contains(&action) {
+                let step = self.snap.unwrap_or(self.quantize_grid);
+                if let Some(selection) = nudged_selection(
+                    self.time_selection,
+                    self.cursor_position,
+                    direction * step,
+                    move_start,
+                ) {
+                    self.time_selection = Some(selection);
+                }
+            }
+        }
+
         // Note time moves
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::ALT | Modifiers::SHIFT,
-                egui::Key::ArrowRight,
-            )) || i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::SHIFT, egui::Key::L))
-        }) {
+        if actions.contains(&StaveAction::NotesLater) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
-                shift_selected(
-                    track,
-                    &stave.note_selection.selected,
-                    &Stave::KEYBOARD_TIME_STEP,
-                )
+                stave.shift_selected_by_step(track, 1)
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::ALT | Modifiers::SHIFT,
-                egui::Key::ArrowLeft,
-            )) || i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::SHIFT, egui::Key::H))
-        }) {
+        if actions.contains(&StaveAction::NotesEarlier) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
-                shift_selected(
-                    track,
-                    &stave.note_selection.selected,
-                    &-Stave::KEYBOARD_TIME_STEP,
-                )
+                stave.shift_selected_by_step(track, -1)
             });
         }
 
         // Note edits
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::H))
-        }) {
+        if actions.contains(&StaveAction::NotesShorter) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 stretch_selected_notes(
                     track,
@@ -628,9 +1262,7 @@ impl Stave {
                 )
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::L))
-        }) {
+        if actions.contains(&StaveAction::NotesLonger) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 stretch_selected_notes(
                     track,
@@ -639,45 +1271,90 @@ impl Stave {
                 )
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::U))
-        }) {
+        if actions.contains(&StaveAction::TransposeUp) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 transpose_selected_notes(track, &stave.note_selection.selected, 1)
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::J))
-        }) {
+        if actions.contains(&StaveAction::TransposeDown) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 transpose_selected_notes(track, &stave.note_selection.selected, -1)
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::I))
-        }) {
+        if actions.contains(&StaveAction::AccentUp) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 accent_selected_notes(track, &stave.note_selection.selected, 1)
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::K))
-        }) {
+        if actions.contains(&StaveAction::AccentDown) {
             self.do_edit_command(&response.ctx, response.id, |stave, track| {
                 accent_selected_notes(track, &stave.note_selection.selected, -1)
             });
         }
 
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::ALT, egui::Key::A))
-        }) {
-            self.zoom_to_fit(Duration::seconds(3).num_microseconds().unwrap_or_default());
+        let quantize_origin = self.quantize_origin();
+        if actions.contains(&StaveAction::SnapNotes) {
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                snap_selected_notes(
+                    track,
+                    &stave.note_selection.selected,
+                    stave.quantize_grid,
+                    stave.snap_tolerance,
+                    quantize_origin,
+                )
+            });
+        }
+        if actions.contains(&StaveAction::Quantize) {
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                quantize_selected_notes(
+                    track,
+                    &stave.note_selection.selected,
+                    stave.quantize_grid,
+                    stave.quantize_swing,
+                    quantize_origin,
+                )
+            });
+        }
+        // Chords: align selected notes to the earliest of them, or to the cursor.
+        let align_command = [StaveAction::AlignChordToCursor, StaveAction::AlignChord]
+            .into_iter()
+            .find(|action| actions.contains(action));
+        if let Some(align) = align_command {
+            let at =
+                Some(self.cursor_position).filter(|_| align == StaveAction::AlignChordToCursor);
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                align_selected_notes(track, &stave.note_selection.selected, at)
+            });
+        }
+        if actions.contains(&StaveAction::Spread) {
+            let range = self
+                .time_selection
+                .map(|sel| (sel.0.min(sel.1), sel.0.max(sel.1)));
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                spread_selected_notes(track, &stave.note_selection.selected, range)
+            });
+        }
+        if actions.contains(&StaveAction::CapDuration) {
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                cap_notes_duration(track, stave.max_note_duration)
+            });
+        }
+
+        // Move selected notes to a MIDI channel.
+        for action in &actions {
+            if let StaveAction::SetChannel(channel) = *action {
+                self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                    set_selected_notes_channel(track, &stave.note_selection.selected, channel)
+                });
+            }
+        }
+
+        if actions.contains(&StaveAction::ZoomToFit) {
+            self.zoom_to_fit();
         }
 
         // Undo/redo
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::Z))
-        }) {
+        if actions.contains(&StaveAction::Undo) {
             let mut changes = vec![];
             let edit_state = if self.history.borrow_mut().undo(&mut changes) {
                 Some((EditCommandType::Undo, changes))
@@ -686,13 +1363,7 @@ impl Stave {
             };
             self.transition = Self::animate_edit(&response.ctx, response.id, edit_state);
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::CTRL, egui::Key::Y))
-                || i.consume_shortcut(&egui::KeyboardShortcut::new(
-                    Modifiers::CTRL | Modifiers::SHIFT,
-                    egui::Key::Z,
-                ))
-        }) {
+        if actions.contains(&StaveAction::Redo) {
             let mut changes = vec![];
             let edit_state = if self.history.borrow_mut().redo(&mut changes) {
                 Some((EditCommandType::Redo, changes))
@@ -702,36 +1373,177 @@ impl Stave {
             self.transition = Self::animate_edit(&response.ctx, response.id, edit_state);
         }
 
-        // Bookmarks & time navigation
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::M))
-        }) {
-            let at = self.cursor_position;
-            let id_seq = &self.history.borrow().id_seq.clone();
-            self.do_edit_command(&response.ctx, response.id, |_stave, track| {
-                set_bookmark(track, id_seq, &at)
+        if actions.contains(&StaveAction::Mirror) {
+            let axis = self.cursor_position;
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                mirror_selected_notes(track, &stave.note_selection.selected, axis)
             });
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(Modifiers::NONE, egui::Key::N))
-        }) {
-            let at = self.cursor_position;
-            self.do_edit_command(&response.ctx, response.id, |_stave, track| {
-                clear_bookmark(track, &at)
+        // Strum from the lowest note up, or from the highest down.
+        let strum_upward = if actions.contains(&StaveAction::StrumDown) {
+            Some(false)
+        } else if actions.contains(&StaveAction::StrumUp) {
+            Some(true)
+        } else {
+            None
+        };
+        if let Some(upward) = strum_upward {
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                strum_selected(
+                    track,
+                    &stave.note_selection.selected,
+                    stave.strum_step,
+                    upward,
+                )
             });
         }
-        // Previous bookmark
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::CTRL,
-                egui::Key::ArrowLeft,
-            ))
-        }) {
-            let at = self.cursor_position;
-            return self
-                .history
-                .borrow()
-                .with_track(|track| {
+        if actions.contains(&StaveAction::Legato) {
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                legato_selected(track, &stave.note_selection.selected)
+            });
+        }
+        // Move sustain of notes to the pedal, or absorb the pedal into note durations.
+        let pedal = if actions.contains(&StaveAction::Unpedal) {
+            Some(false)
+        } else if actions.contains(&StaveAction::Pedal) {
+            Some(true)
+        } else {
+            None
+        };
+        if let Some(pedal) = pedal {
+            let id_seq = &self.history.borrow().id_seq.clone();
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                let selection = &stave.note_selection.selected;
+                if pedal {
+                    pedal_selected_notes(id_seq, track, selection, stave.pedaled_note_duration)
+                } else {
+                    unpedal_selected_notes(id_seq, track, selection)
+                }
+            });
+        }
+        if actions.contains(&StaveAction::Shuffle) {
+            let seed = chrono::Utc::now().timestamp_micros() as u64;
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                shuffle_selected_notes(track, &stave.note_selection.selected, seed)
+            });
+        }
+        if actions.contains(&StaveAction::Humanize) {
+            let seed = chrono::Utc::now().timestamp_micros() as u64;
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                humanize_selected(
+                    track,
+                    &stave.note_selection.selected,
+                    stave.humanize_time,
+                    stave.humanize_velocity,
+                    seed,
+                )
+            });
+        }
+
+        // Select notes like the selected one: of the same pitch, velocity, or duration.
+        let similar = [
+            (StaveAction::SelectSamePitch, NoteAttribute::Pitch),
+            (StaveAction::SelectSameVelocity, NoteAttribute::Velocity),
+            (StaveAction::SelectSameDuration, NoteAttribute::Duration),
+        ]
+        .into_iter()
+        .find(|(action, _)| actions.contains(action));
+        if let Some((_, attribute)) = similar {
+            if self.note_selection.count() == 1 {
+                let reference = *self.note_selection.selected.iter().next().unwrap();
+                if let Some(similar) = self
+                    .history
+                    .borrow()
+                    .with_track(|track| similar_notes(track, reference, attribute))
+                {
+                    self.note_selection.selected = similar;
+                }
+            }
+        }
+
+        if actions.contains(&StaveAction::ToggleLock) {
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                toggle_lock_selected_notes(track, &stave.note_selection.selected)
+            });
+        }
+
+        // Ties
+        if actions.contains(&StaveAction::Untie) {
+            self.ties.untie_selected(&self.note_selection.selected);
+        }
+        if actions.contains(&StaveAction::Tie) {
+            let selection = &self.note_selection.selected;
+            let ties = &mut self.ties;
+            self.history
+                .borrow()
+                .with_track(|track| ties.tie_selected(track, selection));
+        }
+
+        if actions.contains(&StaveAction::AddNote) {
+            return Some(self.add_note_at_cursor(&response.ctx, response.id));
+        }
+
+        if actions.contains(&StaveAction::ToggleDamper) {
+            let at = self.cursor_position;
+            let id_seq = &self.history.borrow().id_seq.clone();
+            self.do_edit_command(&response.ctx, response.id, |stave, track| {
+                toggle_damper_at(id_seq, track, stave.active_controller, at)
+            });
+        }
+
+        // Ctrl+C arrives as a copy event.
+        if actions.contains(&StaveAction::Copy)
+            || response
+                .ctx
+                .input(|i| i.events.iter().any(|ev| matches!(ev, egui::Event::Copy)))
+        {
+            let fragment = self
+                .history
+                .borrow()
+                .with_track(|track| selected_events(track, &self.note_selection.selected));
+            if !fragment.is_empty() {
+                self.clipboard.put(&fragment);
+            }
+        }
+        // Ctrl+V arrives as a paste event if the system clipboard has text.
+        if actions.contains(&StaveAction::Paste)
+            || response.ctx.input(|i| {
+                i.events
+                    .iter()
+                    .any(|ev| matches!(ev, egui::Event::Paste(_)))
+            })
+        {
+            if let Some(fragment) = self.clipboard.get_latest() {
+                let at = self.cursor_position;
+                let match_velocity = self.paste_match_velocity;
+                let id_seq = &self.history.borrow().id_seq.clone();
+                self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                    paste_events(track, id_seq, &fragment, at, match_velocity)
+                });
+            }
+        }
+
+        // Bookmarks & time navigation
+        if actions.contains(&StaveAction::SetBookmark) {
+            let at = self.cursor_position;
+            let id_seq = &self.history.borrow().id_seq.clone();
+            self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                set_bookmark(track, id_seq, &at)
+            });
+        }
+        if actions.contains(&StaveAction::ClearBookmark) {
+            let at = self.cursor_position;
+            self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                clear_bookmark(track, &at)
+            });
+        }
+        // Previous bookmark
+        if actions.contains(&StaveAction::PreviousBookmark) {
+            let at = self.cursor_position;
+            return self
+                .history
+                .borrow()
+                .with_track(|track| {
                     track
                         .events
                         .iter()
@@ -742,12 +1554,7 @@ impl Stave {
                 .or(Some(0));
         }
         // Next bookmark
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::CTRL,
-                egui::Key::ArrowRight,
-            ))
-        }) {
+        if actions.contains(&StaveAction::NextBookmark) {
             let at = self.cursor_position;
             return self
                 .history
@@ -762,54 +1569,50 @@ impl Stave {
                 .map(|ev| ev.at)
                 .or(Some(self.max_time()));
         }
+        // Previous/next note of the last selected pitch
+        for (action, forward) in [
+            (StaveAction::PreviousNoteOfPitch, false),
+            (StaveAction::NextNoteOfPitch, true),
+        ] {
+            if actions.contains(&action) {
+                let (at, pitch) = (self.cursor_position, self.last_pitch);
+                return self
+                    .history
+                    .borrow()
+                    .with_track(|track| adjacent_event(&track.events, at, forward, Some(pitch)))
+                    .or(Some(if forward { self.max_time() } else { 0 }));
+            }
+        }
         // Previous note/event
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::ALT,
-                egui::Key::ArrowLeft,
-            ))
-        }) {
+        if actions.contains(&StaveAction::PreviousEvent) {
             let at = self.cursor_position;
             return self
                 .history
                 .borrow()
-                .with_track(|track| track.events.iter().rfind(|ev| ev.at < at).cloned())
-                .map(|ev| ev.at)
+                .with_track(|track| adjacent_event(&track.events, at, false, None))
                 .or(Some(0));
         }
         // Next note/event
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::ALT,
-                egui::Key::ArrowRight,
-            ))
-        }) {
+        if actions.contains(&StaveAction::NextEvent) {
             let at = self.cursor_position;
             return self
                 .history
                 .borrow()
-                .with_track(move |track| track.events.iter().find(|ev| ev.at > at).cloned())
-                .map(|ev| ev.at)
+                .with_track(move |track| adjacent_event(&track.events, at, true, None))
                 .or(Some(self.max_time()));
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::CTRL,
-                egui::Key::Home,
-            ))
-        }) {
+        if actions.contains(&StaveAction::TrackStart) {
             return Some(0);
         }
-        if response.ctx.input_mut(|i| {
-            i.consume_shortcut(&egui::KeyboardShortcut::new(
-                Modifiers::CTRL,
-                egui::Key::End,
-            ))
-        }) {
+        if actions.contains(&StaveAction::TrackEnd) {
             return Some(self.max_time());
         }
         if let Some(hover_pos) = response.hover_pos() {
-            if response.middle_clicked() {
+            if self
+                .middle_button
+                .seeks_on_click(self.on_bar_ruler(hover_pos.y))
+                && response.middle_clicked()
+            {
                 let at = self.time_from_x(hover_pos.x);
                 return Some(at);
             }
@@ -837,6 +1640,11 @@ impl Stave {
         }
     }
 
+    /// Animation id of the edits that are started outside of the stave's own input handling.
+    fn external_edit_id() -> egui::Id {
+        egui::Id::new("stave external edit")
+    }
+
     fn do_edit_command<Action: FnOnce(&Stave, &Track) -> Option<AppliedCommand>>(
         &mut self,
         context: &Context,
@@ -852,9 +1660,28 @@ impl Stave {
             transition_id,
             diff.clone().map(|diff| (diff.0 .0, diff.1)),
         );
+        if let Some((_, changes)) = &diff {
+            if let Some(at) = follow_edit_at(&(self.time_left, self.time_right), changes) {
+                self.ensure_visible(at);
+            }
+        }
         diff
     }
 
+    /// Insert a note with the last used pitch at the cursor, returns the note's end time.
+    fn add_note_at_cursor(&mut self, context: &Context, transition_id: egui::Id) -> Time {
+        let time_range = (
+            self.cursor_position,
+            self.cursor_position + self.new_note_duration,
+        );
+        let pitch = self.last_pitch;
+        let id_seq = &self.history.borrow().id_seq.clone();
+        self.do_edit_command(context, transition_id, |_stave, _track| {
+            add_new_note(id_seq, &time_range, &pitch)
+        });
+        time_range.1
+    }
+
     fn max_time(&self) -> Time {
         self.history.borrow().with_track(|track| track.max_time())
     }
@@ -889,6 +1716,171 @@ impl Stave {
         }
     }
 
+    /// Box selection with the right mouse button, Ctrl adds to the selection.
+    fn update_select_draw(&mut self, response: &egui::Response, add: bool) {
+        let drag_button = PointerButton::Secondary;
+        let Some(pos) = response.interact_pointer_pos() else {
+            return;
+        };
+        if response.drag_started_by(drag_button) {
+            self.select_draw = Some(Rect::from_min_max(pos, pos));
+        } else if response.drag_stopped_by(drag_button) {
+            if let Some(area) = self.select_draw.take() {
+                let area = Rect::from_two_pos(area.min, pos);
+                let selected = self
+                    .history
+                    .borrow()
+                    .with_track(|track| self.notes_in_area(track, area));
+                if !add {
+                    self.note_selection.clear();
+                }
+                self.note_selection.selected.extend(selected);
+            }
+        } else if response.dragged_by(drag_button) {
+            if let Some(area) = &mut self.select_draw {
+                area.max = pos;
+            }
+        }
+    }
+
+    /// Drag a note's velocity bar, the other selected notes follow proportionally
+    /// if the note is selected.
+    fn update_velocity_drag(&mut self, response: &egui::Response) {
+        let Some(pos) = response.interact_pointer_pos() else {
+            return;
+        };
+        if response.drag_started_by(PointerButton::Primary) {
+            self.velocity_drag = self
+                .history
+                .borrow()
+                .with_track(|track| self.velocity_bar_at(track, pos.x))
+                .map(|(note_id, velocity)| VelocityDrag { note_id, velocity });
+        } else if response.drag_stopped_by(PointerButton::Primary) {
+            if let Some(drag) = self.velocity_drag.take() {
+                let selection = self.velocity_drag_selection(drag.note_id);
+                self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                    scale_selected_velocity(track, &selection, drag.note_id, drag.velocity)
+                });
+            }
+        } else if response.dragged_by(PointerButton::Primary) {
+            let velocity = self.velocity_from_y(pos.y);
+            if let Some(drag) = &mut self.velocity_drag {
+                drag.velocity = velocity;
+            }
+        }
+    }
+
+    /// Notes that a velocity drag of the note changes.
+    fn velocity_drag_selection(&self, note_id: EventId) -> HashSet<EventId> {
+        if self.note_selection.selected.contains(&note_id) {
+            self.note_selection.selected.clone()
+        } else {
+            HashSet::from([note_id])
+        }
+    }
+
+    /// Vertical range of the key lines, the velocity lane is below it
+    /// and the bar ruler (if shown) is above it.
+    fn keys_y_range(&self) -> Rangef {
+        let top = if self.show_bar_ruler {
+            self.view_rect.min.y + BAR_RULER_HEIGHT
+        } else {
+            self.view_rect.min.y
+        };
+        Rangef::new(top, self.view_rect.max.y - VELOCITY_LANE_HEIGHT)
+    }
+
+    fn on_bar_ruler(&self, y: Pix) -> bool {
+        self.show_bar_ruler && y < self.keys_y_range().min
+    }
+
+    fn velocity_lane_rect(&self) -> Rect {
+        Rect::from_x_y_ranges(
+            self.view_rect.x_range(),
+            Rangef::new(self.keys_y_range().max, self.view_rect.max.y),
+        )
+    }
+
+    fn velocity_from_y(&self, y: Pix) -> Level {
+        let lane = self.velocity_lane_rect();
+        let level = (lane.max.y - y) / lane.height() * MAX_LEVEL as Pix;
+        level.round().clamp(1.0, MAX_LEVEL as Pix) as Level
+    }
+
+    /// Note (id and velocity) which velocity bar is the closest to `x`, if it is within reach.
+    fn velocity_bar_at(&self, track: &Track, x: Pix) -> Option<(EventId, Level)> {
+        let reach = (VELOCITY_BAR_REACH / self.time_scale()) as Time;
+        let time_range = (self.time_from_x(x) - reach, self.time_from_x(x) + reach + 1);
+        track
+            .notes_in_range(&time_range)
+            .filter(|ev| time_range.0 <= ev.at)
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Note(note) => Some((ev, note.velocity)),
+                _ => None,
+            })
+            .min_by_key(|(ev, _)| OrderedFloat((self.x_from_time(ev.at) - x).abs()))
+            .map(|(ev, velocity)| (ev.id, velocity))
+    }
+
+    /// Velocity bars of the visible notes, with the dragged values if a drag is in progress.
+    fn draw_velocity_lane(&self, painter: &Painter, track: &Track) {
+        let lane = self.velocity_lane_rect();
+        painter.hline(
+            lane.x_range(),
+            lane.min.y,
+            Stroke::new(1.0, self.grid_colors.bar_line),
+        );
+        let dragged = self.velocity_drag.as_ref().map(|drag| {
+            let original = track.events.iter().find_map(|ev| match &ev.event {
+                TrackEventType::Note(note) if ev.id == drag.note_id => Some(note.velocity),
+                _ => None,
+            });
+            let ratio = drag.velocity as f32 / original.unwrap_or(1).max(1) as f32;
+            (drag, self.velocity_drag_selection(drag.note_id), ratio)
+        });
+        for ev in track.notes_in_range(&(self.time_left, self.time_right)) {
+            let TrackEventType::Note(note) = &ev.event else {
+                continue;
+            };
+            let velocity = match &dragged {
+                Some((drag, _, _)) if drag.note_id == ev.id => drag.velocity,
+                Some((_, selection, ratio)) if selection.contains(&ev.id) && !ev.is_locked() => {
+                    ((note.velocity as f32 * ratio).round() as Level).clamp(1, MAX_LEVEL)
+                }
+                _ => note.velocity,
+            };
+            let top = lane.max.y - lane.height() * velocity as Pix / MAX_LEVEL as Pix;
+            painter.vline(
+                self.x_from_time(ev.at),
+                Rangef::new(top, lane.max.y),
+                Stroke::new(
+                    2.0,
+                    self.note_color(&velocity, self.note_selection.contains(ev)),
+                ),
+            );
+        }
+    }
+
+    /// Notes which rectangles on the stave intersect the area.
+    fn notes_in_area(&self, track: &Track, area: Rect) -> HashSet<EventId> {
+        let (key_ys, half_tone_step) = key_line_ys(&self.keys_y_range(), self.key_lines());
+        let half_height = half_tone_step * 0.45;
+        let pitches: HashSet<Pitch> = key_ys
+            .iter()
+            .filter(|(_, &y)| y - half_height <= area.max.y && area.min.y <= y + half_height)
+            .map(|(&pitch, _)| pitch)
+            .collect();
+        let time_range = (
+            self.time_from_x(area.min.x),
+            self.time_from_x(area.max.x) + 1,
+        );
+        track
+            .notes_in_range(&time_range)
+            .filter(|ev| matches!(&ev.event, TrackEventType::Note(n) if pitches.contains(&n.pitch)))
+            .map(|ev| ev.id)
+            .collect()
+    }
+
     fn update_new_note_draw(
         &mut self,
         response: &egui::Response,
@@ -899,13 +1891,20 @@ impl Stave {
         // TODO Extract the drag pattern? See also update_time_selection.
         //      See how egui can help, there seem to be already some drag&drop support.
         let drag_button = PointerButton::Middle;
+        let on_ruler = response
+            .interact_pointer_pos()
+            .is_some_and(|pos| self.on_bar_ruler(pos.y));
         if response.clicked_by(drag_button) {
             self.note_draw = None;
         } else if response.drag_started_by(drag_button) {
+            if !self.middle_button.draws_on_drag(on_ruler) {
+                return;
+            }
             if let Some(time) = time {
                 if let Some(pitch) = pitch {
+                    let time = self.snap_time(*time);
                     self.note_draw = Some(NoteDraw {
-                        time: (*time, *time),
+                        time: (time, time),
                         pitch: *pitch,
                     });
                 }
@@ -915,20 +1914,30 @@ impl Stave {
                 if !draw.time.is_empty() {
                     let time_range = (draw.time.0, draw.time.1);
                     let id_seq = &self.history.borrow().id_seq.clone();
-                    self.do_edit_command(&response.ctx, response.id, |_stave, track| {
+                    self.do_edit_command(&response.ctx, response.id, |stave, track| {
                         if draw.pitch == PIANO_DAMPER_LANE {
-                            set_damper(id_seq, track, &time_range, !modifiers.alt)
+                            set_damper(
+                                id_seq,
+                                track,
+                                stave.active_controller,
+                                &time_range,
+                                !modifiers.alt,
+                            )
                         } else {
                             add_new_note(id_seq, &time_range, &draw.pitch)
                         }
                     });
+                    if draw.pitch != PIANO_DAMPER_LANE {
+                        self.last_pitch = draw.pitch;
+                    }
                 }
             }
             self.note_draw = None;
         } else if response.dragged_by(drag_button) {
             if let Some(time) = time {
+                let time = self.snap_time(*time);
                 if let Some(draw) = &mut self.note_draw {
-                    draw.time.1 = *time;
+                    draw.time.1 = time;
                 }
             }
         }
@@ -969,13 +1978,17 @@ impl Stave {
         note: &Note,
     ) -> Option<Rect> {
         if let Some(y) = key_ys.get(&note.pitch) {
-            Some(self.draw_note(
+            let rect = self.draw_note(
                 &painter,
                 (event.at, event.at + note.duration),
                 *y,
                 *half_tone_step,
                 self.note_color(&note.velocity, self.note_selection.contains(&event)),
-            ))
+            );
+            if note.locked {
+                painter.rect_stroke(rect, Rounding::ZERO, Stroke::new(2.0, COLOR_LOCKED));
+            }
+            Some(rect)
         } else {
             None
         }
@@ -1003,8 +2016,12 @@ impl Stave {
 
         // May want to handle gracefully when note gets in/out of visible pitch range.
         // Just patching with existing y for now.
-        let y_a = key_ys.get(&p_a).or(key_ys.get(&p_b)).unwrap();
-        let y_b = key_ys.get(&p_b).or(key_ys.get(&p_a)).unwrap();
+        let (Some(y_a), Some(y_b)) = (
+            key_ys.get(&p_a).or(key_ys.get(&p_b)),
+            key_ys.get(&p_b).or(key_ys.get(&p_a)),
+        ) else {
+            return;
+        };
         let y = egui::lerp(*y_a..=*y_b, coeff);
 
         let t1 = egui::lerp(t1_a as f64..=t1_b as f64, coeff as f64) as i64;
@@ -1025,20 +2042,69 @@ impl Stave {
         height: Pix,
         color: Color32,
     ) -> Rect {
-        let paint_rect = Rect {
-            min: Pos2 {
-                x: self.x_from_time(time_range.0),
-                y: y - height * 0.45,
-            },
-            max: Pos2 {
-                x: self.x_from_time(time_range.1),
-                y: y + height * 0.45,
-            },
-        };
+        let paint_rect = note_rect(
+            self.x_from_time(time_range.0),
+            self.x_from_time(time_range.1),
+            y,
+            height,
+            self.min_note_width,
+        );
         painter.rect_filled(paint_rect, Rounding::ZERO, color);
         paint_rect
     }
 
+    /// Arcs from the end of a tied note to the start of the next one.
+    fn draw_ties(
+        &self,
+        key_ys: &BTreeMap<Pitch, Pix>,
+        half_tone_step: Pix,
+        painter: &Painter,
+        track: &Track,
+    ) {
+        let tied: HashSet<EventId> = self.ties.iter().flat_map(|&(a, b)| [a, b]).collect();
+        let notes: HashMap<EventId, (Time, Time, Pitch)> = track
+            .events
+            .iter()
+            .filter(|ev| tied.contains(&ev.id))
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Note(n) => Some((ev.id, (ev.at, ev.at + n.duration, n.pitch))),
+                _ => None,
+            })
+            .collect();
+        for (a, b) in self.ties.iter() {
+            // Tied notes may have been deleted since.
+            let (Some(&(_, end, pitch_a)), Some(&(start, _, pitch_b))) =
+                (notes.get(a), notes.get(b))
+            else {
+                continue;
+            };
+            let (Some(&y_a), Some(&y_b)) = (key_ys.get(&pitch_a), key_ys.get(&pitch_b)) else {
+                continue;
+            };
+            let p0 = Pos2::new(self.x_from_time(end), y_a - half_tone_step * 0.5);
+            let p2 = Pos2::new(self.x_from_time(start), y_b - half_tone_step * 0.5);
+            let control = Pos2::new((p0.x + p2.x) / 2.0, p0.y.min(p2.y) - half_tone_step * 2.0);
+            painter.add(QuadraticBezierShape::from_points_stroke(
+                [p0, control, p2],
+                false,
+                Color32::TRANSPARENT,
+                Stroke::new(1.5, COLOR_TIE),
+            ));
+        }
+    }
+
+    /// Name of the animated edit command in the view's corner, fading out with the animation.
+    fn draw_command_label(painter: &Painter, transition: &EditTransition) {
+        let clip = painter.clip_rect();
+        painter.text(
+            Pos2::new(clip.max.x - 8.0, clip.min.y + 4.0),
+            Align2::RIGHT_TOP,
+            command_label(transition.command_id),
+            FontId::proportional(16.0),
+            Color32::from_black_alpha((255.0 * (1.0 - transition.coeff)) as u8),
+        );
+    }
+
     fn draw_point_accent(
         &self,
         painter: &Painter,
@@ -1088,14 +2154,12 @@ impl Stave {
         }
     }
 
-    fn cc_animation_params(ev: Option<&TrackEvent>) -> Option<(Time, Level)> {
-        ev.and_then(|ev| {
-            if let TrackEventType::Controller(cc) = &ev.event {
-                debug_assert_eq!(cc.controller_id, MIDI_CC_SUSTAIN_ID);
+    fn cc_animation_params(&self, ev: Option<&TrackEvent>) -> Option<(Time, Level)> {
+        ev.and_then(|ev| match &ev.event {
+            TrackEventType::Controller(cc) if cc.controller_id == self.active_controller => {
                 Some((ev.at, cc.value))
-            } else {
-                None
             }
+            _ => None,
         })
     }
 
@@ -1137,7 +2201,7 @@ impl Stave {
         event: &TrackEvent,
         cc: &ControllerSetValue,
     ) {
-        if cc.controller_id == MIDI_CC_SUSTAIN_ID {
+        if cc.controller_id == self.active_controller {
             if let Some(y) = key_ys.get(&PIANO_DAMPER_LANE) {
                 // TODO (visuals, improvement) The time range here is not right: is shown up to the event,
                 //   should be from the event to the next one instead.
@@ -1158,29 +2222,112 @@ impl Stave {
         bounds: Rect,
         keys: &BTreeMap<Pitch, Pix>,
         pitch_hovered: &Option<Pitch>,
+        colors: &GridColors,
     ) {
         for (pitch, y) in keys {
-            let mut color = if is_black_key(&pitch) {
-                Rgba::from_rgb(0.05, 0.05, 0.05)
+            let mut color = if is_black_key(*pitch) {
+                colors.black_key
             } else {
-                Rgba::from_rgb(0.55, 0.55, 0.55)
+                colors.white_key
             };
             if let Some(p) = pitch_hovered {
                 if pitch == p {
-                    color = COLOR_HOVERED
+                    color = COLOR_HOVERED.into()
                 }
             }
             painter.hline(
                 bounds.min.x..=bounds.max.x,
                 *y,
+                Stroke { width: 1.0, color },
+            );
+        }
+    }
+
+    /// Faint lines where snapped times land.
+    fn draw_snap_lines(&self, painter: &Painter) {
+        let Some(step) = self.snap else {
+            return;
+        };
+        if (self.time_scale() * step as f32) < MIN_BAR_WIDTH {
+            return; // Too dense to be useful.
+        }
+        let clip = painter.clip_rect();
+        let origin = self.quantize_origin();
+        let mut at = origin + (self.time_from_x(clip.min.x) - origin).div_euclid(step) * step;
+        let stroke = Stroke {
+            width: 1.0,
+            color: self.grid_colors.bar_line.gamma_multiply(0.3),
+        };
+        while at <= self.time_from_x(clip.max.x) {
+            painter.vline(self.x_from_time(at), clip.y_range(), stroke);
+            at += step;
+        }
+    }
+
+    fn draw_bar_lines(&self, painter: &Painter, meter: &MeterMap) {
+        let visible = (
+            self.time_from_x(painter.clip_rect().min.x),
+            self.time_from_x(painter.clip_rect().max.x),
+        );
+        let bar_lines = meter.bar_lines(&visible);
+        if bar_lines.len() as Pix > painter.clip_rect().width() / MIN_BAR_WIDTH {
+            return; // Too dense to be useful.
+        }
+        for at in bar_lines {
+            painter.vline(
+                self.x_from_time(at),
+                painter.clip_rect().y_range(),
                 Stroke {
-                    width: 1.0,
-                    color: color.into(),
+                    width: if self.heavy_bar_lines { 2.0 } else { 1.0 },
+                    color: self.grid_colors.bar_line,
                 },
             );
         }
     }
 
+    /// A row of bar lines and beat ticks numbered as "bar.beat" along the top edge.
+    fn draw_bar_ruler(&self, painter: &Painter, meter: &MeterMap) {
+        let clip = painter.clip_rect();
+        let area = Rect::from_x_y_ranges(
+            clip.x_range(),
+            Rangef::new(clip.min.y, clip.min.y + BAR_RULER_HEIGHT),
+        );
+        painter.rect_filled(area, Rounding::ZERO, self.grid_colors.white_key);
+        painter.hline(
+            area.x_range(),
+            area.max.y,
+            Stroke::new(1.0, self.grid_colors.bar_line),
+        );
+        let visible = (self.time_from_x(area.min.x), self.time_from_x(area.max.x));
+        let beat_width =
+            self.time_scale() * meter.time_signature_at(visible.0).beat_duration() as f32;
+        let beats = meter.beats(&visible);
+        if beats.len() as Pix > area.width() / MIN_BAR_WIDTH {
+            return; // Too dense to be useful.
+        }
+        let label_beats = beat_width >= MIN_RULER_LABEL_WIDTH;
+        let mut last_label_x = Pix::NEG_INFINITY;
+        for (at, bar, beat) in beats {
+            let x = self.x_from_time(at);
+            let tick = if beat == 1 {
+                area.y_range()
+            } else {
+                Rangef::new(area.max.y - BAR_RULER_HEIGHT / 4.0, area.max.y)
+            };
+            painter.vline(x, tick, Stroke::new(1.0, self.grid_colors.bar_line));
+            if (beat == 1 || label_beats) && x - last_label_x >= MIN_RULER_LABEL_WIDTH {
+                painter.text(
+                    Pos2::new(x + 2.0, area.min.y + 1.0),
+                    Align2::LEFT_TOP,
+                    format!("{}.{}", bar, beat),
+                    FontId::proportional(11.0),
+                    self.grid_colors.black_key,
+                );
+                last_label_x = x;
+            }
+        }
+    }
+
     pub fn draw_time_selection(&self, painter: &Painter, selection: &Range<Time>, color: &Color32) {
         let clip = painter.clip_rect();
         let area = Rect {
@@ -1243,10 +2390,6 @@ fn draw_selection_hints(
     }
 }
 
-fn is_black_key(tone: &Pitch) -> bool {
-    vec![1, 3, 6, 8, 10].contains(&(tone % 12))
-}
-
 fn closest_pitch(pitch_ys: &BTreeMap<Pitch, Pix>, pointer_pos: Pos2) -> Pitch {
     *pitch_ys
         .iter()
@@ -1255,5 +2398,541 @@ fn closest_pitch(pitch_ys: &BTreeMap<Pitch, Pix>, pointer_pos: Pos2) -> Pitch {
         .0
 }
 
+/// Time stretch ratio of the tempo adjustment.
+fn tempo_ratio(step: f32, coarse_step: f32, coarse: bool, stretch: bool) -> f32 {
+    let step = if coarse { coarse_step } else { step };
+    if stretch {
+        step
+    } else {
+        1.0 / step
+    }
+}
+
+/// Time selection with its end (or start) moved by `delta`, `None` if it would become empty.
+/// Without a selection, a new one starts at `at`.
+fn nudged_selection(
+    selection: Option<Range<Time>>,
+    at: Time,
+    delta: Time,
+    move_start: bool,
+) -> Option<Range<Time>> {
+    let (start, end) = selection.map_or((at, at), |(a, b)| (a.min(b), a.max(b)));
+    let nudged = if move_start {
+        (start + delta, end)
+    } else {
+        (start, end + delta)
+    };
+    (nudged.0 < nudged.1).then_some(nudged)
+}
+
+/// On-screen note area, at least `min_width` wide so short notes can still be seen and clicked.
+fn note_rect(x_start: Pix, x_end: Pix, y: Pix, height: Pix, min_width: Pix) -> Rect {
+    Rect {
+        min: Pos2 {
+            x: x_start,
+            y: y - height * 0.45,
+        },
+        max: Pos2 {
+            x: x_end.max(x_start + min_width),
+            y: y + height * 0.45,
+        },
+    }
+}
+
+/// Where to scroll so the result of an edit is seen: the earliest changed note,
+/// `None` if some of the changed notes are already in the view.
+fn follow_edit_at(view: &Range<Time>, changes: &EventActionsList) -> Option<Time> {
+    let changed: Vec<&TrackEvent> = changes
+        .iter()
+        .filter_map(|action| action.after().or(action.before()))
+        .filter(|ev| matches!(ev.event, TrackEventType::Note(_)))
+        .collect();
+    if changed.iter().any(|ev| ev.intersects(view)) {
+        return None;
+    }
+    changed.iter().map(|ev| ev.at).min()
+}
+
+/// Human-readable command name, e.g. "Notes transpose" for NotesTranspose.
+fn command_label(command_id: EditCommandType) -> String {
+    let mut label = String::new();
+    for c in format!("{:?}", command_id).chars() {
+        if c.is_uppercase() && !label.is_empty() {
+            label.push(' ');
+            label.extend(c.to_lowercase());
+        } else {
+            label.push(c);
+        }
+    }
+    label
+}
+
+/// Time of the nearest event before or after (if `forward`) the given time,
+/// optionally only notes of the given pitch.
+fn adjacent_event(
+    events: &[TrackEvent],
+    at: Time,
+    forward: bool,
+    pitch: Option<Pitch>,
+) -> Option<Time> {
+    let matches = |ev: &&TrackEvent| match (pitch, &ev.event) {
+        (None, _) => true,
+        (Some(pitch), TrackEventType::Note(note)) => note.pitch == pitch,
+        _ => false,
+    };
+    if forward {
+        events.iter().filter(|ev| ev.at > at).find(matches)
+    } else {
+        events.iter().filter(|ev| ev.at < at).rfind(matches)
+    }
+    .map(|ev| ev.at)
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::changeset::EventAction;
+    use crate::track::note_event;
+    use crate::track_edit::CommandDiff;
+    use crate::track_history::test_history;
+
+    #[test]
+    fn edge_scroll_speed() {
+        let x_range = Rangef::new(100.0, 500.0);
+        assert_eq!(Stave::edge_scroll_speed(x_range, 300.0), 0.0);
+        assert_eq!(Stave::edge_scroll_speed(x_range, 140.0), 0.0);
+        assert_eq!(Stave::edge_scroll_speed(x_range, 460.0), 0.0);
+        let near_left = Stave::edge_scroll_speed(x_range, 130.0);
+        let at_left = Stave::edge_scroll_speed(x_range, 100.0);
+        assert!(at_left < near_left && near_left < 0.0);
+        assert_eq!(at_left, -Stave::EDGE_SCROLL_MAX_STEP);
+        // Dragging past the edge does not speed up further.
+        assert_eq!(Stave::edge_scroll_speed(x_range, 50.0), at_left);
+        assert_eq!(
+            Stave::edge_scroll_speed(x_range, 490.0),
+            -Stave::edge_scroll_speed(x_range, 110.0)
+        );
+        assert_eq!(
+            Stave::edge_scroll_speed(x_range, 600.0),
+            Stave::EDGE_SCROLL_MAX_STEP
+        );
+    }
+
+    #[test]
+    fn nudge_time_selection() {
+        let step = 250_000;
+        assert_eq!(
+            nudged_selection(Some((1_000_000, 2_000_000)), 0, step, false),
+            Some((1_000_000, 2_250_000))
+        );
+        assert_eq!(
+            nudged_selection(Some((2_000_000, 1_000_000)), 0, -step, true),
+            Some((750_000, 2_000_000))
+        );
+        assert_eq!(
+            nudged_selection(Some((1_000_000, 1_250_000)), 0, -step, false),
+            None
+        );
+        assert_eq!(
+            nudged_selection(None, 500_000, step, false),
+            Some((500_000, 750_000))
+        );
+        assert_eq!(nudged_selection(None, 500_000, step, true), None);
+    }
+
+    #[test]
+    fn short_notes_are_clickable() {
+        // A note that is much shorter than a pixel at this zoom.
+        let rect = note_rect(100.0, 100.01, 50.0, 10.0, 3.0);
+        assert_eq!(rect.x_range(), Rangef::new(100.0, 103.0));
+        assert!(rect.contains(Pos2::new(101.5, 50.0)));
+        assert!(!rect.contains(Pos2::new(99.0, 50.0)));
+        // Longer notes are not changed.
+        let rect = note_rect(100.0, 200.0, 50.0, 10.0, 3.0);
+        assert_eq!(rect.x_range(), Rangef::new(100.0, 200.0));
+        assert_eq!(rect.y_range(), Rangef::new(45.5, 54.5));
+    }
+
+    #[test]
+    fn follow_edits() {
+        let note = |id, at| note_event(id, at, 60, 100);
+        let moved = |id, from, to| EventAction::Update(note(id, from), note(id, to));
+        let view = (1_000, 2_000);
+        assert_eq!(follow_edit_at(&view, &vec![]), None);
+        // Something changed is still in the view.
+        assert_eq!(
+            follow_edit_at(&view, &vec![moved(1, 1_500, 5_000), moved(2, 1_600, 1_700)]),
+            None
+        );
+        assert_eq!(follow_edit_at(&view, &vec![moved(1, 500, 950)]), None);
+        // Everything went off-screen.
+        assert_eq!(
+            follow_edit_at(&view, &vec![moved(1, 1_500, 6_000), moved(2, 1_600, 5_000)]),
+            Some(5_000)
+        );
+        assert_eq!(
+            follow_edit_at(&view, &vec![EventAction::Insert(note(3, 100))]),
+            Some(100)
+        );
+        // Deleted events are looked for where they were.
+        assert_eq!(
+            follow_edit_at(&view, &vec![EventAction::Delete(note(4, 1_200))]),
+            None
+        );
+        // Other events do not move the view.
+        let controller = TrackEvent {
+            id: 5,
+            at: 7_000,
+            event: TrackEventType::Controller(ControllerSetValue {
+                controller_id: 64,
+                value: 127,
+            }),
+        };
+        assert_eq!(
+            follow_edit_at(&view, &vec![EventAction::Insert(controller)]),
+            None
+        );
+    }
+
+    #[test]
+    fn default_grid_colors() {
+        let colors = GridColors::new(&Config::default());
+        // Same as the previously hard-coded ones.
+        assert_eq!(colors.black_key, Rgba::from_rgb(0.05, 0.05, 0.05).into());
+        assert_eq!(colors.white_key, Rgba::from_rgb(0.55, 0.55, 0.55).into());
+        assert_eq!(
+            colors.time_selection,
+            Color32::from_rgba_unmultiplied(64, 80, 100, 60)
+        );
+        assert_eq!(colors.nothing_zone, Color32::from_black_alpha(15));
+    }
+
+    #[test]
+    fn middle_button_dispatch() {
+        for on_ruler in [false, true] {
+            assert!(MiddleButtonMode::SeekAndDraw.seeks_on_click(on_ruler));
+            assert!(MiddleButtonMode::SeekAndDraw.draws_on_drag(on_ruler));
+            assert!(!MiddleButtonMode::DrawOnly.seeks_on_click(on_ruler));
+            assert!(MiddleButtonMode::DrawOnly.draws_on_drag(on_ruler));
+            assert!(MiddleButtonMode::SeekOnly.seeks_on_click(on_ruler));
+            assert!(!MiddleButtonMode::SeekOnly.draws_on_drag(on_ruler));
+        }
+        assert!(MiddleButtonMode::SeekOnRuler.seeks_on_click(true));
+        assert!(!MiddleButtonMode::SeekOnRuler.draws_on_drag(true));
+        assert!(!MiddleButtonMode::SeekOnRuler.seeks_on_click(false));
+        assert!(MiddleButtonMode::SeekOnRuler.draws_on_drag(false));
+        assert_eq!(
+            Config::load(None).middle_button,
+            MiddleButtonMode::SeekAndDraw
+        );
+    }
+
+    fn test_stave(name: &str) -> Stave {
+        let history = test_history(&format!("test_stave_{name}"));
+        Stave::new(&Config::default(), RefCell::new(history))
+    }
+
+    #[test]
+    fn tempo_adjustment_steps() {
+        let config = Config::default();
+        let ratio = |coarse, stretch| {
+            tempo_ratio(config.tempo_step, config.tempo_coarse_step, coarse, stretch)
+        };
+        assert_eq!(ratio(false, true), 1.01);
+        assert_eq!(ratio(true, true), 1.05);
+        assert_eq!(ratio(false, false), 1.0 / 1.01);
+        assert_eq!(ratio(true, false), 1.0 / 1.05);
+    }
+
+    #[test]
+    fn add_notes_at_cursor() {
+        let mut history = test_history("test_stave_add_notes_at_cursor");
+        history.update_track(clear_track);
+        let config = Config::default();
+        let mut stave = Stave::new(&config, RefCell::new(history));
+        stave.last_pitch = 64;
+        stave.cursor_position = 1_000;
+        let context = Context::default();
+        for _ in 0..3 {
+            stave.cursor_position = stave.add_note_at_cursor(&context, egui::Id::new("stave"));
+        }
+        let d = config.new_note_duration;
+        assert_eq!(stave.cursor_position, 1_000 + 3 * d);
+        let notes: Vec<(Time, Pitch, Time)> = stave.history.borrow().with_track(|track| {
+            track
+                .events
+                .iter()
+                .filter_map(|ev| match &ev.event {
+                    TrackEventType::Note(n) => Some((ev.at, n.pitch, n.duration)),
+                    _ => None,
+                })
+                .collect()
+        });
+        assert_eq!(
+            notes,
+            vec![(1_000, 64, d), (1_000 + d, 64, d), (1_000 + 2 * d, 64, d)]
+        );
+    }
+
+    #[test]
+    fn navigate_by_pitch() {
+        let note = |id, at, pitch| note_event(id, at, pitch, 100);
+        let events = vec![
+            note(1, 100, 60),
+            note(2, 200, 62),
+            TrackEvent {
+                id: 3,
+                at: 250,
+                event: TrackEventType::Bookmark,
+            },
+            note(4, 300, 60),
+            note(5, 400, 64),
+        ];
+        assert_eq!(adjacent_event(&events, 100, true, None), Some(200));
+        assert_eq!(adjacent_event(&events, 100, true, Some(60)), Some(300));
+        assert_eq!(adjacent_event(&events, 300, true, Some(60)), None);
+        assert_eq!(adjacent_event(&events, 400, false, Some(60)), Some(300));
+        assert_eq!(adjacent_event(&events, 300, false, Some(60)), Some(100));
+        assert_eq!(adjacent_event(&events, 300, false, None), Some(250));
+    }
+
+    #[test]
+    fn zoom_to_fit_margin() {
+        let minute = 60_000_000;
+        assert_eq!(Stave::fit_margin(minute, 0.05), 3_000_000);
+        assert_eq!(Stave::fit_margin(10 * minute, 0.05), 30_000_000);
+        assert_eq!(Stave::fit_margin(minute, 0.0), Stave::MIN_FIT_MARGIN);
+        assert_eq!(Stave::fit_margin(0, 0.05), Stave::MIN_FIT_MARGIN);
+    }
+
+    #[test]
+    fn degenerate_view_mapping() {
+        let mut stave = test_stave("degenerate_view_mapping");
+        let time_left = stave.time_left;
+        for rect in [
+            Rect::NOTHING,
+            Rect::from_min_size(Pos2::new(10.0, 10.0), egui::vec2(0.0, 100.0)),
+        ] {
+            stave.view_rect = rect;
+            assert!(stave.is_degenerate());
+            assert_eq!(stave.time_scale(), 0.0);
+            assert!(stave.x_from_time(1_000_000).is_finite());
+            assert_eq!(stave.time_from_x(50.0), time_left);
+            stave.scroll_by(100.0);
+            stave.zoom(2.0, 50.0);
+            assert_eq!(stave.time_left, time_left);
+        }
+        stave.view_rect = Rect::from_min_size(Pos2::new(10.0, 10.0), egui::vec2(100.0, 100.0));
+        assert!(!stave.is_degenerate());
+        assert_eq!(stave.time_from_x(10.0), time_left);
+    }
+
+    #[test]
+    fn edit_command_labels() {
+        assert_eq!(command_label(EditCommandType::Undo), "Undo");
+        assert_eq!(
+            command_label(EditCommandType::NotesTranspose),
+            "Notes transpose"
+        );
+        assert_eq!(
+            command_label(EditCommandType::SetTimeSignature),
+            "Set time signature"
+        );
+    }
+
+    #[test]
+    fn soft_notes_are_visible() {
+        // WCAG contrast ratio.
+        let luminance = |c: Color32| {
+            let c = Rgba::from(c);
+            0.2126 * c.r() + 0.7152 * c.g() + 0.0722 * c.b()
+        };
+        let contrast = |a: Color32, b: Color32| {
+            let (a, b) = (luminance(a), luminance(b));
+            (a.max(b) + 0.05) / (a.min(b) + 0.05)
+        };
+        let config = Config::default();
+        let background = GridColors::new(&config).white_key;
+        let colors = Stave::note_color_ramp(config.note_color_floor);
+        assert!(contrast(colors[1], background) >= 1.3);
+        assert!(contrast(colors[MAX_LEVEL as usize], background) >= 4.5);
+        // Without the floor the softest notes blend with the background.
+        assert!(contrast(Stave::note_color_ramp(0.0)[1], background) < 1.3);
+    }
+
+    #[test]
+    fn snap_to_grid() {
+        let mut stave = test_stave("snap_to_grid");
+        assert_eq!(stave.snap_time(1_234), 1_234);
+        stave.snap = Some(100_000);
+        assert_eq!(stave.snap_time(149_000), 100_000);
+        assert_eq!(stave.snap_time(151_000), 200_000);
+        assert_eq!(stave.snap_time(-40_000), 0);
+        // The grid may start at the time selection.
+        stave.quantize_origin = GridOrigin::SelectionStart;
+        stave.time_selection = Some((30_000, 50_000));
+        assert_eq!(stave.snap_time(149_000), 130_000);
+        assert_eq!(stave.snap_time(-40_000), -70_000);
+        stave.time_selection = None;
+
+        let track = Track {
+            events: vec![TrackEvent {
+                id: 1,
+                at: 130_000,
+                event: TrackEventType::Bookmark,
+            }],
+        };
+        stave.note_selection.selected.insert(1);
+        let shift = |stave: &Stave, direction| {
+            let (_, diff) = stave.shift_selected_by_step(&track, direction).unwrap();
+            let CommandDiff::ChangeList { patch } = &diff[0] else {
+                panic!("unexpected {:?}", diff);
+            };
+            let EventAction::Update(_, moved) = &patch[0] else {
+                panic!("unexpected {:?}", patch);
+            };
+            moved.at
+        };
+        assert_eq!(shift(&stave, 1), 200_000);
+        assert_eq!(shift(&stave, -1), 0);
+        stave.snap = None;
+        assert_eq!(shift(&stave, 1), 130_000 + Stave::KEYBOARD_TIME_STEP);
+    }
+
+    #[test]
+    fn undo_afterglow() {
+        let transition = |command_id, coeff| {
+            let mut changeset = Changeset::empty();
+            changeset.add_all(&vec![EventAction::Delete(TrackEvent {
+                id: 7,
+                at: 0,
+                event: TrackEventType::Bookmark,
+            })]);
+            Some(EditTransition {
+                animation_id: egui::Id::new("stave"),
+                command_id,
+                changeset,
+                coeff,
+            })
+        };
+        let glow = || {
+            Some(Afterglow {
+                events: HashSet::from([7]),
+                until: 11.0,
+            })
+        };
+        // Animating.
+        let (tr, ag) = Afterglow::step(transition(EditCommandType::Undo, 0.5), glow(), 10.0, 1.0);
+        assert!(tr.is_some());
+        assert!(ag.is_none());
+        // The animation is over, the changes glow.
+        let (tr, ag) = Afterglow::step(transition(EditCommandType::Undo, 1.0), None, 10.0, 1.0);
+        assert!(tr.is_none());
+        assert_eq!(ag, glow());
+        let (_, ag) = Afterglow::step(None, ag, 10.5, 1.0);
+        assert_eq!(ag, glow());
+        let (_, ag) = Afterglow::step(None, ag, 11.0, 1.0);
+        assert!(ag.is_none());
+        // Only after undo/redo, if enabled.
+        let (_, ag) = Afterglow::step(transition(EditCommandType::Redo, 1.0), None, 10.0, 1.0);
+        assert!(ag.is_some());
+        let (_, ag) = Afterglow::step(transition(EditCommandType::Undo, 1.0), None, 10.0, 0.0);
+        assert!(ag.is_none());
+        let (_, ag) = Afterglow::step(transition(EditCommandType::Paste, 1.0), None, 10.0, 1.0);
+        assert!(ag.is_none());
+    }
+
+    #[test]
+    fn box_selection() {
+        let mut stave = test_stave("box_selection");
+        stave.view_rect = Rect::from_min_size(Pos2::new(0.0, 0.0), egui::vec2(1000.0, 880.0));
+        stave.time_left = 0;
+        stave.time_right = 1_000_000;
+        let note = |id, at, pitch| note_event(id, at, pitch, 100_000);
+        let track = Track {
+            events: vec![
+                note(1, 100_000, 60),
+                note(2, 150_000, 62),
+                note(3, 500_000, 60),
+                note(4, 100_000, 72),
+            ],
+        };
+        let (key_ys, _) = key_line_ys(&stave.keys_y_range(), stave.key_lines());
+        let (y60, y62) = (key_ys[&60], key_ys[&62]);
+        // From the middle of note 1 to the start of note 2, across the pitches.
+        let area = Rect::from_two_pos(
+            Pos2::new(stave.x_from_time(150_000), y60),
+            Pos2::new(stave.x_from_time(150_000), y62),
+        );
+        assert_eq!(stave.notes_in_area(&track, area), HashSet::from([1, 2]));
+        let area = Rect::from_two_pos(
+            Pos2::new(stave.x_from_time(210_000), y60),
+            Pos2::new(stave.x_from_time(600_000), y60 + 1.0),
+        );
+        assert_eq!(stave.notes_in_area(&track, area), HashSet::from([3]));
+    }
+
+    #[test]
+    fn vertical_zoom() {
+        let mut stave = test_stave("vertical_zoom");
+        stave.view_rect = Rect::from_min_size(Pos2::new(0.0, 0.0), egui::vec2(1000.0, 880.0));
+        let lanes = |stave: &Stave| key_line_ys(&stave.keys_y_range(), stave.key_lines());
+        // All keys and the damper lane are shown initially.
+        let (key_ys, step) = lanes(&stave);
+        assert_eq!(key_ys.len(), PIANO_KEY_COUNT as usize + 1);
+
+        let y60 = key_ys[&60];
+        stave.zoom_pitches(64, 60);
+        assert_eq!(stave.key_lines().len(), 24);
+        let (key_ys, zoomed_step) = lanes(&stave);
+        assert!(zoomed_step > 3.0 * step);
+        // Controller values are still visible, the zoomed key stays close to where it was.
+        assert!(key_ys.contains_key(&PIANO_DAMPER_LANE));
+        assert!((key_ys[&60] - y60).abs() < zoomed_step);
+        assert!(!key_ys.contains_key(&PIANO_KEY_LINES.0));
+        // Pointing to a hidden key picks the nearest visible one.
+        assert_eq!(closest_pitch(&key_ys, Pos2::new(0.0, 0.0)), stave.pitch_top);
+
+        stave.zoom_pitches(100, 60);
+        assert_eq!(stave.key_lines().len(), MIN_VISIBLE_KEYS);
+        stave.scroll_pitches(-100);
+        assert_eq!(stave.pitch_bottom, PIANO_KEY_LINES.0);
+        stave.scroll_pitches(100);
+        assert_eq!(stave.pitch_top, PIANO_KEY_LINES.1 - 1);
+        stave.zoom_pitches(-100, 60);
+        assert_eq!(stave.key_lines(), PIANO_KEY_LINES);
+    }
+
+    #[test]
+    fn velocity_lane() {
+        let mut stave = test_stave("velocity_lane");
+        stave.view_rect = Rect::from_min_size(Pos2::new(0.0, 0.0), egui::vec2(1000.0, 880.0));
+        stave.time_left = 0;
+        stave.time_right = 1_000_000;
+        let note = |id, at| note_event(id, at, 60, 100_000);
+        let track = Track {
+            events: vec![note(1, 100_000), note(2, 300_000)],
+        };
+        // Bars are at the notes' starts, not anywhere over the notes.
+        assert_eq!(stave.velocity_bar_at(&track, 102.0), Some((1, 64)));
+        assert_eq!(stave.velocity_bar_at(&track, 297.0), Some((2, 64)));
+        assert_eq!(stave.velocity_bar_at(&track, 150.0), None);
+
+        let lane = stave.velocity_lane_rect();
+        assert_eq!(lane.max.y, 880.0);
+        assert_eq!(stave.keys_y_range().max, lane.min.y);
+        stave.show_bar_ruler = false;
+        assert_eq!(stave.keys_y_range().min, stave.view_rect.min.y);
+        stave.show_bar_ruler = true;
+        assert_eq!(
+            stave.keys_y_range().min,
+            stave.view_rect.min.y + BAR_RULER_HEIGHT
+        );
+        assert_eq!(stave.velocity_from_y(lane.min.y), MAX_LEVEL);
+        assert_eq!(stave.velocity_from_y(lane.max.y + 10.0), 1);
+
+        stave.note_selection.selected = HashSet::from([1, 2]);
+        assert_eq!(stave.velocity_drag_selection(2), HashSet::from([1, 2]));
+        assert_eq!(stave.velocity_drag_selection(3), HashSet::from([3]));
+    }
+}
@@ -0,0 +1,75 @@
+// Ties/slurs between notes. These are visual annotations only, they are not track events,
+// so playback and export never see them.
+
+use std::collections::{BTreeSet, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::track::{EventId, Track, TrackEventType};
+
+/// Pairs of tied notes, (earlier, later).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ties {
+    pairs: BTreeSet<(EventId, EventId)>,
+}
+
+impl Ties {
+    /// Tie every selected note to the next selected one, returns false if nothing is added.
+    pub fn tie_selected(&mut self, track: &Track, selection: &HashSet<EventId>) -> bool {
+        let notes: Vec<EventId> = track
+            .events
+            .iter()
+            .filter(|ev| selection.contains(&ev.id) && matches!(ev.event, TrackEventType::Note(_)))
+            .map(|ev| ev.id)
+            .collect();
+        let mut changed = false;
+        for pair in notes.windows(2) {
+            changed |= self.pairs.insert((pair[0], pair[1]));
+        }
+        changed
+    }
+
+    /// Remove ties of the selected notes, returns false if there were none.
+    pub fn untie_selected(&mut self, selection: &HashSet<EventId>) -> bool {
+        let before = self.pairs.len();
+        self.pairs
+            .retain(|(a, b)| !selection.contains(a) && !selection.contains(b));
+        self.pairs.len() != before
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(EventId, EventId)> {
+        self.pairs.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{EventSource, Transpose};
+    use crate::track::note_event;
+    use crate::track_source::TrackSource;
+    use std::sync::Arc;
+    use sync_cow::SyncCow;
+
+    #[test]
+    fn tie_and_untie() {
+        let note = |id, at| note_event(id, at, 60, 100);
+        let track = Track {
+            events: vec![note(1, 0), note(2, 100), note(3, 200), note(4, 300)],
+        };
+        let mut ties = Ties::default();
+        assert!(ties.tie_selected(&track, &HashSet::from([1, 2, 4])));
+        assert_eq!(ties.iter().collect::<Vec<_>>(), vec![&(1, 2), &(2, 4)]);
+        assert!(!ties.tie_selected(&track, &HashSet::from([1, 2])));
+        assert!(!ties.tie_selected(&track, &HashSet::from([3])));
+        assert!(ties.untie_selected(&HashSet::from([4])));
+        assert_eq!(ties.iter().collect::<Vec<_>>(), vec![&(1, 2)]);
+        assert!(!ties.untie_selected(&HashSet::from([3])));
+
+        // The track is not changed, so playback is the same.
+        let mut source =
+            TrackSource::new(Arc::new(SyncCow::new(track.clone())), Transpose::default());
+        source.seek(&0);
+        assert_eq!(source.next(&1000).len(), 2 * track.events.len());
+    }
+}
@@ -1,15 +1,17 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
 use std::path::PathBuf;
+use std::thread;
 
-use midly::num::u4;
-use midly::{MidiMessage, TrackEventKind};
+use midly::{MetaMessage, MidiMessage, TrackEventKind};
 use serde::{Deserialize, Serialize};
 
 use crate::changeset::{EventAction, EventActionsList, Snapshot};
 use crate::common::Time;
+use crate::meter::TimeSignature;
 use crate::midi;
+use crate::midi::TempoMap;
 use crate::range::{Range, RangeLike};
 use crate::util::IdSeq;
 
@@ -23,19 +25,36 @@ pub const MAX_LEVEL: Level = 127; // Should be equal to u7::max_value().as_int()
 
 #[allow(dead_code)]
 pub const MIDI_CC_MODWHEEL_ID: ControllerId = 1;
+pub const MIDI_CC_VOLUME_ID: ControllerId = 7;
+pub const MIDI_CC_PAN_ID: ControllerId = 10;
 // Damper pedal
 pub const MIDI_CC_SUSTAIN_ID: ControllerId = 64;
 
+/// Metronome click every quarter note, written to exported time signatures.
+const MIDI_CLOCKS_PER_CLICK: u8 = 24;
+const NOTATED_32NDS_PER_QUARTER: u8 = 8;
+
 pub fn is_cc_switch_on(x: Level) -> bool {
     // Not using crappy SLP3-D anyway.
     x >= 64
 }
 
+/// On/off pedals: sustain, portamento, sostenuto, soft, legato and hold 2.
+pub fn is_cc_switch(cc_id: ControllerId) -> bool {
+    (64..=69).contains(&cc_id)
+}
+
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 pub struct Note {
     pub pitch: Pitch,
     pub velocity: Level,
     pub duration: Time,
+    /// MIDI channel the note is exported to, allows to keep separate voices in one track.
+    #[serde(default)]
+    pub channel: ChannelId,
+    /// Protected from selection-based edits.
+    #[serde(default)]
+    pub locked: bool,
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
@@ -56,6 +75,8 @@ pub enum TrackEventType {
     Controller(ControllerSetValue),
     Bookmark,
     Marker(MarkerType),
+    /// Meter change, starts a new bar.
+    TimeSignature(TimeSignature),
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -73,12 +94,17 @@ impl TrackEvent {
         }
     }
 
+    pub fn is_locked(&self) -> bool {
+        matches!(&self.event, TrackEventType::Note(n) if n.locked)
+    }
+
     pub fn intersects(&self, time_range: &Range<Time>) -> bool {
         match &self.event {
             TrackEventType::Note(n) => time_range.intersects(&(self.at, self.at + n.duration)),
             TrackEventType::Bookmark
             | TrackEventType::Controller(_)
-            | TrackEventType::Marker(_) => time_range.contains(&self.at),
+            | TrackEventType::Marker(_)
+            | TrackEventType::TimeSignature(_) => time_range.contains(&self.at),
         }
     }
 }
@@ -95,8 +121,11 @@ impl Ord for TrackEvent {
         Ordering events by track time, while ensuring sorting always produces the same
         sequence every time. The time ordering is important for playback and editing while unique
         sort order ensures we do not have any surprises when changing or serializing the track.
+        Same-time events are ordered by id so an event keeps its place when only its content
+        (e.g. pitch or velocity) changes. Ids are unique within a track, comparing the
+        content only keeps the ordering consistent with Eq.
         */
-        (self.at, &self.event, self.id).cmp(&(other.at, &other.event, other.id))
+        (self.at, self.id, &self.event).cmp(&(other.at, other.id, &other.event))
     }
 }
 
@@ -110,6 +139,8 @@ pub struct Track {
 impl Track {
     pub fn reset(&mut self, snapshot: Snapshot) {
         self.events = snapshot.events;
+        // Snapshots stored with an earlier event ordering are not sorted by the current one.
+        self.events.sort();
     }
 
     fn index_events(&self) -> HashMap<EventId, TrackEvent> {
@@ -154,6 +185,40 @@ impl Track {
         self.commit();
     }
 
+    /// Notes that sound at the given moment.
+    pub fn notes_active_at(&self, at: Time) -> impl Iterator<Item = &TrackEvent> {
+        self.starting_before(at + 1)
+            .filter(move |ev| ev.is_active_at(at))
+    }
+
+    /// Notes that sound at some moment of the range, in track order.
+    pub fn notes_in_range(&self, range: &Range<Time>) -> impl Iterator<Item = &TrackEvent> {
+        let range = *range;
+        self.starting_before(range.1)
+            .filter(move |ev| match &ev.event {
+                TrackEventType::Note(n) => range.0 < ev.at + n.duration,
+                _ => false,
+            })
+    }
+
+    /// Events that start before the moment. Events are ordered by time, so the later ones
+    /// are skipped without looking at them.
+    fn starting_before(&self, at: Time) -> impl Iterator<Item = &TrackEvent> {
+        let end = self.events.partition_point(|ev| ev.at < at);
+        self.events[..end].iter()
+    }
+
+    /// Controllers that have at least one value change in the track.
+    pub fn controller_ids(&self) -> BTreeSet<ControllerId> {
+        self.events
+            .iter()
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Controller(cc) => Some(cc.controller_id),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn max_time(&self) -> Time {
         // Looks cumbersome. Maybe this is a case for handling MIDI (-like) events directly (see README).
         let mut result = 0;
@@ -163,6 +228,7 @@ impl Track {
                 TrackEventType::Controller(_) => ev.at,
                 TrackEventType::Bookmark => ev.at,
                 TrackEventType::Marker(_) => ev.at,
+                TrackEventType::TimeSignature(_) => ev.at,
             };
             result = Time::max(result, end_time);
         }
@@ -170,50 +236,111 @@ impl Track {
     }
 }
 
+/// Pairs note on and off messages into notes. Controller changes pass through as they are.
+#[derive(Debug, Default)]
+pub struct NotePairing {
+    ons: HashMap<(ChannelId, Pitch), (Time, Level)>,
+}
+
+impl NotePairing {
+    /// The event that is complete with this message, if any.
+    pub fn add(
+        &mut self,
+        id_seq: &IdSeq,
+        at: Time,
+        channel: ChannelId,
+        message: &MidiMessage,
+    ) -> Option<TrackEvent> {
+        match *message {
+            // Zero velocity "on" is an "off" by convention.
+            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                self.ons.insert(
+                    (channel, key.as_int() as Pitch),
+                    (at, vel.as_int() as Level),
+                );
+                None
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                let pitch = key.as_int() as Pitch;
+                let Some((t, velocity)) = self.ons.remove(&(channel, pitch)) else {
+                    log::warn!("NoteOff event without NoteOn {:?}", message);
+                    return None;
+                };
+                Some(TrackEvent {
+                    id: id_seq.next(),
+                    at: t,
+                    event: TrackEventType::Note(Note {
+                        duration: at - t,
+                        pitch,
+                        velocity,
+                        channel,
+                        locked: false,
+                    }),
+                })
+            }
+            MidiMessage::Controller { controller, value } => Some(TrackEvent {
+                id: id_seq.next(),
+                at,
+                event: TrackEventType::Controller(ControllerSetValue {
+                    controller_id: controller.into(),
+                    value: value.into(),
+                }),
+            }),
+            _ => {
+                log::trace!("Event ignored {:?}", message);
+                None
+            }
+        }
+    }
+
+    /// End the notes that are still on at the instant.
+    pub fn finish(&mut self, id_seq: &IdSeq, at: Time) -> Vec<TrackEvent> {
+        let mut ons: Vec<_> = self.ons.drain().collect();
+        ons.sort();
+        ons.into_iter()
+            .map(|((channel, pitch), (t, velocity))| TrackEvent {
+                id: id_seq.next(),
+                at: t,
+                event: TrackEventType::Note(Note {
+                    duration: at.saturating_sub(t),
+                    pitch,
+                    velocity,
+                    channel,
+                    locked: false,
+                }),
+            })
+            .collect()
+    }
+}
+
 pub fn from_midi_events(
     id_seq: &IdSeq,
     events: Vec<midly::TrackEvent<'static>>,
-    tick_duration: Time,
+    tempo_map: &TempoMap,
 ) -> Vec<TrackEvent> {
     // TODO The offset calculations are very similar to ones in the engine. Can these be shared?
-    let mut ons: HashMap<Pitch, (Time, MidiMessage)> = HashMap::new();
+    let mut pairing = NotePairing::default();
     let mut track_events = vec![];
-    let mut at: Time = 0;
+    let mut tick = 0u64;
     for ev in events {
-        at += ev.delta.as_int() as Time * tick_duration;
+        tick += ev.delta.as_int() as u64;
+        let at = tempo_map.time(tick);
         match ev.kind {
-            TrackEventKind::Midi { message, .. } => match message {
-                MidiMessage::NoteOn { key, .. } => {
-                    ons.insert(key.as_int() as Pitch, (at, message));
-                }
-                MidiMessage::NoteOff { key, .. } => {
-                    let on = ons.remove(&(key.as_int() as Pitch));
-                    match on {
-                        Some((t, MidiMessage::NoteOn { key, vel })) => {
-                            track_events.push(TrackEvent {
-                                id: id_seq.next(),
-                                at: t,
-                                event: TrackEventType::Note(Note {
-                                    duration: at - t,
-                                    pitch: key.as_int() as Pitch,
-                                    velocity: vel.as_int() as Level,
-                                }),
-                            });
-                        }
-                        None => log::warn!("NoteOff event without NoteOn {:?}", ev),
-                        _ => panic!("ERROR Unexpected state: {:?} event in \"on\" queue.", on),
-                    }
-                }
-                MidiMessage::Controller { controller, value } => track_events.push(TrackEvent {
+            TrackEventKind::Midi { channel, message } => {
+                track_events.extend(pairing.add(id_seq, at, channel.as_int(), &message))
+            }
+            TrackEventKind::Meta(MetaMessage::TimeSignature(beats, beat_unit_pow2, _, _))
+                if beats > 0 && beat_unit_pow2 <= 6 =>
+            {
+                track_events.push(TrackEvent {
                     id: id_seq.next(),
                     at,
-                    event: TrackEventType::Controller(ControllerSetValue {
-                        controller_id: controller.into(),
-                        value: value.into(),
+                    event: TrackEventType::TimeSignature(TimeSignature {
+                        beats,
+                        beat_unit: 1 << beat_unit_pow2,
                     }),
-                }),
-                _ => log::trace!("Event ignored {:?}", ev),
-            },
+                })
+            }
             _ => (),
         };
     }
@@ -222,26 +349,104 @@ pub fn from_midi_events(
     track_events
 }
 
-pub fn import_smf(id_seq: &IdSeq, file_path: &PathBuf) -> Vec<TrackEvent> {
-    let data = std::fs::read(&file_path).unwrap();
-    let events = midi::load_smf(&data);
-    from_midi_events(&id_seq, events.0, events.1 as Time)
+pub fn import_smf(id_seq: &IdSeq, file_path: &PathBuf) -> Result<Vec<TrackEvent>, String> {
+    let data = std::fs::read(&file_path)
+        .map_err(|e| format!("Cannot read {}: {}", file_path.display(), e))?;
+    let events =
+        midi::load_smf(&data).map_err(|message| format!("{}: {}", file_path.display(), message))?;
+    Ok(from_midi_events(id_seq, events.0, &events.1))
+}
+
+/// Track-wide settings that are applied to an exported file.
+#[derive(Debug, Default, Clone)]
+pub struct ExportOptions {
+    /// Initial stereo position (CC 10), `None` leaves the synth's default (center).
+    pub pan: Option<Level>,
+    /// Time resolution of the file (SMF header), `None` uses the default one.
+    pub ticks_per_beat: Option<u16>,
+    /// Semitones added to pitches of the exported notes.
+    pub transpose: i8,
 }
 
-pub fn export_smf(events: &Vec<TrackEvent>, file_path: &PathBuf) {
-    let usec_per_tick = 26u32;
-    let midi_events = to_midi_events(&events, usec_per_tick);
+/// Pitch shifted by the number of semitones, kept within the MIDI range.
+pub fn transposed_pitch(pitch: Pitch, semitones: i8) -> Pitch {
+    (pitch as i16 + semitones as i16).clamp(0, MAX_LEVEL as i16) as Pitch
+}
+
+pub fn export_smf(
+    events: &Vec<TrackEvent>,
+    options: &ExportOptions,
+    file_path: &PathBuf,
+) -> Result<(), String> {
+    let ticks_per_beat = options
+        .ticks_per_beat
+        .unwrap_or(midi::DEFAULT_TICKS_PER_BEAT);
+    let usec_per_tick = midi::export_usec_per_tick(ticks_per_beat);
+    let mut midi_events = if options.transpose == 0 {
+        to_midi_events(events, usec_per_tick)
+    } else {
+        let transposed = events
+            .iter()
+            .map(|ev| {
+                let mut ev = ev.clone();
+                if let TrackEventType::Note(note) = &mut ev.event {
+                    note.pitch = transposed_pitch(note.pitch, options.transpose);
+                }
+                ev
+            })
+            .collect();
+        to_midi_events(&transposed, usec_per_tick)
+    };
+    if let Some(pan) = options.pan {
+        let pans = controller_channels(events)
+            .into_iter()
+            .map(|channel| midly::TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::Controller {
+                        controller: MIDI_CC_PAN_ID.into(),
+                        value: pan.into(),
+                    },
+                },
+            });
+        midi_events.splice(0..0, pans);
+    }
     let mut binary = Vec::new();
-    midi::serialize_smf(midi_events, usec_per_tick, &mut binary).expect("Cannot store SMF track.");
-    std::fs::write(&file_path, binary).expect(&*format!("Cannot save to {}", &file_path.display()));
+    midi::serialize_smf(midi_events, ticks_per_beat, &mut binary)
+        .map_err(|e| format!("Cannot store SMF track: {}", e))?;
+    std::fs::write(file_path, binary)
+        .map_err(|e| format!("Cannot save to {}: {}", file_path.display(), e))
+}
+
+/// Same as [export_smf] but on a separate thread, so large tracks do not stall the UI.
+/// `on_done` receives the file path or an error message.
+pub fn export_smf_in_background(
+    events: Vec<TrackEvent>,
+    options: ExportOptions,
+    file_path: PathBuf,
+    on_done: impl FnOnce(Result<PathBuf, String>) + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || on_done(export_smf(&events, &options, &file_path).map(|_| file_path)))
+}
+
+/// Channels that controller changes go to: 0, where they always were, and every channel
+/// the notes are on, so reassigned voices keep the pedal and volume.
+pub fn controller_channels(events: &[TrackEvent]) -> BTreeSet<ChannelId> {
+    let mut channels = BTreeSet::from([0]);
+    channels.extend(events.iter().filter_map(|ev| match &ev.event {
+        TrackEventType::Note(note) => Some(note.channel),
+        _ => None,
+    }));
+    channels
 }
 
 /// Reverse of from_midi_events
 pub fn to_midi_events(
     events: &Vec<TrackEvent>,
-    usec_per_tick: u32,
+    usec_per_tick: f64,
 ) -> Vec<midly::TrackEvent<'static>> {
-    let channel = u4::from(0); // Channel hard coded.
+    let controller_channels = controller_channels(events);
     let mut buffer: Vec<(Time, TrackEventKind)> = vec![];
     for ev in events {
         match &ev.event {
@@ -249,7 +454,7 @@ pub fn to_midi_events(
                 buffer.push((
                     ev.at,
                     TrackEventKind::Midi {
-                        channel,
+                        channel: n.channel.into(),
                         message: MidiMessage::NoteOn {
                             key: n.pitch.into(),
                             vel: n.velocity.into(),
@@ -259,7 +464,7 @@ pub fn to_midi_events(
                 buffer.push((
                     ev.at + n.duration,
                     TrackEventKind::Midi {
-                        channel,
+                        channel: n.channel.into(),
                         message: MidiMessage::NoteOff {
                             key: n.pitch.into(),
                             vel: n.velocity.into(),
@@ -268,15 +473,28 @@ pub fn to_midi_events(
                 ));
             }
             TrackEventType::Controller(v) => {
+                for channel in &controller_channels {
+                    buffer.push((
+                        ev.at,
+                        TrackEventKind::Midi {
+                            channel: (*channel).into(),
+                            message: MidiMessage::Controller {
+                                controller: v.controller_id.into(),
+                                value: v.value.into(),
+                            },
+                        },
+                    ));
+                }
+            }
+            TrackEventType::TimeSignature(sig) => {
                 buffer.push((
                     ev.at,
-                    TrackEventKind::Midi {
-                        channel,
-                        message: MidiMessage::Controller {
-                            controller: v.controller_id.into(),
-                            value: v.value.into(),
-                        },
-                    },
+                    TrackEventKind::Meta(MetaMessage::TimeSignature(
+                        sig.beats,
+                        sig.beat_unit.trailing_zeros() as u8,
+                        MIDI_CLOCKS_PER_CLICK,
+                        NOTATED_32NDS_PER_QUARTER,
+                    )),
                 ));
             }
             // Non MIDI events.
@@ -286,35 +504,433 @@ pub fn to_midi_events(
     }
     buffer.sort_by_key(|(at, _)| at.to_owned());
     let mut midi_events = vec![];
-    let mut running_at: Time = 0;
+    // Deltas are taken between rounded absolute ticks, so rounding errors do not accumulate.
+    let mut running_tick: u32 = 0;
     for (at, kind) in buffer {
+        let tick = (at.max(0) as f64 / usec_per_tick).round() as u32;
         midi_events.push(midly::TrackEvent {
-            delta: (((at - running_at) as f64 / usec_per_tick as f64) as u32).into(),
+            delta: (tick - running_tick).into(),
             kind,
         });
-        running_at = at;
+        running_tick = tick;
     }
     midi_events
 }
 
+/// Unlocked note with velocity 64 on channel 0 for tests,
+/// see [TrackEvent::with_velocity] and [TrackEvent::with_channel].
+#[cfg(test)]
+pub fn note_event(id: EventId, at: Time, pitch: Pitch, duration: Time) -> TrackEvent {
+    TrackEvent {
+        id,
+        at,
+        event: TrackEventType::Note(Note {
+            pitch,
+            velocity: 64,
+            duration,
+            channel: 0,
+            locked: false,
+        }),
+    }
+}
+
+#[cfg(test)]
+impl TrackEvent {
+    pub fn with_velocity(mut self, velocity: Level) -> TrackEvent {
+        if let TrackEventType::Note(note) = &mut self.event {
+            note.velocity = velocity;
+        }
+        self
+    }
+
+    pub fn with_channel(mut self, channel: ChannelId) -> TrackEvent {
+        if let TrackEventType::Note(note) = &mut self.event {
+            note.channel = channel;
+        }
+        self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use midly::live::LiveEvent;
+    use midly::num::u4;
 
     #[test]
     fn track_load() {
         let id_seq = IdSeq::new(0);
         let path_short = PathBuf::from("./test/files/short.mid");
-        let events = import_smf(&id_seq, &path_short);
+        let events = import_smf(&id_seq, &path_short).unwrap();
         assert_eq!(events.len(), 10);
         let path_exported = PathBuf::from("./target/test_track_load.mid");
-        export_smf(&events, &path_exported);
+        export_smf(&events, &ExportOptions::default(), &path_exported).unwrap();
 
         // The recorded SMD may have some additional system/heartbeat events,
         // so comparing the sequence only after a save.
         let id_seq = IdSeq::new(0);
-        let events2 = import_smf(&id_seq, &path_exported);
+        let events2 = import_smf(&id_seq, &path_exported).unwrap();
         assert_eq!(events2.len(), 10);
         assert_eq!(events, events2);
     }
+
+    #[test]
+    fn note_pairing() {
+        let id_seq = IdSeq::new(0);
+        let mut pairing = NotePairing::default();
+        let message = |event| match event {
+            LiveEvent::Midi { message, .. } => message,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            pairing.add(&id_seq, 10, 0, &message(midi::note_on(0, 60, 80))),
+            None
+        );
+        assert_eq!(
+            pairing.add(&id_seq, 20, 0, &message(midi::note_on(0, 64, 70))),
+            None
+        );
+        // Zero velocity "on" ends the note.
+        let note = pairing.add(&id_seq, 30, 0, &message(midi::note_on(0, 60, 0)));
+        assert_eq!(
+            note.map(|ev| (ev.at, ev.event)),
+            Some((
+                10,
+                TrackEventType::Note(Note {
+                    pitch: 60,
+                    velocity: 80,
+                    duration: 20,
+                    channel: 0,
+                    locked: false,
+                })
+            ))
+        );
+        assert_eq!(
+            pairing.add(&id_seq, 40, 0, &message(midi::note_off(0, 60, 0))),
+            None
+        );
+        let held = pairing.finish(&id_seq, 50);
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].at, 20);
+        assert!(matches!(&held[0].event, TrackEventType::Note(n) if n.duration == 30));
+        assert!(pairing.finish(&id_seq, 60).is_empty());
+    }
+
+    #[test]
+    fn reset_sorts_old_snapshots() {
+        // Same-time events used to be ordered by their content rather than by id.
+        let snapshot = Snapshot {
+            version: 0,
+            events: vec![note_event(2, 100, 60, 10), note_event(1, 100, 70, 10)],
+        };
+        let mut track = Track::default();
+        track.reset(snapshot);
+        track.commit();
+        assert_eq!(
+            track.events.iter().map(|ev| ev.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn pitch_change_keeps_order() {
+        let mut track = Track::default();
+        track.insert_event(note_event(1, 100, 70, 10));
+        track.insert_event(note_event(2, 100, 60, 10));
+        track.insert_event(note_event(3, 100, 65, 10));
+        track.insert_event(note_event(4, 50, 80, 10));
+        let ids = |track: &Track| track.events.iter().map(|ev| ev.id).collect::<Vec<_>>();
+        assert_eq!(ids(&track), vec![4, 1, 2, 3]);
+
+        let before = track.events[1].clone();
+        let mut after = before.clone();
+        if let TrackEventType::Note(n) = &mut after.event {
+            n.pitch = 20;
+            n.velocity = 100;
+        }
+        track.patch(&vec![EventAction::Update(before, after)]);
+        assert_eq!(ids(&track), vec![4, 1, 2, 3]);
+
+        let before = track.events[3].clone();
+        let mut after = before.clone();
+        after.at = 50;
+        track.patch(&vec![EventAction::Update(before, after)]);
+        assert_eq!(ids(&track), vec![3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn import_controllers() {
+        let cc = |controller: u8, value: u8| midly::TrackEvent {
+            delta: 10.into(),
+            kind: TrackEventKind::Midi {
+                channel: u4::from(0),
+                message: MidiMessage::Controller {
+                    controller: controller.into(),
+                    value: value.into(),
+                },
+            },
+        };
+        let id_seq = IdSeq::new(0);
+        let track = Track {
+            events: from_midi_events(
+                &id_seq,
+                vec![cc(11, 100), cc(64, 127), cc(11, 90)],
+                &TempoMap::constant(1.0),
+            ),
+        };
+        assert_eq!(track.events.len(), 3);
+        assert_eq!(
+            track.events[2].event,
+            TrackEventType::Controller(ControllerSetValue {
+                controller_id: 11,
+                value: 90,
+            })
+        );
+        assert_eq!(
+            track.controller_ids(),
+            BTreeSet::from([11, MIDI_CC_SUSTAIN_ID])
+        );
+    }
+
+    #[test]
+    fn notes_active_at() {
+        let track = Track {
+            events: vec![
+                note_event(1, 0, 60, 100),
+                note_event(2, 50, 62, 100),
+                note_event(3, 100, 64, 10),
+            ],
+        };
+        let active = |at| -> Vec<EventId> { track.notes_active_at(at).map(|ev| ev.id).collect() };
+        assert_eq!(active(0), vec![1]);
+        assert_eq!(active(60), vec![1, 2]);
+        // Note end is not included.
+        assert_eq!(active(100), vec![2, 3]);
+        assert_eq!(active(200), Vec::<EventId>::new());
+    }
+
+    #[test]
+    fn notes_in_range() {
+        let track = Track {
+            events: vec![
+                note_event(1, 0, 60, 1000),
+                note_event(2, 50, 62, 100),
+                TrackEvent {
+                    id: 3,
+                    at: 120,
+                    event: TrackEventType::Bookmark,
+                },
+                note_event(4, 150, 64, 10),
+                note_event(5, 300, 65, 10),
+            ],
+        };
+        let notes =
+            |range| -> Vec<EventId> { track.notes_in_range(&range).map(|ev| ev.id).collect() };
+        assert_eq!(notes((0, 10)), vec![1]);
+        assert_eq!(notes((100, 200)), vec![1, 2, 4]);
+        // Ends are not included.
+        assert_eq!(notes((150, 300)), vec![1, 4]);
+        assert_eq!(notes((1000, 2000)), Vec::<EventId>::new());
+        assert_eq!(notes((-100, 0)), Vec::<EventId>::new());
+        assert_eq!(notes((160, 160)), vec![1]);
+    }
+
+    #[test]
+    fn export_pan() {
+        let id_seq = IdSeq::new(0);
+        let events = import_smf(&id_seq, &PathBuf::from("./test/files/short.mid")).unwrap();
+        let path_exported = PathBuf::from("./target/test_export_pan.mid");
+        let options = ExportOptions {
+            pan: Some(20),
+            ..Default::default()
+        };
+        export_smf(&events, &options, &path_exported).unwrap();
+
+        let (midi_events, _) = midi::load_smf(&std::fs::read(&path_exported).unwrap()).unwrap();
+        assert_eq!(midi_events[0].delta, 0);
+        assert_eq!(
+            midi_events[0].kind,
+            TrackEventKind::Midi {
+                channel: u4::from(0),
+                message: MidiMessage::Controller {
+                    controller: MIDI_CC_PAN_ID.into(),
+                    value: 20.into(),
+                },
+            }
+        );
+        // Notes on other channels get the pan too.
+        let mut events = events;
+        events.push(note_event(1_000_000, 0, 60, 500).with_channel(3));
+        export_smf(&events, &options, &path_exported).unwrap();
+        let (midi_events, _) = midi::load_smf(&std::fs::read(&path_exported).unwrap()).unwrap();
+        let pan_channels: Vec<u8> = midi_events
+            .iter()
+            .filter_map(|ev| match ev.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::Controller { controller, value },
+                } if controller == MIDI_CC_PAN_ID => {
+                    assert_eq!(value, 20);
+                    Some(channel.as_int())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pan_channels, vec![0, 3]);
+        // Center pan is not written.
+        export_smf(&events, &ExportOptions::default(), &path_exported).unwrap();
+        let (midi_events, _) = midi::load_smf(&std::fs::read(&path_exported).unwrap()).unwrap();
+        assert!(!midi_events.iter().any(|ev| matches!(
+            ev.kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::Controller { controller, .. },
+                ..
+            } if controller == MIDI_CC_PAN_ID
+        )));
+    }
+
+    #[test]
+    fn reassigned_channel_keeps_pedal() {
+        let events = vec![
+            TrackEvent {
+                id: 1,
+                at: 0,
+                event: TrackEventType::Controller(ControllerSetValue {
+                    controller_id: MIDI_CC_SUSTAIN_ID,
+                    value: MAX_LEVEL,
+                }),
+            },
+            note_event(2, 1000, 60, 500).with_channel(3),
+        ];
+        let pedal_channels: Vec<u8> = to_midi_events(&events, 1.0)
+            .iter()
+            .filter_map(|ev| match ev.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::Controller { controller, .. },
+                } if controller == MIDI_CC_SUSTAIN_ID => Some(channel.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pedal_channels, vec![0, 3]);
+    }
+
+    #[test]
+    fn export_in_background() {
+        let id_seq = IdSeq::new(0);
+        let events = import_smf(&id_seq, &PathBuf::from("./test/files/short.mid")).unwrap();
+        let options = ExportOptions {
+            pan: Some(30),
+            ..Default::default()
+        };
+        let path_sync = PathBuf::from("./target/test_export_sync.mid");
+        export_smf(&events, &options, &path_sync).unwrap();
+
+        let path_background = PathBuf::from("./target/test_export_background.mid");
+        let (sender, receiver) = std::sync::mpsc::channel();
+        export_smf_in_background(
+            events.clone(),
+            options.clone(),
+            path_background.clone(),
+            move |result| sender.send(result).unwrap(),
+        )
+        .join()
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap(), Ok(path_background.clone()));
+        assert_eq!(
+            std::fs::read(&path_sync).unwrap(),
+            std::fs::read(&path_background).unwrap()
+        );
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        export_smf_in_background(
+            events,
+            options,
+            PathBuf::from("./target/no-such-directory/test.mid"),
+            move |result| sender.send(result).unwrap(),
+        )
+        .join()
+        .unwrap();
+        assert!(receiver.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn time_signature_midi_round_trip() {
+        let events = vec![
+            note_event(1, 0, 60, 1000),
+            TrackEvent {
+                id: 2,
+                at: 2000,
+                event: TrackEventType::TimeSignature(TimeSignature {
+                    beats: 6,
+                    beat_unit: 8,
+                }),
+            },
+        ];
+        let midi_events = to_midi_events(&events, 10.0);
+        let id_seq = IdSeq::new(0);
+        let imported = from_midi_events(&id_seq, midi_events, &TempoMap::constant(10.0));
+        assert_eq!(
+            imported.iter().map(|ev| &ev.event).collect::<Vec<_>>(),
+            events.iter().map(|ev| &ev.event).collect::<Vec<_>>()
+        );
+        assert_eq!(imported[1].at, 2000);
+    }
+
+    #[test]
+    fn note_across_merged_tracks() {
+        use midly::num::{u28, u4, u7};
+        let event = |delta: u32, message| midly::TrackEvent {
+            delta: u28::from(delta),
+            kind: TrackEventKind::Midi {
+                channel: u4::from(0),
+                message,
+            },
+        };
+        let (key, vel) = (u7::from(60), u7::from(64));
+        let track_a = vec![
+            event(100, MidiMessage::NoteOn { key, vel }),
+            event(
+                500,
+                MidiMessage::NoteOn {
+                    key: u7::from(62),
+                    vel,
+                },
+            ),
+        ];
+        let track_b = vec![
+            event(300, MidiMessage::NoteOff { key, vel }),
+            event(
+                400,
+                MidiMessage::NoteOff {
+                    key: u7::from(62),
+                    vel,
+                },
+            ),
+        ];
+        let merged = midi::merge_tracks(&[track_a, track_b]);
+        let id_seq = IdSeq::new(0);
+        let imported = from_midi_events(&id_seq, merged, &TempoMap::constant(10.0));
+        let notes: Vec<_> = imported
+            .iter()
+            .map(|ev| match &ev.event {
+                TrackEventType::Note(n) => (ev.at, n.pitch, n.duration),
+                _ => panic!("unexpected {:?}", ev),
+            })
+            .collect();
+        assert_eq!(notes, vec![(1000, 60, 2000), (6000, 62, 1000)]);
+    }
+
+    #[test]
+    fn no_export_drift() {
+        let usec_per_tick = 26.0;
+        // Deltas that are not multiples of the tick duration.
+        let events: Vec<TrackEvent> = (0..10_000)
+            .map(|i| note_event(i, i as Time * 1_013, 60, 500))
+            .collect();
+        let midi_events = to_midi_events(&events, usec_per_tick);
+        let last_tick: u32 = midi_events.iter().map(|ev| ev.delta.as_int()).sum();
+        let expected = (events.last().unwrap().at + 500) as f64 / usec_per_tick;
+        assert!((last_tick as f64 - expected).abs() <= 1.0);
+    }
 }
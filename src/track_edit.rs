@@ -1,17 +1,20 @@
+use midly::live::LiveEvent;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::changeset::{EventAction, EventActionsList};
 use crate::common::Time;
+use crate::meter::TimeSignature;
 use crate::range::{Range, RangeLike, RangeSpan};
 use crate::stave::PIANO_KEY_LINES;
 use crate::track::{
-    is_cc_switch_on, ControllerId, ControllerSetValue, EventId, Level, MarkerType, Note, Pitch,
-    Track, TrackEvent, TrackEventType, MAX_LEVEL, MIDI_CC_SUSTAIN_ID,
+    is_cc_switch, is_cc_switch_on, ChannelId, ControllerId, ControllerSetValue, EventId, Level,
+    MarkerType, Note, NotePairing, Pitch, Track, TrackEvent, TrackEventType, MAX_LEVEL,
+    MIDI_CC_SUSTAIN_ID,
 };
-use crate::util::IdSeq;
+use crate::util::{IdSeq, Rng};
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum EditCommandType {
     ShiftTail,
     TapeInsert,
@@ -33,6 +36,31 @@ pub enum EditCommandType {
     ClearBookmark,
     SetTimeSelection,
     ClearTimeSelection,
+    // Several versions collapsed into one, see TrackHistory::compact.
+    Compacted,
+    NotesCapDuration,
+    NotesSetChannel,
+    NotesQuantize,
+    TapeDuplicate,
+    ClearTrack,
+    SetTimeSignature,
+    ClearTimeSignature,
+    InsertGap,
+    NotesAlign,
+    NotesSpread,
+    Paste,
+    NotesShuffle,
+    Script,
+    NotesLock,
+    NotesMirror,
+    Merge,
+    NotesStrum,
+    NotesPedal,
+    NotesUnpedal,
+    NotesLegato,
+    Record,
+    NotesHumanize,
+    NotesSnap,
 }
 
 /**
@@ -121,6 +149,18 @@ pub fn tape_insert(range: &Range<Time>) -> Option<AppliedCommand> {
     Some((EditCommandType::TapeInsert, diffs))
 }
 
+/// Make room of the given length at the moment, without a time selection.
+pub fn insert_gap(at: &Time, length: Time) -> Option<AppliedCommand> {
+    assert!(length >= 0);
+    Some((
+        EditCommandType::InsertGap,
+        vec![CommandDiff::TailShift {
+            at: *at,
+            delta: length,
+        }],
+    ))
+}
+
 pub fn tape_delete(track: &Track, range: &Range<Time>) -> Option<AppliedCommand> {
     let delta = range.1 - range.0;
     assert!(delta >= 0);
@@ -138,6 +178,71 @@ pub fn tape_delete(track: &Track, range: &Range<Time>) -> Option<AppliedCommand>
     })
 }
 
+/// Insert a copy of the range's notes and controller changes right after the range.
+pub fn tape_duplicate(
+    track: &Track,
+    id_seq: &IdSeq,
+    range: &Range<Time>,
+) -> Option<AppliedCommand> {
+    let delta = range.1 - range.0;
+    if delta <= 0 {
+        return None;
+    }
+    let mut patch = vec![];
+    for ev in &track.events {
+        if range.contains(&ev.at)
+            && matches!(
+                ev.event,
+                TrackEventType::Note(_) | TrackEventType::Controller(_)
+            )
+        {
+            patch.push(EventAction::Insert(TrackEvent {
+                id: id_seq.next(),
+                at: ev.at + delta,
+                event: ev.event.clone(),
+            }));
+        }
+    }
+    Some((
+        EditCommandType::TapeDuplicate,
+        vec![
+            // Events starting at the range end should also be moved.
+            CommandDiff::TailShift {
+                at: range.1 - 1,
+                delta,
+            },
+            CommandDiff::ChangeList { patch },
+        ],
+    ))
+}
+
+/// Delete the selected events together with the time range as a single edit.
+pub fn tape_delete_with_selected(
+    track: &Track,
+    range: &Range<Time>,
+    selection: &HashSet<EventId>,
+) -> Option<AppliedCommand> {
+    let delta = range.1 - range.0;
+    assert!(delta >= 0);
+    let Some(tail_shift) = checked_tail_shift(track, &range.0, &range.1, &-delta) else {
+        log::warn!(
+            "Cannot shift the tail after {:?}, nothing is deleted.",
+            range
+        );
+        return None;
+    };
+    let mut patch = vec![];
+    for ev in &track.events {
+        if ev.intersects(range) || is_editable(ev, selection) {
+            patch.push(EventAction::Delete(ev.clone()));
+        }
+    }
+    Some((
+        EditCommandType::TapeDelete,
+        vec![CommandDiff::ChangeList { patch }, tail_shift],
+    ))
+}
+
 /// `ratio` 1.0 no change, `<1.0 srink/sped-up, >1.0 extend/slow-down.
 pub fn tape_stretch(track: &Track, range: &Range<Time>, ratio: f32) -> Option<AppliedCommand> {
     // TODO (implementation) After this action the time selection length should also be
@@ -223,6 +328,11 @@ fn checked_tail_shift(track: &Track, at: &Time, after: &Time, delta: &Time) -> O
     })
 }
 
+/// Selected events may be changed unless they are locked.
+fn is_editable(ev: &TrackEvent, selection: &HashSet<EventId>) -> bool {
+    selection.contains(&ev.id) && !ev.is_locked()
+}
+
 fn edit_selected(
     track: &Track,
     selection: &HashSet<EventId>,
@@ -230,7 +340,7 @@ fn edit_selected(
 ) -> Vec<CommandDiff> {
     let mut patch = vec![];
     for ev in &track.events {
-        if selection.contains(&ev.id) {
+        if is_editable(ev, selection) {
             if let Some(action) = action(&ev) {
                 patch.push(action);
             }
@@ -262,6 +372,21 @@ fn edit_selected_notes<'a, Action: Fn(&Note) -> Option<Note>>(
     edit_selected(track, selection, &event_action)
 }
 
+pub fn clear_track(track: &Track) -> Option<AppliedCommand> {
+    if track.events.is_empty() {
+        return None;
+    }
+    let patch = track
+        .events
+        .iter()
+        .map(|ev| EventAction::Delete(ev.clone()))
+        .collect();
+    Some((
+        EditCommandType::ClearTrack,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
 pub fn delete_selected(track: &Track, selection: &HashSet<EventId>) -> Option<AppliedCommand> {
     let diff = edit_selected(track, selection, &|ev| {
         Some(EventAction::Delete(ev.clone()))
@@ -332,174 +457,1089 @@ pub fn accent_selected_notes(
     Some((EditCommandType::NotesAccent, diff))
 }
 
-pub fn add_new_note(id_seq: &IdSeq, range: &Range<Time>, pitch: &Pitch) -> Option<AppliedCommand> {
-    let mut diff = vec![];
-    assert!(range.1 - range.0 > 0);
-    diff.push(CommandDiff::ChangeList {
-        patch: vec![EventAction::Insert(TrackEvent {
-            id: id_seq.next(),
-            at: range.0,
-            event: TrackEventType::Note(Note {
-                pitch: *pitch,
-                velocity: MAX_LEVEL / 2,
-                duration: range.1 - range.0,
-            }),
-        })],
+/// Set the reference note's velocity, the other selected notes are scaled proportionally.
+pub fn scale_selected_velocity(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    reference: EventId,
+    velocity: Level,
+) -> Option<AppliedCommand> {
+    let original = track.events.iter().find_map(|ev| match &ev.event {
+        TrackEventType::Note(note) if ev.id == reference => Some(note.velocity),
+        _ => None,
+    })?;
+    let ratio = velocity as f32 / original.max(1) as f32;
+    let diff = edit_selected(track, selection, &|ev: &TrackEvent| {
+        let TrackEventType::Note(note) = &ev.event else {
+            return None;
+        };
+        let new_velocity = if ev.id == reference {
+            velocity
+        } else {
+            scale_velocity(note.velocity, ratio)
+        };
+        if new_velocity == note.velocity {
+            return None;
+        }
+        let mut ev2 = ev.clone();
+        ev2.event = TrackEventType::Note(Note {
+            velocity: new_velocity,
+            ..note.clone()
+        });
+        Some(EventAction::Update(ev.clone(), ev2))
     });
-    Some((EditCommandType::AddNote, diff))
-}
-
-fn sustain_event(id_seq: &IdSeq, at: &Time, on: bool) -> TrackEvent {
-    TrackEvent {
-        id: id_seq.next(),
-        at: *at,
-        event: TrackEventType::Controller(ControllerSetValue {
-            controller_id: MIDI_CC_SUSTAIN_ID,
-            value: if on { MAX_LEVEL } else { 0 },
-        }),
-    }
+    Some((EditCommandType::NotesAccent, diff))
 }
 
-pub fn set_damper(
-    id_seq: &IdSeq,
+pub fn set_selected_notes_channel(
     track: &Track,
-    range: &Range<Time>,
-    on: bool,
+    selection: &HashSet<EventId>,
+    channel: ChannelId,
 ) -> Option<AppliedCommand> {
-    let mut patch = vec![];
-    let on_before = is_cc_switch_on(cc_value_at(&track.events, &range.0, &MIDI_CC_SUSTAIN_ID));
-    let on_after = is_cc_switch_on(cc_value_at(
-        &track.events,
-        &(range.1 + 1),
-        &MIDI_CC_SUSTAIN_ID,
-    ));
-
-    clear_cc_events(track, range, MIDI_CC_SUSTAIN_ID, &mut patch);
-    if on {
-        if !on_before {
-            let on_ev = sustain_event(&id_seq, &range.0, true);
-            patch.push(EventAction::Insert(on_ev));
-        }
-        if !on_after {
-            let off_ev = sustain_event(&id_seq, &range.1, false);
-            patch.push(EventAction::Insert(off_ev));
+    assert!(channel < 16);
+    let diff = edit_selected_notes(track, selection, &|note: &Note| {
+        if note.channel == channel {
+            None
+        } else {
+            let mut note = note.clone();
+            note.channel = channel;
+            Some(note)
         }
+    });
+    Some((EditCommandType::NotesSetChannel, diff))
+}
+
+/// Grid position with index `i`, odd positions are delayed by `swing` (fraction of the grid step).
+fn swing_grid_time(i: Time, grid: Time, swing: f32) -> Time {
+    let delay = if i.rem_euclid(2) == 1 {
+        (grid as f32 * swing) as Time
     } else {
-        if on_before {
-            let off_ev = sustain_event(&id_seq, &range.0, false);
-            patch.push(EventAction::Insert(off_ev));
-        }
-        if on_after {
-            let on_ev = sustain_event(&id_seq, &range.1, true);
-            patch.push(EventAction::Insert(on_ev));
-        }
-    }
+        0
+    };
+    i * grid + delay
+}
 
-    Some((
-        EditCommandType::SetDamper,
-        vec![CommandDiff::ChangeList { patch }],
-    ))
+/// Grid position closest to `at`.
+pub fn quantized_time(at: Time, grid: Time, swing: f32) -> Time {
+    let i = at.div_euclid(grid);
+    (i - 1..=i + 1)
+        .map(|i| swing_grid_time(i, grid, swing))
+        .min_by_key(|t| (t - at).abs())
+        .unwrap()
 }
 
-fn clear_cc_events(
+/// Move starts of the selected notes to the closest grid positions.
+/// The grid starts at `origin`, `swing` delays every other grid position, 0.0 is straight quantize.
+pub fn quantize_selected_notes(
     track: &Track,
-    range: &Range<Time>,
-    cc_id: ControllerId,
-    patch: &mut Vec<EventAction>,
-) {
-    for ev in &track.events {
-        if range.contains(&ev.at) {
-            if let TrackEventType::Controller(cc) = &ev.event {
-                if cc.controller_id == cc_id {
-                    patch.push(EventAction::Delete(ev.clone()));
-                }
+    selection: &HashSet<EventId>,
+    grid: Time,
+    swing: f32,
+    origin: Time,
+) -> Option<AppliedCommand> {
+    assert!(grid > 0);
+    assert!((0.0..1.0).contains(&swing));
+    let diff = edit_selected(track, selection, &|ev| {
+        if let TrackEventType::Note(_) = &ev.event {
+            let delta = origin + quantized_time(ev.at - origin, grid, swing) - ev.at;
+            if delta != 0 {
+                return Some(shift_event(ev, &delta));
             }
         }
-    }
+        None
+    });
+    Some((EditCommandType::NotesQuantize, diff))
 }
 
-fn cc_value_at(events: &Vec<TrackEvent>, at: &Time, cc_id: &ControllerId) -> Level {
-    let mut idx = events.partition_point(|x| x.at < *at);
-    while idx > 0 {
-        idx -= 1;
-        if let Some(ev) = events.get(idx) {
-            if let TrackEventType::Controller(cc) = &ev.event {
-                if cc.controller_id == *cc_id {
-                    return cc.value;
-                }
+/// Move starts of the selected notes to the closest grid positions, only the ones that are
+/// within `tolerance` of the grid. Notes that are further away are left as they are.
+pub fn snap_selected_notes(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    grid: Time,
+    tolerance: Time,
+    origin: Time,
+) -> Option<AppliedCommand> {
+    assert!(grid > 0);
+    let diff = edit_selected(track, selection, &|ev| {
+        if let TrackEventType::Note(_) = &ev.event {
+            let delta = origin + quantized_time(ev.at - origin, grid, 0.0) - ev.at;
+            if delta != 0 && delta.abs() <= tolerance {
+                return Some(shift_event(ev, &delta));
             }
         }
-    }
-    return 0; // default
+        None
+    });
+    Some((EditCommandType::NotesSnap, diff))
 }
 
-/// Lookup a bookmark at the exact given time.
-pub fn bookmark_at(track: &Track, at: &Time) -> Option<TrackEvent> {
-    track
-        .events
-        .iter()
-        .find(|ev| ev.at == *at && ev.event == TrackEventType::Bookmark)
-        .cloned()
+/// Move starts of the selected notes to `at`, or to the earliest selected note start
+/// if it is not given. Durations stay.
+pub fn align_selected_notes(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    at: Option<Time>,
+) -> Option<AppliedCommand> {
+    let is_selected_note = |ev: &&TrackEvent| {
+        is_editable(ev, selection) && matches!(ev.event, TrackEventType::Note(_))
+    };
+    let at = at.or_else(|| {
+        track
+            .events
+            .iter()
+            .filter(is_selected_note)
+            .map(|ev| ev.at)
+            .min()
+    })?;
+    let diff = edit_selected(track, selection, &|ev| {
+        if is_selected_note(&ev) && ev.at != at {
+            Some(shift_event(ev, &(at - ev.at)))
+        } else {
+            None
+        }
+    });
+    Some((EditCommandType::NotesAlign, diff))
 }
 
-pub fn set_bookmark(track: &Track, id_seq: &IdSeq, at: &Time) -> Option<AppliedCommand> {
-    if bookmark_at(track, at).is_some() {
+/// Distribute start times of the selected notes evenly, keeping their order. Notes that start
+/// together (chords) move together. Without `range` the first and the last onsets stay,
+/// otherwise the onsets are spread over the range, starting at its beginning.
+pub fn spread_selected_notes(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    range: Option<Range<Time>>,
+) -> Option<AppliedCommand> {
+    let mut onsets: Vec<Time> = track
+        .events
+        .iter()
+        .filter(|ev| is_editable(ev, selection) && matches!(ev.event, TrackEventType::Note(_)))
+        .map(|ev| ev.at)
+        .collect();
+    onsets.dedup(); // Events are ordered by time.
+    if onsets.len() < 2 {
         return None;
     }
-    Some((
-        EditCommandType::SetBookmark,
-        vec![CommandDiff::ChangeList {
-            patch: vec![EventAction::Insert(TrackEvent {
-                id: id_seq.next(),
-                at: *at,
-                event: TrackEventType::Bookmark,
-            })],
-        }],
-    ))
+    let (start, step) = match range {
+        Some(range) => (range.0, (range.1 - range.0) as f64 / onsets.len() as f64),
+        None => (
+            onsets[0],
+            (onsets[onsets.len() - 1] - onsets[0]) as f64 / (onsets.len() - 1) as f64,
+        ),
+    };
+    let diff = edit_selected(track, selection, &|ev| {
+        if !matches!(ev.event, TrackEventType::Note(_)) {
+            return None;
+        }
+        let i = onsets.binary_search(&ev.at).unwrap();
+        let delta = start + (i as f64 * step).round() as Time - ev.at;
+        if delta != 0 {
+            Some(shift_event(ev, &delta))
+        } else {
+            None
+        }
+    });
+    Some((EditCommandType::NotesSpread, diff))
 }
 
-pub fn clear_bookmark(track: &Track, at: &Time) -> Option<AppliedCommand> {
-    if let Some(bm) = bookmark_at(track, at) {
-        Some((
-            EditCommandType::ClearBookmark,
-            vec![CommandDiff::ChangeList {
-                patch: vec![EventAction::Delete(bm.clone())],
-            }],
-        ))
-    } else {
-        None
+/// Roll a chord: the selected notes ordered by pitch (from the lowest one if `upward`,
+/// from the highest otherwise) are delayed by `delta_per_note` more than the previous one.
+/// The first note stays.
+pub fn strum_selected(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    delta_per_note: Time,
+    upward: bool,
+) -> Option<AppliedCommand> {
+    let mut notes: Vec<(Pitch, EventId)> = track
+        .events
+        .iter()
+        .filter(|ev| is_editable(ev, selection))
+        .filter_map(|ev| match &ev.event {
+            TrackEventType::Note(note) => Some((note.pitch, ev.id)),
+            _ => None,
+        })
+        .collect();
+    if notes.len() < 2 {
+        return None;
     }
+    notes.sort();
+    if !upward {
+        notes.reverse();
+    }
+    let order: HashMap<EventId, Time> = notes
+        .iter()
+        .enumerate()
+        .map(|(i, (_, id))| (*id, i as Time))
+        .collect();
+    let diff = edit_selected(track, selection, &|ev| {
+        let delta = order.get(&ev.id)? * delta_per_note;
+        if delta != 0 {
+            Some(shift_event(ev, &delta))
+        } else {
+            None
+        }
+    });
+    Some((EditCommandType::NotesStrum, diff))
 }
 
-pub fn lookup_markers<'a>(track: &'a Track) -> impl Iterator<Item = &'a TrackEvent> {
-    track
+/// Make the selected notes end exactly where the next later selected note starts,
+/// filling gaps and trimming overlaps. The last notes keep their durations.
+pub fn legato_selected(track: &Track, selection: &HashSet<EventId>) -> Option<AppliedCommand> {
+    let mut onsets: Vec<Time> = track
         .events
         .iter()
-        .filter(|ev| matches!(ev.event, TrackEventType::Marker(_)))
+        .filter(|ev| is_editable(ev, selection) && matches!(ev.event, TrackEventType::Note(_)))
+        .map(|ev| ev.at)
+        .collect();
+    onsets.dedup();
+    if onsets.len() < 2 {
+        return None;
+    }
+    let diff = edit_selected(track, selection, &|ev| {
+        let TrackEventType::Note(note) = &ev.event else {
+            return None;
+        };
+        let next = onsets.get(onsets.partition_point(|&at| at <= ev.at))?;
+        let duration = next - ev.at;
+        if duration == note.duration {
+            return None;
+        }
+        let mut ev2 = ev.clone();
+        ev2.event = TrackEventType::Note(Note {
+            duration,
+            ..note.clone()
+        });
+        Some(EventAction::Update(ev.clone(), ev2))
+    });
+    Some((EditCommandType::NotesLegato, diff))
 }
 
-pub fn set_time_selection(
+/// Randomly exchange start times among the selected notes of the same pitch.
+/// The same seed gives the same result.
+pub fn shuffle_selected_notes(
     track: &Track,
-    id_seq: &IdSeq,
-    range: &Range<Time>,
+    selection: &HashSet<EventId>,
+    seed: u64,
 ) -> Option<AppliedCommand> {
-    debug_assert!(!range.is_empty());
-    let mut diffs = vec![];
-    if let Some((_, clear_actions)) = clear_time_selection(track) {
-        // Only supporting at most one selected time range for now.
-        diffs.extend(clear_actions);
+    let mut voices: BTreeMap<Pitch, Vec<(EventId, Time)>> = BTreeMap::new();
+    for ev in track.events.iter().filter(|ev| is_editable(ev, selection)) {
+        if let TrackEventType::Note(note) = &ev.event {
+            voices.entry(note.pitch).or_default().push((ev.id, ev.at));
+        }
     }
-    diffs.push(CommandDiff::ChangeList {
-        patch: vec![
-            EventAction::Insert(TrackEvent {
-                id: id_seq.next(),
-                at: range.0,
-                event: TrackEventType::Marker(MarkerType::TimeSelectionStart),
-            }),
-            EventAction::Insert(TrackEvent {
-                id: id_seq.next(),
-                at: range.1,
-                event: TrackEventType::Marker(MarkerType::TimeSelectionEnd),
+    let mut rng = Rng::new(seed);
+    let mut onsets: HashMap<EventId, Time> = HashMap::new();
+    for notes in voices.values() {
+        let mut times: Vec<Time> = notes.iter().map(|(_, at)| *at).collect();
+        for i in (1..times.len()).rev() {
+            times.swap(i, rng.below(i + 1));
+        }
+        onsets.extend(notes.iter().map(|(id, _)| *id).zip(times));
+    }
+    if onsets.len() < 2 {
+        return None;
+    }
+    let diff = edit_selected(track, selection, &|ev| {
+        let &at = onsets.get(&ev.id)?;
+        (at != ev.at).then(|| shift_event(ev, &(at - ev.at)))
+    });
+    Some((EditCommandType::NotesShuffle, diff))
+}
+
+/// Offset each selected note's start by up to `time_jitter` and velocity by up to `vel_jitter`,
+/// in either direction. Same seed gives same offsets.
+pub fn humanize_selected(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    time_jitter: Time,
+    vel_jitter: Level,
+    seed: u64,
+) -> Option<AppliedCommand> {
+    let mut rng = Rng::new(seed);
+    let mut jitter = |max: i64| {
+        if max <= 0 {
+            0
+        } else {
+            rng.below(2 * max as usize + 1) as i64 - max
+        }
+    };
+    let mut changes: HashMap<EventId, (Time, Level)> = HashMap::new();
+    for ev in track.events.iter().filter(|ev| is_editable(ev, selection)) {
+        if let TrackEventType::Note(note) = &ev.event {
+            // Both offsets are drawn for every note, so each one does not depend on the other's bound.
+            let at = (ev.at + jitter(time_jitter)).max(0);
+            let velocity = (note.velocity as i64 + jitter(vel_jitter as i64))
+                .clamp(1, MAX_LEVEL as i64) as Level;
+            if at != ev.at || velocity != note.velocity {
+                changes.insert(ev.id, (at, velocity));
+            }
+        }
+    }
+    if changes.is_empty() {
+        return None;
+    }
+    let diff = edit_selected(track, selection, &|ev| {
+        let &(at, velocity) = changes.get(&ev.id)?;
+        let TrackEventType::Note(note) = &ev.event else {
+            return None;
+        };
+        let mut ev2 = ev.clone();
+        ev2.at = at;
+        ev2.event = TrackEventType::Note(Note {
+            velocity,
+            ..note.clone()
+        });
+        Some(EventAction::Update(ev.clone(), ev2))
+    });
+    Some((EditCommandType::NotesHumanize, diff))
+}
+
+/// Lock the selected notes, or unlock them if all of them are locked already.
+pub fn toggle_lock_selected_notes(
+    track: &Track,
+    selection: &HashSet<EventId>,
+) -> Option<AppliedCommand> {
+    let notes: Vec<&TrackEvent> = track
+        .events
+        .iter()
+        .filter(|ev| selection.contains(&ev.id) && matches!(ev.event, TrackEventType::Note(_)))
+        .collect();
+    if notes.is_empty() {
+        return None;
+    }
+    let locked = !notes.iter().all(|ev| ev.is_locked());
+    let patch = notes
+        .into_iter()
+        .filter(|ev| ev.is_locked() != locked)
+        .map(|ev| {
+            let mut ev2 = ev.clone();
+            if let TrackEventType::Note(note) = &mut ev2.event {
+                note.locked = locked;
+            }
+            EventAction::Update(ev.clone(), ev2)
+        })
+        .collect();
+    Some((
+        EditCommandType::NotesLock,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+/// Note property to compare in [similar_notes].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteAttribute {
+    Pitch,
+    Velocity,
+    Duration,
+}
+
+/// Notes that have the same attribute value as the reference note (including it),
+/// `None` if there is no such note.
+pub fn similar_notes(
+    track: &Track,
+    reference: EventId,
+    attribute: NoteAttribute,
+) -> Option<HashSet<EventId>> {
+    let key = |note: &Note| match attribute {
+        NoteAttribute::Pitch => note.pitch as Time,
+        NoteAttribute::Velocity => note.velocity as Time,
+        NoteAttribute::Duration => note.duration,
+    };
+    let value = track.events.iter().find_map(|ev| match &ev.event {
+        TrackEventType::Note(note) if ev.id == reference => Some(key(note)),
+        _ => None,
+    })?;
+    Some(
+        track
+            .events
+            .iter()
+            .filter(|ev| matches!(&ev.event, TrackEventType::Note(note) if key(note) == value))
+            .map(|ev| ev.id)
+            .collect(),
+    )
+}
+
+/// Reflect onsets of the selected notes around `axis`, durations stay.
+/// Nothing is changed if a note would start before the track start.
+pub fn mirror_selected_notes(
+    track: &Track,
+    selection: &HashSet<EventId>,
+    axis: Time,
+) -> Option<AppliedCommand> {
+    let mirrored = |at: Time| 2 * axis - at;
+    let notes = || {
+        track
+            .events
+            .iter()
+            .filter(|ev| is_editable(ev, selection) && matches!(ev.event, TrackEventType::Note(_)))
+    };
+    if notes().any(|ev| mirrored(ev.at) < 0) {
+        return None;
+    }
+    let diff = edit_selected(track, selection, &|ev| {
+        if matches!(ev.event, TrackEventType::Note(_)) && mirrored(ev.at) != ev.at {
+            Some(shift_event(ev, &(mirrored(ev.at) - ev.at)))
+        } else {
+            None
+        }
+    });
+    Some((EditCommandType::NotesMirror, diff))
+}
+
+/// Time span from the earliest start to the latest end of the selected notes.
+pub fn selected_notes_range(track: &Track, selection: &HashSet<EventId>) -> Option<Range<Time>> {
+    track
+        .events
+        .iter()
+        .filter(|ev| selection.contains(&ev.id))
+        .filter_map(|ev| match &ev.event {
+            TrackEventType::Note(note) => Some((ev.at, ev.at + note.duration)),
+            _ => None,
+        })
+        .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)))
+}
+
+/// Shorten all notes that are longer than `max_duration`.
+pub fn cap_notes_duration(track: &Track, max_duration: Time) -> Option<AppliedCommand> {
+    assert!(max_duration > 0);
+    let mut patch = vec![];
+    for ev in &track.events {
+        if let TrackEventType::Note(n) = &ev.event {
+            if n.duration > max_duration {
+                let mut capped = ev.clone();
+                capped.event = TrackEventType::Note(Note {
+                    duration: max_duration,
+                    ..n.clone()
+                });
+                patch.push(EventAction::Update(ev.clone(), capped));
+            }
+        }
+    }
+    if patch.is_empty() {
+        return None;
+    }
+    Some((
+        EditCommandType::NotesCapDuration,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+pub fn add_new_note(id_seq: &IdSeq, range: &Range<Time>, pitch: &Pitch) -> Option<AppliedCommand> {
+    let mut diff = vec![];
+    assert!(range.1 - range.0 > 0);
+    diff.push(CommandDiff::ChangeList {
+        patch: vec![EventAction::Insert(TrackEvent {
+            id: id_seq.next(),
+            at: range.0,
+            event: TrackEventType::Note(Note {
+                pitch: *pitch,
+                velocity: MAX_LEVEL / 2,
+                duration: range.1 - range.0,
+                channel: 0,
+                locked: false,
+            }),
+        })],
+    });
+    Some((EditCommandType::AddNote, diff))
+}
+
+/// Copies of the selected events, in track order.
+pub fn selected_events(track: &Track, selection: &HashSet<EventId>) -> Vec<TrackEvent> {
+    track
+        .events
+        .iter()
+        .filter(|ev| selection.contains(&ev.id))
+        .cloned()
+        .collect()
+}
+
+/// Insert copies of the events with new ids, shifted so the earliest one starts at `at`.
+/// With `match_velocity` the notes' velocities are scaled so their average is the same as
+/// of the notes already in the pasted time range.
+pub fn paste_events(
+    track: &Track,
+    id_seq: &IdSeq,
+    fragment: &[TrackEvent],
+    at: Time,
+    match_velocity: bool,
+) -> Option<AppliedCommand> {
+    let start = fragment.iter().map(|ev| ev.at).min()?;
+    let end = fragment.iter().map(event_end).max()?;
+    let ratio = if match_velocity {
+        let target = (at, at + end - start);
+        velocity_ratio(
+            average_velocity(track.notes_in_range(&target)),
+            average_velocity(fragment.iter()),
+        )
+    } else {
+        1.0
+    };
+    let patch = fragment
+        .iter()
+        .map(|ev| {
+            let mut ev = TrackEvent {
+                id: id_seq.next(),
+                at: ev.at - start + at,
+                event: ev.event.clone(),
+            };
+            if let TrackEventType::Note(note) = &mut ev.event {
+                note.velocity = scale_velocity(note.velocity, ratio);
+            }
+            EventAction::Insert(ev)
+        })
+        .collect();
+    Some((
+        EditCommandType::Paste,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+/// Insert imported events with new ids, offset by `at`. Times and durations are multiplied
+/// by `time_scale`, e.g. to match the tempo of the imported file to the track's one.
+pub fn merge_events(
+    id_seq: &IdSeq,
+    events: &[TrackEvent],
+    at: Time,
+    time_scale: f64,
+) -> Option<AppliedCommand> {
+    if events.is_empty() {
+        return None;
+    }
+    let scale = |t: Time| (t as f64 * time_scale).round() as Time;
+    let patch = events
+        .iter()
+        .map(|ev| {
+            let mut ev = TrackEvent {
+                id: id_seq.next(),
+                at: at + scale(ev.at),
+                event: ev.event.clone(),
+            };
+            if let TrackEventType::Note(note) = &mut ev.event {
+                note.duration = scale(note.duration).max(1);
+            }
+            EventAction::Insert(ev)
+        })
+        .collect();
+    Some((
+        EditCommandType::Merge,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+/// Insert events played live, as (track time, event). Notes that are still held are ended at `end`.
+/// With a punch range only the events that start in it are kept, notes are cut at its end,
+/// and they replace the notes (except locked ones) and controller changes that were in the range.
+/// With `quantize` (grid step, swing, grid origin) notes are moved to the closest grid positions
+/// as [quantize_selected_notes] does.
+pub fn record_events(
+    id_seq: &IdSeq,
+    track: &Track,
+    events: &[(Time, LiveEvent<'static>)],
+    end: Time,
+    punch: Option<&Range<Time>>,
+    quantize: Option<(Time, f32, Time)>,
+) -> Option<AppliedCommand> {
+    let mut pairing = NotePairing::default();
+    let mut recorded = vec![];
+    for (at, event) in events {
+        if let LiveEvent::Midi { channel, message } = event {
+            recorded.extend(pairing.add(id_seq, *at, channel.as_int(), message));
+        }
+    }
+    recorded.extend(pairing.finish(id_seq, end));
+    if let Some((grid, swing, origin)) = quantize {
+        for ev in recorded.iter_mut() {
+            if let TrackEventType::Note(_) = ev.event {
+                ev.at = origin + quantized_time(ev.at - origin, grid, swing);
+            }
+        }
+    }
+    let mut patch = vec![];
+    if let Some(punch) = punch {
+        recorded.retain(|ev| punch.contains(&ev.at));
+        for ev in recorded.iter_mut() {
+            if let TrackEventType::Note(note) = &mut ev.event {
+                note.duration = note.duration.min(punch.1 - ev.at);
+            }
+        }
+        let replaced: HashSet<EventId> = track
+            .events
+            .iter()
+            .filter(|ev| punch.contains(&ev.at))
+            .filter(|ev| {
+                matches!(
+                    ev.event,
+                    TrackEventType::Note(_) | TrackEventType::Controller(_)
+                )
+            })
+            .map(|ev| ev.id)
+            .collect();
+        // Locked notes stay.
+        patch.extend(
+            track
+                .events
+                .iter()
+                .filter(|ev| is_editable(ev, &replaced))
+                .map(|ev| EventAction::Delete(ev.clone())),
+        );
+    }
+    if recorded.is_empty() {
+        return None;
+    }
+    recorded.sort_by_key(|ev| ev.at);
+    patch.extend(recorded.into_iter().map(EventAction::Insert));
+    Some((
+        EditCommandType::Record,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+fn event_end(ev: &TrackEvent) -> Time {
+    match &ev.event {
+        TrackEventType::Note(note) => ev.at + note.duration,
+        _ => ev.at,
+    }
+}
+
+fn average_velocity<'a>(events: impl Iterator<Item = &'a TrackEvent>) -> Option<f32> {
+    let (sum, count) = events
+        .filter_map(|ev| match &ev.event {
+            TrackEventType::Note(note) => Some(note.velocity as f32),
+            _ => None,
+        })
+        .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    (count > 0).then(|| sum / count as f32)
+}
+
+/// Velocity scale to make the fragment as loud as the target, 1 if either has no notes.
+fn velocity_ratio(target: Option<f32>, fragment: Option<f32>) -> f32 {
+    match (target, fragment) {
+        (Some(target), Some(fragment)) if fragment > 0.0 => target / fragment,
+        _ => 1.0,
+    }
+}
+
+fn scale_velocity(velocity: Level, ratio: f32) -> Level {
+    (velocity as f32 * ratio)
+        .round()
+        .clamp(1.0, MAX_LEVEL as f32) as Level
+}
+
+fn switch_event(id_seq: &IdSeq, cc_id: ControllerId, at: &Time, on: bool) -> TrackEvent {
+    TrackEvent {
+        id: id_seq.next(),
+        at: *at,
+        event: TrackEventType::Controller(ControllerSetValue {
+            controller_id: cc_id,
+            value: if on { MAX_LEVEL } else { 0 },
+        }),
+    }
+}
+
+/// Set on/off state of a switch controller (e.g. sustain pedal) in the time range,
+/// other controllers are not changed.
+pub fn set_damper(
+    id_seq: &IdSeq,
+    track: &Track,
+    cc_id: ControllerId,
+    range: &Range<Time>,
+    on: bool,
+) -> Option<AppliedCommand> {
+    if !is_cc_switch(cc_id) {
+        return None;
+    }
+    let mut patch = vec![];
+    damper_patch(id_seq, track, cc_id, range, on, &mut patch);
+    Some((
+        EditCommandType::SetDamper,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+fn damper_patch(
+    id_seq: &IdSeq,
+    track: &Track,
+    cc_id: ControllerId,
+    range: &Range<Time>,
+    on: bool,
+    patch: &mut Vec<EventAction>,
+) {
+    let on_before = is_cc_switch_on(cc_value_at(&track.events, &range.0, &cc_id));
+    let on_after = is_cc_switch_on(cc_value_at(&track.events, &(range.1 + 1), &cc_id));
+
+    clear_cc_events(track, range, cc_id, patch);
+    if on {
+        if !on_before {
+            let on_ev = switch_event(&id_seq, cc_id, &range.0, true);
+            patch.push(EventAction::Insert(on_ev));
+        }
+        if !on_after {
+            let off_ev = switch_event(&id_seq, cc_id, &range.1, false);
+            patch.push(EventAction::Insert(off_ev));
+        }
+    } else {
+        if on_before {
+            let off_ev = switch_event(&id_seq, cc_id, &range.0, false);
+            patch.push(EventAction::Insert(off_ev));
+        }
+        if on_after {
+            let on_ev = switch_event(&id_seq, cc_id, &range.1, true);
+            patch.push(EventAction::Insert(on_ev));
+        }
+    }
+}
+
+/// Flip on/off state of a switch controller from the moment on, until its next change.
+/// Toggling again at the same moment restores the previous state.
+/// Other than switch controllers are not changed.
+pub fn toggle_damper_at(
+    id_seq: &IdSeq,
+    track: &Track,
+    cc_id: ControllerId,
+    at: Time,
+) -> Option<AppliedCommand> {
+    if !is_cc_switch(cc_id) {
+        return None;
+    }
+    let on_before = is_cc_switch_on(cc_value_at(&track.events, &at, &cc_id));
+    let on = !is_cc_switch_on(cc_value_at(&track.events, &(at + 1), &cc_id));
+    let mut patch = vec![];
+    clear_cc_events(track, &(at, at + 1), cc_id, &mut patch);
+    if on != on_before {
+        patch.push(EventAction::Insert(switch_event(id_seq, cc_id, &at, on)));
+    }
+    Some((
+        EditCommandType::SetDamper,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+/// Express sustain of the selected notes with the pedal: it is held through every group
+/// of overlapping notes, and the notes longer than `duration` are shortened to it.
+/// Notes of a group ring until the group ends, as they would with the pedal.
+pub fn pedal_selected_notes(
+    id_seq: &IdSeq,
+    track: &Track,
+    selection: &HashSet<EventId>,
+    duration: Time,
+) -> Option<AppliedCommand> {
+    assert!(duration > 0);
+    let mut groups: Vec<Range<Time>> = vec![];
+    let mut patch = vec![];
+    for ev in track.events.iter().filter(|ev| is_editable(ev, selection)) {
+        let TrackEventType::Note(note) = &ev.event else {
+            continue;
+        };
+        let end = ev.at + note.duration;
+        match groups.last_mut() {
+            Some(group) if ev.at < group.1 => group.1 = group.1.max(end),
+            _ => groups.push((ev.at, end)),
+        }
+        if note.duration > duration {
+            let mut shortened = ev.clone();
+            shortened.event = TrackEventType::Note(Note {
+                duration,
+                ..note.clone()
+            });
+            patch.push(EventAction::Update(ev.clone(), shortened));
+        }
+    }
+    if groups.is_empty() {
+        return None;
+    }
+    for group in &groups {
+        damper_patch(id_seq, track, MIDI_CC_SUSTAIN_ID, group, true, &mut patch);
+    }
+    Some((
+        EditCommandType::NotesPedal,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+/// Absorb the sustain pedal into durations: the selected notes that are released
+/// with the pedal down last until the pedal is released (or their key is struck again),
+/// and the pedal is lifted over the selected notes.
+pub fn unpedal_selected_notes(
+    id_seq: &IdSeq,
+    track: &Track,
+    selection: &HashSet<EventId>,
+) -> Option<AppliedCommand> {
+    let release_after = |at: Time| {
+        track
+            .events
+            .iter()
+            .skip(track.events.partition_point(|ev| ev.at < at))
+            .find_map(|ev| match &ev.event {
+                TrackEventType::Controller(cc)
+                    if cc.controller_id == MIDI_CC_SUSTAIN_ID && !is_cc_switch_on(cc.value) =>
+                {
+                    Some(ev.at)
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| track.max_time())
+    };
+    let mut span: Option<Range<Time>> = None;
+    let mut patch = vec![];
+    for (i, ev) in track.events.iter().enumerate() {
+        if !is_editable(ev, selection) {
+            continue;
+        }
+        let TrackEventType::Note(note) = &ev.event else {
+            continue;
+        };
+        let end = ev.at + note.duration;
+        let mut new_end = end;
+        if is_cc_switch_on(cc_value_at(&track.events, &end, &MIDI_CC_SUSTAIN_ID)) {
+            let restruck = track.events[i + 1..]
+                .iter()
+                .find_map(|other| match &other.event {
+                    TrackEventType::Note(n)
+                        if other.at > ev.at
+                            && n.pitch == note.pitch
+                            && n.channel == note.channel =>
+                    {
+                        Some(other.at)
+                    }
+                    _ => None,
+                });
+            new_end = restruck
+                .map_or(release_after(end), |at| at.min(release_after(end)))
+                .max(end);
+        }
+        span = Some(span.map_or((ev.at, new_end), |(a, b)| (a.min(ev.at), b.max(new_end))));
+        if new_end != end {
+            let mut extended = ev.clone();
+            extended.event = TrackEventType::Note(Note {
+                duration: new_end - ev.at,
+                ..note.clone()
+            });
+            patch.push(EventAction::Update(ev.clone(), extended));
+        }
+    }
+    let span = span?;
+    // The pedal release at the span end is not needed anymore either.
+    damper_patch(
+        id_seq,
+        track,
+        MIDI_CC_SUSTAIN_ID,
+        &(span.0, span.1 + 1),
+        false,
+        &mut patch,
+    );
+    if patch.is_empty() {
+        return None;
+    }
+    Some((
+        EditCommandType::NotesUnpedal,
+        vec![CommandDiff::ChangeList { patch }],
+    ))
+}
+
+fn clear_cc_events(
+    track: &Track,
+    range: &Range<Time>,
+    cc_id: ControllerId,
+    patch: &mut Vec<EventAction>,
+) {
+    for ev in &track.events {
+        if range.contains(&ev.at) {
+            if let TrackEventType::Controller(cc) = &ev.event {
+                if cc.controller_id == cc_id {
+                    patch.push(EventAction::Delete(ev.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Controller value set before the moment, 0 if there is none.
+pub fn cc_value_at(events: &[TrackEvent], at: &Time, cc_id: &ControllerId) -> Level {
+    let mut idx = events.partition_point(|x| x.at < *at);
+    while idx > 0 {
+        idx -= 1;
+        if let Some(ev) = events.get(idx) {
+            if let TrackEventType::Controller(cc) = &ev.event {
+                if cc.controller_id == *cc_id {
+                    return cc.value;
+                }
+            }
+        }
+    }
+    return 0; // default
+}
+
+/// Events that start in the time range, moved so the range starts at 0.
+/// Controller values in effect at the range start (e.g. a held pedal) are prepended
+/// so the fragment sounds as it does in the track. Notes that sound at the range start
+/// are clipped to start with the range.
+pub fn region_events(track: &Track, range: &Range<Time>) -> Vec<TrackEvent> {
+    let mut events = vec![];
+    for cc_id in track.controller_ids() {
+        let value = cc_value_at(&track.events, &range.0, &cc_id);
+        if value != 0 {
+            events.push(TrackEvent {
+                id: 0,
+                at: 0,
+                event: TrackEventType::Controller(ControllerSetValue {
+                    controller_id: cc_id,
+                    value,
+                }),
+            });
+        }
+    }
+    for ev in &track.events {
+        if range.contains(&ev.at)
+            && matches!(
+                ev.event,
+                TrackEventType::Note(_) | TrackEventType::Controller(_)
+            )
+        {
+            let mut ev = ev.clone();
+            ev.at -= range.0;
+            events.push(ev);
+        } else if let TrackEventType::Note(note) = &ev.event {
+            if ev.at < range.0 && range.0 < ev.at + note.duration {
+                events.push(TrackEvent {
+                    id: ev.id,
+                    at: 0,
+                    event: TrackEventType::Note(Note {
+                        duration: ev.at + note.duration - range.0,
+                        ..note.clone()
+                    }),
+                });
+            }
+        }
+    }
+    events
+}
+
+/// Selected events, moved so the earliest of them starts at 0.
+pub fn selected_region_events(track: &Track, selection: &HashSet<EventId>) -> Vec<TrackEvent> {
+    let mut events = selected_events(track, selection);
+    if let Some(start) = events.iter().map(|ev| ev.at).min() {
+        for ev in &mut events {
+            ev.at -= start;
+        }
+    }
+    events
+}
+
+/// Lookup a bookmark at the exact given time.
+pub fn bookmark_at(track: &Track, at: &Time) -> Option<TrackEvent> {
+    track
+        .events
+        .iter()
+        .find(|ev| ev.at == *at && ev.event == TrackEventType::Bookmark)
+        .cloned()
+}
+
+pub fn set_bookmark(track: &Track, id_seq: &IdSeq, at: &Time) -> Option<AppliedCommand> {
+    if bookmark_at(track, at).is_some() {
+        return None;
+    }
+    Some((
+        EditCommandType::SetBookmark,
+        vec![CommandDiff::ChangeList {
+            patch: vec![EventAction::Insert(TrackEvent {
+                id: id_seq.next(),
+                at: *at,
+                event: TrackEventType::Bookmark,
+            })],
+        }],
+    ))
+}
+
+pub fn clear_bookmark(track: &Track, at: &Time) -> Option<AppliedCommand> {
+    if let Some(bm) = bookmark_at(track, at) {
+        Some((
+            EditCommandType::ClearBookmark,
+            vec![CommandDiff::ChangeList {
+                patch: vec![EventAction::Delete(bm.clone())],
+            }],
+        ))
+    } else {
+        None
+    }
+}
+
+/// Lookup a time signature change at the exact given time.
+pub fn time_signature_at(track: &Track, at: &Time) -> Option<TrackEvent> {
+    track
+        .events
+        .iter()
+        .find(|ev| ev.at == *at && matches!(ev.event, TrackEventType::TimeSignature(_)))
+        .cloned()
+}
+
+/// Change meter starting at the given time, replaces a change that is already there.
+pub fn set_time_signature(
+    track: &Track,
+    id_seq: &IdSeq,
+    at: &Time,
+    signature: TimeSignature,
+) -> Option<AppliedCommand> {
+    let action = match time_signature_at(track, at) {
+        Some(ev) if ev.event == TrackEventType::TimeSignature(signature) => return None,
+        Some(ev) => {
+            let mut after = ev.clone();
+            after.event = TrackEventType::TimeSignature(signature);
+            EventAction::Update(ev, after)
+        }
+        None => EventAction::Insert(TrackEvent {
+            id: id_seq.next(),
+            at: *at,
+            event: TrackEventType::TimeSignature(signature),
+        }),
+    };
+    Some((
+        EditCommandType::SetTimeSignature,
+        vec![CommandDiff::ChangeList {
+            patch: vec![action],
+        }],
+    ))
+}
+
+pub fn clear_time_signature(track: &Track, at: &Time) -> Option<AppliedCommand> {
+    time_signature_at(track, at).map(|ev| {
+        (
+            EditCommandType::ClearTimeSignature,
+            vec![CommandDiff::ChangeList {
+                patch: vec![EventAction::Delete(ev)],
+            }],
+        )
+    })
+}
+
+pub fn lookup_markers<'a>(track: &'a Track) -> impl Iterator<Item = &'a TrackEvent> {
+    track
+        .events
+        .iter()
+        .filter(|ev| matches!(ev.event, TrackEventType::Marker(_)))
+}
+
+pub fn set_time_selection(
+    track: &Track,
+    id_seq: &IdSeq,
+    range: &Range<Time>,
+) -> Option<AppliedCommand> {
+    debug_assert!(!range.is_empty());
+    let mut diffs = vec![];
+    if let Some((_, clear_actions)) = clear_time_selection(track) {
+        // Only supporting at most one selected time range for now.
+        diffs.extend(clear_actions);
+    }
+    diffs.push(CommandDiff::ChangeList {
+        patch: vec![
+            EventAction::Insert(TrackEvent {
+                id: id_seq.next(),
+                at: range.0,
+                event: TrackEventType::Marker(MarkerType::TimeSelectionStart),
+            }),
+            EventAction::Insert(TrackEvent {
+                id: id_seq.next(),
+                at: range.1,
+                event: TrackEventType::Marker(MarkerType::TimeSelectionEnd),
             }),
         ],
     });
@@ -519,6 +1559,9 @@ pub fn clear_time_selection(track: &Track) -> Option<AppliedCommand> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::midi::{controller_set, note_off, note_on};
+    use crate::track::{import_smf, note_event, to_midi_events, MIDI_CC_VOLUME_ID};
+    use std::path::PathBuf;
 
     fn make_test_track() -> Track {
         let mut events: Vec<TrackEvent> = vec![];
@@ -530,15 +1573,7 @@ mod tests {
                 value: 55,
             }),
         });
-        events.push(TrackEvent {
-            id: 20,
-            at: 14,
-            event: TrackEventType::Note(Note {
-                pitch: 10,
-                velocity: 20,
-                duration: 30,
-            }),
-        });
+        events.push(note_event(20, 14, 10, 30).with_velocity(20));
         events.push(TrackEvent {
             id: 30,
             at: 15,
@@ -571,11 +1606,120 @@ mod tests {
         assert_eq!(0, cc_value_at(&track.events, &0, &99));
     }
 
+    #[test]
+    fn check_cap_notes_duration() {
+        let mut track = make_test_track();
+        track
+            .events
+            .push(note_event(50, 30, 11, 100).with_velocity(20));
+        let applied_command = cap_notes_duration(&track, 50).unwrap();
+        let mut cs = vec![];
+        apply_diffs(&mut track, &applied_command.1, &mut cs);
+        assert_eq!(cs.len(), 1);
+        let durations: Vec<Time> = track
+            .events
+            .iter()
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Note(n) => Some(n.duration),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(durations, vec![30, 50]);
+        assert!(cap_notes_duration(&track, 50).is_none());
+    }
+
+    #[test]
+    fn check_toggle_damper() {
+        let mut track = make_test_track();
+        let id_seq = IdSeq::new(0);
+        let mut toggle = |at| {
+            let applied_command =
+                toggle_damper_at(&id_seq, &track, MIDI_CC_SUSTAIN_ID, at).unwrap();
+            apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+            let pedal: Vec<(Time, Level)> = track
+                .events
+                .iter()
+                .filter_map(|ev| match &ev.event {
+                    TrackEventType::Controller(cc) if cc.controller_id == MIDI_CC_SUSTAIN_ID => {
+                        Some((ev.at, cc.value))
+                    }
+                    _ => None,
+                })
+                .collect();
+            pedal
+        };
+        assert_eq!(toggle(12), vec![(12, MAX_LEVEL)]);
+        assert_eq!(toggle(18), vec![(12, MAX_LEVEL), (18, 0)]);
+        // Toggling inside the pedaled range splits it.
+        assert_eq!(toggle(15), vec![(12, MAX_LEVEL), (15, 0), (18, 0)]);
+        // The same position again restores the state.
+        assert_eq!(toggle(15), vec![(12, MAX_LEVEL), (18, 0)]);
+        assert_eq!(toggle(12), vec![(18, 0)]);
+    }
+
+    #[test]
+    fn check_pedal_notes() {
+        let note = note_event;
+        let pedal = |id, at, on| TrackEvent {
+            id,
+            at,
+            event: TrackEventType::Controller(ControllerSetValue {
+                controller_id: MIDI_CC_SUSTAIN_ID,
+                value: if on { MAX_LEVEL } else { 0 },
+            }),
+        };
+        let summary = |track: &Track| -> Vec<(Time, Option<Time>)> {
+            track
+                .events
+                .iter()
+                .map(|ev| match &ev.event {
+                    TrackEventType::Note(n) => (ev.at, Some(n.duration)),
+                    _ => (ev.at, None),
+                })
+                .collect()
+        };
+        // An arpeggio that is held by the pedal, the last key is struck twice.
+        let pedaled = Track {
+            events: vec![
+                note(2, 0, 60, 100),
+                pedal(1, 0, true),
+                note(3, 100, 64, 100),
+                note(4, 200, 67, 100),
+                note(5, 300, 67, 100),
+                pedal(6, 500, false),
+            ],
+        };
+        let id_seq = IdSeq::new(10);
+        let selection = HashSet::from([2, 3, 4, 5]);
+
+        let mut track = pedaled.clone();
+        let (command_type, diff) = unpedal_selected_notes(&id_seq, &track, &selection).unwrap();
+        assert_eq!(command_type, EditCommandType::NotesUnpedal);
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        assert_eq!(
+            summary(&track),
+            vec![
+                (0, Some(500)),
+                (100, Some(400)),
+                (200, Some(100)),
+                (300, Some(200))
+            ]
+        );
+        assert!(unpedal_selected_notes(&id_seq, &track, &selection).is_none());
+
+        let (command_type, diff) = pedal_selected_notes(&id_seq, &track, &selection, 100).unwrap();
+        assert_eq!(command_type, EditCommandType::NotesPedal);
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        assert_eq!(summary(&track), summary(&pedaled));
+        assert!(pedal_selected_notes(&id_seq, &track, &HashSet::new(), 100).is_none());
+    }
+
     #[test]
     fn check_set_damper_to() {
         let mut track = make_test_track();
         let id_seq = IdSeq::new(0);
-        let applied_command = set_damper(&id_seq, &track, &(13, 17), true).unwrap();
+        let applied_command =
+            set_damper(&id_seq, &track, MIDI_CC_SUSTAIN_ID, &(13, 17), true).unwrap();
         let mut cs = vec![];
         apply_diffs(&mut track, &applied_command.1, &mut cs);
 
@@ -631,5 +1775,690 @@ mod tests {
                 })
                 .collect::<Vec<Option<bool>>>()
         );
+
+        // Continuous controllers are not switched.
+        assert!(set_damper(&id_seq, &track, MIDI_CC_VOLUME_ID, &(13, 17), true).is_none());
+        assert!(toggle_damper_at(&id_seq, &track, MIDI_CC_VOLUME_ID, 13).is_none());
+    }
+
+    #[test]
+    fn check_set_notes_channel() {
+        let mut track = make_test_track();
+        track
+            .events
+            .push(note_event(50, 30, 11, 100).with_velocity(20));
+        let selection = HashSet::from([50]);
+        let applied_command = set_selected_notes_channel(&track, &selection, 3).unwrap();
+        let mut cs = vec![];
+        apply_diffs(&mut track, &applied_command.1, &mut cs);
+        assert_eq!(cs.len(), 1);
+
+        let note_channels: Vec<(u8, u8)> = to_midi_events(&track.events, 1.0)
+            .iter()
+            .filter_map(|ev| match ev.kind {
+                midly::TrackEventKind::Midi {
+                    channel,
+                    message: midly::MidiMessage::NoteOn { key, .. },
+                } => Some((key.as_int(), channel.as_int())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_channels, vec![(10, 0), (11, 3)]);
+    }
+
+    #[test]
+    fn check_quantize_swing() {
+        let grid = 100;
+        // Straight.
+        assert_eq!(quantized_time(0, grid, 0.0), 0);
+        assert_eq!(quantized_time(140, grid, 0.0), 100);
+        assert_eq!(quantized_time(160, grid, 0.0), 200);
+        assert_eq!(quantized_time(-30, grid, 0.0), 0);
+        // Odd subdivisions are delayed by the swing amount.
+        assert_eq!(quantized_time(110, grid, 0.3), 130);
+        assert_eq!(quantized_time(140, grid, 0.3), 130);
+        assert_eq!(quantized_time(190, grid, 0.3), 200);
+        assert_eq!(quantized_time(310, grid, 0.3), 330);
+        assert_eq!(quantized_time(210, grid, 0.3), 200);
+        // Before the grid origin, odd positions are still the delayed ones.
+        assert_eq!(quantized_time(-80, grid, 0.3), -70);
+
+        let mut track = make_test_track();
+        let selection = HashSet::from([20]);
+        let applied_command = quantize_selected_notes(&track, &selection, 10, 0.5, 0).unwrap();
+        let mut cs = vec![];
+        apply_diffs(&mut track, &applied_command.1, &mut cs);
+        assert_eq!(cs.len(), 1);
+        assert_eq!(track.events.iter().find(|ev| ev.id == 20).unwrap().at, 15);
+    }
+
+    #[test]
+    fn check_quantize_origin() {
+        let mut track = make_test_track();
+        let selection = HashSet::from([20]);
+        // The note at 14 is on the grid that starts at 4.
+        assert!(quantize_selected_notes(&track, &selection, 10, 0.0, 4)
+            .unwrap()
+            .1
+            .iter()
+            .all(|diff| matches!(diff, CommandDiff::ChangeList { patch } if patch.is_empty())));
+        let applied_command = quantize_selected_notes(&track, &selection, 10, 0.0, 7).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(track.events.iter().find(|ev| ev.id == 20).unwrap().at, 17);
+    }
+
+    #[test]
+    fn check_snap_notes() {
+        let note = |id, at| note_event(id, at, 60, 50);
+        let mut track = Track {
+            events: vec![note(1, 98), note(2, 140), note(3, 205), note(4, 260)],
+        };
+        let selection = HashSet::from([1, 2, 3, 4]);
+        let applied_command = snap_selected_notes(&track, &selection, 100, 10, 0).unwrap();
+        assert!(matches!(applied_command.0, EditCommandType::NotesSnap));
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        let starts: Vec<Time> = track.events.iter().map(|ev| ev.at).collect();
+        assert_eq!(starts, vec![100, 140, 200, 260]);
+    }
+
+    #[test]
+    fn check_tape_duplicate() {
+        let note = |id, at, pitch| note_event(id, at, pitch, 30).with_velocity(20);
+        let mut track = Track {
+            events: vec![note(1, 100, 60), note(2, 150, 62), note(3, 200, 64)],
+        };
+        let id_seq = IdSeq::new(10);
+        let applied_command = tape_duplicate(&track, &id_seq, &(100, 200)).unwrap();
+        let mut cs = vec![];
+        apply_diffs(&mut track, &applied_command.1, &mut cs);
+        let notes: Vec<(Time, Pitch)> = track
+            .events
+            .iter()
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Note(n) => Some((ev.at, n.pitch)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![(100, 60), (150, 62), (200, 60), (250, 62), (300, 64)]
+        );
+        let ids: HashSet<EventId> = track.events.iter().map(|ev| ev.id).collect();
+        assert_eq!(ids.len(), 5);
+
+        let mut cs = vec![];
+        revert_diffs(&mut track, &applied_command.1, &mut cs);
+        assert_eq!(
+            track.events,
+            vec![note(1, 100, 60), note(2, 150, 62), note(3, 200, 64)]
+        );
+    }
+
+    #[test]
+    fn check_region_events() {
+        let id_seq = IdSeq::new(100);
+        let mut track = make_test_track();
+        let applied_command =
+            set_damper(&id_seq, &track, MIDI_CC_SUSTAIN_ID, &(5, 30), true).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+
+        let events = region_events(&track, &(12, 40));
+        let sustain_on = TrackEventType::Controller(ControllerSetValue {
+            controller_id: MIDI_CC_SUSTAIN_ID,
+            value: MAX_LEVEL,
+        });
+        // Controller 13 is set in the test track before the region too.
+        let restored: Vec<&TrackEventType> = events
+            .iter()
+            .take_while(|ev| ev.at == 0)
+            .map(|ev| &ev.event)
+            .collect();
+        assert_eq!(
+            restored,
+            vec![
+                &TrackEventType::Controller(ControllerSetValue {
+                    controller_id: 13,
+                    value: 55,
+                }),
+                &sustain_on
+            ]
+        );
+        // The note at 14 is moved to the region start.
+        assert!(events
+            .iter()
+            .any(|ev| ev.id == 20 && ev.at == 2 && matches!(ev.event, TrackEventType::Note(_))));
+        // Nothing to restore when the pedal is not held.
+        let events = region_events(&track, &(35, 40));
+        assert!(!events.iter().any(|ev| ev.event == sustain_on));
+        // The note 14..44 is still sounding, it is clipped to the range start.
+        assert!(events.iter().any(|ev| ev.id == 20
+            && ev.at == 0
+            && matches!(&ev.event, TrackEventType::Note(n) if n.duration == 9)));
+
+        let events = selected_region_events(&track, &HashSet::from([20, 30]));
+        assert_eq!(
+            events.iter().map(|ev| (ev.id, ev.at)).collect::<Vec<_>>(),
+            vec![(20, 0), (30, 1)]
+        );
+    }
+
+    #[test]
+    fn time_signature_replaced_at_same_time() {
+        let id_seq = IdSeq::new(100);
+        let mut track = make_test_track();
+        let waltz = TimeSignature {
+            beats: 3,
+            beat_unit: 4,
+        };
+        let applied_command = set_time_signature(&track, &id_seq, &20, waltz).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert!(set_time_signature(&track, &id_seq, &20, waltz).is_none());
+
+        let applied_command =
+            set_time_signature(&track, &id_seq, &20, TimeSignature::COMMON).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        let signatures = |track: &Track| {
+            track
+                .events
+                .iter()
+                .filter_map(|ev| match ev.event {
+                    TrackEventType::TimeSignature(sig) => Some((ev.at, sig)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(signatures(&track), vec![(20, TimeSignature::COMMON)]);
+
+        let applied_command = clear_time_signature(&track, &20).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert!(signatures(&track).is_empty());
+        assert!(clear_time_signature(&track, &20).is_none());
+    }
+
+    #[test]
+    fn check_insert_gap() {
+        let mut track = make_test_track();
+        let before = track.events.clone();
+        let at = 14;
+        let applied_command = insert_gap(&at, 100).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(track.events.len(), before.len());
+        for ev in &before {
+            let moved = track.events.iter().find(|x| x.id == ev.id).unwrap();
+            let expected = if ev.at > at { ev.at + 100 } else { ev.at };
+            assert_eq!(moved.at, expected, "event {}", ev.id);
+        }
+        revert_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(track.events, before);
+    }
+
+    #[test]
+    fn check_align_notes() {
+        let note = |id, at, pitch| note_event(id, at, pitch, 30 + id as Time).with_velocity(20);
+        let mut track = Track {
+            events: vec![note(1, 100, 60), note(2, 130, 64), note(3, 180, 67)],
+        };
+        let selection = HashSet::from([1, 2, 3]);
+        let applied_command = align_selected_notes(&track, &selection, None).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        let notes: Vec<(Time, Pitch, Time)> = track
+            .events
+            .iter()
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Note(n) => Some((ev.at, n.pitch, n.duration)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes, vec![(100, 60, 31), (100, 64, 32), (100, 67, 33)]);
+
+        let applied_command = align_selected_notes(&track, &selection, Some(50)).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert!(track.events.iter().all(|ev| ev.at == 50));
+        assert!(align_selected_notes(&track, &HashSet::new(), None).is_none());
+    }
+
+    #[test]
+    fn check_spread_notes() {
+        let note = |id, at| note_event(id, at, 60 + id as Pitch, 30).with_velocity(20);
+        let mut track = Track {
+            events: vec![note(1, 100), note(2, 110), note(3, 250), note(4, 400)],
+        };
+        let selection = HashSet::from([1, 2, 3, 4]);
+        let onsets = |track: &Track| {
+            let mut onsets: Vec<(EventId, Time)> =
+                track.events.iter().map(|ev| (ev.id, ev.at)).collect();
+            onsets.sort();
+            onsets
+        };
+        let applied_command = spread_selected_notes(&track, &selection, None).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(onsets(&track), vec![(1, 100), (2, 200), (3, 300), (4, 400)]);
+
+        let applied_command = spread_selected_notes(&track, &selection, Some((0, 1000))).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(onsets(&track), vec![(1, 0), (2, 250), (3, 500), (4, 750)]);
+
+        // Chords stay together.
+        let mut track = Track {
+            events: vec![note(1, 0), note(2, 0), note(3, 10), note(4, 100)],
+        };
+        let applied_command = spread_selected_notes(&track, &selection, None).unwrap();
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(onsets(&track), vec![(1, 0), (2, 0), (3, 50), (4, 100)]);
+        assert!(spread_selected_notes(&track, &HashSet::from([1, 2]), None).is_none());
+    }
+
+    #[test]
+    fn check_selected_notes_range() {
+        let note = |id, at, duration| note_event(id, at, 60, duration).with_velocity(20);
+        let track = Track {
+            events: vec![
+                note(1, 100, 500),
+                note(2, 200, 50),
+                note(3, 300, 100),
+                TrackEvent {
+                    id: 4,
+                    at: 900,
+                    event: TrackEventType::Bookmark,
+                },
+            ],
+        };
+        // A longer earlier note determines the end.
+        assert_eq!(
+            selected_notes_range(&track, &HashSet::from([1, 2, 3])),
+            Some((100, 600))
+        );
+        assert_eq!(
+            selected_notes_range(&track, &HashSet::from([2, 3, 4])),
+            Some((200, 400))
+        );
+        assert_eq!(selected_notes_range(&track, &HashSet::from([4])), None);
+    }
+
+    #[test]
+    fn locked_notes_stay() {
+        let mut track = make_test_track();
+        for (id, pitch) in [(50, 60), (60, 64)] {
+            track
+                .events
+                .push(note_event(id, id as Time, pitch, 30).with_velocity(20));
+        }
+        let selection = HashSet::from([50, 60]);
+        let lock = toggle_lock_selected_notes(&track, &HashSet::from([60])).unwrap();
+        apply_diffs(&mut track, &lock.1, &mut vec![]);
+        assert!(track.events[5].is_locked());
+
+        let transpose = transpose_selected_notes(&track, &selection, 2).unwrap();
+        apply_diffs(&mut track, &transpose.1, &mut vec![]);
+        let pitches: Vec<Pitch> = track
+            .events
+            .iter()
+            .filter_map(|ev| match &ev.event {
+                TrackEventType::Note(n) => Some(n.pitch),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pitches, vec![10, 62, 64]);
+        let delete = delete_selected(&track, &selection).unwrap();
+        apply_diffs(&mut track, &delete.1, &mut vec![]);
+        assert_eq!(track.events.last().unwrap().id, 60);
+        assert_eq!(track.events.len(), 5);
+
+        // Unlocked when all the selected notes are locked.
+        let unlock = toggle_lock_selected_notes(&track, &selection).unwrap();
+        apply_diffs(&mut track, &unlock.1, &mut vec![]);
+        assert!(!track.events.last().unwrap().is_locked());
+    }
+
+    #[test]
+    fn check_similar_notes() {
+        let note = |id, pitch, velocity| {
+            note_event(id, id as Time * 100, pitch, 100).with_velocity(velocity)
+        };
+        let track = Track {
+            events: vec![
+                note(1, 60, 20),
+                note(2, 62, 30),
+                note(3, 60, 30),
+                TrackEvent {
+                    id: 4,
+                    at: 400,
+                    event: TrackEventType::Controller(ControllerSetValue {
+                        controller_id: 60,
+                        value: 60,
+                    }),
+                },
+                note(5, 60, 40),
+            ],
+        };
+        assert_eq!(
+            similar_notes(&track, 3, NoteAttribute::Pitch),
+            Some(HashSet::from([1, 3, 5]))
+        );
+        assert_eq!(
+            similar_notes(&track, 3, NoteAttribute::Velocity),
+            Some(HashSet::from([2, 3]))
+        );
+        assert_eq!(similar_notes(&track, 4, NoteAttribute::Pitch), None);
+    }
+
+    #[test]
+    fn paste_velocity_scaling() {
+        assert_eq!(velocity_ratio(Some(80.0), Some(40.0)), 2.0);
+        assert_eq!(velocity_ratio(None, Some(40.0)), 1.0);
+        assert_eq!(velocity_ratio(Some(80.0), None), 1.0);
+        assert_eq!(scale_velocity(30, 2.0), 60);
+        assert_eq!(scale_velocity(100, 2.0), MAX_LEVEL);
+        assert_eq!(scale_velocity(1, 0.1), 1);
+
+        let note = |id, at, velocity| note_event(id, at, 60, 100).with_velocity(velocity);
+        // Target range is 1000..1300, the note at 2000 is outside of it.
+        let track = Track {
+            events: vec![note(1, 1000, 90), note(2, 1100, 110), note(3, 2000, 10)],
+        };
+        let fragment = vec![note(1, 500, 40), note(2, 700, 60)];
+        let id_seq = IdSeq::new(10);
+        let pasted = |match_velocity| {
+            let (_, diff) = paste_events(&track, &id_seq, &fragment, 1000, match_velocity).unwrap();
+            let mut track = track.clone();
+            apply_diffs(&mut track, &diff, &mut vec![]);
+            track
+                .events
+                .iter()
+                .filter(|ev| ev.id >= 10)
+                .map(|ev| match &ev.event {
+                    TrackEventType::Note(n) => (ev.at, n.velocity),
+                    _ => panic!("unexpected {:?}", ev),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pasted(false), vec![(1000, 40), (1200, 60)]);
+        assert_eq!(pasted(true), vec![(1000, 80), (1200, 120)]);
+    }
+
+    #[test]
+    fn record_played_events() {
+        let id_seq = IdSeq::new(0);
+        assert!(record_events(&id_seq, &Track::default(), &[], 1_000, None, None).is_none());
+        let events = vec![
+            (100, note_on(0, 60, 80)),
+            (150, controller_set(0, MIDI_CC_SUSTAIN_ID, 127)),
+            (200, note_on(0, 64, 70)),
+            (300, note_off(0, 60, 0)),
+        ];
+        let mut track = Track::default();
+        let (command_type, diff) =
+            record_events(&id_seq, &track, &events, 1_000, None, None).unwrap();
+        assert_eq!(command_type, EditCommandType::Record);
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        let recorded: Vec<(Time, Time)> = track
+            .events
+            .iter()
+            .map(|ev| match &ev.event {
+                TrackEventType::Note(n) => (ev.at, n.duration),
+                _ => (ev.at, 0),
+            })
+            .collect();
+        // The held note ends when the recording stops.
+        assert_eq!(recorded, vec![(100, 200), (150, 0), (200, 800)]);
+
+        // Input quantize moves note starts only.
+        let mut track = Track::default();
+        let (_, diff) =
+            record_events(&id_seq, &track, &events, 1_000, None, Some((80, 0.0, 10))).unwrap();
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        let starts: Vec<Time> = track.events.iter().map(|ev| ev.at).collect();
+        assert_eq!(starts, vec![90, 150, 170]);
+    }
+
+    #[test]
+    fn punch_in_recording() {
+        let id_seq = IdSeq::new(100);
+        let note = |id, at, pitch| note_event(id, at, pitch, 50);
+        let mut locked = note(4, 600, 65);
+        if let TrackEventType::Note(n) = &mut locked.event {
+            n.locked = true;
+        }
+        let mut track = Track {
+            events: vec![note(1, 100, 60), note(2, 500, 62), locked, note(3, 900, 64)],
+        };
+        let punch = (400, 800);
+        let events = vec![
+            (300, note_on(0, 70, 80)),
+            (350, note_off(0, 70, 0)),
+            (450, note_on(0, 71, 80)),
+            (500, note_off(0, 71, 0)),
+            (700, note_on(0, 72, 80)),
+            (850, note_on(0, 73, 80)),
+        ];
+        // Nothing is played in the range, the track is kept.
+        assert!(record_events(&id_seq, &track, &events[..2], 1_000, Some(&punch), None).is_none());
+        let (_, diff) = record_events(&id_seq, &track, &events, 1_000, Some(&punch), None).unwrap();
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        let notes: Vec<(Time, Pitch, Time)> = track
+            .events
+            .iter()
+            .map(|ev| match &ev.event {
+                TrackEventType::Note(n) => (ev.at, n.pitch, n.duration),
+                _ => panic!("unexpected {:?}", ev),
+            })
+            .collect();
+        // The held note is cut at the punch out.
+        assert_eq!(
+            notes,
+            vec![
+                (100, 60, 50),
+                (450, 71, 50),
+                (600, 65, 50),
+                (700, 72, 100),
+                (900, 64, 50)
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_file() {
+        let path = PathBuf::from("./test/files/short.mid");
+        let imported = import_smf(&IdSeq::new(0), &path).unwrap();
+        let id_seq = IdSeq::new(1_000_000);
+        let mut track = Track {
+            events: import_smf(&id_seq, &path).unwrap(),
+        };
+        let original_ids: HashSet<EventId> = track.events.iter().map(|ev| ev.id).collect();
+        let (command_type, diff) = merge_events(&id_seq, &imported, 1_000, 2.0).unwrap();
+        assert_eq!(command_type, EditCommandType::Merge);
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        assert_eq!(track.events.len(), 2 * imported.len());
+        let timings = |events: Vec<&TrackEvent>| {
+            let mut timings: Vec<(Time, Time)> = events
+                .iter()
+                .map(|ev| match &ev.event {
+                    TrackEventType::Note(n) => (ev.at, n.duration),
+                    _ => (ev.at, 0),
+                })
+                .collect();
+            timings.sort();
+            timings
+        };
+        let merged = track
+            .events
+            .iter()
+            .filter(|ev| !original_ids.contains(&ev.id))
+            .collect();
+        let expected: Vec<(Time, Time)> = timings(imported.iter().collect())
+            .into_iter()
+            .map(|(at, duration)| (1_000 + 2 * at, 2 * duration))
+            .collect();
+        assert_eq!(timings(merged), expected);
+        assert!(merge_events(&id_seq, &[], 0, 1.0).is_none());
+    }
+
+    #[test]
+    fn check_scale_velocity() {
+        let note = |id, velocity| note_event(id, id as Time * 100, 60, 100).with_velocity(velocity);
+        let mut track = Track {
+            events: vec![note(1, 40), note(2, 60), note(3, 100), note(4, 50)],
+        };
+        let (_, diff) = scale_selected_velocity(&track, &HashSet::from([1, 2, 3]), 1, 80).unwrap();
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        let velocities: Vec<Level> = track
+            .events
+            .iter()
+            .map(|ev| match &ev.event {
+                TrackEventType::Note(n) => n.velocity,
+                _ => panic!("unexpected {:?}", ev),
+            })
+            .collect();
+        assert_eq!(velocities, vec![80, 120, MAX_LEVEL, 50]);
+        assert!(scale_selected_velocity(&track, &HashSet::from([1]), 99, 80).is_none());
+    }
+
+    #[test]
+    fn check_mirror_notes() {
+        let note = |id, at| note_event(id, at, 60, 30);
+        let mut track = Track {
+            events: vec![note(1, 100), note(2, 250), note(3, 260)],
+        };
+        let selection = HashSet::from([1, 2]);
+        let applied_command = mirror_selected_notes(&track, &selection, 200).unwrap();
+        assert_eq!(applied_command.0, EditCommandType::NotesMirror);
+        apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+        assert_eq!(track.events, vec![note(2, 150), note(3, 260), note(1, 300)]);
+        // Would start before the track start.
+        assert!(mirror_selected_notes(&track, &selection, 50).is_none());
+    }
+
+    #[test]
+    fn check_legato_notes() {
+        let note = |id, at, duration| note_event(id, at, 60, duration);
+        let mut track = Track {
+            events: vec![
+                note(1, 0, 50),
+                note(2, 100, 150),
+                note(3, 100, 20),
+                note(4, 200, 10),
+                note(5, 300, 10),
+            ],
+        };
+        let (command_type, diff) = legato_selected(&track, &HashSet::from([1, 2, 3, 4])).unwrap();
+        assert_eq!(command_type, EditCommandType::NotesLegato);
+        apply_diffs(&mut track, &diff, &mut vec![]);
+        let durations: Vec<(Time, Time)> = track
+            .events
+            .iter()
+            .map(|ev| match &ev.event {
+                TrackEventType::Note(n) => (ev.at, n.duration),
+                _ => panic!("unexpected {:?}", ev),
+            })
+            .collect();
+        // Gaps are filled, the overlap is trimmed, the last selected and unselected notes stay.
+        assert_eq!(
+            durations,
+            vec![(0, 100), (100, 100), (100, 100), (200, 10), (300, 10)]
+        );
+        assert!(legato_selected(&track, &HashSet::from([2, 3])).is_none());
+    }
+
+    #[test]
+    fn check_strum_notes() {
+        let note = |id, pitch| note_event(id, 100, pitch, 50);
+        let track = Track {
+            events: vec![note(1, 64), note(2, 60), note(3, 67), note(4, 72)],
+        };
+        let selection = HashSet::from([1, 2, 3]);
+        let strummed = |upward| {
+            let mut track = track.clone();
+            let (command_type, diff) = strum_selected(&track, &selection, 10, upward).unwrap();
+            assert_eq!(command_type, EditCommandType::NotesStrum);
+            apply_diffs(&mut track, &diff, &mut vec![]);
+            let mut starts: Vec<(EventId, Time)> =
+                track.events.iter().map(|ev| (ev.id, ev.at)).collect();
+            starts.sort();
+            starts
+        };
+        assert_eq!(strummed(true), vec![(1, 110), (2, 100), (3, 120), (4, 100)]);
+        assert_eq!(
+            strummed(false),
+            vec![(1, 110), (2, 120), (3, 100), (4, 100)]
+        );
+        assert!(strum_selected(&track, &HashSet::from([1]), 10, true).is_none());
+    }
+
+    #[test]
+    fn check_humanize_notes() {
+        let note = |id, at, velocity| note_event(id, at, 60, 30).with_velocity(velocity);
+        let original = Track {
+            events: vec![note(1, 5, 2), note(2, 100, 125), note(3, 200, 64)],
+        };
+        let selection: HashSet<EventId> = [1, 2].into_iter().collect();
+        assert!(humanize_selected(&original, &selection, 0, 0, 7).is_none());
+        let humanized = |seed| {
+            let mut track = original.clone();
+            let (command_type, diff) = humanize_selected(&track, &selection, 10, 5, seed).unwrap();
+            assert_eq!(command_type, EditCommandType::NotesHumanize);
+            apply_diffs(&mut track, &diff, &mut vec![]);
+            let mut reverted = track.clone();
+            revert_diffs(&mut reverted, &diff, &mut vec![]);
+            assert_eq!(reverted.events, original.events);
+            track
+        };
+        let track = humanized(7);
+        assert_eq!(track.events, humanized(7).events);
+        for (before, after) in original.events.iter().zip(&track.events) {
+            let (TrackEventType::Note(n0), TrackEventType::Note(n1)) =
+                (&before.event, &after.event)
+            else {
+                panic!("unexpected {:?}", after);
+            };
+            assert!(after.at >= 0 && (after.at - before.at).abs() <= 10);
+            assert!((n1.velocity as i64 - n0.velocity as i64).abs() <= 5);
+            // Velocity 0 would be a note-off.
+            assert!(n1.velocity >= 1 && n1.velocity <= MAX_LEVEL);
+            if !selection.contains(&before.id) {
+                assert_eq!(before, after);
+            }
+        }
+        for seed in 0..20 {
+            let TrackEventType::Note(soft) = &humanized(seed).events[0].event else {
+                panic!("expected a note");
+            };
+            assert!(soft.velocity >= 1);
+        }
+    }
+
+    #[test]
+    fn check_shuffle_notes() {
+        let note = |id, at, pitch| note_event(id, at, pitch, 30).with_velocity(id as Level);
+        let original = Track {
+            events: (0..20)
+                .map(|i| note(i, 100 * i as Time, 60 + (i % 2) as Pitch))
+                .collect(),
+        };
+        let selection: HashSet<EventId> = (0..20).filter(|i| *i != 5).collect();
+        let onsets = |track: &Track, pitch: Pitch| {
+            let mut onsets: Vec<Time> = track
+                .events
+                .iter()
+                .filter(|ev| matches!(&ev.event, TrackEventType::Note(n) if n.pitch == pitch))
+                .map(|ev| ev.at)
+                .collect();
+            onsets.sort();
+            onsets
+        };
+        let shuffled = |seed| {
+            let mut track = original.clone();
+            let applied_command = shuffle_selected_notes(&track, &selection, seed).unwrap();
+            apply_diffs(&mut track, &applied_command.1, &mut vec![]);
+            track
+        };
+        let track = shuffled(1);
+        assert_ne!(track.events, original.events);
+        for pitch in [60, 61] {
+            assert_eq!(onsets(&track, pitch), onsets(&original, pitch));
+        }
+        // Unselected notes stay.
+        assert!(track.events.iter().any(|ev| ev.id == 5 && ev.at == 500));
+        assert_eq!(shuffled(1).events, track.events);
+        assert!(shuffle_selected_notes(&original, &HashSet::from([1]), 1).is_none());
     }
 }
@@ -1,10 +1,11 @@
+use std::collections::HashSet;
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use crate::changeset::{EventAction, EventActionsList, HistoryLogEntry, Snapshot};
+use crate::changeset::{Changeset, EventAction, EventActionsList, HistoryLogEntry, Snapshot};
 use crate::common::VersionId;
-use crate::track::{import_smf, Track};
+use crate::track::{import_smf, EventId, Track};
 use crate::track_edit::{apply_diffs, revert_diffs, AppliedCommand, CommandDiff, EditCommandType};
 use crate::util;
 use crate::util::IdSeq;
@@ -13,6 +14,9 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sync_cow::SyncCow;
 
+/// Compaction progress is logged after reading this many versions.
+const COMPACT_PROGRESS_STEP: VersionId = 1000;
+
 // Undo/redo history and snapshots.
 // #[derive(Debug)]
 pub struct TrackHistory {
@@ -21,6 +25,12 @@ pub struct TrackHistory {
     pub version: VersionId,
     pub max_version: VersionId, // May be higher than self.version after an undo.
     pub directory: PathBuf,
+    /// While a group is open, repeated commands are merged into one version, see [Self::begin_group].
+    grouping: bool,
+    /// Command of the current version if it was pushed in the open group.
+    group_command: Option<EditCommandType>,
+    /// Incremented on every change of the track, including the ones merged into the current version.
+    edit_count: u64,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -41,6 +51,9 @@ impl Version {
 impl TrackHistory {
     const SNAPSHOT_NAME_EXT: &'static str = "snapshot";
     const DIFF_NAME_EXT: &'static str = "changeset";
+    /// A snapshot is stored at every this many versions, so a version is restored
+    /// without replaying the whole history.
+    const SNAPSHOT_INTERVAL: VersionId = 64;
 
     pub fn with_track<Out, Action: FnOnce(&Track) -> Out>(&self, action: Action) -> Out {
         let track = self.track.read();
@@ -61,7 +74,16 @@ impl TrackHistory {
                 apply_diffs(track, &applied_command.1, &mut changes);
                 track.commit();
             });
+            self.edit_count += 1;
             self.update(&applied_command);
+            if !applied_command.1.is_empty() {
+                log::info!(
+                    "Applied {:?}, version {}, {} events changed.",
+                    applied_command.0,
+                    self.version,
+                    changed_events_count(&changes)
+                );
+            }
             Some((applied_command, changes))
         } else {
             None
@@ -75,16 +97,69 @@ impl TrackHistory {
             return;
         }
         // The changeset should be already applied to track by now.
+        if self.grouping && self.group_command == Some(*command_id) && self.merge_into_current(diff)
+        {
+            log::trace!("Merged into version {}.", self.version);
+            return;
+        }
         let log_entry = HistoryLogEntry {
             base_version: self.version,
             version: self.version + 1,
             command_id: *command_id,
             diff: diff.iter().cloned().collect(), // XXX Maybe share the vector?
         };
+        // The undone versions (and their snapshots) are replaced by the new one.
+        self.discard_tail(self.version);
         self.push(log_entry);
-        // TODO also store a new snapshot here if necessary
-        //   (avoid long timeouts and long diff-only runs between snapshots).
-        self.discard_tail(self.max_version);
+        if self.grouping {
+            self.group_command = Some(*command_id);
+        }
+        if self.version % Self::SNAPSHOT_INTERVAL == 0 {
+            let snapshot = self.with_track(|track| Snapshot::of_track(self.version, track));
+            util::store(&snapshot, &self.current_snapshot_path());
+            log::debug!("Stored a snapshot of version {}.", self.version);
+        }
+    }
+
+    /// Start merging consecutive same commands (e.g. repeated by a held key) into one version,
+    /// so they are undone in one step. Does nothing if a group is already open.
+    pub fn begin_group(&mut self) {
+        if !self.grouping {
+            self.grouping = true;
+            self.group_command = None;
+        }
+    }
+
+    /// Close the current group, the next command starts a new version.
+    pub fn finish_group(&mut self) {
+        self.grouping = false;
+        self.group_command = None;
+    }
+
+    /// Add the change lists to the current version's diff, returns false if these cannot be merged.
+    fn merge_into_current(&mut self, diff: &Vec<CommandDiff>) -> bool {
+        let is_change_list = |diff: &Vec<CommandDiff>| {
+            diff.iter()
+                .all(|d| matches!(d, CommandDiff::ChangeList { .. }))
+        };
+        if !is_change_list(diff) || self.current_snapshot_path().is_file() {
+            return false;
+        }
+        let mut entry: HistoryLogEntry = util::load(&self.diff_path(self.version));
+        if !is_change_list(&entry.diff) {
+            return false;
+        }
+        let mut changeset = Changeset::empty();
+        for d in entry.diff.iter().chain(diff) {
+            if let CommandDiff::ChangeList { patch } = d {
+                changeset.add_all(patch);
+            }
+        }
+        entry.diff = vec![CommandDiff::ChangeList {
+            patch: changeset.to_actions(),
+        }];
+        util::store(&entry, &self.diff_path(self.version));
+        true
     }
 
     /// Save the current version into history.
@@ -106,6 +181,8 @@ impl TrackHistory {
         //   in `changes`. This will likely happen at startup. It is possible compact them into
         //   a changeset immediately, but want to profile both options before deciding.
         assert!(TrackHistory::is_valid_version_id(version_id));
+        // Only the latest edit can be extended.
+        self.group_command = None;
         let version = self.get_version(version_id);
         assert_eq!(version.id, version_id);
         if version.is_empty() {
@@ -115,6 +192,7 @@ impl TrackHistory {
         {
             let track = self.track.clone();
             track.edit(|track| self.apply_patches(changes, version, track));
+            self.edit_count += 1;
         }
         log::debug!(
             "Actual version {}, required version {}",
@@ -132,13 +210,22 @@ impl TrackHistory {
         version: Version,
         mut track: &mut Track,
     ) {
-        // TODO (feature, persistence) Support multiple snapshots. Now expecting only the starting one with id 0.
-        //   Should use snapshot if it is found but diff is missing.
-        //   Maybe prefer snapshots when both diff and snapshot are present.
-        if let Some(snapshot_path) = version.snapshot_path {
+        // Start from the nearest snapshot if that is closer than the current version.
+        // Single steps (undo/redo) are replayed anyway, so their changes are reported.
+        let steps = (version.id - self.version).abs();
+        let snapshot = self
+            .list_snapshots()
+            .filter(|(id, _)| *id <= version.id)
+            .max()
+            .filter(|(id, _)| steps > 1 && version.id - id < steps);
+        if let Some((snapshot_id, snapshot_path)) = snapshot {
             track.reset(util::load(&snapshot_path));
-            self.set_version(version.id);
-            log::debug!("Found a snapshot for revision {}.", version.id);
+            self.set_version(snapshot_id);
+            log::debug!(
+                "Found a snapshot of version {} for version {}.",
+                snapshot_id,
+                version.id
+            );
         }
         // Replays
         while self.version < version.id {
@@ -159,7 +246,12 @@ impl TrackHistory {
     }
 
     /// Maybe undo last edit action.
+    /// Bookmarks are workspace state rather than music: the latest musical edit is undone
+    /// even if bookmarks were set or cleared after it, and these bookmark changes stay.
+    /// Bookmark changes are undone only when there are no musical edits before them
+    /// (since the track was loaded or the history was compacted).
     pub fn undo(&mut self, changes: &mut EventActionsList) -> bool {
+        self.lift_last_edit();
         let prev_version_id = self.version - 1;
         if TrackHistory::is_valid_version_id(prev_version_id) {
             assert!(self.go_to_version(prev_version_id, changes));
@@ -169,6 +261,70 @@ impl TrackHistory {
         }
     }
 
+    /// Move the latest musical edit above the workspace-only versions that follow it,
+    /// so it becomes the current version. The track state at the current version stays the same.
+    /// Does nothing if the edit and the workspace changes may depend on each other
+    /// (e.g. the edit moves or deletes bookmarks), or there are snapshots in between.
+    fn lift_last_edit(&mut self) {
+        let mut workspace: Vec<HistoryLogEntry> = vec![];
+        let mut version_id = self.version;
+        while TrackHistory::is_valid_version_id(version_id - 1) {
+            let version = self.get_version(version_id);
+            if version.diff_path.is_none()
+                || (version_id < self.version && version.snapshot_path.is_some())
+            {
+                return;
+            }
+            let entry: HistoryLogEntry = util::load(&self.diff_path(version_id));
+            if !is_workspace_command(entry.command_id) {
+                // The loaded or compacted history is a base to build upon, not an edit.
+                let is_base = matches!(
+                    entry.command_id,
+                    EditCommandType::Load | EditCommandType::Compacted
+                );
+                if is_base || workspace.is_empty() || !commutes(&entry, &workspace) {
+                    return;
+                }
+                log::debug!(
+                    "Moving version {} above {} bookmark change(s).",
+                    version_id,
+                    workspace.len()
+                );
+                let top = self.version;
+                let mut reordered = vec![];
+                for (i, mut other) in workspace.into_iter().rev().enumerate() {
+                    other.base_version = version_id + i as VersionId - 1;
+                    other.version = version_id + i as VersionId;
+                    reordered.push(other);
+                }
+                let mut lifted = entry;
+                lifted.base_version = top - 1;
+                lifted.version = top;
+                reordered.push(lifted);
+                // Complete files replace the old ones, so an interrupted write does not
+                // leave a broken version.
+                let staged: Vec<(PathBuf, PathBuf)> = reordered
+                    .iter()
+                    .map(|entry| {
+                        let path = self.diff_path(entry.version);
+                        let temp_path =
+                            path.with_extension(Self::DIFF_NAME_EXT.to_string() + ".tmp");
+                        util::store(entry, &temp_path);
+                        (temp_path, path)
+                    })
+                    .collect();
+                for (temp_path, path) in staged {
+                    fs::rename(&temp_path, &path)
+                        .unwrap_or_else(|_| panic!("replace {}", path.display()));
+                }
+                self.group_command = None;
+                return;
+            }
+            workspace.push(entry);
+            version_id -= 1;
+        }
+    }
+
     /// Maybe redo next edit action.
     pub fn redo(&mut self, changes: &mut EventActionsList) -> bool {
         self.go_to_version(self.version + 1, changes)
@@ -191,12 +347,123 @@ impl TrackHistory {
                 fs::remove_file(path).expect("delete diff");
             }
         }
+        // Also the ones that may be left after a gap, these must not be mistaken for
+        // states of the new versions.
+        let orphans: Vec<PathBuf> = self
+            .list_snapshots()
+            .filter(|(id, _)| *id > max_version)
+            .map(|(_, path)| path)
+            .collect();
+        for path in orphans {
+            fs::remove_file(path).expect("delete snapshot");
+        }
     }
 
     pub fn version(&self) -> VersionId {
         self.version
     }
 
+    /// Changes when the track is edited, unlike the version that stays the same
+    /// when an edit is merged into the current one.
+    pub fn edit_count(&self) -> u64 {
+        self.edit_count
+    }
+
+    /// Collapse runs of consecutive change-list-only versions into single versions.
+    /// Intermediate states of a run become unreachable while the states at the run ends
+    /// (including the current one) are preserved. Runs do not cross snapshots and the
+    /// current version. Versions are renumbered afterwards to keep the chain contiguous.
+    pub fn compact(&mut self) {
+        let mut entries: Vec<HistoryLogEntry> = vec![];
+        let mut run = Changeset::empty();
+        let mut run_start: Option<HistoryLogEntry> = None;
+        // Maps new version ids to the old ones, for snapshots.
+        let mut snapshot_versions = vec![];
+        let mut current_version = self.version;
+        let close_run = |run: &mut Changeset,
+                         run_start: &mut Option<HistoryLogEntry>,
+                         entries: &mut Vec<HistoryLogEntry>| {
+            if let Some(mut entry) = run_start.take() {
+                entry.diff = vec![CommandDiff::ChangeList {
+                    patch: run.to_actions(),
+                }];
+                entries.push(entry);
+            }
+            *run = Changeset::empty();
+        };
+        for version_id in 1..=self.max_version {
+            if version_id % COMPACT_PROGRESS_STEP == 0 {
+                log::info!(
+                    "Compacting history, {} of {} versions read.",
+                    version_id,
+                    self.max_version
+                );
+            }
+            let entry: HistoryLogEntry = util::load(&self.diff_path(version_id));
+            let is_change_list = entry
+                .diff
+                .iter()
+                .all(|d| matches!(d, CommandDiff::ChangeList { .. }));
+            let after_snapshot = self.snapshot_path(version_id - 1).is_file();
+            if after_snapshot || !is_change_list {
+                close_run(&mut run, &mut run_start, &mut entries);
+            }
+            if after_snapshot {
+                snapshot_versions.push((version_id - 1, entries.len() as VersionId));
+            }
+            if is_change_list {
+                for d in &entry.diff {
+                    if let CommandDiff::ChangeList { patch } = d {
+                        let mut changeset = Changeset::empty();
+                        changeset.add_all(patch);
+                        run.merge(&changeset);
+                    }
+                }
+                if run_start.is_none() {
+                    run_start = Some(HistoryLogEntry {
+                        command_id: EditCommandType::Compacted,
+                        ..entry
+                    });
+                }
+            } else {
+                entries.push(entry);
+            }
+            if version_id == self.version {
+                close_run(&mut run, &mut run_start, &mut entries);
+                current_version = entries.len() as VersionId;
+            }
+        }
+        close_run(&mut run, &mut run_start, &mut entries);
+        if self.snapshot_path(self.max_version).is_file() {
+            snapshot_versions.push((self.max_version, entries.len() as VersionId));
+        }
+        log::info!(
+            "Compacted history from {} to {} versions.",
+            self.max_version,
+            entries.len()
+        );
+
+        for version_id in 1..=self.max_version {
+            fs::remove_file(self.diff_path(version_id)).expect("delete diff");
+        }
+        for (old_id, new_id) in snapshot_versions {
+            if old_id != new_id {
+                let mut snapshot: Snapshot = util::load(&self.snapshot_path(old_id));
+                snapshot.version = new_id;
+                util::store(&snapshot, &self.snapshot_path(new_id));
+                fs::remove_file(self.snapshot_path(old_id)).expect("delete snapshot");
+            }
+        }
+        self.max_version = entries.len() as VersionId;
+        for (i, mut entry) in entries.into_iter().enumerate() {
+            entry.version = i as VersionId + 1;
+            entry.base_version = i as VersionId;
+            util::store(&entry, &self.diff_path(entry.version));
+        }
+        self.version = current_version;
+        self.write_meta();
+    }
+
     fn check_directory_writable(directory: &PathBuf) {
         let metadata = fs::metadata(&directory).expect(
             format!(
@@ -227,11 +494,14 @@ impl TrackHistory {
             version: 0,
             max_version: 0,
             track: Arc::new(SyncCow::new(Track::default())),
+            grouping: false,
+            group_command: None,
+            edit_count: 0,
         }
     }
 
     /// Create the fist version of a new history.
-    pub fn init(mut self, source_file: &PathBuf) -> Self {
+    pub fn init(mut self, source_file: &PathBuf) -> Result<Self, String> {
         if !self.is_empty() {
             panic!("Cannot init with new source file: the project history is not empty.")
         }
@@ -244,12 +514,11 @@ impl TrackHistory {
             );
         }
         let version = self.version;
-
+        let events = import_smf(&self.id_seq, source_file)?;
         {
-            let id_seq = self.id_seq.clone();
             self.update_track(|track| {
                 let mut patch = vec![];
-                for ev in import_smf(&id_seq, source_file) {
+                for ev in events {
                     patch.push(EventAction::Insert(ev));
                 }
                 util::store(&Snapshot::of_track(version, track), &starting_snapshot_path);
@@ -260,7 +529,7 @@ impl TrackHistory {
             });
         }
         self.write_meta();
-        self
+        Ok(self)
     }
 
     pub fn open(&mut self) {
@@ -271,6 +540,7 @@ impl TrackHistory {
             self.id_seq = Arc::new(IdSeq::new(meta.next_id));
             self.track
                 .edit(|track| track.reset(util::load(&self.snapshot_path(initial_version_id))));
+            self.edit_count += 1;
         }
         self.set_version(initial_version_id);
         assert!(self.go_to_version(meta.current_version, &mut vec![]));
@@ -382,9 +652,61 @@ struct Meta {
     max_version: VersionId,
 }
 
+/// Commands that change the workspace (bookmarks) rather than the music.
+fn is_workspace_command(command_id: EditCommandType) -> bool {
+    matches!(
+        command_id,
+        EditCommandType::SetBookmark | EditCommandType::ClearBookmark
+    )
+}
+
+/// Whether the changes can be applied in any order: they are change lists of different events.
+fn commutes(entry: &HistoryLogEntry, others: &[HistoryLogEntry]) -> bool {
+    let event_ids = |entry: &HistoryLogEntry| -> Option<HashSet<EventId>> {
+        let mut ids = HashSet::new();
+        for diff in &entry.diff {
+            let CommandDiff::ChangeList { patch } = diff else {
+                return None;
+            };
+            ids.extend(patch.iter().map(|action| action.event_id()));
+        }
+        Some(ids)
+    };
+    let Some(ids) = event_ids(entry) else {
+        return false;
+    };
+    others
+        .iter()
+        .all(|other| event_ids(other).is_some_and(|other_ids| ids.is_disjoint(&other_ids)))
+}
+
+/// Number of distinct events affected by the changes.
+fn changed_events_count(changes: &EventActionsList) -> usize {
+    changes
+        .iter()
+        .map(EventAction::event_id)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Opened history of test/files/short.mid in a fresh target/<name> directory.
+#[cfg(test)]
+pub fn test_history(name: &str) -> TrackHistory {
+    let mut history = TrackHistory::with_directory(&util::fresh_test_dir(name))
+        .init(&PathBuf::from("test/files/short.mid"))
+        .unwrap();
+    history.open();
+    history
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::track::{TrackEvent, TrackEventType};
+    use crate::track_edit::{
+        clear_bookmark, clear_track, set_bookmark, shift_tail, tape_delete_with_selected,
+        transpose_selected_notes,
+    };
 
     #[test]
     fn parse_snapshot_name() {
@@ -408,6 +730,215 @@ mod tests {
         );
     }
 
+    #[test]
+    fn compact_inverse_edits() {
+        let mut history = test_history("test_history_compact");
+        let initial_events = history.with_track(|t| t.events.clone());
+        let selection: HashSet<EventId> = initial_events.iter().map(|ev| ev.id).collect();
+        history.update_track(|track| transpose_selected_notes(track, &selection, 1));
+        history.update_track(|track| transpose_selected_notes(track, &selection, -1));
+        // Loading the file is the first version.
+        assert_eq!(history.version(), 3);
+
+        history.compact();
+        assert_eq!(history.version(), 1);
+        assert_eq!(history.max_version, 1);
+        // The transpositions cancel each other, only the initial inserts remain.
+        let entry: HistoryLogEntry = util::load(&history.diff_path(1));
+        let [CommandDiff::ChangeList { patch }] = &entry.diff[..] else {
+            panic!("expecting a single change list");
+        };
+        assert_eq!(patch.len(), initial_events.len());
+        assert!(patch
+            .iter()
+            .all(|a| matches!(a, EventAction::Insert(ev) if initial_events.contains(ev))));
+
+        let mut reopened = TrackHistory::with_directory(&history.directory);
+        reopened.open();
+        assert_eq!(reopened.version(), 1);
+        assert_eq!(reopened.with_track(|t| t.events.clone()), initial_events);
+    }
+
+    #[test]
+    fn undo_tape_delete_with_selected() {
+        let mut history = test_history("test_history_tape_delete");
+        let initial_events = history.with_track(|t| t.events.clone());
+        let last = initial_events.last().unwrap();
+        let selection = HashSet::from([last.id]);
+        let range = (initial_events[0].at, initial_events[0].at + 1);
+        history.update_track(|track| tape_delete_with_selected(track, &range, &selection));
+        assert_eq!(history.version(), 2);
+        assert!(history.with_track(|t| t.events.iter().all(|ev| ev.id != last.id)));
+
+        let mut changes = vec![];
+        assert!(history.undo(&mut changes));
+        assert_eq!(history.version(), 1);
+        assert_eq!(history.with_track(|t| t.events.clone()), initial_events);
+    }
+
+    #[test]
+    fn undo_clear_track() {
+        let mut history = test_history("test_history_clear_track");
+        let initial_events = history.with_track(|t| t.events.clone());
+        assert!(!initial_events.is_empty());
+        history.update_track(clear_track);
+        assert!(history.with_track(|t| t.events.is_empty()));
+        assert!(history.update_track(clear_track).is_none());
+
+        let mut changes = vec![];
+        assert!(history.undo(&mut changes));
+        assert_eq!(history.version(), 1);
+        assert_eq!(history.with_track(|t| t.events.clone()), initial_events);
+    }
+
+    #[test]
+    fn finished_group_is_not_merged() {
+        let mut history = test_history("test_history_group");
+        let initial_events = history.with_track(|t| t.events.clone());
+        let selection: HashSet<EventId> = initial_events.iter().map(|ev| ev.id).collect();
+        history.begin_group();
+        history.update_track(|track| transpose_selected_notes(track, &selection, 1));
+        let edit_count = history.edit_count();
+        history.update_track(|track| transpose_selected_notes(track, &selection, 1));
+        assert_eq!(history.version(), 2);
+        // The merged edit still has to be picked up by the playback.
+        assert_eq!(history.edit_count(), edit_count + 1);
+        history.finish_group();
+        history.update_track(|track| transpose_selected_notes(track, &selection, 1));
+        assert_eq!(history.version(), 3);
+
+        let mut changes = vec![];
+        assert!(history.undo(&mut changes));
+        assert!(history.undo(&mut changes));
+        assert_eq!(history.with_track(|t| t.events.clone()), initial_events);
+        // Merged version replays as a whole.
+        assert!(history.redo(&mut changes));
+        let pitches = |events: &Vec<TrackEvent>| {
+            events
+                .iter()
+                .filter_map(|ev| match &ev.event {
+                    TrackEventType::Note(n) => Some(n.pitch),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let transposed: Vec<_> = pitches(&initial_events).iter().map(|p| p + 2).collect();
+        assert_eq!(history.with_track(|t| pitches(&t.events)), transposed);
+    }
+
+    #[test]
+    fn periodic_snapshots() {
+        let mut history = test_history("test_history_snapshots");
+        let selection: HashSet<EventId> =
+            history.with_track(|t| t.events.iter().map(|ev| ev.id).collect());
+        let start = history.version();
+        let interval = TrackHistory::SNAPSHOT_INTERVAL;
+        let mut states = vec![];
+        for i in 0..interval + 8 {
+            states.push(history.with_track(|t| t.events.clone()));
+            let delta = if i % 2 == 0 { 3 } else { -2 };
+            history.update_track(|track| transpose_selected_notes(track, &selection, delta));
+        }
+        states.push(history.with_track(|t| t.events.clone()));
+        let snapshot_versions = |history: &TrackHistory| {
+            let mut ids: Vec<VersionId> = history.list_snapshots().map(|(id, _)| id).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(snapshot_versions(&history), vec![0, interval]);
+
+        // Reopened history starts from the latest snapshot and gets the same state.
+        let mut reopened = TrackHistory::with_directory(&history.directory);
+        reopened.open();
+        assert_eq!(reopened.version(), history.version());
+        assert_eq!(
+            reopened.with_track(|t| t.events.clone()),
+            *states.last().unwrap()
+        );
+        for target in [interval + 1, interval - 1, 3, interval + 5] {
+            assert!(reopened.go_to_version(target, &mut vec![]));
+            assert_eq!(
+                reopened.with_track(|t| t.events.clone()),
+                states[(target - start) as usize]
+            );
+        }
+
+        // A new edit after an undo discards the later snapshot.
+        assert!(reopened.go_to_version(interval - 2, &mut vec![]));
+        reopened.update_track(|track| transpose_selected_notes(track, &selection, 1));
+        assert_eq!(reopened.version(), interval - 1);
+        assert_eq!(snapshot_versions(&reopened), vec![0]);
+    }
+
+    #[test]
+    fn undo_keeps_bookmarks() {
+        let mut history = test_history("test_history_bookmarks");
+        let start = history.version();
+        let id_seq = history.id_seq.clone();
+        let initial_events = history.with_track(|t| t.events.clone());
+        let selection: HashSet<EventId> = initial_events.iter().map(|ev| ev.id).collect();
+        let bookmarks = |history: &TrackHistory| {
+            history.with_track(|t| {
+                t.events
+                    .iter()
+                    .filter(|ev| ev.event == TrackEventType::Bookmark)
+                    .map(|ev| ev.at)
+                    .collect::<Vec<_>>()
+            })
+        };
+        history.update_track(|track| transpose_selected_notes(track, &selection, 1));
+        let transposed = history.with_track(|t| t.events.clone());
+        history.update_track(|track| set_bookmark(track, &id_seq, &1000));
+        history.update_track(|track| set_bookmark(track, &id_seq, &2000));
+        history.update_track(|track| clear_bookmark(track, &1000));
+        let top = history.version();
+
+        let mut changes = vec![];
+        assert!(history.undo(&mut changes));
+        assert_eq!(history.version(), top - 1);
+        // Only the transposition is reverted.
+        assert!(changes
+            .iter()
+            .all(|action| matches!(action, EventAction::Update(..))));
+        assert_eq!(bookmarks(&history), vec![2000]);
+        // Reordered versions are written completely, no temporary files are left.
+        assert!(fs::read_dir(&history.directory).unwrap().all(|entry| !entry
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .ends_with(".tmp")));
+        let notes = |events: Vec<TrackEvent>| -> Vec<TrackEvent> {
+            events
+                .into_iter()
+                .filter(|ev| ev.event != TrackEventType::Bookmark)
+                .collect()
+        };
+        assert_eq!(
+            notes(history.with_track(|t| t.events.clone())),
+            initial_events
+        );
+        assert!(history.redo(&mut changes));
+        assert_eq!(
+            notes(history.with_track(|t| t.events.clone())),
+            notes(transposed)
+        );
+        assert_eq!(bookmarks(&history), vec![2000]);
+
+        // Bookmarks move with a tail shift, so it is undone in order.
+        history.update_track(|track| shift_tail(track, &0, &500));
+        history.update_track(|track| set_bookmark(track, &id_seq, &100));
+        assert!(history.undo(&mut changes));
+        assert_eq!(bookmarks(&history), vec![2500]);
+        assert!(history.undo(&mut changes));
+        assert_eq!(bookmarks(&history), vec![2000]);
+
+        // Without earlier musical edits bookmark changes are undone.
+        while history.version() > start {
+            assert!(history.undo(&mut changes));
+        }
+        assert_eq!(history.with_track(|t| t.events.clone()), initial_events);
+    }
+
     #[test]
     fn meta_serialization() {
         let mut history = TrackHistory::with_directory(&PathBuf::from("target"));
@@ -418,4 +949,20 @@ mod tests {
         assert_eq!(321, m.current_version);
         assert_eq!(0, m.next_id);
     }
+
+    #[test]
+    fn count_changed_events() {
+        let bookmark = |id, at| TrackEvent {
+            id,
+            at,
+            event: TrackEventType::Bookmark,
+        };
+        assert_eq!(changed_events_count(&vec![]), 0);
+        let changes = vec![
+            EventAction::Insert(bookmark(1, 10)),
+            EventAction::Delete(bookmark(2, 20)),
+            EventAction::Update(bookmark(1, 10), bookmark(1, 30)),
+        ];
+        assert_eq!(changed_events_count(&changes), 2);
+    }
 }
@@ -1,18 +1,27 @@
 use std::fmt::{Debug, Formatter};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use sync_cow::SyncCow;
 
 use crate::common::Time;
 use crate::engine;
-use crate::engine::{EngineEvent, EventSource};
+use crate::engine::{EngineEvent, EventSource, Transpose};
 use crate::midi::{controller_set, note_off, note_on};
-use crate::track::{Track, TrackEventType};
+use crate::track::{
+    controller_channels, transposed_pitch, ChannelId, ControllerId, Level, Track, TrackEventType,
+};
+use crate::track_edit::cc_value_at;
 
 pub struct TrackSource {
     track: Arc<SyncCow<Track>>,
     current_idx: usize,
+    /// Events before this moment are already produced.
     running_at: Time,
+    /// Playback pitch offset, the track itself is not changed.
+    transpose: Transpose,
+    /// Channels that controller changes are sent to, updated on seek.
+    controller_channels: Vec<ChannelId>,
 }
 
 impl Debug for TrackSource {
@@ -28,11 +37,13 @@ impl Debug for TrackSource {
 }
 
 impl TrackSource {
-    pub fn new(track: Arc<SyncCow<Track>>) -> TrackSource {
+    pub fn new(track: Arc<SyncCow<Track>>, transpose: Transpose) -> TrackSource {
         TrackSource {
             track,
             current_idx: 0,
             running_at: 0,
+            transpose,
+            controller_channels: vec![engine::output_channel(0)],
         }
     }
 }
@@ -71,61 +82,82 @@ impl EventSource for TrackSource {
                 break;
             }
         }
+        self.controller_channels = controller_channels(&track.events)
+            .into_iter()
+            .map(engine::output_channel)
+            .collect();
         self.running_at = *at;
     }
 
     fn next(&mut self, at: &Time) -> Vec<EngineEvent> {
         let track = self.track.read();
+        // Offs are produced together with their ons, so a change does not leave notes hanging.
+        let transpose = self.transpose.load(Ordering::Relaxed);
         let mut events = vec![];
         while self.current_idx < track.events.len() {
             let notes = &track.events;
             let event = &notes[self.current_idx];
             let running_at = event.at;
             if running_at > *at {
-                return events;
+                break;
             }
-            self.running_at = running_at;
             match &event.event {
                 TrackEventType::Note(note) => {
+                    let pitch = transposed_pitch(note.pitch, transpose);
+                    let channel = engine::output_channel(note.channel);
                     events.push(EngineEvent {
                         at: running_at,
-                        event: note_on(engine::MIDI_CHANNEL, note.pitch, note.velocity),
+                        event: note_on(channel, pitch, note.velocity),
                     });
                     events.push(EngineEvent {
                         at: running_at + note.duration,
-                        event: note_off(engine::MIDI_CHANNEL, note.pitch, note.velocity),
+                        event: note_off(channel, pitch, note.velocity),
                     });
                 }
                 TrackEventType::Controller(set_val) => {
-                    events.push(EngineEvent {
-                        at: running_at,
-                        event: controller_set(
-                            engine::MIDI_CHANNEL,
-                            set_val.controller_id,
-                            set_val.value,
-                        ),
-                    });
+                    for channel in &self.controller_channels {
+                        events.push(EngineEvent {
+                            at: running_at,
+                            event: controller_set(*channel, set_val.controller_id, set_val.value),
+                        });
+                    }
                 }
                 // Non audible events.
-                TrackEventType::Bookmark | TrackEventType::Marker(_) => (),
+                TrackEventType::Bookmark
+                | TrackEventType::Marker(_)
+                | TrackEventType::TimeSignature(_) => (),
             }
             self.current_idx += 1;
         }
+        self.running_at = self.running_at.max(at + 1);
         events
     }
+
+    fn resync(&mut self) {
+        // The index may point anywhere in the edited track.
+        let at = self.running_at;
+        self.seek(&at);
+    }
+
+    fn controller_value_at(&self, controller_id: ControllerId, at: &Time) -> Option<Level> {
+        Some(cc_value_at(&self.track.read().events, at, &controller_id))
+    }
+
+    fn controller_channels(&self) -> Vec<ChannelId> {
+        self.controller_channels.clone()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::track;
-    use crate::track::TrackEvent;
+    use crate::track::{note_event, ControllerSetValue, TrackEvent, MAX_LEVEL, MIDI_CC_SUSTAIN_ID};
 
     use super::*;
 
     #[test]
     fn empty_track() {
         let track = Arc::new(SyncCow::new(Track::default()));
-        let mut source = TrackSource::new(track);
+        let mut source = TrackSource::new(track, Transpose::default());
         source.seek(&100_000i64);
         assert_eq!(source.running_at, 100_000);
         source.seek(&0);
@@ -136,18 +168,12 @@ mod tests {
     #[test]
     fn one_note() {
         let mut track = Track::default();
-        track.events.push(TrackEvent {
-            id: 13,
-            at: 1000,
-            event: TrackEventType::Note(track::Note {
-                pitch: 55,
-                velocity: 55,
-                duration: 12,
-            }),
-        });
+        track
+            .events
+            .push(note_event(13, 1000, 55, 12).with_velocity(55));
         let track = Arc::new(SyncCow::new(track));
 
-        let mut source = TrackSource::new(track);
+        let mut source = TrackSource::new(track, Transpose::default());
         source.seek(&0);
         assert_eq!(source.running_at, 0);
         assert_eq!(source.current_idx, 0);
@@ -158,4 +184,97 @@ mod tests {
         assert_eq!(source.running_at, 2000);
         assert_eq!(source.current_idx, 1)
     }
+
+    #[test]
+    fn transposed_playback() {
+        let track = Arc::new(SyncCow::new(Track {
+            events: vec![note_event(1, 100, 60, 10)],
+        }));
+        let transpose = Transpose::default();
+        let mut source = TrackSource::new(track.clone(), transpose.clone());
+        transpose.store(-3, Ordering::Relaxed);
+        source.seek(&0);
+        let events: Vec<EngineEvent> = source.next(&200);
+        assert_eq!(
+            events.iter().map(|ev| ev.event).collect::<Vec<_>>(),
+            vec![
+                note_on(engine::MIDI_CHANNEL, 57, 64),
+                note_off(engine::MIDI_CHANNEL, 57, 64)
+            ]
+        );
+        match &track.read().events[0].event {
+            TrackEventType::Note(note) => assert_eq!(note.pitch, 60),
+            ev => panic!("unexpected {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn notes_play_on_their_channel() {
+        let note = |id, at, channel| note_event(id, at, 60, 10).with_channel(channel);
+        let track = Arc::new(SyncCow::new(Track {
+            events: vec![note(1, 100, 0), note(2, 200, 5)],
+        }));
+        let mut source = TrackSource::new(track, Transpose::default());
+        source.seek(&0);
+        assert_eq!(
+            source
+                .next(&300)
+                .iter()
+                .map(|ev| ev.event)
+                .collect::<Vec<_>>(),
+            vec![
+                note_on(engine::MIDI_CHANNEL, 60, 64),
+                note_off(engine::MIDI_CHANNEL, 60, 64),
+                note_on(engine::output_channel(5), 60, 64),
+                note_off(engine::output_channel(5), 60, 64)
+            ]
+        );
+    }
+
+    #[test]
+    fn reassigned_channel_keeps_pedal() {
+        let track = Arc::new(SyncCow::new(Track {
+            events: vec![
+                TrackEvent {
+                    id: 1,
+                    at: 100,
+                    event: TrackEventType::Controller(ControllerSetValue {
+                        controller_id: MIDI_CC_SUSTAIN_ID,
+                        value: MAX_LEVEL,
+                    }),
+                },
+                note_event(2, 200, 60, 10).with_channel(3),
+            ],
+        }));
+        let mut source = TrackSource::new(track, Transpose::default());
+        source.seek(&0);
+        let events: Vec<_> = source.next(&100).iter().map(|ev| ev.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                controller_set(engine::output_channel(0), MIDI_CC_SUSTAIN_ID, MAX_LEVEL),
+                controller_set(engine::output_channel(3), MIDI_CC_SUSTAIN_ID, MAX_LEVEL),
+            ]
+        );
+    }
+
+    #[test]
+    fn resync_after_edit() {
+        let note = |id, at| note_event(id, at, 60, 10);
+        let track = Arc::new(SyncCow::new(Track {
+            events: vec![note(1, 100), note(2, 200), note(3, 300)],
+        }));
+        let mut source = TrackSource::new(track.clone(), Transpose::default());
+        source.seek(&0);
+        assert_eq!(source.next(&150).len(), 2);
+        assert_eq!(source.current_idx, 1);
+
+        // An earlier event shifts the rest, the index now points to the already played note.
+        track.edit(|t| t.events.insert(0, note(4, 50)));
+        source.resync();
+        assert_eq!(source.current_idx, 2);
+        let events = source.next(&250);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].at, 200);
+    }
 }
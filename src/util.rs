@@ -1,12 +1,13 @@
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::sync::atomic;
+use std::sync::atomic::AtomicBool;
 
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default)]
 pub struct IdSeq(atomic::AtomicU64);
@@ -25,30 +26,129 @@ impl IdSeq {
     }
 }
 
+/// Small deterministic pseudo-random sequence (SplitMix64), for generative edits.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Value in 0..n, n must be positive.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Encoding of stored files (history, project settings, clipboard).
+/// Files of either format can be loaded, compact ones are recognized by the gzip header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageFormat {
+    /// Gzipped MessagePack without field names, the smallest.
+    #[default]
+    Compact,
+    /// MessagePack with field names and no compression, can be read by other tools.
+    Plain,
+}
+
+static PLAIN_STORAGE: AtomicBool = AtomicBool::new(false);
+
+/// Format of files written by [store], applies to the whole application.
+pub fn set_storage_format(format: StorageFormat) {
+    PLAIN_STORAGE.store(format == StorageFormat::Plain, atomic::Ordering::SeqCst);
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 pub fn load<T: DeserializeOwned>(file_path: &PathBuf) -> T {
-    let binary = std::fs::read(file_path).expect(&*format!("load from {}", &file_path.display()));
-    let mut decoder = GzDecoder::new(binary.as_slice());
-    let mut binary = vec![];
-    decoder.read_to_end(&mut binary).expect("unzip serialized");
-    rmp_serde::from_slice(&binary).expect("deserialize")
+    try_load(file_path).unwrap_or_else(|message| panic!("{}", message))
+}
+
+/// Same as [load], for files that can be dropped if they are missing or unreadable.
+pub fn try_load<T: DeserializeOwned>(file_path: &PathBuf) -> Result<T, String> {
+    let mut binary = std::fs::read(file_path)
+        .map_err(|e| format!("load from {}: {}", file_path.display(), e))?;
+    if binary.starts_with(&GZIP_MAGIC) {
+        let mut decoder = GzDecoder::new(binary.as_slice());
+        let mut unzipped = vec![];
+        decoder
+            .read_to_end(&mut unzipped)
+            .map_err(|e| format!("unzip {}: {}", file_path.display(), e))?;
+        binary = unzipped;
+    }
+    rmp_serde::from_slice(&binary)
+        .map_err(|e| format!("deserialize {}: {}", file_path.display(), e))
 }
 
 pub fn store<T: Serialize>(x: &T, file_path: &PathBuf) {
-    let mut binary = Vec::new();
-    x.serialize(
-        // TODO If using compact representation (without field names), add some format version info
-        //  in the data and/or in file names.
-        //  Consider using protobuf.
-        &mut rmp_serde::Serializer::new(&mut binary), /*.with_struct_map()*/
-    )
-    .expect("serialize");
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
-    encoder
-        .write_all(&binary.as_slice())
-        .expect("gzip serialized");
-    let binary = encoder.finish().expect("gzip serialized");
+    let format = if PLAIN_STORAGE.load(atomic::Ordering::SeqCst) {
+        StorageFormat::Plain
+    } else {
+        StorageFormat::Compact
+    };
+    store_as(x, file_path, format);
+}
+
+pub fn store_as<T: Serialize>(x: &T, file_path: &PathBuf, format: StorageFormat) {
+    let binary = match format {
+        StorageFormat::Compact => {
+            // TODO If using compact representation (without field names), add some format version info
+            //  in the data and/or in file names.
+            //  Consider using protobuf.
+            let binary = rmp_serde::to_vec(x).expect("serialize");
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder
+                .write_all(binary.as_slice())
+                .expect("gzip serialized");
+            encoder.finish().expect("gzip serialized")
+        }
+        StorageFormat::Plain => rmp_serde::to_vec_named(x).expect("serialize"),
+    };
     std::fs::write(file_path, &binary).expect(&*format!("write to {}", &file_path.display()));
 }
 
+/// Empty directory target/<name> for the files of a test.
 #[cfg(test)]
-mod tests {}
+pub fn fresh_test_dir(name: &str) -> PathBuf {
+    let directory = PathBuf::from("target").join(name);
+    if directory.exists() {
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::{note_event, TrackEvent};
+
+    #[test]
+    fn storage_formats() {
+        let directory = fresh_test_dir("test_storage_formats");
+        let events = vec![note_event(7, 1_000, 60, 500)];
+        for format in [StorageFormat::Compact, StorageFormat::Plain] {
+            let path = directory.join(format!("{:?}", format));
+            store_as(&events, &path, format);
+            let loaded: Vec<TrackEvent> = load(&path);
+            assert_eq!(loaded, events);
+            let binary = std::fs::read(&path).unwrap();
+            assert_eq!(
+                binary.starts_with(&GZIP_MAGIC),
+                format == StorageFormat::Compact
+            );
+            // Plain files have the field names.
+            let has_names = binary.windows(8).any(|w| w == b"velocity");
+            assert_eq!(has_names, format == StorageFormat::Plain);
+        }
+    }
+}